@@ -2,31 +2,150 @@ use obbidl_derive::include_obbidl_file;
 
 include_obbidl_file!("test.txt");
 
-use obbidl_lib::channel::{TestChannel, TestChannelError};
+pub mod asynchronous {
+    use obbidl_derive::include_obbidl_file;
+
+    include_obbidl_file!("counter.txt", async);
+}
+
+pub mod geometry {
+    use obbidl_derive::include_obbidl_file;
+
+    include_obbidl_file!(
+        "points.txt",
+        items(structs),
+        derive(Debug, Clone, PartialEq),
+        Point { derive(Debug, Clone, Copy, PartialEq) }
+    );
+}
+
+pub mod shapes {
+    use obbidl_derive::include_obbidl_file;
+
+    include_obbidl_file!(
+        "points.txt",
+        items(protocols),
+        structs(crate::geometry),
+        nesting(flat)
+    );
+}
+
+// The same messages in each wire format.
+pub mod binary_messages {
+    use obbidl_derive::include_obbidl_file;
+
+    include_obbidl_file!("messages.txt", derive(Debug, Clone, PartialEq));
+}
+
+pub mod compact_messages {
+    use obbidl_derive::include_obbidl_file;
+
+    include_obbidl_file!("messages.txt", format(compact), derive(Debug, Clone, PartialEq));
+}
+
+pub mod protobuf_messages {
+    use obbidl_derive::include_obbidl_file;
+
+    include_obbidl_file!("messages.txt", format(protobuf), derive(Debug, Clone, PartialEq));
+}
+
+pub mod json_messages {
+    use obbidl_derive::include_obbidl_file;
+
+    include_obbidl_file!("messages.txt", format(json), derive(Debug, Clone, PartialEq));
+}
+
+pub mod cbor_messages {
+    use obbidl_derive::include_obbidl_file;
+
+    include_obbidl_file!("messages.txt", format(cbor), derive(Debug, Clone, PartialEq));
+}
+
+pub mod json_labelled {
+    use obbidl_derive::include_obbidl_file;
+
+    include_obbidl_file!("labelled.txt", format(json));
+}
+
+pub mod cbor_labelled {
+    use obbidl_derive::include_obbidl_file;
+
+    include_obbidl_file!("labelled.txt", format(cbor));
+}
+
+pub mod state_names {
+    use obbidl_derive::include_obbidl_file;
+
+    include_obbidl_file!("names.txt");
+}
+
+// Payload items named like the locals of the generated code.
+pub mod local_names {
+    pub mod binary {
+        use obbidl_derive::include_obbidl_file;
+
+        include_obbidl_file!("locals.txt");
+    }
+
+    pub mod compact {
+        use obbidl_derive::include_obbidl_file;
+
+        include_obbidl_file!("locals.txt", format(compact));
+    }
+
+    pub mod json {
+        use obbidl_derive::include_obbidl_file;
+
+        include_obbidl_file!("locals.txt", format(json));
+    }
+
+    pub mod cbor {
+        use obbidl_derive::include_obbidl_file;
+
+        include_obbidl_file!("locals.txt", format(cbor));
+    }
+
+    pub mod protobuf {
+        use obbidl_derive::include_obbidl_file;
+
+        include_obbidl_file!("locals.txt", format(protobuf));
+    }
+}
+
+use std::error::Error;
+
+use asynchronous::counter;
+use obbidl_lib::{
+    channel::{AsyncTestChannel, RecvError, TestChannel, TestChannelError},
+    test_support::run_both,
+};
+use sensors::{dev, hub};
 use test::{cli, ser};
 
 fn main() {
     thing().unwrap();
+    sensors().unwrap();
+    count().unwrap();
 }
 
-fn thing() -> Result<(), TestChannelError> {
+fn thing() -> Result<(), RecvError<TestChannelError>> {
     let (client_channel, server_channel) = TestChannel::new();
 
-    let client = cli::S0::new(client_channel);
-    let server = ser::S0::new(server_channel);
+    let client = cli::Start::new(client_channel.with_logging());
+    let server = ser::Start::new(server_channel);
 
     let client = client.send_a(26)?.send_b(23)?;
 
     let (server, a) = match server.recv_default()? {
-        ser::S0Response::a { state, param0 } => (state, param0),
+        ser::StartResponse::A { state, param0 } => (state, param0),
     };
     let (server, b) = match server.recv_default()? {
-        ser::S1Response::b { state, param0 } => (state, param0),
+        ser::AResponse::B { state, param0 } => (state, param0),
     };
     server.send_c(a + b)?.finish();
 
     match client.recv_default()? {
-        cli::S2Response::c { state, param0 } => {
+        cli::BResponse::C { state, param0 } => {
             state.finish();
             println!("{}", param0)
         }
@@ -34,3 +153,661 @@ fn thing() -> Result<(), TestChannelError> {
 
     Ok(())
 }
+
+fn sensors() -> Result<(), Box<dyn Error>> {
+    let (hub_channel, dev_channel) = TestChannel::new();
+
+    let hub = hub::Idle::new(hub_channel).send_start()?;
+    let dev = match dev::Idle::new(dev_channel).recv_default()? {
+        dev::IdleResponse::Start { state } => state,
+    };
+
+    let (hub_temperature, hub_humidity, hub_interval) = hub.split()?;
+    let (dev_temperature, dev_humidity, dev_interval) = dev.split()?;
+
+    let hub_interval = hub_interval.send_interval(1000)?.done();
+    let dev_interval = match dev_interval.recv_default()? {
+        dev::IntervalResponse::Interval { state, param0 } => {
+            println!("interval {}", param0);
+            state.done()
+        }
+    };
+
+    let dev_temperature = dev_temperature
+        .send_temperature(21)?
+        .send_temperature(-3)?
+        .done()?;
+    let dev_humidity = dev_humidity.send_humidity(40)?.done()?;
+
+    let mut temperature = hub_temperature;
+    let hub_temperature = loop {
+        match temperature.recv_default()? {
+            hub::TemperatureResponse::Temperature { state, param0 } => {
+                println!("temperature {}", param0);
+                temperature = state;
+            }
+            hub::TemperatureResponse::Done { state } => break state,
+        }
+    };
+    let mut humidity = hub_humidity;
+    let hub_humidity = loop {
+        match humidity.recv_default()? {
+            hub::HumidityResponse::Humidity { state, param0 } => {
+                println!("humidity {}", param0);
+                humidity = state;
+            }
+            hub::HumidityResponse::Done { state } => break state,
+        }
+    };
+
+    hub::Sensing::join(hub_temperature, hub_humidity, hub_interval)
+        .send_stop()?
+        .finish();
+    match dev::Sensing::join(dev_temperature, dev_humidity, dev_interval).recv_default()? {
+        dev::StoppingResponse::Stop { state } => {
+            state.finish();
+        }
+    }
+
+    Ok(())
+}
+
+fn count() -> Result<(), Box<dyn Error>> {
+    let (cli_channel, ser_channel) = AsyncTestChannel::new();
+
+    let client = async {
+        let mut client = counter::cli::Start::connect(cli_channel).await?;
+        for values in [&[1, 2, 3][..], &[10, 20]] {
+            match client.send_add(values).await?.recv_default().await? {
+                counter::cli::AddResponse::Total { state, param0 } => {
+                    println!("total {}", param0);
+                    client = state;
+                }
+            }
+        }
+        client.send_stop().await?.finish();
+        Ok::<_, Box<dyn Error>>(())
+    };
+    let server = async {
+        let mut server = counter::ser::Start::connect(ser_channel).await?;
+        loop {
+            match server.recv_default().await? {
+                counter::ser::StartResponse::Add { state, values } => {
+                    server = state.send_total(values.iter().sum()).await?;
+                }
+                counter::ser::StartResponse::Stop { state } => {
+                    state.finish();
+                    return Ok::<_, Box<dyn Error>>(());
+                }
+            }
+        }
+    };
+
+    let (client, server) = run_both(client, server);
+    client?;
+    server
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, fs, panic, process, thread, time::Duration};
+
+    use obbidl_lib::{
+        cbor,
+        channel::{
+            AsyncChannel, AsyncTestChannel, Channel, Framed, MemoryChannel, RecvError, TestChannel,
+        },
+        json,
+        transcript::{Event, Recorder, Replay, ReplayError},
+    };
+
+    #[cfg(unix)]
+    use obbidl_lib::channel::UnixChannel;
+
+    use super::{
+        counter, dev,
+        geometry::{Point, Triangle},
+        hub, run_both,
+        shapes::{shapes_cli, shapes_ser},
+    };
+
+    #[test]
+    fn test_count() {
+        super::count().unwrap();
+    }
+
+    // The generated futures are 'Send', so they can be moved to another
+    // thread, as a multithreaded executor would.
+    #[test]
+    fn test_async_send() {
+        let (cli_channel, ser_channel) = AsyncTestChannel::new();
+        let client = async {
+            let client = counter::cli::Start::connect(cli_channel).await.unwrap();
+            let client = client.send_add(&[4, 5]).await.unwrap();
+            match client.recv_default().await.unwrap() {
+                counter::cli::AddResponse::Total { state, param0 } => {
+                    state.send_stop().await.unwrap().finish();
+                    param0
+                }
+            }
+        };
+        let server = async {
+            let server = counter::ser::Start::connect(ser_channel).await.unwrap();
+            let server = match server.recv_default().await.unwrap() {
+                counter::ser::StartResponse::Add { state, values } => {
+                    state.send_total(values.iter().sum()).await.unwrap()
+                }
+                counter::ser::StartResponse::Stop { .. } => panic!("expected add"),
+            };
+            match server.recv_default().await.unwrap() {
+                counter::ser::StartResponse::Stop { state } => state.finish(),
+                counter::ser::StartResponse::Add { .. } => panic!("expected stop"),
+            };
+        };
+        let (total, ()) = thread::spawn(move || run_both(client, server)).join().unwrap();
+        assert_eq!(total, 9);
+    }
+
+    #[test]
+    fn test_fixed_array_of_structs() {
+        let (cli_channel, ser_channel) = TestChannel::new();
+        let triangle = Triangle {
+            corners: [
+                Point { x: 0, y: 0 },
+                Point { x: 4, y: 0 },
+                Point { x: 0, y: 3 },
+            ],
+        };
+        shapes_cli::Start::new(cli_channel)
+            .send_triangle(&triangle)
+            .unwrap()
+            .finish();
+        match shapes_ser::Start::new(ser_channel).recv_default().unwrap() {
+            shapes_ser::StartResponse::Triangle { state, shape } => {
+                state.finish();
+                assert_eq!(shape, triangle);
+            }
+        }
+    }
+
+    // Both sides of 'test', the server answers 'a + b'.
+    fn test_server<C: Channel>(channel: C) -> Result<C, RecvError<C::Error>> {
+        use super::test::ser;
+
+        let server = ser::Start::new(channel);
+        let (server, a) = match server.recv_default()? {
+            ser::StartResponse::A { state, param0 } => (state, param0),
+        };
+        let (server, b) = match server.recv_default()? {
+            ser::AResponse::B { state, param0 } => (state, param0),
+        };
+        Ok(server.send_c(a + b)?.finish())
+    }
+
+    fn test_client<C: Channel>(
+        channel: C,
+        a: u32,
+        b: u32,
+    ) -> Result<(u32, C), RecvError<C::Error>> {
+        use super::test::cli;
+
+        let client = cli::Start::new(channel).send_a(a)?.send_b(b)?;
+        match client.recv_default()? {
+            cli::BResponse::C { state, param0 } => Ok((param0, state.finish())),
+        }
+    }
+
+    #[test]
+    fn test_threads() {
+        let (cli_channel, ser_channel) = MemoryChannel::new();
+        let timeout = Duration::from_secs(10);
+        let server = thread::spawn(move || test_server(ser_channel.with_timeout(timeout)));
+        let (c, _) = test_client(cli_channel.with_timeout(timeout), 26, 23).unwrap();
+        assert_eq!(c, 49);
+        server.join().unwrap().unwrap();
+    }
+
+    // The server's answer is still buffered when it finishes.
+    #[cfg(unix)]
+    #[test]
+    fn test_unix_channel() {
+        let (cli_channel, ser_channel) = UnixChannel::pair().unwrap();
+        let server = thread::spawn(move || test_server(ser_channel).unwrap().flush());
+        assert_eq!(test_client(cli_channel, 26, 23).unwrap().0, 49);
+        server.join().unwrap().unwrap();
+    }
+
+    // Every branch of 'sensors' runs on its own thread on both sides.
+    #[test]
+    fn test_branch_threads() {
+        let (hub_channel, dev_channel) = MemoryChannel::new();
+        let dev = thread::spawn(move || {
+            let dev = match dev::Idle::new(dev_channel).recv_default().unwrap() {
+                dev::IdleResponse::Start { state } => state,
+            };
+            let (temperature, humidity, interval) = dev.split().unwrap();
+            let temperature = thread::spawn(move || {
+                temperature
+                    .send_temperature(21)
+                    .unwrap()
+                    .send_temperature(-3)
+                    .unwrap()
+                    .done()
+                    .unwrap()
+            });
+            let humidity =
+                thread::spawn(move || humidity.send_humidity(40).unwrap().done().unwrap());
+            let interval = match interval.recv_default().unwrap() {
+                dev::IntervalResponse::Interval { state, param0 } => {
+                    assert_eq!(param0, 1000);
+                    state.done()
+                }
+            };
+            let dev = dev::Sensing::join(
+                temperature.join().unwrap(),
+                humidity.join().unwrap(),
+                interval,
+            );
+            match dev.recv_default().unwrap() {
+                dev::StoppingResponse::Stop { state } => state.finish(),
+            };
+        });
+
+        let hub = hub::Idle::new(hub_channel).send_start().unwrap();
+        let (temperature, humidity, interval) = hub.split().unwrap();
+        let temperature = thread::spawn(move || {
+            let mut temperature = temperature;
+            let mut values = vec![];
+            loop {
+                match temperature.recv_default().unwrap() {
+                    hub::TemperatureResponse::Temperature { state, param0 } => {
+                        values.push(param0);
+                        temperature = state;
+                    }
+                    hub::TemperatureResponse::Done { state } => return (state, values),
+                }
+            }
+        });
+        let humidity = thread::spawn(move || match humidity.recv_default().unwrap() {
+            hub::HumidityResponse::Humidity { state, param0 } => {
+                assert_eq!(param0, 40);
+                match state.recv_default().unwrap() {
+                    hub::HumidityResponse::Done { state } => state,
+                    hub::HumidityResponse::Humidity { .. } => panic!("expected the end"),
+                }
+            }
+            hub::HumidityResponse::Done { .. } => panic!("expected a humidity"),
+        });
+        let interval = interval.send_interval(1000).unwrap().done();
+
+        let (temperature, values) = temperature.join().unwrap();
+        assert_eq!(values, [21, -3]);
+        hub::Sensing::join(temperature, humidity.join().unwrap(), interval)
+            .send_stop()
+            .unwrap()
+            .finish();
+        dev.join().unwrap();
+    }
+
+    #[test]
+    fn test_record_replay() {
+        let path = env::temp_dir().join(format!("obbidl-transcript-{}.txt", process::id()));
+        let (cli_channel, ser_channel) = MemoryChannel::new();
+        let server = thread::spawn(move || test_server(ser_channel));
+        let recorder = Recorder::create(cli_channel, &path).unwrap();
+        assert_eq!(test_client(recorder, 26, 23).unwrap().0, 49);
+        server.join().unwrap().unwrap();
+
+        // The server isn't needed to replay the session, which uses all of
+        // the transcript.
+        let replay = Replay::open(&path).unwrap();
+        let (c, replay) = test_client(replay, 26, 23).unwrap();
+        assert_eq!(c, 49);
+        replay.finish().unwrap();
+
+        // 'b' is the second record, and its last byte is different.
+        let replay = Replay::open(&path).unwrap();
+        assert_eq!(
+            test_client(replay, 26, 24).err(),
+            Some(RecvError::Channel(ReplayError {
+                record: 1,
+                offset: 3,
+                expected: Event::Send(23),
+                found: Event::Send(24),
+            }))
+        );
+
+        // A client that stops after 'a' leaves the rest of the transcript.
+        let stopped = panic::catch_unwind(|| {
+            let replay = Replay::open(&path).unwrap().check_on_drop();
+            let client = super::test::cli::Start::new(replay).send_a(26).unwrap();
+            drop(client);
+        });
+        assert!(stopped.is_err());
+        fs::remove_file(&path).unwrap();
+    }
+
+    // Sends one of each message and returns what was received, so the formats
+    // can be compared.
+    macro_rules! round_trip {
+        ($format:ident) => {
+            round_trip!($format, |channel| channel)
+        };
+        // 'wrap' is applied to both ends of the channel.
+        ($format:ident, $wrap:expr) => {{
+            use super::$format::{
+                messages::{cli, ser},
+                Frame, Reading,
+            };
+
+            let (cli_channel, ser_channel) = TestChannel::new();
+            let wrap = $wrap;
+            let (cli_channel, ser_channel) = (wrap(cli_channel), wrap(ser_channel));
+            let frame = Frame {
+                corners: [
+                    Reading {
+                        sensor: 1,
+                        values: vec![-1, i16::MAX],
+                        ok: true,
+                    },
+                    Reading {
+                        sensor: 2,
+                        values: vec![],
+                        ok: false,
+                    },
+                ],
+                tags: [vec![u64::MAX], vec![]],
+            };
+            cli::Start::new(cli_channel)
+                .send_ints(
+                    u8::MAX,
+                    u16::MAX,
+                    u32::MAX,
+                    u64::MAX,
+                    i8::MIN,
+                    i16::MIN,
+                    i32::MIN,
+                    i64::MIN,
+                )
+                .unwrap()
+                .send_flags(true, &[false, true])
+                .unwrap()
+                .send_frame(&frame)
+                .unwrap()
+                .send_nested(&[[1, 2], [3, 4]], &[vec![5], vec![]])
+                .unwrap()
+                .send_done()
+                .unwrap()
+                .finish();
+
+            let mut received = vec![];
+            let mut ser = ser::Start::new(ser_channel);
+            loop {
+                ser = match ser.recv_default().unwrap() {
+                    ser::StartResponse::Ints {
+                        state,
+                        a,
+                        b,
+                        c,
+                        d,
+                        e,
+                        f,
+                        g,
+                        h,
+                    } => {
+                        received.push(format!("{:?}", (a, b, c, d, e, f, g, h)));
+                        state
+                    }
+                    ser::StartResponse::Flags {
+                        state,
+                        param0,
+                        param1,
+                    } => {
+                        received.push(format!("{:?}", (param0, param1)));
+                        state
+                    }
+                    ser::StartResponse::Frame { state, param0 } => {
+                        assert_eq!(param0, frame);
+                        received.push(format!("{:?}", param0));
+                        state
+                    }
+                    ser::StartResponse::Nested {
+                        state,
+                        param0,
+                        param1,
+                    } => {
+                        received.push(format!("{:?}", (param0, param1)));
+                        state
+                    }
+                    ser::StartResponse::Done { state } => {
+                        state.finish();
+                        break;
+                    }
+                };
+            }
+            received
+        }};
+    }
+
+    #[test]
+    fn test_format_round_trip() {
+        let binary = round_trip!(binary_messages);
+        assert_eq!(binary.len(), 4);
+        assert_eq!(round_trip!(compact_messages), binary);
+        assert_eq!(round_trip!(json_messages), binary);
+        assert_eq!(round_trip!(cbor_messages), binary);
+        assert_eq!(round_trip!(protobuf_messages), binary);
+        assert_eq!(round_trip!(binary_messages, Framed::new), binary);
+        assert_eq!(round_trip!(json_messages, Framed::new), binary);
+    }
+
+    #[test]
+    fn test_json_lines() {
+        use super::json_messages::messages::cli;
+
+        let (cli_channel, mut ser_channel) = TestChannel::new();
+        cli::Start::new(cli_channel)
+            .send_flags(true, &[false])
+            .unwrap()
+            .send_done()
+            .unwrap()
+            .finish();
+        assert_eq!(
+            json::recv(&mut ser_channel).unwrap().to_string(),
+            r#"{"label":"flags","data":{"param0":true,"param1":[false]}}"#
+        );
+        assert_eq!(
+            json::recv(&mut ser_channel).unwrap().to_string(),
+            r#"{"label":"done"}"#
+        );
+    }
+
+    #[test]
+    fn test_compact_ints() {
+        use super::compact_messages::messages::cli;
+
+        let (cli_channel, mut ser_channel) = TestChannel::new();
+        cli::Start::new(cli_channel)
+            .send_ints(1, 2, 3, 4, -1, -2, -3, -4)
+            .unwrap()
+            .send_done()
+            .unwrap()
+            .finish();
+        // The tag, then a byte for each integer.
+        let mut sent = [0; 9];
+        ser_channel.recv(&mut sent).unwrap();
+        assert_eq!(sent, [3, 1, 2, 3, 4, 0xff, 3, 5, 7]);
+    }
+
+    #[test]
+    fn test_protobuf_messages() {
+        use super::protobuf_messages::messages::cli;
+
+        let (cli_channel, mut ser_channel) = TestChannel::new();
+        cli::Start::new(cli_channel)
+            .send_flags(true, &[false])
+            .unwrap()
+            .send_done()
+            .unwrap()
+            .finish();
+        // The length, field 2 'flags' holding field 1 'true' and field 2
+        // packed '[false]', then the length and field 1 'done'.
+        let mut sent = [0; 11];
+        ser_channel.recv(&mut sent).unwrap();
+        assert_eq!(
+            sent,
+            [0x07, 0x12, 0x05, 0x08, 0x01, 0x12, 0x01, 0x00, 0x02, 0x0a, 0x00]
+        );
+    }
+
+    #[test]
+    fn test_cbor_maps() {
+        use super::cbor_messages::messages::cli;
+
+        let (cli_channel, mut ser_channel) = TestChannel::new();
+        cli::Start::new(cli_channel)
+            .send_flags(true, &[false])
+            .unwrap()
+            .send_done()
+            .unwrap()
+            .finish();
+        assert_eq!(
+            cbor::recv(&mut ser_channel).unwrap(),
+            cbor::Value::Map(vec![
+                ("label".to_string(), cbor::Value::Text("flags".to_string())),
+                (
+                    "data".to_string(),
+                    cbor::Value::Map(vec![
+                        ("param0".to_string(), cbor::Value::Bool(true)),
+                        (
+                            "param1".to_string(),
+                            cbor::Value::Array(vec![cbor::Value::Bool(false)])
+                        ),
+                    ])
+                ),
+            ])
+        );
+        let mut done = [0; 12];
+        ser_channel.recv(&mut done).unwrap();
+        assert_eq!(done, *b"\xa1\x65label\x64done");
+    }
+
+    // Payload items named like the keys the formats use themselves.
+    #[test]
+    fn test_label_field() {
+        macro_rules! round_trip {
+            ($module:ident) => {{
+                use super::$module::labelled::{cli, ser};
+
+                let (cli_channel, ser_channel) = TestChannel::new();
+                cli::Start::new(cli_channel)
+                    .send_note(7, true)
+                    .unwrap()
+                    .finish();
+                match ser::Start::new(ser_channel).recv_default().unwrap() {
+                    ser::StartResponse::Note { state, label, data } => {
+                        state.finish();
+                        (label, data)
+                    }
+                    ser::StartResponse::Label { .. } => panic!("expected note"),
+                }
+            }};
+        }
+
+        assert_eq!(round_trip!(json_labelled), (7, true));
+        assert_eq!(round_trip!(cbor_labelled), (7, true));
+    }
+
+    #[test]
+    fn test_local_names() {
+        macro_rules! round_trip {
+            ($module:ident) => {{
+                use super::local_names::$module::locals::{cli, ser};
+
+                let (cli_channel, ser_channel) = TestChannel::new();
+                cli::Start::new(cli_channel)
+                    .send_note(1, 6, 2, 7, true, 3, 4, 5)
+                    .unwrap()
+                    .finish();
+                match ser::Start::new(ser_channel).recv_default().unwrap() {
+                    ser::StartResponse::Note {
+                        state,
+                        receiver,
+                        buf,
+                        message,
+                        payload,
+                        data,
+                        id,
+                        number,
+                        tag,
+                    } => {
+                        state.finish();
+                        (receiver, buf, message, payload, data, id, number, tag)
+                    }
+                    ser::StartResponse::Skip { .. } => panic!("expected note"),
+                }
+            }};
+        }
+
+        let expected = (1, 6, 2, 7, true, 3, 4, 5);
+        assert_eq!(round_trip!(binary), expected);
+        assert_eq!(round_trip!(compact), expected);
+        assert_eq!(round_trip!(json), expected);
+        assert_eq!(round_trip!(cbor), expected);
+        assert_eq!(round_trip!(protobuf), expected);
+    }
+
+    // The state after 'FooResponse' is renamed, since 'FooResponse' is the
+    // type 'Foo' receives into.
+    #[test]
+    fn test_state_named_like_response() {
+        use super::state_names::names::{cli, ser};
+
+        let (cli_channel, ser_channel) = TestChannel::new();
+        let client = cli::Start::new(cli_channel).send_foo().unwrap();
+        let server = match ser::Start::new(ser_channel).recv_default().unwrap() {
+            ser::StartResponse::Foo { state } => state.send_foo_response(7).unwrap(),
+        };
+        let client = match client.recv_default().unwrap() {
+            cli::FooResponse::FooResponse { state, param0 } => {
+                assert_eq!(param0, 7);
+                state
+            }
+        };
+        let _: cli::FooResponse2<_> = client;
+        client.send_bar().unwrap().finish();
+        match server.recv_default().unwrap() {
+            ser::FooResponse2Response::Bar { state } => state.finish(),
+        };
+    }
+
+    #[test]
+    fn test_unknown_label() {
+        use super::json_messages::messages::ser;
+
+        let (mut cli_channel, ser_channel) = TestChannel::new();
+        cli_channel.send(b"{\"label\":\"stop\"}\n").unwrap();
+        let received = ser::Start::new(ser_channel).recv_default();
+        assert!(matches!(
+            received,
+            Err(RecvError::UnknownLabel { state: "Start", label }) if label == "stop"
+        ));
+    }
+
+    #[test]
+    fn test_unknown_tag() {
+        let (mut cli_channel, ser_channel) = AsyncTestChannel::new();
+        let (sent, received) = run_both(
+            cli_channel.send(&[7]),
+            counter::ser::Start::new(ser_channel).recv_default(),
+        );
+        sent.unwrap();
+        assert!(matches!(
+            received,
+            Err(RecvError::UnknownTag {
+                state: "Start",
+                tag: 7
+            })
+        ));
+    }
+}
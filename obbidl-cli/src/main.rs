@@ -5,23 +5,63 @@ use std::{
 };
 
 use obbidl_lib::{
-    ast::File, compile::compile_protocol_file, format::binary::Binary, format_rust,
-    generate::GenerateRust, parser::parse, validate::validate_protocol_file,
+    compile::Limits,
+    compiler::{Compiler, Target},
+    format::WireFormat,
+    generate::Mode,
+    lint::{Level, Lint, LintConfig},
 };
 
 fn main() -> ExitCode {
     let mut graph = false;
+    let mut proto = false;
     let mut path = None;
     let mut svg = false;
     let mut output_path = None;
+    let mut lints = LintConfig::default();
+    let mut limits = Limits::default();
+    let mut format = WireFormat::Binary;
+    let mut mode = Mode::Blocking;
 
     for arg in env::args().skip(1) {
         match arg.as_str() {
             "--graph" => graph = true,
             "--svg" => svg = true,
+            "--proto" => proto = true,
+            "--async" => mode = Mode::Async,
             arg => {
                 if let Some(arg) = arg.strip_prefix("--output=") {
                     output_path = Some(arg.to_string());
+                } else if let Some(arg) = arg.strip_prefix("--format=") {
+                    format = match arg {
+                        "binary" => WireFormat::Binary,
+                        "json" => WireFormat::Json,
+                        "cbor" => WireFormat::Cbor,
+                        "compact" => WireFormat::Compact,
+                        "protobuf" => WireFormat::Protobuf,
+                        _ => {
+                            println!("unknown format '{}'", arg);
+                            return ExitCode::FAILURE;
+                        }
+                    };
+                } else if let Some(arg) = arg.strip_prefix("--max-states=") {
+                    let Ok(max_states) = arg.parse() else {
+                        println!("invalid state limit '{}'", arg);
+                        return ExitCode::FAILURE;
+                    };
+                    limits.max_states = max_states;
+                } else if let Some(arg) = arg.strip_prefix("--max-transitions=") {
+                    let Ok(max_transitions) = arg.parse() else {
+                        println!("invalid transition limit '{}'", arg);
+                        return ExitCode::FAILURE;
+                    };
+                    limits.max_transitions = max_transitions;
+                } else if let Some((level, name)) = lint_arg(arg) {
+                    let Some(lint) = Lint::from_name(name) else {
+                        println!("unknown lint '{}'", name);
+                        return ExitCode::FAILURE;
+                    };
+                    lints.set(lint, level);
                 } else {
                     path = Some(arg.to_string());
                 }
@@ -34,51 +74,51 @@ fn main() -> ExitCode {
         return ExitCode::FAILURE;
     };
 
-    let source = fs::read_to_string(path).unwrap();
-    let file = match parse::<File>(&source) {
-        Ok(ast) => ast,
-        Err(err) => {
-            println!("{}", err);
-            return ExitCode::FAILURE;
-        }
+    if svg && !graph {
+        println!("flag --svg must be used with flag --graph");
+        return ExitCode::FAILURE;
+    }
+    let target = if graph {
+        Target::Graph
+    } else if proto {
+        Target::Proto
+    } else {
+        Target::Rust
     };
-    let file_fsm = compile_protocol_file(&file);
 
-    let output = if graph {
-        let graph = file_fsm.graph_viz().to_string();
-        if svg {
-            let graph_path = temp_dir().join("output.dot");
-            if fs::write(&graph_path, graph).is_err() {
-                println!("cannot create temporary file");
-                return ExitCode::FAILURE;
+    let source = fs::read_to_string(path).unwrap();
+    let compiler = Compiler::new()
+        .target(target)
+        .format(format)
+        .mode(mode)
+        .lints(lints)
+        .limits(limits)
+        .rustfmt(true);
+    let mut output = match compiler.compile_source(&source) {
+        Ok(mut output) => {
+            for warning in &output.warnings {
+                eprintln!("{}", warning);
             }
-            let output = Command::new("dot")
-                .arg("-Tsvg")
-                .arg(&graph_path)
-                .output()
-                .unwrap();
-            String::from_utf8(output.stdout).unwrap()
-        } else {
-            graph
+            output.files.remove(0).code
         }
-    } else {
-        if svg {
-            println!("flag --svg must be used with flag --graph");
+        Err(diagnostics) => {
+            println!("{}", diagnostics);
             return ExitCode::FAILURE;
         }
-        let file = match validate_protocol_file(&file_fsm, &file.structs) {
-            Ok(file) => file,
-            Err(errors) => {
-                for err in errors {
-                    println!("{}", err.pretty_print(&source));
-                }
-                return ExitCode::FAILURE;
-            }
-        };
-        let output = GenerateRust::<Binary>::new(&file).to_string();
-
-        format_rust(&output)
     };
+    if svg {
+        let graph_path = temp_dir().join("output.dot");
+        if fs::write(&graph_path, output).is_err() {
+            println!("cannot create temporary file");
+            return ExitCode::FAILURE;
+        }
+        let svg = Command::new("dot")
+            .arg("-Tsvg")
+            .arg(&graph_path)
+            .output()
+            .unwrap();
+        output = String::from_utf8(svg.stdout).unwrap();
+    }
 
     if let Some(output_path) = output_path {
         if fs::write(output_path, output).is_err() {
@@ -91,3 +131,13 @@ fn main() -> ExitCode {
 
     ExitCode::SUCCESS
 }
+
+fn lint_arg(arg: &str) -> Option<(Level, &str)> {
+    if let Some(name) = arg.strip_prefix("--allow=") {
+        Some((Level::Allow, name))
+    } else if let Some(name) = arg.strip_prefix("--warn=") {
+        Some((Level::Warn, name))
+    } else {
+        arg.strip_prefix("--deny=").map(|name| (Level::Deny, name))
+    }
+}
@@ -1,27 +1,246 @@
 use std::{env, fs, path::Path};
 
+use obbidl_lib::{
+    compiler::Compiler,
+    format::WireFormat,
+    generate::{Items, Layout, Mode, Nesting, Options, StructOptions, Visibility},
+};
 use proc_macro::TokenStream;
-use quote::quote;
-use syn::LitStr;
+use quote::{quote, quote_spanned, ToTokens};
+use syn::{
+    braced, parenthesized,
+    parse::{Parse, ParseStream},
+    punctuated::Punctuated,
+    Ident, LitStr, Token,
+};
+
+// A path, followed by a comma separated list of options:
+//   async                     generate async code
+//   format(compact)           'binary' by default, or 'compact', 'json', 'cbor'
+//                             or 'protobuf'
+//   derive(Debug, Clone)      derives for every struct
+//   vis(pub(crate))           visibility of struct fields
+//   Point { derive(Default) } options for the struct 'Point' only
+//   structs(crate::proto)     the module the structs are generated in
+//   nesting(flat)             'protocol_role' modules instead of nested ones
+//   items(structs)            only generate the structs or the protocols
+struct Input {
+    path: LitStr,
+    options: Options,
+    // Whether there are options besides 'async' and 'format'.
+    customized: bool,
+}
+
+impl Parse for Input {
+    fn parse(input: ParseStream) -> syn::Result<Input> {
+        let path = input.parse()?;
+        let mut options = Options::default();
+        let mut overrides = vec![];
+        let mut customized = false;
+        while input.parse::<Option<Token![,]>>()?.is_some() {
+            if input.parse::<Option<Token![async]>>()?.is_some() {
+                options.mode = Mode::Async;
+                continue;
+            } else if is_option(input, &["format"])? {
+                options.format = parse_format(input)?;
+                continue;
+            }
+            customized = true;
+            if input.peek2(syn::token::Brace) {
+                let name: Ident = input.parse()?;
+                let content;
+                braced!(content in input);
+                let struct_options =
+                    Punctuated::<StructOption, Token![,]>::parse_terminated(&content)?;
+                overrides.push((name.to_string(), struct_options));
+            } else if is_option(input, &["structs", "nesting", "items"])? {
+                parse_layout_option(input, &mut options.layout)?;
+            } else {
+                input.parse::<StructOption>()?.apply(&mut options.structs);
+            }
+        }
+        // Options for a struct start out as the ones for the whole file,
+        // whichever order they were written in.
+        for (name, struct_options) in overrides {
+            let mut overridden = options.structs.clone();
+            for option in struct_options {
+                option.apply(&mut overridden);
+            }
+            options.struct_overrides.insert(name, overridden);
+        }
+        Ok(Input {
+            path,
+            options,
+            customized,
+        })
+    }
+}
+
+// The same file may be included more than once with different options, so
+// the output is named after them, e.g. 'points.json.async.rs'. Options other
+// than the format and mode only go in as a hash of the macro's input, which
+// unlike 'DefaultHasher' is the same in every build.
+fn output_name(input: &Input, tokens: &str) -> String {
+    let mut name = format!("{:?}", input.options.format).to_lowercase();
+    if input.options.mode == Mode::Async {
+        name.push_str(".async");
+    }
+    if input.customized {
+        // FNV-1a
+        let mut hash: u64 = 0xcbf29ce484222325;
+        for byte in tokens.bytes() {
+            hash ^= byte as u64;
+            hash = hash.wrapping_mul(0x100000001b3);
+        }
+        name.push_str(&format!(".{:016x}", hash));
+    }
+    name.push_str(".rs");
+    name
+}
+
+fn is_option(input: ParseStream, names: &[&str]) -> syn::Result<bool> {
+    let name: Ident = input.fork().parse()?;
+    Ok(names.iter().any(|option| name == option))
+}
+
+fn parse_format(input: ParseStream) -> syn::Result<WireFormat> {
+    input.parse::<Ident>()?;
+    let content;
+    parenthesized!(content in input);
+    let value: Ident = content.parse()?;
+    match value.to_string().as_str() {
+        "binary" => Ok(WireFormat::Binary),
+        "json" => Ok(WireFormat::Json),
+        "cbor" => Ok(WireFormat::Cbor),
+        "compact" => Ok(WireFormat::Compact),
+        "protobuf" => Ok(WireFormat::Protobuf),
+        _ => Err(syn::Error::new(value.span(), "unknown format")),
+    }
+}
+
+fn parse_layout_option(input: ParseStream, layout: &mut Layout) -> syn::Result<()> {
+    let name: Ident = input.parse()?;
+    let content;
+    parenthesized!(content in input);
+    if name == "structs" {
+        let path: syn::Path = content.parse()?;
+        layout.structs_path = Some(path.to_token_stream().to_string().replace(' ', ""));
+        return Ok(());
+    }
+    let value: Ident = content.parse()?;
+    match (name.to_string().as_str(), value.to_string().as_str()) {
+        ("nesting", "nested") => layout.nesting = Nesting::Nested,
+        ("nesting", "flat") => layout.nesting = Nesting::Flat,
+        ("items", "all") => layout.items = Items::All,
+        ("items", "structs") => layout.items = Items::Structs,
+        ("items", "protocols") => layout.items = Items::Protocols,
+        _ => return Err(syn::Error::new(value.span(), "unknown value")),
+    }
+    Ok(())
+}
+
+enum StructOption {
+    Derive(Vec<String>),
+    Vis(Visibility),
+}
+
+impl StructOption {
+    fn apply(self, options: &mut StructOptions) {
+        match self {
+            StructOption::Derive(derives) => options.derives = derives,
+            StructOption::Vis(visibility) => options.visibility = visibility,
+        }
+    }
+}
+
+impl Parse for StructOption {
+    fn parse(input: ParseStream) -> syn::Result<StructOption> {
+        let name: Ident = input.parse()?;
+        let content;
+        parenthesized!(content in input);
+        if name == "derive" {
+            let paths = Punctuated::<syn::Path, Token![,]>::parse_terminated(&content)?;
+            Ok(StructOption::Derive(
+                paths
+                    .iter()
+                    .map(|path| path.to_token_stream().to_string().replace(' ', ""))
+                    .collect(),
+            ))
+        } else if name == "vis" {
+            Ok(StructOption::Vis(match content.parse()? {
+                syn::Visibility::Public(_) => Visibility::Public,
+                syn::Visibility::Restricted(restricted) if restricted.path.is_ident("crate") => {
+                    Visibility::Crate
+                }
+                syn::Visibility::Restricted(restricted) => {
+                    let path = restricted
+                        .path
+                        .to_token_stream()
+                        .to_string()
+                        .replace(' ', "");
+                    match restricted.in_token {
+                        Some(_) => Visibility::Restricted(format!("in {}", path)),
+                        None => Visibility::Restricted(path),
+                    }
+                }
+                syn::Visibility::Inherited => Visibility::Private,
+            }))
+        } else {
+            Err(syn::Error::new(name.span(), "expected 'derive' or 'vis'"))
+        }
+    }
+}
 
 #[proc_macro]
 pub fn include_obbidl_file(tokens: TokenStream) -> TokenStream {
     let cwd = env::current_dir().unwrap();
-    let str_lit: LitStr = syn::parse_macro_input!(tokens);
-    let str = str_lit.value();
+    let tokens_str = tokens.to_string();
+    let input: Input = syn::parse_macro_input!(tokens);
+    let str = input.path.value();
     let path = cwd.join(&str);
     let path_str = path.to_str().unwrap();
     let source = fs::read_to_string(&path).unwrap();
-    let output = obbidl_lib::build1(&source);
+    let output_name = output_name(&input, &tokens_str);
+    let (output, warnings) = match Compiler::new()
+        .options(input.options)
+        .rustfmt(true)
+        .compile_source(&source)
+    {
+        Ok(mut output) => (output.files.remove(0).code, output.warnings),
+        Err(diagnostics) => {
+            let message = format!("cannot compile '{}':\n{}", str, diagnostics);
+            return syn::Error::new(input.path.span(), message)
+                .to_compile_error()
+                .into();
+        }
+    };
 
     let out_dir = env::var("OUT_DIR").unwrap();
-    let output_path = Path::new(&out_dir).join(&str).with_extension("rs");
+    let output_path = Path::new(&out_dir).join(&str).with_extension(output_name);
     let output_path_str = output_path.to_str();
-    fs::write(&output_path, output).unwrap();
+    // Rewriting an unchanged file would make cargo rebuild whatever depends
+    // on its modification time.
+    if fs::read_to_string(&output_path).ok().as_deref() != Some(output.as_str()) {
+        fs::write(&output_path, output).unwrap();
+    }
+
+    // Proc macros can't emit warnings on stable, so each one is the note of a
+    // deprecated item that is used straight away.
+    let warnings = warnings.iter().map(|warning| {
+        let note = format!("in '{}':\n{}", str, warning.message.trim_end());
+        quote_spanned! {input.path.span()=>
+            const _: () = {
+                #[deprecated(note = #note)]
+                struct ObbidlWarning;
+                let _ = ObbidlWarning;
+            };
+        }
+    });
 
     quote! {
         const SOURCE: &str = include_str!(#path_str);
         include!(#output_path_str);
+        #(#warnings)*
     }
     .into()
 }
@@ -19,7 +19,13 @@ pub struct Protocol {
 #[derive(Debug, Clone, PartialEq)]
 pub struct Struct {
     pub name: String,
-    pub fields: Vec<(String, Type)>,
+    pub fields: Vec<Span<Field>>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Field {
+    pub name: String,
+    pub ty: Type,
 }
 
 #[derive(Debug, Clone, PartialEq, Eq, Hash)]
@@ -32,6 +38,13 @@ pub enum Stmt {
     Choice(Sequences),
     Fin(Sequence),
     Inf(Sequence),
+    State(Span<StateLabel>),
+}
+
+// Names the state the protocol is in at this point.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct StateLabel {
+    pub name: String,
 }
 
 #[derive(Debug, Clone)]
@@ -61,6 +74,7 @@ impl Eq for Sequences {}
 pub struct Message {
     pub label: String,
     pub payload: Payload,
+    pub id: Option<u64>,
     pub from: Role,
     pub to: Role,
 }
@@ -133,7 +147,7 @@ pub struct File {
 
 #[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub struct Payload {
-    pub items: Vec<PayloadItem>,
+    pub items: Vec<Span<PayloadItem>>,
 }
 
 #[derive(Debug, Clone, PartialEq, Eq, Hash)]
@@ -148,6 +162,51 @@ impl Payload {
     }
 }
 
+impl Sequence {
+    pub fn first_message(&self) -> Option<&Span<Message>> {
+        self.0.iter().find_map(Stmt::first_message)
+    }
+    // Every message in the sequence, in the order they are written in.
+    pub fn messages(&self) -> Vec<&Span<Message>> {
+        let mut msgs = vec![];
+        for stmt in &self.0 {
+            match stmt {
+                Stmt::Message(msg) => msgs.push(msg),
+                Stmt::Par(seqs) | Stmt::Choice(seqs) => {
+                    msgs.extend(seqs.0.iter().flat_map(Sequence::messages))
+                }
+                Stmt::Fin(seq) | Stmt::Inf(seq) => msgs.extend(seq.messages()),
+                Stmt::State(_) => {}
+            }
+        }
+        msgs
+    }
+}
+
+impl Sequence {
+    // The first 'par' block in the sequence, including ones nested in other
+    // statements.
+    pub fn first_par(&self) -> Option<&Sequences> {
+        self.0.iter().find_map(|stmt| match stmt {
+            Stmt::Par(seqs) => Some(seqs),
+            Stmt::Choice(seqs) => seqs.0.iter().find_map(Sequence::first_par),
+            Stmt::Fin(seq) | Stmt::Inf(seq) => seq.first_par(),
+            Stmt::Message(_) | Stmt::State(_) => None,
+        })
+    }
+}
+
+impl Stmt {
+    pub fn first_message(&self) -> Option<&Span<Message>> {
+        match self {
+            Stmt::Message(msg) => Some(msg),
+            Stmt::Par(seqs) | Stmt::Choice(seqs) => seqs.0.iter().find_map(Sequence::first_message),
+            Stmt::Fin(seq) | Stmt::Inf(seq) => seq.first_message(),
+            Stmt::State(_) => None,
+        }
+    }
+}
+
 impl Parse for Role {
     fn parse<'a>(parser: &mut Parser<'a>) -> ParseResult<'a, Self> {
         Ok(Role(parser.expect_token(TokenType::Ident)?.to_string()))
@@ -161,14 +220,7 @@ impl Parse for Payload {
             .eat_token(TokenType::Symbol(Symbol::CloseBrace))
             .is_none()
         {
-            let name = if let Some(token) = parser.eat_token(TokenType::Ident) {
-                parser.expect_token(TokenType::Symbol(Symbol::Colon))?;
-                Some(token.to_string())
-            } else {
-                None
-            };
-            let ty = parser.parse()?;
-            items.push(PayloadItem { name, ty });
+            items.push(parser.parse()?);
             if !parser.eat_token(TokenType::Symbol(Symbol::Comma)).is_some() {
                 parser.expect_token(TokenType::Symbol(Symbol::CloseBrace))?;
                 break;
@@ -178,6 +230,19 @@ impl Parse for Payload {
     }
 }
 
+impl Parse for PayloadItem {
+    fn parse<'a>(parser: &mut Parser<'a>) -> ParseResult<'a, Self> {
+        let name = if let Some(token) = parser.eat_token(TokenType::Ident) {
+            parser.expect_token(TokenType::Symbol(Symbol::Colon))?;
+            Some(token.to_string())
+        } else {
+            None
+        };
+        let ty = parser.parse()?;
+        Ok(PayloadItem { name, ty })
+    }
+}
+
 impl Parse for Message {
     fn parse<'a>(parser: &mut Parser<'a>) -> ParseResult<'a, Self> {
         if let Some(label) = parser.eat_token(TokenType::Ident) {
@@ -189,6 +254,14 @@ impl Parse for Message {
             } else {
                 Payload::empty()
             };
+            let id = if parser
+                .eat_token(TokenType::Symbol(Symbol::Equals))
+                .is_some()
+            {
+                Some(parser.expect_token(TokenType::Integer)?.parse().unwrap())
+            } else {
+                None
+            };
             parser.expect_token(TokenType::Keyword(Keyword::From))?;
             let from = parser.parse()?;
             parser.expect_token(TokenType::Keyword(Keyword::To))?;
@@ -197,6 +270,7 @@ impl Parse for Message {
             Ok(Message {
                 label: label.to_string(),
                 payload,
+                id,
                 from,
                 to,
             })
@@ -206,6 +280,31 @@ impl Parse for Message {
     }
 }
 
+impl Parse for StateLabel {
+    fn parse<'a>(parser: &mut Parser<'a>) -> ParseResult<'a, Self> {
+        // 'state' isn't a keyword, since it was a common name before it
+        // was added.
+        if parser
+            .eat_contextual_keyword("state", TokenType::Ident)
+            .is_none()
+        {
+            return Err(parser.invalid_token());
+        }
+        let name = parser.expect_token(TokenType::Ident)?.to_string();
+        parser.expect_token(TokenType::Symbol(Symbol::Semicolon))?;
+        Ok(StateLabel { name })
+    }
+}
+
+impl Parse for Field {
+    fn parse<'a>(parser: &mut Parser<'a>) -> ParseResult<'a, Self> {
+        let name = parser.expect_token(TokenType::Ident)?.to_string();
+        parser.expect_token(TokenType::Symbol(Symbol::Colon))?;
+        let ty = parser.parse()?;
+        Ok(Field { name, ty })
+    }
+}
+
 impl Parse for Struct {
     fn parse<'a>(parser: &mut Parser<'a>) -> ParseResult<'a, Self> {
         parser.expect_token(TokenType::Keyword(Keyword::Struct))?;
@@ -216,10 +315,7 @@ impl Parse for Struct {
             .eat_token(TokenType::Symbol(Symbol::CloseCurlyBrace))
             .is_none()
         {
-            let field_name = parser.expect_token(TokenType::Ident)?.to_string();
-            parser.expect_token(TokenType::Symbol(Symbol::Colon))?;
-            let ty = parser.parse::<Type>()?;
-            items.push((field_name, ty));
+            items.push(parser.parse()?);
             if parser.eat_token(TokenType::Symbol(Symbol::Comma)).is_none() {
                 parser.expect_token(TokenType::Symbol(Symbol::CloseCurlyBrace))?;
                 break;
@@ -281,7 +377,9 @@ impl Parse for Type {
 
 impl Parse for Stmt {
     fn parse<'a>(parser: &mut Parser<'a>) -> ParseResult<'a, Self> {
-        if let Some(msg) = parser.parse_maybe()? {
+        if let Some(label) = parser.parse_maybe()? {
+            Ok(Stmt::State(label))
+        } else if let Some(msg) = parser.parse_maybe()? {
             Ok(Stmt::Message(msg))
         } else if parser
             .eat_token(TokenType::Keyword(Keyword::Choice))
@@ -406,7 +504,11 @@ impl fmt::Display for Message {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         write!(f, "{}", self.label)?;
         if self.payload.items.len() > 0 {
-            write!(f, "({})", display_utils::join(&self.payload.items, ", "))?;
+            let items = self.payload.items.iter().map(|item| &item.inner);
+            write!(f, "({})", display_utils::join(items, ", "))?;
+        }
+        if let Some(id) = self.id {
+            write!(f, " = {}", id)?;
         }
         write!(f, " from {} to {};", self.from, self.to)?;
         Ok(())
@@ -416,8 +518,9 @@ impl fmt::Display for Message {
 #[cfg(test)]
 mod tests {
     use crate::{
-        ast::{IntSize, IntType, Message, Payload, PayloadItem, Struct, Type},
-        parser::parse,
+        ast::{Field, IntSize, IntType, Message, Payload, PayloadItem, Stmt, Struct, Type},
+        lexer::Position,
+        parser::{parse, RawSpan, Span},
         report::Report,
     };
 
@@ -427,6 +530,17 @@ mod tests {
         Role(name.into())
     }
 
+    // Spans aren't compared, so any will do.
+    fn span<T>(inner: T) -> Span<T> {
+        Span {
+            span: RawSpan {
+                start: Position::START,
+                end: Position::START,
+            },
+            inner,
+        }
+    }
+
     #[test]
     fn test_parse_msg() {
         let msg = parse::<Message>("X from Y to Z;").report();
@@ -435,6 +549,27 @@ mod tests {
             Message {
                 label: "X".to_string(),
                 payload: Payload::empty(),
+                id: None,
+                from: role("Y"),
+                to: role("Z"),
+            }
+        )
+    }
+
+    #[test]
+    fn test_parse_msg_id() {
+        let msg = parse::<Message>("X(u8) = 300 from Y to Z;").report();
+        assert_eq!(
+            msg,
+            Message {
+                label: "X".to_string(),
+                payload: Payload {
+                    items: vec![span(PayloadItem {
+                        name: None,
+                        ty: Type::Int(IntType::U8)
+                    })]
+                },
+                id: Some(300),
                 from: role("Y"),
                 to: role("Z"),
             }
@@ -449,14 +584,15 @@ mod tests {
             Message {
                 label: "X".to_string(),
                 payload: Payload {
-                    items: vec![PayloadItem {
+                    items: vec![span(PayloadItem {
                         name: Some("x".to_string()),
                         ty: Type::Int(IntType {
                             signed: false,
                             size: IntSize::B32
                         })
-                    }]
+                    })]
                 },
+                id: None,
                 from: role("Y"),
                 to: role("Z"),
             }
@@ -471,10 +607,37 @@ mod tests {
             Struct {
                 name: "Point".to_string(),
                 fields: vec![
-                    ("x".to_string(), Type::Int(IntType::U32)),
-                    ("y".to_string(), Type::Int(IntType::U32))
+                    span(Field {
+                        name: "x".to_string(),
+                        ty: Type::Int(IntType::U32)
+                    }),
+                    span(Field {
+                        name: "y".to_string(),
+                        ty: Type::Int(IntType::U32)
+                    })
                 ]
             }
-        )
+        );
+        assert_eq!(struct_.fields[1].span.start.offset, 23);
+        assert_eq!(struct_.fields[1].span.end.offset, 29);
+    }
+
+    #[test]
+    fn test_parse_state_as_name() {
+        let struct_ = parse::<Struct>("struct robot { state: u8 }").report();
+        assert_eq!(struct_.fields[0].inner.name, "state");
+        let msg = parse::<Message>("state(state: u8) from state to Y;").report();
+        assert_eq!(msg.label, "state");
+        assert_eq!(msg.payload.items[0].inner.name.as_deref(), Some("state"));
+        assert_eq!(msg.from, role("state"));
+
+        let Stmt::State(label) = parse::<Stmt>("state Idle;").report() else {
+            panic!("expected a state label")
+        };
+        assert_eq!(label.inner.name, "Idle");
+        assert!(matches!(
+            parse::<Stmt>("state from Y to Z;").report(),
+            Stmt::Message(_)
+        ));
     }
 }
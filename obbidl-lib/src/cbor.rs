@@ -0,0 +1,620 @@
+use std::{error::Error, fmt};
+
+use crate::{
+    channel::{AsyncChannel, Channel, RecvError},
+    encode::into_array,
+};
+
+// The runtime side of the CBOR wire format (RFC 8949). Each message is a map
+// with the label of the message under "label" and, unless the payload is
+// empty, a map under "data" with the payload items under their names, the
+// same shape as a JSON message.
+
+// How deeply arrays and maps may be nested.
+const MAX_DEPTH: usize = 128;
+
+// Major types, the top three bits of the first byte of each item.
+const UNSIGNED: u8 = 0;
+const NEGATIVE: u8 = 1;
+const TEXT: u8 = 3;
+const ARRAY: u8 = 4;
+const MAP: u8 = 5;
+const SIMPLE: u8 = 7;
+
+const FALSE: u64 = 20;
+const TRUE: u64 = 21;
+const NULL: u64 = 22;
+
+// Only the subset of CBOR that payloads need: integers, bools, text, arrays
+// and maps with text keys. Lengths are always definite.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    Null,
+    Bool(bool),
+    Int(i128),
+    Text(String),
+    Array(Vec<Value>),
+    Map(Vec<(String, Value)>),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct CborError(String);
+
+impl fmt::Display for CborError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl Error for CborError {}
+
+impl Value {
+    pub fn field(&self, name: &str) -> Result<&Value, CborError> {
+        let Value::Map(entries) = self else {
+            return Err(expected("a map", self));
+        };
+        entries
+            .iter()
+            .find(|(key, _)| key == name)
+            .map(|(_, value)| value)
+            .ok_or_else(|| CborError(format!("missing field '{}'", name)))
+    }
+
+    pub fn get<T: FromCbor>(&self, name: &str) -> Result<T, CborError> {
+        T::from_cbor(self.field(name)?).map_err(|err| CborError(format!("'{}': {}", name, err)))
+    }
+
+    pub fn label(&self) -> Result<&str, CborError> {
+        match self.field("label")? {
+            Value::Text(label) => Ok(label),
+            value => Err(expected("text", value)),
+        }
+    }
+
+    /// Writes the value in the shortest form. Integers must fit in 64 bits
+    /// and a sign, as they always do when they come from a payload.
+    pub fn encode(&self, buf: &mut Vec<u8>) {
+        match self {
+            Value::Null => header(buf, SIMPLE, NULL),
+            Value::Bool(false) => header(buf, SIMPLE, FALSE),
+            Value::Bool(true) => header(buf, SIMPLE, TRUE),
+            Value::Int(value) => {
+                let (major, argument) = if *value < 0 {
+                    (NEGATIVE, -1 - value)
+                } else {
+                    (UNSIGNED, *value)
+                };
+                let argument = u64::try_from(argument).expect("integer is too large for CBOR");
+                header(buf, major, argument);
+            }
+            Value::Text(text) => encode_text(buf, text),
+            Value::Array(items) => {
+                header(buf, ARRAY, items.len() as u64);
+                for item in items {
+                    item.encode(buf);
+                }
+            }
+            Value::Map(entries) => {
+                header(buf, MAP, entries.len() as u64);
+                for (key, value) in entries {
+                    encode_text(buf, key);
+                    value.encode(buf);
+                }
+            }
+        }
+    }
+
+    fn kind(&self) -> &'static str {
+        match self {
+            Value::Null => "null",
+            Value::Bool(_) => "a bool",
+            Value::Int(_) => "an integer",
+            Value::Text(_) => "text",
+            Value::Array(_) => "an array",
+            Value::Map(_) => "a map",
+        }
+    }
+}
+
+fn expected(kind: &str, value: &Value) -> CborError {
+    CborError(format!("expected {}, found {}", kind, value.kind()))
+}
+
+fn header(buf: &mut Vec<u8>, major: u8, argument: u64) {
+    let major = major << 5;
+    if argument < 24 {
+        buf.push(major | argument as u8);
+    } else if let Ok(argument) = u8::try_from(argument) {
+        buf.push(major | 24);
+        buf.push(argument);
+    } else if let Ok(argument) = u16::try_from(argument) {
+        buf.push(major | 25);
+        buf.extend_from_slice(&argument.to_be_bytes());
+    } else if let Ok(argument) = u32::try_from(argument) {
+        buf.push(major | 26);
+        buf.extend_from_slice(&argument.to_be_bytes());
+    } else {
+        buf.push(major | 27);
+        buf.extend_from_slice(&argument.to_be_bytes());
+    }
+}
+
+fn encode_text(buf: &mut Vec<u8>, text: &str) {
+    header(buf, TEXT, text.len() as u64);
+    buf.extend_from_slice(text.as_bytes());
+}
+
+// Reads a value as its bytes arrive. The header of each item says how many
+// more bytes it needs, so nothing after the end of the value is read and the
+// same decoder works for blocking and async channels.
+struct Decoder {
+    max_length: u32,
+    next: Next,
+    // Arrays and maps that are still being read, innermost last.
+    open: Vec<Open>,
+}
+
+enum Next {
+    Initial,
+    Argument { major: u8, size: usize },
+    Text(usize),
+}
+
+enum Open {
+    Array {
+        remaining: usize,
+        items: Vec<Value>,
+    },
+    Map {
+        remaining: usize,
+        entries: Vec<(String, Value)>,
+        key: Option<String>,
+    },
+}
+
+impl Decoder {
+    fn new(max_length: u32) -> Decoder {
+        Decoder {
+            max_length,
+            next: Next::Initial,
+            open: vec![],
+        }
+    }
+
+    fn needed(&self) -> usize {
+        match self.next {
+            Next::Initial => 1,
+            Next::Argument { size, .. } => size,
+            Next::Text(length) => length,
+        }
+    }
+
+    // Takes exactly 'needed' bytes, and returns the value once it is
+    // complete.
+    fn feed<E>(&mut self, bytes: &[u8]) -> Result<Option<Value>, RecvError<E>> {
+        match self.next {
+            Next::Initial => {
+                let (major, info) = (bytes[0] >> 5, bytes[0] & 0x1f);
+                match info {
+                    0..=23 => self.item(major, info as u64),
+                    24..=27 if major == SIMPLE => Err(malformed("floats are not supported")),
+                    24..=27 => {
+                        self.next = Next::Argument {
+                            major,
+                            size: 1 << (info - 24),
+                        };
+                        Ok(None)
+                    }
+                    31 => Err(malformed("indefinite lengths are not supported")),
+                    _ => Err(malformed("invalid header")),
+                }
+            }
+            Next::Argument { major, .. } => {
+                let argument = bytes
+                    .iter()
+                    .fold(0, |argument, &byte| argument << 8 | byte as u64);
+                self.item(major, argument)
+            }
+            Next::Text(_) => {
+                self.next = Next::Initial;
+                let text = String::from_utf8(bytes.to_vec()).map_err(RecvError::malformed)?;
+                self.push(Value::Text(text))
+            }
+        }
+    }
+
+    // An item whose header has been read.
+    fn item<E>(&mut self, major: u8, argument: u64) -> Result<Option<Value>, RecvError<E>> {
+        self.next = Next::Initial;
+        match major {
+            UNSIGNED => self.push(Value::Int(argument as i128)),
+            NEGATIVE => self.push(Value::Int(-1 - argument as i128)),
+            TEXT => match self.length(argument)? {
+                0 => self.push(Value::Text(String::new())),
+                length => {
+                    self.next = Next::Text(length);
+                    Ok(None)
+                }
+            },
+            ARRAY | MAP => {
+                let remaining = self.length(argument)?;
+                if remaining == 0 {
+                    return self.push(match major {
+                        ARRAY => Value::Array(vec![]),
+                        _ => Value::Map(vec![]),
+                    });
+                }
+                if self.open.len() == MAX_DEPTH {
+                    return Err(RecvError::Malformed(format!(
+                        "nested more than {} deep",
+                        MAX_DEPTH
+                    )));
+                }
+                self.open.push(match major {
+                    ARRAY => Open::Array {
+                        remaining,
+                        items: vec![],
+                    },
+                    _ => Open::Map {
+                        remaining,
+                        entries: vec![],
+                        key: None,
+                    },
+                });
+                Ok(None)
+            }
+            SIMPLE => match argument {
+                FALSE => self.push(Value::Bool(false)),
+                TRUE => self.push(Value::Bool(true)),
+                NULL => self.push(Value::Null),
+                _ => Err(RecvError::Malformed(format!(
+                    "unsupported simple value {}",
+                    argument
+                ))),
+            },
+            _ => Err(RecvError::Malformed(format!(
+                "unsupported major type {}",
+                major
+            ))),
+        }
+    }
+
+    fn length<E>(&self, argument: u64) -> Result<usize, RecvError<E>> {
+        if argument > self.max_length as u64 {
+            return Err(RecvError::LengthTooLarge {
+                length: u32::try_from(argument).unwrap_or(u32::MAX),
+                max: self.max_length,
+            });
+        }
+        Ok(argument as usize)
+    }
+
+    // Adds a complete value to the innermost open array or map, closing it
+    // and any around it that are then complete.
+    fn push<E>(&mut self, mut value: Value) -> Result<Option<Value>, RecvError<E>> {
+        loop {
+            let Some(open) = self.open.last_mut() else {
+                return Ok(Some(value));
+            };
+            let remaining = match open {
+                Open::Array { remaining, items } => {
+                    items.push(value);
+                    remaining
+                }
+                Open::Map {
+                    remaining,
+                    entries,
+                    key,
+                } => match key.take() {
+                    Some(key) => {
+                        entries.push((key, value));
+                        remaining
+                    }
+                    None => {
+                        let Value::Text(text) = value else {
+                            return Err(malformed("map keys must be text"));
+                        };
+                        *key = Some(text);
+                        return Ok(None);
+                    }
+                },
+            };
+            *remaining -= 1;
+            if *remaining > 0 {
+                return Ok(None);
+            }
+            value = match self.open.pop() {
+                Some(Open::Array { items, .. }) => Value::Array(items),
+                Some(Open::Map { entries, .. }) => Value::Map(entries),
+                None => unreachable!(),
+            };
+        }
+    }
+}
+
+fn malformed<E>(message: &str) -> RecvError<E> {
+    RecvError::Malformed(message.to_string())
+}
+
+// Generated code implements these for each struct, as maps with an entry for
+// each of the struct's fields.
+pub trait ToCbor {
+    fn to_cbor(&self) -> Value;
+}
+
+pub trait FromCbor: Sized {
+    fn from_cbor(value: &Value) -> Result<Self, CborError>;
+}
+
+impl ToCbor for bool {
+    fn to_cbor(&self) -> Value {
+        Value::Bool(*self)
+    }
+}
+
+impl FromCbor for bool {
+    fn from_cbor(value: &Value) -> Result<Self, CborError> {
+        match value {
+            Value::Bool(value) => Ok(*value),
+            value => Err(expected("a bool", value)),
+        }
+    }
+}
+
+macro_rules! impl_int {
+    ($($ty:ty),*) => {
+        $(
+            impl ToCbor for $ty {
+                fn to_cbor(&self) -> Value {
+                    Value::Int(*self as i128)
+                }
+            }
+
+            impl FromCbor for $ty {
+                fn from_cbor(value: &Value) -> Result<Self, CborError> {
+                    match value {
+                        Value::Int(value) => <$ty>::try_from(*value).map_err(|_| {
+                            CborError(format!("{} is out of range for {}", value, stringify!($ty)))
+                        }),
+                        value => Err(expected("an integer", value)),
+                    }
+                }
+            }
+        )*
+    };
+}
+
+impl_int!(u8, u16, u32, u64, i8, i16, i32, i64);
+
+impl<T: ToCbor> ToCbor for [T] {
+    fn to_cbor(&self) -> Value {
+        Value::Array(self.iter().map(T::to_cbor).collect())
+    }
+}
+
+impl<T: ToCbor> ToCbor for Vec<T> {
+    fn to_cbor(&self) -> Value {
+        self.as_slice().to_cbor()
+    }
+}
+
+impl<T: FromCbor> FromCbor for Vec<T> {
+    fn from_cbor(value: &Value) -> Result<Self, CborError> {
+        match value {
+            Value::Array(items) => items.iter().map(T::from_cbor).collect(),
+            value => Err(expected("an array", value)),
+        }
+    }
+}
+
+impl<T: ToCbor, const N: usize> ToCbor for [T; N] {
+    fn to_cbor(&self) -> Value {
+        self.as_slice().to_cbor()
+    }
+}
+
+impl<T: FromCbor, const N: usize> FromCbor for [T; N] {
+    fn from_cbor(value: &Value) -> Result<Self, CborError> {
+        let items = Vec::<T>::from_cbor(value)?;
+        if items.len() != N {
+            return Err(CborError(format!(
+                "expected {} items, found {}",
+                N,
+                items.len()
+            )));
+        }
+        Ok(into_array(items))
+    }
+}
+
+pub fn send<C: Channel>(channel: &mut C, value: &Value) -> Result<(), C::Error> {
+    let mut buf = vec![];
+    value.encode(&mut buf);
+    channel.send(&buf)
+}
+
+pub async fn send_async<C: AsyncChannel>(channel: &mut C, value: &Value) -> Result<(), C::Error> {
+    let mut buf = vec![];
+    value.encode(&mut buf);
+    channel.send(&buf).await
+}
+
+// Arrays and text are limited to the channel's 'MAX_LENGTH'.
+pub fn recv<C: Channel>(channel: &mut C) -> Result<Value, RecvError<C::Error>> {
+    let mut decoder = Decoder::new(C::MAX_LENGTH);
+    loop {
+        let mut bytes = vec![0; decoder.needed()];
+        channel.recv(&mut bytes)?;
+        if let Some(value) = decoder.feed(&bytes)? {
+            return Ok(value);
+        }
+    }
+}
+
+pub async fn recv_async<C: AsyncChannel>(channel: &mut C) -> Result<Value, RecvError<C::Error>> {
+    let mut decoder = Decoder::new(C::MAX_LENGTH);
+    loop {
+        let mut bytes = vec![0; decoder.needed()];
+        channel.recv(&mut bytes).await?;
+        if let Some(value) = decoder.feed(&bytes)? {
+            return Ok(value);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fmt::Debug;
+
+    use crate::channel::{Channel, RecvError, TestChannel};
+
+    use super::{recv, send, FromCbor, ToCbor, Value};
+
+    fn text(text: &str) -> Value {
+        Value::Text(text.to_string())
+    }
+
+    // Examples from appendix A of RFC 8949.
+    fn examples() -> Vec<(Value, &'static str)> {
+        vec![
+            (Value::Int(0), "00"),
+            (Value::Int(23), "17"),
+            (Value::Int(24), "1818"),
+            (Value::Int(100), "1864"),
+            (Value::Int(1000), "1903e8"),
+            (Value::Int(1000000), "1a000f4240"),
+            (Value::Int(1000000000000), "1b000000e8d4a51000"),
+            (Value::Int(u64::MAX as i128), "1bffffffffffffffff"),
+            (Value::Int(-(u64::MAX as i128) - 1), "3bffffffffffffffff"),
+            (Value::Int(-1), "20"),
+            (Value::Int(-1000), "3903e7"),
+            (Value::Bool(false), "f4"),
+            (Value::Bool(true), "f5"),
+            (Value::Null, "f6"),
+            (text(""), "60"),
+            (text("a"), "6161"),
+            (text("\u{fc}"), "62c3bc"),
+            (Value::Array(vec![]), "80"),
+            (
+                Value::Array(vec![Value::Int(1), Value::Int(2), Value::Int(3)]),
+                "83010203",
+            ),
+            (
+                Value::Map(vec![
+                    ("a".to_string(), Value::Int(1)),
+                    (
+                        "b".to_string(),
+                        Value::Array(vec![Value::Int(2), Value::Int(3)]),
+                    ),
+                ]),
+                "a26161016162820203",
+            ),
+        ]
+    }
+
+    fn hex(bytes: &[u8]) -> String {
+        bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+    }
+
+    fn unhex(hex: &str) -> Vec<u8> {
+        (0..hex.len())
+            .step_by(2)
+            .map(|index| u8::from_str_radix(&hex[index..index + 2], 16).unwrap())
+            .collect()
+    }
+
+    fn decode(hex: &str) -> Result<Value, RecvError<impl Debug>> {
+        let (mut a, mut b) = TestChannel::new();
+        a.send(&unhex(hex)).unwrap();
+        recv(&mut b)
+    }
+
+    #[test]
+    fn test_encode() {
+        for (value, expected) in examples() {
+            let mut buf = vec![];
+            value.encode(&mut buf);
+            assert_eq!(hex(&buf), expected, "{:?}", value);
+        }
+    }
+
+    #[test]
+    fn test_decode() {
+        let (mut a, mut b) = TestChannel::new();
+        for (value, _) in examples() {
+            send(&mut a, &value).unwrap();
+        }
+        for (value, _) in examples() {
+            assert_eq!(recv(&mut b).unwrap(), value);
+        }
+        // Not the shortest form, but still valid.
+        assert_eq!(decode("1a00000001").unwrap(), Value::Int(1));
+    }
+
+    #[test]
+    fn test_decode_errors() {
+        for hex in [
+            "f93c00", // a float
+            "9f01ff", // an indefinite length array
+            "4101",   // a byte string
+            "c001",   // a tag
+            "a10101", // a map with an integer key
+            "61ff",   // invalid UTF-8
+            "1c",     // a reserved header
+            "f7",     // an unsupported simple value
+        ] {
+            assert!(
+                matches!(decode(hex), Err(RecvError::Malformed(_))),
+                "{}",
+                hex
+            );
+        }
+        assert!(matches!(
+            decode("9bffffffffffffffff"),
+            Err(RecvError::LengthTooLarge {
+                length: u32::MAX,
+                ..
+            })
+        ));
+        assert!(matches!(
+            decode(&"81".repeat(1000)),
+            Err(RecvError::Malformed(_))
+        ));
+    }
+
+    fn round_trip<T: ToCbor + FromCbor + PartialEq + Debug>(value: T) {
+        let (mut a, mut b) = TestChannel::new();
+        send(&mut a, &value.to_cbor()).unwrap();
+        assert_eq!(T::from_cbor(&recv(&mut b).unwrap()).unwrap(), value);
+    }
+
+    #[test]
+    fn test_round_trip() {
+        round_trip(true);
+        round_trip(false);
+        round_trip(0u8);
+        round_trip(u8::MAX);
+        round_trip(u16::MAX);
+        round_trip(u32::MAX);
+        round_trip(u64::MAX);
+        round_trip(i8::MIN);
+        round_trip(i16::MIN);
+        round_trip(i32::MIN);
+        round_trip(i64::MIN);
+        round_trip(i64::MAX);
+        round_trip(vec![[1u16, 2], [3, 4]]);
+        round_trip(vec![vec![true], vec![], vec![false, true]]);
+        round_trip([vec![0u8], vec![7]]);
+    }
+
+    #[test]
+    fn test_convert() {
+        assert!(u8::from_cbor(&Value::Int(256)).is_err());
+        assert!(i8::from_cbor(&Value::Int(-129)).is_err());
+        assert!(<[bool; 2]>::from_cbor(&[true].to_cbor()).is_err());
+        assert!(bool::from_cbor(&Value::Int(1)).is_err());
+        let map = Value::Map(vec![("x".to_string(), Value::Int(3))]);
+        assert_eq!(map.get::<u32>("x").unwrap(), 3);
+        assert!(map.get::<u32>("y").is_err());
+    }
+}
@@ -1,15 +1,31 @@
 use std::{
     cell::RefCell,
     collections::VecDeque,
-    io::{self, Read, Write},
-    mem::size_of,
-    net::TcpStream,
+    error::Error,
+    fmt,
+    future::poll_fn,
+    io::{self, BufWriter, Read, Write},
+    mem::{self, size_of},
+    net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs},
     rc::{Rc, Weak},
+    sync::{self, Arc, Condvar, Mutex, MutexGuard},
+    task::{Poll, Waker},
+    time::{Duration, Instant},
+};
+
+#[cfg(unix)]
+use std::{
+    os::unix::net::{UnixListener, UnixStream},
+    path::Path,
 };
 
 pub trait Channel {
     type Error;
 
+    // The longest array that will be received, so a corrupted length can't
+    // make the receiver allocate arbitrary amounts of memory.
+    const MAX_LENGTH: u32 = 1 << 16;
+
     fn recv(&mut self, data: &mut [u8]) -> Result<(), Self::Error>;
     fn send(&mut self, data: &[u8]) -> Result<(), Self::Error>;
 
@@ -27,17 +43,671 @@ pub trait Channel {
         self.recv(&mut bytes)?;
         Ok(u32::from_be_bytes(bytes))
     }
+
+    fn recv_length(&mut self) -> Result<usize, RecvError<Self::Error>> {
+        let length = self.recv_u32()?;
+        if length > Self::MAX_LENGTH {
+            return Err(RecvError::LengthTooLarge {
+                length,
+                max: Self::MAX_LENGTH,
+            });
+        }
+        Ok(length as usize)
+    }
+}
+
+// The same as 'Channel' for code generated in async mode. Nothing here
+// depends on a particular executor. The futures are 'Send' whenever the
+// channel's are, so a 'Send' channel can run on a multithreaded executor.
+#[allow(async_fn_in_trait)]
+pub trait AsyncChannel {
+    type Error;
+
+    const MAX_LENGTH: u32 = 1 << 16;
+
+    async fn recv(&mut self, data: &mut [u8]) -> Result<(), Self::Error>;
+    async fn send(&mut self, data: &[u8]) -> Result<(), Self::Error>;
+
+    async fn recv_u8(&mut self) -> Result<u8, Self::Error> {
+        let mut data = [0; 1];
+        self.recv(&mut data).await?;
+        Ok(data[0])
+    }
+    async fn send_u8(&mut self, data: u8) -> Result<(), Self::Error> {
+        self.send(&[data]).await
+    }
+
+    async fn recv_u32(&mut self) -> Result<u32, Self::Error> {
+        let mut bytes = [0; size_of::<u32>()];
+        self.recv(&mut bytes).await?;
+        Ok(u32::from_be_bytes(bytes))
+    }
+
+    async fn recv_length(&mut self) -> Result<usize, RecvError<Self::Error>> {
+        let length = self.recv_u32().await?;
+        if length > Self::MAX_LENGTH {
+            return Err(RecvError::LengthTooLarge {
+                length,
+                max: Self::MAX_LENGTH,
+            });
+        }
+        Ok(length as usize)
+    }
+}
+
+// Everything that can go wrong while receiving a message. Anything but
+// 'Channel' means the other role sent something the protocol doesn't allow.
+#[derive(Debug, Clone, PartialEq)]
+pub enum RecvError<E> {
+    Channel(E),
+    UnknownTag { state: &'static str, tag: u64 },
+    UnknownLabel { state: &'static str, label: String },
+    InvalidBool(u8),
+    LengthTooLarge { length: u32, max: u32 },
+    // A message that doesn't decode in a text based format.
+    Malformed(String),
 }
 
-struct TcpChannel {
-    stream: TcpStream,
+impl<E> RecvError<E> {
+    pub fn malformed(err: impl fmt::Display) -> RecvError<E> {
+        RecvError::Malformed(err.to_string())
+    }
+}
+
+impl<E> From<E> for RecvError<E> {
+    fn from(err: E) -> RecvError<E> {
+        RecvError::Channel(err)
+    }
+}
+
+impl<E: fmt::Display> fmt::Display for RecvError<E> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RecvError::Channel(err) => write!(f, "{}", err),
+            RecvError::UnknownTag { state, tag } => {
+                write!(f, "unknown message tag {} in state '{}'", tag, state)
+            }
+            RecvError::UnknownLabel { state, label } => {
+                write!(f, "unknown message label '{}' in state '{}'", label, state)
+            }
+            RecvError::InvalidBool(value) => write!(f, "invalid bool {}", value),
+            RecvError::LengthTooLarge { length, max } => {
+                write!(f, "array length {} is larger than {}", length, max)
+            }
+            RecvError::Malformed(err) => write!(f, "malformed message: {}", err),
+        }
+    }
+}
+
+impl<E: Error> Error for RecvError<E> {}
+
+pub trait SplitChannel: Channel + Sized {
+    type Sender: Channel<Error = Self::Error>;
+    type Receiver: Channel<Error = Self::Error>;
+
+    // Can fail for channels that have to flush or duplicate a stream first.
+    fn split(self) -> Result<(Self::Sender, Self::Receiver), Self::Error>;
+    fn unsplit(sender: Self::Sender, receiver: Self::Receiver) -> Self;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum HandshakeError<E> {
+    Channel(E),
+    FingerprintMismatch { local: u64, remote: u64 },
+}
+
+impl<E> From<E> for HandshakeError<E> {
+    fn from(err: E) -> HandshakeError<E> {
+        HandshakeError::Channel(err)
+    }
+}
+
+impl<E: fmt::Display> fmt::Display for HandshakeError<E> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HandshakeError::Channel(err) => write!(f, "{}", err),
+            HandshakeError::FingerprintMismatch { local, remote } => write!(
+                f,
+                "protocol fingerprint {:016x} doesn't match {:016x}",
+                remote, local
+            ),
+        }
+    }
+}
+
+impl<E: Error> Error for HandshakeError<E> {}
+
+// Both roles send their fingerprint before reading the other one, so neither
+// side waits on the other.
+pub fn handshake<C: Channel>(
+    channel: &mut C,
+    fingerprint: u64,
+) -> Result<(), HandshakeError<C::Error>> {
+    channel.send(&u64::to_be_bytes(fingerprint))?;
+    let mut bytes = [0; size_of::<u64>()];
+    channel.recv(&mut bytes)?;
+    let remote = u64::from_be_bytes(bytes);
+    if remote != fingerprint {
+        return Err(HandshakeError::FingerprintMismatch {
+            local: fingerprint,
+            remote,
+        });
+    }
+    Ok(())
 }
 
-impl Channel for TcpChannel {
+pub async fn handshake_async<C: AsyncChannel>(
+    channel: &mut C,
+    fingerprint: u64,
+) -> Result<(), HandshakeError<C::Error>> {
+    channel.send(&u64::to_be_bytes(fingerprint)).await?;
+    let mut bytes = [0; size_of::<u64>()];
+    channel.recv(&mut bytes).await?;
+    let remote = u64::from_be_bytes(bytes);
+    if remote != fingerprint {
+        return Err(HandshakeError::FingerprintMismatch {
+            local: fingerprint,
+            remote,
+        });
+    }
+    Ok(())
+}
+
+// Carries the branches of a 'par' block over a single channel. Every send is
+// wrapped in a frame of the branch index and the length of the data, and an
+// empty frame marks the end of a branch. Whichever branch needs data reads
+// the next frame and queues it for the branch it belongs to, so branches can
+// be driven from different threads.
+struct Mux<C: SplitChannel> {
+    sender: Mutex<C::Sender>,
+    receiver: Mutex<C::Receiver>,
+    state: Mutex<MuxState>,
+    ready: Condvar,
+}
+
+// Once receiving a frame fails the channel can't be trusted to be at the
+// start of the next frame, so every branch that waits for data afterwards
+// gets 'Failed' instead of waiting forever.
+struct MuxState {
+    queues: Vec<BranchQueue>,
+    failed: bool,
+}
+
+#[derive(Default)]
+struct BranchQueue {
+    data: VecDeque<u8>,
+    ended: bool,
+}
+
+pub struct Branch<C: SplitChannel> {
+    mux: Arc<Mux<C>>,
+    index: u8,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MuxError<E> {
+    Channel(E),
+    InvalidBranch(u8),
+    BranchEnded(u8),
+    Failed,
+    FrameTooLong { length: u32, max: u32 },
+}
+
+impl<E> From<E> for MuxError<E> {
+    fn from(err: E) -> MuxError<E> {
+        MuxError::Channel(err)
+    }
+}
+
+impl<E: fmt::Display> fmt::Display for MuxError<E> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MuxError::Channel(err) => write!(f, "{}", err),
+            MuxError::InvalidBranch(index) => write!(f, "invalid branch {}", index),
+            MuxError::BranchEnded(index) => write!(f, "branch {} has ended", index),
+            MuxError::Failed => write!(f, "another branch failed to receive"),
+            MuxError::FrameTooLong { length, max } => {
+                write!(f, "frame length {} is larger than {}", length, max)
+            }
+        }
+    }
+}
+
+impl<E: Error> Error for MuxError<E> {}
+
+impl<C: SplitChannel> Branch<C> {
+    pub fn split(channel: C, count: u8) -> Result<Vec<Branch<C>>, C::Error> {
+        let (sender, receiver) = channel.split()?;
+        let mux = Arc::new(Mux {
+            sender: Mutex::new(sender),
+            receiver: Mutex::new(receiver),
+            state: Mutex::new(MuxState {
+                queues: (0..count).map(|_| BranchQueue::default()).collect(),
+                failed: false,
+            }),
+            ready: Condvar::new(),
+        });
+        Ok((0..count)
+            .map(|index| Branch {
+                mux: Arc::clone(&mux),
+                index,
+            })
+            .collect())
+    }
+    pub fn join(branches: Vec<Branch<C>>) -> C {
+        let mux = Arc::clone(&branches[0].mux);
+        drop(branches);
+        let Ok(mux) = Arc::try_unwrap(mux) else {
+            panic!("a branch is still in use")
+        };
+        C::unsplit(
+            mux.sender.into_inner().unwrap(),
+            mux.receiver.into_inner().unwrap(),
+        )
+    }
+    pub fn send_end(&mut self) -> Result<(), MuxError<C::Error>> {
+        self.send_frame(&[])
+    }
+    // Waits until either data or the end of the branch is available, and
+    // returns true in the second case.
+    pub fn recv_end(&mut self) -> Result<bool, MuxError<C::Error>> {
+        let state = self.wait_for(|queue| !queue.data.is_empty() || queue.ended)?;
+        Ok(state.queues[self.index as usize].data.is_empty())
+    }
+    // The frame is sent in one go, so that an unbuffered sender writes it
+    // with a single call.
+    fn send_frame(&mut self, data: &[u8]) -> Result<(), MuxError<C::Error>> {
+        let mut frame = Vec::with_capacity(1 + size_of::<u32>() + data.len());
+        frame.push(self.index);
+        frame.extend(u32::to_be_bytes(data.len() as u32));
+        frame.extend(data);
+        self.mux.sender.lock().unwrap().send(&frame)?;
+        Ok(())
+    }
+    fn wait_for(
+        &self,
+        ready: impl Fn(&BranchQueue) -> bool,
+    ) -> Result<MutexGuard<'_, MuxState>, MuxError<C::Error>> {
+        let mut state = self.mux.state.lock().unwrap();
+        loop {
+            if ready(&state.queues[self.index as usize]) {
+                return Ok(state);
+            }
+            if state.queues[self.index as usize].ended {
+                return Err(MuxError::BranchEnded(self.index));
+            }
+            if state.failed {
+                return Err(MuxError::Failed);
+            }
+            match self.mux.receiver.try_lock() {
+                // The frame is queued before the receiver is released, as
+                // otherwise the branch it belongs to could find its queue
+                // empty and start waiting for a frame that has already been
+                // read.
+                Ok(mut receiver) => {
+                    drop(state);
+                    let frame = recv_frame(&mut *receiver);
+                    state = self.mux.state.lock().unwrap();
+                    drop(receiver);
+                    let queued = state.queue(frame);
+                    if queued.is_err() {
+                        state.failed = true;
+                    }
+                    self.mux.ready.notify_all();
+                    queued?;
+                }
+                Err(_) => state = self.mux.ready.wait(state).unwrap(),
+            }
+        }
+    }
+}
+
+impl MuxState {
+    fn queue<E>(&mut self, frame: Result<(u8, Vec<u8>), MuxError<E>>) -> Result<(), MuxError<E>> {
+        let (index, data) = frame?;
+        let queue = self
+            .queues
+            .get_mut(index as usize)
+            .ok_or(MuxError::InvalidBranch(index))?;
+        if data.is_empty() {
+            queue.ended = true;
+        }
+        queue.data.extend(data);
+        Ok(())
+    }
+}
+
+// Senders split their data into frames of at most 'MAX_LENGTH' bytes, so a
+// longer one can only come from a corrupted length.
+fn recv_frame<C: Channel>(channel: &mut C) -> Result<(u8, Vec<u8>), MuxError<C::Error>> {
+    let index = channel.recv_u8()?;
+    let length = channel.recv_u32()?;
+    if length > C::MAX_LENGTH {
+        return Err(MuxError::FrameTooLong {
+            length,
+            max: C::MAX_LENGTH,
+        });
+    }
+    let mut data = vec![0; length as usize];
+    channel.recv(&mut data)?;
+    Ok((index, data))
+}
+
+impl<C: SplitChannel> Channel for Branch<C> {
+    type Error = MuxError<C::Error>;
+
+    const MAX_LENGTH: u32 = C::MAX_LENGTH;
+
+    fn recv(&mut self, data: &mut [u8]) -> Result<(), Self::Error> {
+        let mut state = self.wait_for(|queue| queue.data.len() >= data.len())?;
+        let queue = &mut state.queues[self.index as usize];
+        for byte in data {
+            *byte = queue.data.pop_front().unwrap();
+        }
+        Ok(())
+    }
+
+    fn send(&mut self, data: &[u8]) -> Result<(), Self::Error> {
+        for chunk in data.chunks(C::MAX_LENGTH as usize) {
+            self.send_frame(chunk)?;
+        }
+        Ok(())
+    }
+}
+
+// Frames every 'send' for byte streams that may lose or corrupt bytes, like
+// serial links. The data and its CRC-32 are COBS encoded so that a frame
+// never contains a zero byte, and each frame ends with one. A frame that
+// fails its CRC is a 'Corrupted' error, and since receiving always stops at
+// the zero byte that ends a frame, the next receive starts on the frame after
+// it. Frames don't line up with receives, so generated code has to send each
+// message in one go for a corrupted frame to only lose that message.
+pub struct Framed<C> {
+    channel: C,
+    max_frame_length: usize,
+    // The encoded bytes of the frame being received.
+    frame: Vec<u8>,
+    // Set when the frame being received is too long to be buffered, so the
+    // rest of it is skipped.
+    overflow: bool,
+    // The data of good frames that hasn't been received yet.
+    data: VecDeque<u8>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FrameError<E> {
+    Channel(E),
+    Corrupted,
+    TooLong { max: usize },
+}
+
+impl<E> From<E> for FrameError<E> {
+    fn from(err: E) -> FrameError<E> {
+        FrameError::Channel(err)
+    }
+}
+
+impl<E: fmt::Display> fmt::Display for FrameError<E> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FrameError::Channel(err) => write!(f, "{}", err),
+            FrameError::Corrupted => write!(f, "corrupted frame"),
+            FrameError::TooLong { max } => write!(f, "frame is longer than {} bytes", max),
+        }
+    }
+}
+
+impl<E: Error> Error for FrameError<E> {}
+
+const FRAME_DELIMITER: u8 = 0;
+
+impl<C> Framed<C> {
+    pub fn new(channel: C) -> Framed<C> {
+        Framed {
+            channel,
+            max_frame_length: 1 << 20,
+            frame: vec![],
+            overflow: false,
+            data: VecDeque::new(),
+        }
+    }
+    // The longest data sent or received in one frame, 1 MiB by default.
+    pub fn with_max_frame_length(mut self, max_frame_length: usize) -> Framed<C> {
+        self.max_frame_length = max_frame_length;
+        self
+    }
+    pub fn into_inner(self) -> C {
+        self.channel
+    }
+
+    fn encode<E>(&self, data: &[u8]) -> Result<Vec<u8>, FrameError<E>> {
+        if data.len() > self.max_frame_length {
+            return Err(FrameError::TooLong {
+                max: self.max_frame_length,
+            });
+        }
+        let mut frame = data.to_vec();
+        frame.extend(crc32(data).to_be_bytes());
+        let mut encoded = cobs_encode(&frame);
+        encoded.push(FRAME_DELIMITER);
+        Ok(encoded)
+    }
+
+    // Adds a received byte to the current frame, and the data of the frame
+    // to the data to receive if it was the last one.
+    fn push<E>(&mut self, byte: u8) -> Result<(), FrameError<E>> {
+        if byte != FRAME_DELIMITER {
+            // COBS adds a byte for every 254, and the CRC is 4 more.
+            let max = self.max_frame_length + self.max_frame_length / 254 + 5;
+            if self.frame.len() < max {
+                self.frame.push(byte);
+            } else {
+                self.overflow = true;
+            }
+            return Ok(());
+        }
+        let frame = mem::take(&mut self.frame);
+        if mem::take(&mut self.overflow) {
+            return Err(FrameError::TooLong {
+                max: self.max_frame_length,
+            });
+        }
+        // Empty frames carry nothing, a sender may use them to flush out
+        // noise.
+        if frame.is_empty() {
+            return Ok(());
+        }
+        let mut frame = cobs_decode(&frame).ok_or(FrameError::Corrupted)?;
+        let Some(length) = frame.len().checked_sub(size_of::<u32>()) else {
+            return Err(FrameError::Corrupted);
+        };
+        let crc = u32::from_be_bytes(frame[length..].try_into().unwrap());
+        frame.truncate(length);
+        if crc32(&frame) != crc {
+            return Err(FrameError::Corrupted);
+        }
+        self.data.extend(frame);
+        Ok(())
+    }
+}
+
+impl<C: Channel> Channel for Framed<C> {
+    type Error = FrameError<C::Error>;
+
+    const MAX_LENGTH: u32 = C::MAX_LENGTH;
+
+    fn recv(&mut self, data: &mut [u8]) -> Result<(), Self::Error> {
+        for byte in data {
+            while self.data.is_empty() {
+                let next = self.channel.recv_u8()?;
+                self.push(next)?;
+            }
+            *byte = self.data.pop_front().unwrap();
+        }
+        Ok(())
+    }
+
+    fn send(&mut self, data: &[u8]) -> Result<(), Self::Error> {
+        if data.is_empty() {
+            return Ok(());
+        }
+        let frame = self.encode(data)?;
+        Ok(self.channel.send(&frame)?)
+    }
+}
+
+impl<C: AsyncChannel> AsyncChannel for Framed<C> {
+    type Error = FrameError<C::Error>;
+
+    const MAX_LENGTH: u32 = C::MAX_LENGTH;
+
+    async fn recv(&mut self, data: &mut [u8]) -> Result<(), Self::Error> {
+        for byte in data {
+            while self.data.is_empty() {
+                let next = self.channel.recv_u8().await?;
+                self.push(next)?;
+            }
+            *byte = self.data.pop_front().unwrap();
+        }
+        Ok(())
+    }
+
+    async fn send(&mut self, data: &[u8]) -> Result<(), Self::Error> {
+        if data.is_empty() {
+            return Ok(());
+        }
+        let frame = self.encode(data)?;
+        Ok(self.channel.send(&frame).await?)
+    }
+}
+
+impl<C: SplitChannel> SplitChannel for Framed<C> {
+    type Sender = Framed<C::Sender>;
+    type Receiver = Framed<C::Receiver>;
+
+    fn split(self) -> Result<(Framed<C::Sender>, Framed<C::Receiver>), Self::Error> {
+        let (sender, receiver) = self.channel.split()?;
+        Ok((
+            Framed::new(sender).with_max_frame_length(self.max_frame_length),
+            Framed {
+                channel: receiver,
+                max_frame_length: self.max_frame_length,
+                frame: self.frame,
+                overflow: self.overflow,
+                data: self.data,
+            },
+        ))
+    }
+
+    fn unsplit(sender: Framed<C::Sender>, receiver: Framed<C::Receiver>) -> Framed<C> {
+        Framed {
+            channel: C::unsplit(sender.channel, receiver.channel),
+            max_frame_length: receiver.max_frame_length,
+            frame: receiver.frame,
+            overflow: receiver.overflow,
+            data: receiver.data,
+        }
+    }
+}
+
+// Consistent overhead byte stuffing: each run of non-zero bytes is preceded
+// by its length plus one, and the zero byte after it is implied, except after
+// a run of 254 bytes.
+fn cobs_encode(data: &[u8]) -> Vec<u8> {
+    let mut encoded = vec![0];
+    let mut code_index = 0;
+    for &byte in data {
+        if byte != 0 {
+            encoded.push(byte);
+        }
+        let length = encoded.len() - code_index;
+        if byte == 0 || length == 0xff {
+            encoded[code_index] = if byte == 0 { length } else { 0xff } as u8;
+            code_index = encoded.len();
+            encoded.push(0);
+        }
+    }
+    encoded[code_index] = (encoded.len() - code_index) as u8;
+    encoded
+}
+
+fn cobs_decode(encoded: &[u8]) -> Option<Vec<u8>> {
+    let mut data = Vec::with_capacity(encoded.len());
+    let mut rest = encoded;
+    while let Some((&code, tail)) = rest.split_first() {
+        // Zero bytes only appear between frames.
+        let run = tail.get(..(code as usize).checked_sub(1)?)?;
+        data.extend(run);
+        rest = &tail[run.len()..];
+        if code != 0xff && !rest.is_empty() {
+            data.push(0);
+        }
+    }
+    Some(data)
+}
+
+// CRC-32 as used by Ethernet and zlib.
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &byte in data {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 == 1 {
+                (crc >> 1) ^ 0xedb8_8320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
+}
+
+// A channel over any byte stream. Sends are buffered until the next receive,
+// an explicit 'flush' or the channel being dropped, so a message written in
+// pieces still goes out in one write. Errors while flushing on drop are lost,
+// so flush the channel that 'finish' returns to see them.
+pub struct IoChannel<T: Read + Write> {
+    stream: BufWriter<T>,
+}
+
+pub type TcpChannel = IoChannel<TcpStream>;
+#[cfg(unix)]
+pub type UnixChannel = IoChannel<UnixStream>;
+
+impl<T: Read + Write> IoChannel<T> {
+    pub fn new(stream: T) -> IoChannel<T> {
+        IoChannel {
+            stream: BufWriter::new(stream),
+        }
+    }
+    pub fn with_capacity(capacity: usize, stream: T) -> IoChannel<T> {
+        IoChannel {
+            stream: BufWriter::with_capacity(capacity, stream),
+        }
+    }
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.stream.flush()
+    }
+    pub fn get_ref(&self) -> &T {
+        self.stream.get_ref()
+    }
+    // Writing to the stream directly skips the data that is still buffered.
+    pub fn get_mut(&mut self) -> &mut T {
+        self.stream.get_mut()
+    }
+    // Flushes the buffered data first.
+    pub fn into_inner(self) -> io::Result<T> {
+        self.stream.into_inner().map_err(|err| err.into_error())
+    }
+}
+
+impl<T: Read + Write> Channel for IoChannel<T> {
     type Error = io::Error;
 
     fn recv(&mut self, data: &mut [u8]) -> Result<(), Self::Error> {
-        self.stream.read_exact(data)
+        // The other side may be waiting for what was sent before it replies.
+        self.stream.flush()?;
+        self.stream.get_mut().read_exact(data)
     }
 
     fn send(&mut self, data: &[u8]) -> Result<(), Self::Error> {
@@ -45,9 +715,81 @@ impl Channel for TcpChannel {
     }
 }
 
+impl IoChannel<TcpStream> {
+    // Nagle's algorithm is turned off since writes are already buffered.
+    pub fn connect(addr: impl ToSocketAddrs) -> io::Result<TcpChannel> {
+        let stream = TcpStream::connect(addr)?;
+        stream.set_nodelay(true)?;
+        Ok(IoChannel::new(stream))
+    }
+    pub fn accept(listener: &TcpListener) -> io::Result<(TcpChannel, SocketAddr)> {
+        let (stream, addr) = listener.accept()?;
+        stream.set_nodelay(true)?;
+        Ok((IoChannel::new(stream), addr))
+    }
+}
+
+impl SplitChannel for IoChannel<TcpStream> {
+    type Sender = TcpChannel;
+    type Receiver = TcpChannel;
+
+    fn split(self) -> io::Result<(TcpChannel, TcpChannel)> {
+        split_stream(self, TcpStream::try_clone)
+    }
+
+    fn unsplit(_: TcpChannel, receiver: TcpChannel) -> TcpChannel {
+        receiver
+    }
+}
+
+// Both halves write to the same socket, so the sender doesn't buffer: data
+// it held back would never be flushed by a receive on the other half. The
+// receiver keeps the capacity for when the halves are put back together.
+fn split_stream<T: Read + Write>(
+    channel: IoChannel<T>,
+    try_clone: impl Fn(&T) -> io::Result<T>,
+) -> io::Result<(IoChannel<T>, IoChannel<T>)> {
+    let capacity = channel.stream.capacity();
+    let stream = channel.into_inner()?;
+    let sender = IoChannel::with_capacity(0, try_clone(&stream)?);
+    Ok((sender, IoChannel::with_capacity(capacity, stream)))
+}
+
+#[cfg(unix)]
+impl IoChannel<UnixStream> {
+    pub fn connect(path: impl AsRef<Path>) -> io::Result<UnixChannel> {
+        Ok(IoChannel::new(UnixStream::connect(path)?))
+    }
+    pub fn accept(listener: &UnixListener) -> io::Result<UnixChannel> {
+        let (stream, _) = listener.accept()?;
+        Ok(IoChannel::new(stream))
+    }
+    // Both ends of a connected pair of sockets, e.g. to talk to a child
+    // process.
+    pub fn pair() -> io::Result<(UnixChannel, UnixChannel)> {
+        let (a, b) = UnixStream::pair()?;
+        Ok((IoChannel::new(a), IoChannel::new(b)))
+    }
+}
+
+#[cfg(unix)]
+impl SplitChannel for IoChannel<UnixStream> {
+    type Sender = UnixChannel;
+    type Receiver = UnixChannel;
+
+    fn split(self) -> io::Result<(UnixChannel, UnixChannel)> {
+        split_stream(self, UnixStream::try_clone)
+    }
+
+    fn unsplit(_: UnixChannel, receiver: UnixChannel) -> UnixChannel {
+        receiver
+    }
+}
+
 pub struct TestChannel {
     send: Weak<RefCell<VecDeque<u8>>>,
     recv: Rc<RefCell<VecDeque<u8>>>,
+    log: bool,
 }
 
 #[derive(Debug, Clone, Copy)]
@@ -56,6 +798,17 @@ pub enum TestChannelError {
     Closed,
 }
 
+impl fmt::Display for TestChannelError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TestChannelError::NoData => write!(f, "no data to receive"),
+            TestChannelError::Closed => write!(f, "channel closed"),
+        }
+    }
+}
+
+impl Error for TestChannelError {}
+
 impl Channel for TestChannel {
     type Error = TestChannelError;
 
@@ -68,7 +821,9 @@ impl Channel for TestChannel {
     }
 
     fn send(&mut self, data: &[u8]) -> Result<(), Self::Error> {
-        println!("sending: {:?}", data);
+        if self.log {
+            println!("sending: {:?}", data);
+        }
         let rc = self.send.upgrade().ok_or(TestChannelError::Closed)?;
         let mut queue = rc.borrow_mut();
         for byte in data {
@@ -78,6 +833,34 @@ impl Channel for TestChannel {
     }
 }
 
+impl SplitChannel for TestChannel {
+    type Sender = TestChannel;
+    type Receiver = TestChannel;
+
+    fn split(self) -> Result<(TestChannel, TestChannel), Self::Error> {
+        Ok((
+            TestChannel {
+                send: self.send,
+                recv: Rc::new(RefCell::new(VecDeque::new())),
+                log: self.log,
+            },
+            TestChannel {
+                send: Weak::new(),
+                recv: self.recv,
+                log: self.log,
+            },
+        ))
+    }
+
+    fn unsplit(sender: TestChannel, receiver: TestChannel) -> TestChannel {
+        TestChannel {
+            send: sender.send,
+            recv: receiver.recv,
+            log: sender.log,
+        }
+    }
+}
+
 impl TestChannel {
     pub fn new() -> (TestChannel, TestChannel) {
         let a = Rc::new(RefCell::new(VecDeque::new()));
@@ -90,11 +873,837 @@ impl TestChannel {
             TestChannel {
                 send: a_weak,
                 recv: b,
+                log: false,
             },
             TestChannel {
                 send: b_weak,
                 recv: a,
+                log: false,
             },
         )
     }
+    // Prints everything sent on this end.
+    pub fn with_logging(mut self) -> TestChannel {
+        self.log = true;
+        self
+    }
+}
+
+// A pair of connected channels that can be used from different threads.
+// Receiving blocks until there is enough data, the other end is dropped or
+// the timeout runs out, and sending blocks while the other end has
+// 'capacity' bytes it hasn't received yet. Data is received as it arrives,
+// so the part of a receive that came before a timeout is lost.
+pub struct MemoryChannel {
+    send: Arc<MemoryPipe>,
+    recv: Arc<MemoryPipe>,
+    timeout: Option<Duration>,
+    log: bool,
+}
+
+struct MemoryPipe {
+    state: Mutex<MemoryPipeState>,
+    changed: Condvar,
+    capacity: usize,
+}
+
+#[derive(Default)]
+struct MemoryPipeState {
+    data: VecDeque<u8>,
+    closed: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MemoryChannelError {
+    Closed,
+    Timeout,
+}
+
+impl fmt::Display for MemoryChannelError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MemoryChannelError::Closed => write!(f, "channel closed"),
+            MemoryChannelError::Timeout => write!(f, "channel timed out"),
+        }
+    }
+}
+
+impl Error for MemoryChannelError {}
+
+impl MemoryChannel {
+    pub fn new() -> (MemoryChannel, MemoryChannel) {
+        MemoryChannel::with_capacity(usize::MAX)
+    }
+    pub fn with_capacity(capacity: usize) -> (MemoryChannel, MemoryChannel) {
+        assert!(capacity > 0, "a channel needs room for at least one byte");
+        let a = MemoryPipe::new(capacity);
+        let b = MemoryPipe::new(capacity);
+        (
+            MemoryChannel {
+                send: Arc::clone(&a),
+                recv: Arc::clone(&b),
+                timeout: None,
+                log: false,
+            },
+            MemoryChannel {
+                send: b,
+                recv: a,
+                timeout: None,
+                log: false,
+            },
+        )
+    }
+    // How long a send or a receive on this end waits for the other end.
+    pub fn with_timeout(mut self, timeout: Duration) -> MemoryChannel {
+        self.timeout = Some(timeout);
+        self
+    }
+    // Prints everything sent on this end.
+    pub fn with_logging(mut self) -> MemoryChannel {
+        self.log = true;
+        self
+    }
+}
+
+impl MemoryPipe {
+    fn new(capacity: usize) -> Arc<MemoryPipe> {
+        Arc::new(MemoryPipe {
+            state: Mutex::new(MemoryPipeState::default()),
+            changed: Condvar::new(),
+            capacity,
+        })
+    }
+    fn closed() -> Arc<MemoryPipe> {
+        let pipe = MemoryPipe::new(1);
+        pipe.close();
+        pipe
+    }
+    // Waits until 'ready' or the deadline, whichever is first.
+    fn wait_until(
+        &self,
+        deadline: Option<Instant>,
+        ready: impl Fn(&MemoryPipeState) -> bool,
+    ) -> Result<MutexGuard<'_, MemoryPipeState>, MemoryChannelError> {
+        let mut state = self.state.lock().unwrap();
+        while !ready(&state) {
+            state = match deadline {
+                Some(deadline) => {
+                    let now = Instant::now();
+                    if now >= deadline {
+                        return Err(MemoryChannelError::Timeout);
+                    }
+                    self.changed.wait_timeout(state, deadline - now).unwrap().0
+                }
+                None => self.changed.wait(state).unwrap(),
+            };
+        }
+        Ok(state)
+    }
+    fn close(&self) {
+        self.state.lock().unwrap().closed = true;
+        self.changed.notify_all();
+    }
+}
+
+impl Channel for MemoryChannel {
+    type Error = MemoryChannelError;
+
+    fn recv(&mut self, data: &mut [u8]) -> Result<(), Self::Error> {
+        let deadline = self.timeout.map(|timeout| Instant::now() + timeout);
+        let mut filled = 0;
+        while filled < data.len() {
+            let mut state = self
+                .recv
+                .wait_until(deadline, |state| !state.data.is_empty() || state.closed)?;
+            if state.data.is_empty() {
+                return Err(MemoryChannelError::Closed);
+            }
+            let count = state.data.len().min(data.len() - filled);
+            for (byte, received) in data[filled..].iter_mut().zip(state.data.drain(..count)) {
+                *byte = received;
+            }
+            filled += count;
+            self.recv.changed.notify_all();
+        }
+        Ok(())
+    }
+
+    fn send(&mut self, data: &[u8]) -> Result<(), Self::Error> {
+        if self.log {
+            println!("sending: {:?}", data);
+        }
+        let deadline = self.timeout.map(|timeout| Instant::now() + timeout);
+        let capacity = self.send.capacity;
+        let mut sent = 0;
+        while sent < data.len() {
+            let mut state = self.send.wait_until(deadline, |state| {
+                state.data.len() < capacity || state.closed
+            })?;
+            if state.closed {
+                return Err(MemoryChannelError::Closed);
+            }
+            let count = (capacity - state.data.len()).min(data.len() - sent);
+            state.data.extend(&data[sent..sent + count]);
+            sent += count;
+            self.send.changed.notify_all();
+        }
+        Ok(())
+    }
+}
+
+// Each half gets a closed pipe in place of the one it doesn't use, so dropping
+// a half only closes its own direction.
+impl SplitChannel for MemoryChannel {
+    type Sender = MemoryChannel;
+    type Receiver = MemoryChannel;
+
+    fn split(mut self) -> Result<(MemoryChannel, MemoryChannel), Self::Error> {
+        let sender = MemoryChannel {
+            send: mem::replace(&mut self.send, MemoryPipe::closed()),
+            recv: MemoryPipe::closed(),
+            timeout: self.timeout,
+            log: self.log,
+        };
+        let receiver = MemoryChannel {
+            send: MemoryPipe::closed(),
+            recv: mem::replace(&mut self.recv, MemoryPipe::closed()),
+            timeout: self.timeout,
+            log: self.log,
+        };
+        Ok((sender, receiver))
+    }
+
+    fn unsplit(mut sender: MemoryChannel, mut receiver: MemoryChannel) -> MemoryChannel {
+        MemoryChannel {
+            send: mem::replace(&mut sender.send, MemoryPipe::closed()),
+            recv: mem::replace(&mut receiver.recv, MemoryPipe::closed()),
+            timeout: receiver.timeout,
+            log: sender.log,
+        }
+    }
+}
+
+// Data sent before the drop can still be received by the other end.
+impl Drop for MemoryChannel {
+    fn drop(&mut self) {
+        self.send.close();
+        self.recv.close();
+    }
+}
+
+#[derive(Default)]
+struct Pipe {
+    data: VecDeque<u8>,
+    waker: Option<Waker>,
+}
+
+pub struct AsyncTestChannel {
+    send: sync::Weak<Mutex<Pipe>>,
+    recv: Arc<Mutex<Pipe>>,
+}
+
+impl AsyncChannel for AsyncTestChannel {
+    type Error = TestChannelError;
+
+    async fn recv(&mut self, data: &mut [u8]) -> Result<(), Self::Error> {
+        let mut filled = 0;
+        poll_fn(|cx| {
+            let mut pipe = self.recv.lock().unwrap();
+            while filled < data.len() {
+                let Some(byte) = pipe.data.pop_front() else {
+                    break;
+                };
+                data[filled] = byte;
+                filled += 1;
+            }
+            if filled == data.len() {
+                Poll::Ready(Ok(()))
+            } else if Arc::weak_count(&self.recv) == 0 {
+                Poll::Ready(Err(TestChannelError::Closed))
+            } else {
+                pipe.waker = Some(cx.waker().clone());
+                Poll::Pending
+            }
+        })
+        .await
+    }
+
+    async fn send(&mut self, data: &[u8]) -> Result<(), Self::Error> {
+        let arc = self.send.upgrade().ok_or(TestChannelError::Closed)?;
+        let mut pipe = arc.lock().unwrap();
+        pipe.data.extend(data);
+        if let Some(waker) = pipe.waker.take() {
+            waker.wake();
+        }
+        Ok(())
+    }
+}
+
+impl AsyncTestChannel {
+    pub fn new() -> (AsyncTestChannel, AsyncTestChannel) {
+        let a = Arc::new(Mutex::new(Pipe::default()));
+        let b = Arc::new(Mutex::new(Pipe::default()));
+
+        (
+            AsyncTestChannel {
+                send: Arc::downgrade(&a),
+                recv: b.clone(),
+            },
+            AsyncTestChannel {
+                send: Arc::downgrade(&b),
+                recv: a,
+            },
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, io::Read, net::TcpListener, process, thread, time::Duration};
+
+    use crate::test_support::run_both;
+
+    use super::{
+        cobs_decode, cobs_encode, crc32, handshake, handshake_async, AsyncChannel,
+        AsyncTestChannel, Branch, Channel, FrameError, Framed, HandshakeError, MemoryChannel,
+        MemoryChannelError, MuxError, RecvError, SplitChannel, TcpChannel, TestChannel,
+        TestChannelError,
+    };
+
+    #[cfg(unix)]
+    use super::{UnixChannel, UnixListener};
+
+    #[test]
+    fn test_branches_interleaved() {
+        let (a, b) = TestChannel::new();
+        let mut a_branches = Branch::split(a, 2).unwrap();
+        let mut b_branches = Branch::split(b, 2).unwrap();
+
+        a_branches[0].send(&[1, 2]).unwrap();
+        a_branches[1].send(&[3]).unwrap();
+        a_branches[0].send(&[4]).unwrap();
+        a_branches[1].send_end().unwrap();
+
+        let mut data = [0; 1];
+        b_branches[1].recv(&mut data).unwrap();
+        assert_eq!(data, [3]);
+        assert!(b_branches[1].recv_end().unwrap());
+        let mut data = [0; 3];
+        b_branches[0].recv(&mut data).unwrap();
+        assert_eq!(data, [1, 2, 4]);
+
+        b_branches[0].send(&[5]).unwrap();
+        let mut a = Branch::join(a_branches);
+        let mut b = Branch::join(b_branches);
+        assert_eq!(a.recv_u8().unwrap(), 0);
+        assert_eq!(a.recv_u32().unwrap(), 1);
+        assert_eq!(a.recv_u8().unwrap(), 5);
+
+        a.send_u8(6).unwrap();
+        assert_eq!(b.recv_u8().unwrap(), 6);
+    }
+
+    #[test]
+    fn test_branch_ended() {
+        let (a, b) = TestChannel::new();
+        let mut a_branches = Branch::split(a, 1).unwrap();
+        let mut b_branches = Branch::split(b, 1).unwrap();
+
+        a_branches[0].send_end().unwrap();
+        let mut data = [0; 1];
+        assert!(matches!(
+            b_branches[0].recv(&mut data),
+            Err(MuxError::BranchEnded(0))
+        ));
+    }
+
+    #[test]
+    fn test_invalid_branch() {
+        let (a, b) = TestChannel::new();
+        let mut a_branches = Branch::split(a, 2).unwrap();
+        let mut b_branches = Branch::split(b, 1).unwrap();
+
+        a_branches[1].send(&[1]).unwrap();
+        assert!(matches!(
+            b_branches[0].recv_end(),
+            Err(MuxError::InvalidBranch(1))
+        ));
+    }
+
+    // A branch that is waiting while another one reads from a closed channel
+    // is woken up with an error.
+    #[test]
+    fn test_branch_failed() {
+        let (a, b) = MemoryChannel::new();
+        let mut branches = Branch::split(a, 2).unwrap();
+        let mut second = branches.pop().unwrap();
+        let mut first = branches.pop().unwrap();
+        let reader = thread::spawn(move || first.recv_u8());
+        let waiter = thread::spawn(move || second.recv_u8());
+        thread::sleep(Duration::from_millis(10));
+        drop(b);
+        let results = [reader.join().unwrap(), waiter.join().unwrap()];
+        assert!(results.contains(&Err(MuxError::Channel(MemoryChannelError::Closed))));
+        assert!(results.contains(&Err(MuxError::Failed)));
+    }
+
+    // The last frame is for the branch that doesn't own the receiver, and the
+    // peer only sends the next one after that branch replies.
+    #[test]
+    fn test_branch_frame_for_waiting_branch() {
+        for _ in 0..200 {
+            let (a, b) = MemoryChannel::new();
+            let a = a.with_timeout(Duration::from_secs(1));
+            let b = b.with_timeout(Duration::from_secs(1));
+            let mut a_branches = Branch::split(a, 2).unwrap();
+            let mut b_branches = Branch::split(b, 2).unwrap();
+            let mut second = b_branches.pop().unwrap();
+            let mut first = b_branches.pop().unwrap();
+            let owner = thread::spawn(move || {
+                let ended = first.recv_end();
+                (first, ended)
+            });
+            let waiter = thread::spawn(move || {
+                let byte = second.recv_u8()?;
+                second.send_u8(byte + 1)?;
+                Ok::<_, MuxError<MemoryChannelError>>(second)
+            });
+            a_branches[1].send_u8(1).unwrap();
+            assert_eq!(a_branches[1].recv_u8().unwrap(), 2);
+            a_branches[0].send_end().unwrap();
+            let (first, ended) = owner.join().unwrap();
+            assert_eq!(ended, Ok(true));
+            let second = waiter.join().unwrap().unwrap();
+            Branch::join(vec![first, second]);
+        }
+    }
+
+    #[test]
+    fn test_branch_frame_length() {
+        let (a, b) = MemoryChannel::new();
+        let mut a_branches = Branch::split(a, 1).unwrap();
+        let mut b_branches = Branch::split(b, 1).unwrap();
+        let data = vec![7; MemoryChannel::MAX_LENGTH as usize + 1];
+        a_branches[0].send(&data).unwrap();
+        let mut received = vec![0; data.len()];
+        b_branches[0].recv(&mut received).unwrap();
+        assert_eq!(received, data);
+
+        let (mut a, b) = MemoryChannel::new();
+        let mut b_branches = Branch::split(b, 1).unwrap();
+        a.send_u8(0).unwrap();
+        a.send(&u32::to_be_bytes(u32::MAX)).unwrap();
+        assert_eq!(
+            b_branches[0].recv_u8(),
+            Err(MuxError::FrameTooLong {
+                length: u32::MAX,
+                max: MemoryChannel::MAX_LENGTH
+            })
+        );
+    }
+
+    #[test]
+    fn test_handshake() {
+        let (mut a, mut b) = TestChannel::new();
+        a.send(&u64::to_be_bytes(7)).unwrap();
+        b.send(&u64::to_be_bytes(7)).unwrap();
+        handshake(&mut a, 7).unwrap();
+
+        let (mut a, mut b) = TestChannel::new();
+        b.send(&u64::to_be_bytes(8)).unwrap();
+        assert!(matches!(
+            handshake(&mut a, 7),
+            Err(HandshakeError::FingerprintMismatch {
+                local: 7,
+                remote: 8
+            })
+        ));
+    }
+
+    #[test]
+    fn test_async_channel() {
+        let (mut a, mut b) = AsyncTestChannel::new();
+        let (sent, received) = run_both(
+            async {
+                a.send(&[1, 2]).await?;
+                a.send_u8(3).await
+            },
+            async {
+                let mut data = [0; 3];
+                b.recv(&mut data).await?;
+                Ok::<_, TestChannelError>(data)
+            },
+        );
+        sent.unwrap();
+        assert_eq!(received.unwrap(), [1, 2, 3]);
+
+        drop(a);
+        let (_, received) = run_both(async {}, b.recv_u8());
+        assert!(matches!(received, Err(TestChannelError::Closed)));
+    }
+
+    #[test]
+    #[should_panic(expected = "waiting on each other")]
+    fn test_async_deadlock() {
+        let (mut a, mut b) = AsyncTestChannel::new();
+        let _ = run_both(a.recv_u8(), b.recv_u8());
+    }
+
+    #[test]
+    fn test_handshake_async() {
+        let (mut a, mut b) = AsyncTestChannel::new();
+        let (a_result, b_result) = run_both(handshake_async(&mut a, 7), handshake_async(&mut b, 7));
+        a_result.unwrap();
+        b_result.unwrap();
+
+        let (mut a, mut b) = AsyncTestChannel::new();
+        let (a_result, _) = run_both(handshake_async(&mut a, 7), handshake_async(&mut b, 8));
+        assert!(matches!(
+            a_result,
+            Err(HandshakeError::FingerprintMismatch {
+                local: 7,
+                remote: 8
+            })
+        ));
+    }
+
+    #[test]
+    fn test_recv_length() {
+        let (mut a, mut b) = TestChannel::new();
+        a.send(&u32::to_be_bytes(3)).unwrap();
+        assert_eq!(b.recv_length().unwrap(), 3);
+
+        a.send(&u32::to_be_bytes(u32::MAX)).unwrap();
+        assert!(matches!(
+            b.recv_length(),
+            Err(RecvError::LengthTooLarge {
+                length: u32::MAX,
+                max: 65536
+            })
+        ));
+    }
+
+    #[test]
+    fn test_cobs() {
+        let cases: [(&[u8], &[u8]); 5] = [
+            (&[], &[0x01]),
+            (&[0x00], &[0x01, 0x01]),
+            (&[0x00, 0x00], &[0x01, 0x01, 0x01]),
+            (&[0x11, 0x22, 0x00, 0x33], &[0x03, 0x11, 0x22, 0x02, 0x33]),
+            (&[0x11, 0x00, 0x00, 0x00], &[0x02, 0x11, 0x01, 0x01, 0x01]),
+        ];
+        for (data, encoded) in cases {
+            assert_eq!(cobs_encode(data), encoded);
+            assert_eq!(cobs_decode(encoded).unwrap(), data);
+        }
+
+        // Runs of 254 non-zero bytes don't need a zero after them.
+        let data: Vec<u8> = (1..=255).collect();
+        let encoded = cobs_encode(&data);
+        assert_eq!(encoded.len(), 257);
+        assert_eq!(encoded[0], 0xff);
+        assert_eq!(encoded[255..], [0x02, 0xff]);
+        assert!(!encoded.contains(&0));
+        assert_eq!(cobs_decode(&encoded).unwrap(), data);
+
+        assert_eq!(cobs_decode(&[0x03, 0x11]), None);
+    }
+
+    #[test]
+    fn test_crc32() {
+        assert_eq!(crc32(b""), 0);
+        assert_eq!(crc32(b"123456789"), 0xcbf4_3926);
+    }
+
+    #[test]
+    fn test_framed() {
+        let (a, b) = TestChannel::new();
+        let mut a = Framed::new(a);
+        let mut b = Framed::new(b);
+        a.send(&[1, 0, 2]).unwrap();
+        a.send(&[]).unwrap();
+        a.send_u8(3).unwrap();
+        let mut data = [0; 4];
+        b.recv(&mut data).unwrap();
+        assert_eq!(data, [1, 0, 2, 3]);
+        assert!(matches!(
+            b.recv_u8(),
+            Err(FrameError::Channel(TestChannelError::NoData))
+        ));
+
+        let mut raw = a.into_inner();
+        raw.send(&[0, 0]).unwrap();
+        let mut a = Framed::new(raw);
+        a.send_u8(4).unwrap();
+        assert_eq!(b.recv_u8().unwrap(), 4);
+    }
+
+    // Sends three frames with 'corrupt' applied to the bytes of the second
+    // one, and checks that only the second one is lost.
+    fn resync(corrupt: impl FnOnce(&mut Vec<u8>)) {
+        let (mut a, b) = TestChannel::new();
+        let mut b = Framed::new(b);
+        let framed = Framed::new(());
+        let mut frame = framed.encode::<()>(&[3, 4]).unwrap();
+        corrupt(&mut frame);
+        a.send(&framed.encode::<()>(&[1, 2]).unwrap()).unwrap();
+        a.send(&frame).unwrap();
+        a.send(&framed.encode::<()>(&[5, 6]).unwrap()).unwrap();
+
+        let mut data = [0; 2];
+        b.recv(&mut data).unwrap();
+        assert_eq!(data, [1, 2]);
+        assert!(matches!(b.recv(&mut data), Err(FrameError::Corrupted)));
+        b.recv(&mut data).unwrap();
+        assert_eq!(data, [5, 6]);
+        assert!(b.recv_u8().is_err());
+    }
+
+    #[test]
+    fn test_framed_resync() {
+        resync(|frame| frame[2] ^= 0x40);
+        resync(|frame| {
+            frame.remove(3);
+        });
+        // A frame with a byte lost before the CRC.
+        resync(|frame| {
+            frame.remove(1);
+        });
+        // Corrupted COBS codes.
+        resync(|frame| frame[0] = 0x7f);
+    }
+
+    #[test]
+    fn test_framed_too_long() {
+        let (a, b) = TestChannel::new();
+        let mut a = Framed::new(a);
+        let mut b = Framed::new(b).with_max_frame_length(2);
+        a.send(&[1, 2, 3]).unwrap();
+        a.send(&[4, 5]).unwrap();
+        assert!(matches!(b.recv_u8(), Err(FrameError::TooLong { max: 2 })));
+        assert_eq!(b.recv_u8().unwrap(), 4);
+        assert!(matches!(
+            b.send(&[1, 2, 3]),
+            Err(FrameError::TooLong { max: 2 })
+        ));
+    }
+
+    #[test]
+    fn test_framed_async() {
+        let (a, b) = AsyncTestChannel::new();
+        let mut a = Framed::new(a);
+        let mut b = Framed::new(b);
+        let (sent, received) = run_both(
+            async {
+                a.send(&[1, 0]).await?;
+                a.send_u8(2).await
+            },
+            async {
+                let mut data = [0; 3];
+                b.recv(&mut data).await?;
+                Ok::<_, FrameError<TestChannelError>>(data)
+            },
+        );
+        sent.unwrap();
+        assert_eq!(received.unwrap(), [1, 0, 2]);
+    }
+
+    #[test]
+    fn test_framed_branches() {
+        let (a, b) = TestChannel::new();
+        let mut a_branches = Branch::split(Framed::new(a), 2).unwrap();
+        let mut b_branches = Branch::split(Framed::new(b), 2).unwrap();
+        a_branches[1].send(&[1]).unwrap();
+        a_branches[0].send(&[2]).unwrap();
+        assert_eq!(b_branches[0].recv_u8().unwrap(), 2);
+        assert_eq!(b_branches[1].recv_u8().unwrap(), 1);
+    }
+
+    #[test]
+    fn test_tcp_channel() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = thread::spawn(move || {
+            let (mut channel, _) = TcpChannel::accept(&listener).unwrap();
+            handshake(&mut channel, 7).unwrap();
+            let mut data = [0; 3];
+            channel.recv(&mut data).unwrap();
+            channel.send(&data).unwrap();
+            channel.flush().unwrap();
+        });
+
+        let mut channel = TcpChannel::connect(addr).unwrap();
+        handshake(&mut channel, 7).unwrap();
+        channel.send(&[1, 2]).unwrap();
+        channel.send_u8(3).unwrap();
+        let mut data = [0; 3];
+        channel.recv(&mut data).unwrap();
+        assert_eq!(data, [1, 2, 3]);
+        server.join().unwrap();
+    }
+
+    // Each branch is driven from its own thread on both ends of a socket.
+    #[test]
+    fn test_tcp_branches() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = thread::spawn(move || {
+            let (mut channel, _) = TcpChannel::accept(&listener).unwrap();
+            assert_eq!(channel.recv_u8().unwrap(), 0);
+            let branches: Vec<_> = Branch::split(channel, 2)
+                .unwrap()
+                .into_iter()
+                .map(|mut branch| {
+                    thread::spawn(move || {
+                        let byte = branch.recv_u8().unwrap();
+                        branch.send_u8(byte + 1).unwrap();
+                        branch
+                    })
+                })
+                .collect();
+            let mut channel =
+                Branch::join(branches.into_iter().map(|t| t.join().unwrap()).collect());
+            channel.send_u8(9).unwrap();
+            channel.flush().unwrap();
+        });
+
+        let mut channel = TcpChannel::connect(addr).unwrap();
+        channel.send_u8(0).unwrap();
+        // The byte before the split is only buffered until then.
+        let branches: Vec<_> = Branch::split(channel, 2)
+            .unwrap()
+            .into_iter()
+            .enumerate()
+            .map(|(index, mut branch)| {
+                thread::spawn(move || {
+                    branch.send_u8(index as u8 * 10).unwrap();
+                    assert_eq!(branch.recv_u8().unwrap(), index as u8 * 10 + 1);
+                    branch
+                })
+            })
+            .collect();
+        let mut channel = Branch::join(branches.into_iter().map(|t| t.join().unwrap()).collect());
+        assert_eq!(channel.recv_u8().unwrap(), 9);
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn test_memory_channel_split() {
+        let (a, mut b) = MemoryChannel::new();
+        let (mut sender, receiver) = a.split().unwrap();
+        sender.send_u8(1).unwrap();
+        assert_eq!(b.recv_u8().unwrap(), 1);
+        b.send_u8(2).unwrap();
+        let mut a = MemoryChannel::unsplit(sender, receiver);
+        assert_eq!(a.recv_u8().unwrap(), 2);
+        a.send_u8(3).unwrap();
+        assert_eq!(b.recv_u8().unwrap(), 3);
+
+        let (sender, receiver) = a.split().unwrap();
+        drop(receiver);
+        assert!(matches!(b.send_u8(4), Err(MemoryChannelError::Closed)));
+        drop(sender);
+        assert!(matches!(b.recv_u8(), Err(MemoryChannelError::Closed)));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_io_channel_buffering() {
+        let (mut a, b) = UnixChannel::pair().unwrap();
+        let mut b = b.into_inner().unwrap();
+        a.send(&[1, 2]).unwrap();
+        b.set_nonblocking(true).unwrap();
+        assert!(b.read(&mut [0; 2]).is_err());
+
+        a.flush().unwrap();
+        let mut data = [0; 2];
+        b.read_exact(&mut data).unwrap();
+        assert_eq!(data, [1, 2]);
+
+        a.send_u8(3).unwrap();
+        let a = a.into_inner().unwrap();
+        drop(a);
+        b.set_nonblocking(false).unwrap();
+        let mut rest = vec![];
+        b.read_to_end(&mut rest).unwrap();
+        assert_eq!(rest, [3]);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_unix_channel() {
+        let path = env::temp_dir().join(format!("obbidl-channel-{}.sock", process::id()));
+        let _ = std::fs::remove_file(&path);
+        let listener = UnixListener::bind(&path).unwrap();
+        let server = thread::spawn(move || {
+            let mut channel = UnixChannel::accept(&listener).unwrap();
+            let byte = channel.recv_u8().unwrap();
+            channel.send_u8(byte + 1).unwrap();
+        });
+
+        let mut channel = UnixChannel::connect(&path).unwrap();
+        channel.send_u8(1).unwrap();
+        assert_eq!(channel.recv_u8().unwrap(), 2);
+        server.join().unwrap();
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_memory_channel() {
+        let (mut a, b) = MemoryChannel::new();
+        let server = thread::spawn(move || {
+            let mut b = b;
+            handshake(&mut b, 7).unwrap();
+            let mut data = [0; 3];
+            b.recv(&mut data).unwrap();
+            b.send(&data).unwrap();
+        });
+        handshake(&mut a, 7).unwrap();
+        a.send(&[1, 2]).unwrap();
+        a.send_u8(3).unwrap();
+        let mut data = [0; 3];
+        a.recv(&mut data).unwrap();
+        assert_eq!(data, [1, 2, 3]);
+        server.join().unwrap();
+
+        // Everything the other end sent has been received.
+        assert!(matches!(a.recv_u8(), Err(MemoryChannelError::Closed)));
+        assert!(matches!(a.send_u8(4), Err(MemoryChannelError::Closed)));
+    }
+
+    #[test]
+    fn test_memory_channel_capacity() {
+        let (mut a, mut b) = MemoryChannel::with_capacity(2);
+        let data: Vec<u8> = (0..10).collect();
+        let sent = data.clone();
+        let sender = thread::spawn(move || {
+            a.send(&sent).unwrap();
+            a
+        });
+        let mut received = [0; 10];
+        b.recv(&mut received).unwrap();
+        assert_eq!(received[..], data[..]);
+        let mut a = sender.join().unwrap();
+
+        a.send(&[1, 2]).unwrap();
+        let mut a = a.with_timeout(Duration::from_millis(10));
+        assert!(matches!(a.send_u8(3), Err(MemoryChannelError::Timeout)));
+        assert_eq!(b.recv_u8().unwrap(), 1);
+        a.send_u8(3).unwrap();
+    }
+
+    #[test]
+    fn test_memory_channel_timeout() {
+        let (mut a, b) = MemoryChannel::new();
+        let mut b = b.with_timeout(Duration::from_millis(10));
+        assert!(matches!(b.recv_u8(), Err(MemoryChannelError::Timeout)));
+        a.send_u8(1).unwrap();
+        assert_eq!(b.recv_u8().unwrap(), 1);
+    }
+
+    #[test]
+    fn test_memory_channel_is_send() {
+        fn is_send<T: Send>() {}
+        is_send::<MemoryChannel>();
+    }
 }
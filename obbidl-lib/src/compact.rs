@@ -0,0 +1,414 @@
+use crate::{
+    channel::{AsyncChannel, Channel, RecvError},
+    encode::into_array,
+};
+
+// The compact binary encoding, for slow links. Integers and array lengths are
+// LEB128 varints, with signed integers zigzag encoded so that small negative
+// numbers are short too. 'u8' and 'i8' are single bytes as in
+// 'obbidl_lib::encode', since a varint is never shorter than that. The traits
+// are the same as that module's.
+pub trait Encode {
+    fn encode(&self, buf: &mut Vec<u8>);
+}
+
+pub trait Decode: Sized {
+    fn decode<C: Channel>(channel: &mut C) -> Result<Self, RecvError<C::Error>>;
+
+    #[allow(async_fn_in_trait)]
+    async fn decode_async<C: AsyncChannel>(channel: &mut C) -> Result<Self, RecvError<C::Error>>;
+}
+
+pub fn encode_varint(mut value: u64, buf: &mut Vec<u8>) {
+    while value >= 0x80 {
+        buf.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    buf.push(value as u8);
+}
+
+pub fn decode_varint<C: Channel>(channel: &mut C) -> Result<u64, RecvError<C::Error>> {
+    let mut value = 0;
+    let mut index = 0;
+    loop {
+        if push_varint_byte(&mut value, index, channel.recv_u8()?)? {
+            return Ok(value);
+        }
+        index += 1;
+    }
+}
+
+pub async fn decode_varint_async<C: AsyncChannel>(
+    channel: &mut C,
+) -> Result<u64, RecvError<C::Error>> {
+    let mut value = 0;
+    let mut index = 0;
+    loop {
+        if push_varint_byte(&mut value, index, channel.recv_u8().await?)? {
+            return Ok(value);
+        }
+        index += 1;
+    }
+}
+
+// Adds the 'index'th byte of a varint to its value, and returns whether it
+// was the last one.
+fn push_varint_byte<E>(value: &mut u64, index: usize, byte: u8) -> Result<bool, RecvError<E>> {
+    // The tenth byte holds only the top bit of a u64.
+    if index == 9 && byte > 1 {
+        return Err(RecvError::Malformed("varint is too long".to_string()));
+    }
+    *value |= ((byte & 0x7f) as u64) << (7 * index);
+    Ok(byte & 0x80 == 0)
+}
+
+pub(crate) fn zigzag(value: i64) -> u64 {
+    ((value << 1) ^ (value >> 63)) as u64
+}
+
+pub(crate) fn unzigzag(value: u64) -> i64 {
+    (value >> 1) as i64 ^ -((value & 1) as i64)
+}
+
+fn check_length<E>(length: u64, max: u32) -> Result<usize, RecvError<E>> {
+    if length > max as u64 {
+        return Err(RecvError::LengthTooLarge {
+            length: u32::try_from(length).unwrap_or(u32::MAX),
+            max,
+        });
+    }
+    Ok(length as usize)
+}
+
+fn out_of_range<E>(value: impl std::fmt::Display, ty: &str) -> RecvError<E> {
+    RecvError::Malformed(format!("{} is out of range for {}", value, ty))
+}
+
+impl Encode for bool {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        buf.push(*self as u8);
+    }
+}
+
+impl Decode for bool {
+    fn decode<C: Channel>(channel: &mut C) -> Result<Self, RecvError<C::Error>> {
+        match channel.recv_u8()? {
+            0 => Ok(false),
+            1 => Ok(true),
+            value => Err(RecvError::InvalidBool(value)),
+        }
+    }
+
+    async fn decode_async<C: AsyncChannel>(channel: &mut C) -> Result<Self, RecvError<C::Error>> {
+        match channel.recv_u8().await? {
+            0 => Ok(false),
+            1 => Ok(true),
+            value => Err(RecvError::InvalidBool(value)),
+        }
+    }
+}
+
+impl Encode for u8 {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        buf.push(*self);
+    }
+}
+
+impl Decode for u8 {
+    fn decode<C: Channel>(channel: &mut C) -> Result<Self, RecvError<C::Error>> {
+        Ok(channel.recv_u8()?)
+    }
+
+    async fn decode_async<C: AsyncChannel>(channel: &mut C) -> Result<Self, RecvError<C::Error>> {
+        Ok(channel.recv_u8().await?)
+    }
+}
+
+impl Encode for i8 {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        buf.push(*self as u8);
+    }
+}
+
+impl Decode for i8 {
+    fn decode<C: Channel>(channel: &mut C) -> Result<Self, RecvError<C::Error>> {
+        Ok(channel.recv_u8()? as i8)
+    }
+
+    async fn decode_async<C: AsyncChannel>(channel: &mut C) -> Result<Self, RecvError<C::Error>> {
+        Ok(channel.recv_u8().await? as i8)
+    }
+}
+
+macro_rules! impl_unsigned {
+    ($($ty:ty),*) => {
+        $(
+            impl Encode for $ty {
+                fn encode(&self, buf: &mut Vec<u8>) {
+                    encode_varint(*self as u64, buf);
+                }
+            }
+
+            impl Decode for $ty {
+                fn decode<C: Channel>(channel: &mut C) -> Result<Self, RecvError<C::Error>> {
+                    let value = decode_varint(channel)?;
+                    <$ty>::try_from(value).map_err(|_| out_of_range(value, stringify!($ty)))
+                }
+
+                async fn decode_async<C: AsyncChannel>(
+                    channel: &mut C,
+                ) -> Result<Self, RecvError<C::Error>> {
+                    let value = decode_varint_async(channel).await?;
+                    <$ty>::try_from(value).map_err(|_| out_of_range(value, stringify!($ty)))
+                }
+            }
+        )*
+    };
+}
+
+impl_unsigned!(u16, u32, u64);
+
+macro_rules! impl_signed {
+    ($($ty:ty),*) => {
+        $(
+            impl Encode for $ty {
+                fn encode(&self, buf: &mut Vec<u8>) {
+                    encode_varint(zigzag(*self as i64), buf);
+                }
+            }
+
+            impl Decode for $ty {
+                fn decode<C: Channel>(channel: &mut C) -> Result<Self, RecvError<C::Error>> {
+                    let value = unzigzag(decode_varint(channel)?);
+                    <$ty>::try_from(value).map_err(|_| out_of_range(value, stringify!($ty)))
+                }
+
+                async fn decode_async<C: AsyncChannel>(
+                    channel: &mut C,
+                ) -> Result<Self, RecvError<C::Error>> {
+                    let value = unzigzag(decode_varint_async(channel).await?);
+                    <$ty>::try_from(value).map_err(|_| out_of_range(value, stringify!($ty)))
+                }
+            }
+        )*
+    };
+}
+
+impl_signed!(i16, i32, i64);
+
+// Arrays without a fixed size are prefixed with their length as a varint.
+impl<T: Encode> Encode for [T] {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        encode_varint(self.len() as u64, buf);
+        for item in self {
+            item.encode(buf);
+        }
+    }
+}
+
+impl<T: Encode> Encode for Vec<T> {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        self.as_slice().encode(buf)
+    }
+}
+
+impl<T: Decode> Decode for Vec<T> {
+    fn decode<C: Channel>(channel: &mut C) -> Result<Self, RecvError<C::Error>> {
+        let length = check_length(decode_varint(channel)?, C::MAX_LENGTH)?;
+        let mut items = Vec::with_capacity(length);
+        for _ in 0..length {
+            items.push(T::decode(channel)?);
+        }
+        Ok(items)
+    }
+
+    async fn decode_async<C: AsyncChannel>(channel: &mut C) -> Result<Self, RecvError<C::Error>> {
+        let length = check_length(decode_varint_async(channel).await?, C::MAX_LENGTH)?;
+        let mut items = Vec::with_capacity(length);
+        for _ in 0..length {
+            items.push(T::decode_async(channel).await?);
+        }
+        Ok(items)
+    }
+}
+
+impl<T: Encode, const N: usize> Encode for [T; N] {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        for item in self {
+            item.encode(buf);
+        }
+    }
+}
+
+impl<T: Decode, const N: usize> Decode for [T; N] {
+    fn decode<C: Channel>(channel: &mut C) -> Result<Self, RecvError<C::Error>> {
+        let mut items = Vec::with_capacity(N);
+        for _ in 0..N {
+            items.push(T::decode(channel)?);
+        }
+        Ok(into_array(items))
+    }
+
+    async fn decode_async<C: AsyncChannel>(channel: &mut C) -> Result<Self, RecvError<C::Error>> {
+        let mut items = Vec::with_capacity(N);
+        for _ in 0..N {
+            items.push(T::decode_async(channel).await?);
+        }
+        Ok(into_array(items))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        fmt::Debug,
+        future::Future,
+        pin::pin,
+        task::{Context, Poll, Waker},
+    };
+
+    use crate::channel::{AsyncChannel, AsyncTestChannel, Channel, RecvError, TestChannel};
+
+    use super::{Decode, Encode};
+
+    fn encoded<T: Encode + ?Sized>(value: &T) -> Vec<u8> {
+        let mut buf = vec![];
+        value.encode(&mut buf);
+        buf
+    }
+
+    fn round_trip<T: Encode + Decode + PartialEq + Debug>(value: T) {
+        let (mut a, mut b) = TestChannel::new();
+        a.send(&encoded(&value)).unwrap();
+        assert_eq!(T::decode(&mut b).unwrap(), value);
+    }
+
+    // Everything is sent before it is received, so decoding never waits.
+    fn now<F: Future>(future: F) -> F::Output {
+        match pin!(future).poll(&mut Context::from_waker(Waker::noop())) {
+            Poll::Ready(output) => output,
+            Poll::Pending => panic!("expected the future to be ready"),
+        }
+    }
+
+    // Every power of two and its neighbours, where varints change length,
+    // and the limits of each type.
+    macro_rules! boundaries {
+        ($ty:ty) => {{
+            let mut values = vec![];
+            for bit in 0..<$ty>::BITS {
+                let power = (1 as $ty) << bit;
+                for value in [power, (0 as $ty).wrapping_sub(power)] {
+                    values.extend([value.wrapping_sub(1), value, value.wrapping_add(1)]);
+                }
+            }
+            values
+        }};
+    }
+
+    #[test]
+    fn test_boundaries() {
+        for value in boundaries!(u16) {
+            round_trip(value);
+        }
+        for value in boundaries!(u32) {
+            round_trip(value);
+        }
+        for value in boundaries!(u64) {
+            round_trip(value);
+        }
+        for value in boundaries!(i16) {
+            round_trip(value);
+        }
+        for value in boundaries!(i32) {
+            round_trip(value);
+        }
+        for value in boundaries!(i64) {
+            round_trip(value);
+        }
+    }
+
+    #[test]
+    fn test_exhaustive() {
+        for value in u8::MIN..=u8::MAX {
+            round_trip(value);
+        }
+        for value in i8::MIN..=i8::MAX {
+            round_trip(value);
+        }
+        for value in u16::MIN..=u16::MAX {
+            round_trip(value);
+        }
+        for value in i16::MIN..=i16::MAX {
+            round_trip(value);
+        }
+        round_trip(true);
+        round_trip(false);
+        round_trip(vec![[1u16, 2], [3, 4]]);
+        round_trip(vec![vec![true], vec![], vec![false, true]]);
+        round_trip([vec![0i64], vec![-7]]);
+    }
+
+    #[test]
+    fn test_wire_format() {
+        assert_eq!(encoded(&0u16), [0x00]);
+        assert_eq!(encoded(&127u16), [0x7f]);
+        assert_eq!(encoded(&128u16), [0x80, 0x01]);
+        assert_eq!(encoded(&300u32), [0xac, 0x02]);
+        assert_eq!(encoded(&u16::MAX), [0xff, 0xff, 0x03]);
+        assert_eq!(encoded(&u64::MAX).len(), 10);
+        assert_eq!(encoded(&0i32), [0x00]);
+        assert_eq!(encoded(&-1i32), [0x01]);
+        assert_eq!(encoded(&1i32), [0x02]);
+        assert_eq!(encoded(&-64i16), [0x7f]);
+        assert_eq!(encoded(&64i16), [0x80, 0x01]);
+        assert_eq!(encoded(&i64::MIN).len(), 10);
+        assert_eq!(encoded(&255u8), [0xff]);
+        assert_eq!(encoded(&-1i8), [0xff]);
+        assert_eq!(encoded(&[1u32, 2][..]), [2, 1, 2]);
+        assert_eq!(encoded(&[true, false]), [1, 0]);
+    }
+
+    #[test]
+    fn test_decode_errors() {
+        let decode_u64 = |bytes: &[u8]| {
+            let (mut a, mut b) = TestChannel::new();
+            a.send(bytes).unwrap();
+            u64::decode(&mut b)
+        };
+        assert!(decode_u64(&[0xff; 9]).is_err());
+        assert!(matches!(
+            decode_u64(&[0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x02]),
+            Err(RecvError::Malformed(_))
+        ));
+        // Not the shortest form, but still valid.
+        assert_eq!(decode_u64(&[0x81, 0x80, 0x00]).unwrap(), 1);
+
+        let (mut a, mut b) = TestChannel::new();
+        a.send(&encoded(&(u16::MAX as u32 + 1))).unwrap();
+        assert!(matches!(u16::decode(&mut b), Err(RecvError::Malformed(_))));
+        a.send(&encoded(&(i16::MIN as i32 - 1))).unwrap();
+        assert!(matches!(i16::decode(&mut b), Err(RecvError::Malformed(_))));
+        a.send(&encoded(&u32::MAX)).unwrap();
+        assert!(matches!(
+            Vec::<u8>::decode(&mut b),
+            Err(RecvError::LengthTooLarge {
+                length: u32::MAX,
+                ..
+            })
+        ));
+        a.send(&[2]).unwrap();
+        assert!(matches!(
+            bool::decode(&mut b),
+            Err(RecvError::InvalidBool(2))
+        ));
+    }
+
+    #[test]
+    fn test_decode_async() {
+        let (mut a, mut b) = AsyncTestChannel::new();
+        let value = vec![[-1i32, 1], [i32::MAX, i32::MIN]];
+        now(a.send(&encoded(&value))).unwrap();
+        assert_eq!(now(Vec::<[i32; 2]>::decode_async(&mut b)).unwrap(), value);
+    }
+}
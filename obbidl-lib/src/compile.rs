@@ -1,9 +1,15 @@
-use std::collections::{HashMap, VecDeque};
+use std::{
+    collections::{HashMap, VecDeque},
+    fmt,
+};
+
+use colored::Colorize;
 
 use crate::{
-    ast::{File, Message, Protocol, Role, Sequence, Sequences, Stmt},
+    ast::{File, Message, Protocol, Role, Sequence, Sequences, StateLabel, Stmt},
     parser::Span,
-    state_machine::{StateMachine, Transition},
+    residual::{NodeId, Residuals},
+    state_machine::{Fork, State, StateMachine, Transition},
 };
 
 #[derive(Debug, Clone)]
@@ -18,42 +24,143 @@ pub struct ProtocolFileStateMachines {
     pub protocols: Vec<Span<ProtocolStateMachine>>,
 }
 
+#[derive(Debug, Clone, Copy)]
+pub struct Limits {
+    pub max_states: usize,
+    pub max_transitions: usize,
+}
+
+impl Default for Limits {
+    fn default() -> Limits {
+        Limits {
+            max_states: 10_000,
+            max_transitions: 100_000,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Error<'a> {
+    pub protocol: &'a Span<Protocol>,
+    pub err: ProtocolError<'a>,
+}
+
+pub struct PrettyPrintError<'a> {
+    error: &'a Error<'a>,
+    source: &'a str,
+}
+
+#[derive(Debug, Clone)]
+pub enum ProtocolError<'a> {
+    TooManyStates {
+        limit: usize,
+        par: Option<ParBlock<'a>>,
+    },
+    TooManyTransitions {
+        limit: usize,
+        par: Option<ParBlock<'a>>,
+    },
+    AmbiguousStateLabel(Vec<&'a Span<StateLabel>>),
+}
+
+#[derive(Debug, Clone)]
+pub struct ParBlock<'a> {
+    pub branches: Vec<&'a Span<Message>>,
+    pub estimated_states: u64,
+}
+
 const DEFAULT_ROLES: &[&str] = &["C", "S"];
 
-pub fn compile_protocol_file(file: &File) -> ProtocolFileStateMachines {
+pub fn compile_protocol_file<'a>(
+    file: &'a File,
+    limits: &Limits,
+) -> Result<ProtocolFileStateMachines, Vec<Error<'a>>> {
     let mut protocols = vec![];
+    let mut errors = vec![];
     for protocol in &file.protocols {
-        protocols.push(
-            protocol
-                .as_ref()
-                .map(|protocol| compile_protocol(&protocol)),
-        )
+        match compile_protocol(&protocol.inner, limits) {
+            Ok(state_machine) => protocols.push(Span {
+                span: protocol.span,
+                inner: state_machine,
+            }),
+            Err(err) => errors.push(Error { protocol, err }),
+        }
+    }
+    if !errors.is_empty() {
+        return Err(errors);
     }
-    ProtocolFileStateMachines { protocols }
+    Ok(ProtocolFileStateMachines { protocols })
 }
 
-pub fn compile_protocol(protocol: &Protocol) -> ProtocolStateMachine {
+pub fn compile_protocol<'a>(
+    protocol: &'a Protocol,
+    limits: &Limits,
+) -> Result<ProtocolStateMachine, ProtocolError<'a>> {
     let mut state_machine = StateMachine::new();
-    let mut states = HashMap::new();
-    let mut queue = VecDeque::new();
-
-    let start = state_machine.new_state();
-    queue.push_front((protocol.seq.clone(), start));
-    states.insert(protocol.seq.clone(), start);
-
-    while let Some((seq, start)) = queue.pop_back() {
-        for (msg, seq) in generate_transitions(&seq) {
-            let end = *states.entry(seq.clone()).or_insert_with(|| {
-                let state = state_machine.new_state();
-                queue.push_front((seq, state));
-                state
-            });
+    let mut residuals = Residuals::new();
+    let mut worklist = Worklist::default();
+
+    let start_residual = residuals.intern_seq(&protocol.seq);
+    worklist.state(&mut state_machine, None, start_residual);
+
+    while let Some((branch, residual, start)) = worklist.queue.pop_back() {
+        let labels = residuals.labels(residual);
+        if labels.len() > 1 {
+            return Err(ProtocolError::AmbiguousStateLabel(
+                labels.into_iter().map(|id| residuals.label(id)).collect(),
+            ));
+        }
+        if let Some(id) = labels.first() {
+            state_machine.set_label(start, residuals.label(*id).clone());
+        }
+
+        // Only 'par' blocks outside of other branches are forked, nested ones
+        // are interleaved within their branch.
+        let fork = match branch {
+            None => residuals.fork(residual),
+            Some(_) => None,
+        };
+        if let Some((branch_residuals, join)) = fork {
+            let fork = Fork {
+                branches: branch_residuals
+                    .into_iter()
+                    .enumerate()
+                    .map(|(index, residual)| {
+                        worklist.state(&mut state_machine, Some((start, index)), residual)
+                    })
+                    .collect(),
+                join: worklist.state(&mut state_machine, None, join),
+            };
+            state_machine.add_fork(start, fork);
+        } else {
+            if residuals.may_terminate(residual) {
+                state_machine.set_final(start);
+            }
+            for (msg, residual) in residuals.merged_transitions(residual) {
+                let end = worklist.state(&mut state_machine, branch, residual);
+                state_machine.add_transition(Transition {
+                    start,
+                    end,
+                    msg: residuals.message(msg).clone(),
+                })
+            }
+        }
 
-            state_machine.add_transition(Transition { start, end, msg })
+        if state_machine.state_count() > limits.max_states {
+            return Err(ProtocolError::TooManyStates {
+                limit: limits.max_states,
+                par: largest_par_block(&protocol.seq),
+            });
+        }
+        if state_machine.transition_count() > limits.max_transitions {
+            return Err(ProtocolError::TooManyTransitions {
+                limit: limits.max_transitions,
+                par: largest_par_block(&protocol.seq),
+            });
         }
     }
 
-    ProtocolStateMachine {
+    Ok(ProtocolStateMachine {
         name: protocol.name.clone(),
         roles: protocol.roles.clone().unwrap_or_else(|| {
             DEFAULT_ROLES
@@ -61,205 +168,465 @@ pub fn compile_protocol(protocol: &Protocol) -> ProtocolStateMachine {
                 .map(|role| Role(role.to_string()))
                 .collect()
         }),
-        state_machine,
+        state_machine: state_machine.minimize(),
+    })
+}
+
+type Branch = Option<(State, usize)>;
+
+// States are compiled separately for every branch of a forked 'par' block, so
+// they are keyed by the branch they belong to as well as their residual.
+#[derive(Default)]
+struct Worklist {
+    states: HashMap<(Branch, NodeId), State>,
+    queue: VecDeque<(Branch, NodeId, State)>,
+}
+
+impl Worklist {
+    fn state(
+        &mut self,
+        state_machine: &mut StateMachine,
+        branch: Branch,
+        residual: NodeId,
+    ) -> State {
+        *self.states.entry((branch, residual)).or_insert_with(|| {
+            let state = state_machine.new_state();
+            if let Some((fork, index)) = branch {
+                state_machine.set_branch(state, fork, index);
+            }
+            self.queue.push_front((branch, residual, state));
+            state
+        })
     }
 }
 
-fn seq_may_terminate(seq: &Sequence) -> bool {
-    seq.0.iter().all(stmt_may_terminate)
+// A rough upper bound of the number of states a sequence compiles to on its
+// own, where the branches of a 'par' block are interleaved in every order.
+fn estimate_seq_states(seq: &Sequence) -> u64 {
+    seq.0
+        .iter()
+        .map(estimate_stmt_states)
+        .fold(0, u64::saturating_add)
 }
 
-fn stmt_may_terminate(stmt: &Stmt) -> bool {
+fn estimate_stmt_states(stmt: &Stmt) -> u64 {
     match stmt {
-        Stmt::Message(_) => false,
-        Stmt::Choice(seqs) => seqs.0.iter().any(seq_may_terminate),
-        Stmt::Par(seqs) => seqs.0.iter().all(seq_may_terminate),
-        Stmt::Fin(_) => true,
-        Stmt::Inf(_) => false,
+        Stmt::Message(_) => 1,
+        Stmt::State(_) => 0,
+        Stmt::Choice(seqs) => seqs
+            .0
+            .iter()
+            .map(estimate_seq_states)
+            .fold(0, u64::saturating_add),
+        Stmt::Par(seqs) => estimate_par_states(seqs) - 1,
+        Stmt::Fin(seq) | Stmt::Inf(seq) => estimate_seq_states(seq),
     }
 }
 
-pub fn generate_transitions(seq: &Sequence) -> Vec<(Span<Message>, Sequence)> {
-    let mut iter = seq.0.iter();
-    let Some(stmt) = iter.next() else {
-        return vec![];
-    };
-    let mut trans = vec![];
-    match stmt {
-        Stmt::Message(msg) => trans.push((msg.clone(), Sequence(vec![]))),
-        Stmt::Choice(seqs) => {
-            for seq in &seqs.0 {
-                for (msg, rem_seq) in generate_transitions(seq) {
-                    trans.push((msg, Sequence(rem_seq.0.clone())))
+fn estimate_par_states(seqs: &Sequences) -> u64 {
+    seqs.0
+        .iter()
+        .map(|seq| estimate_seq_states(seq).saturating_add(1))
+        .fold(1, u64::saturating_mul)
+}
+
+fn largest_par_block(seq: &Sequence) -> Option<ParBlock<'_>> {
+    let mut blocks = vec![];
+    collect_par_blocks(seq, &mut blocks);
+    blocks
+        .into_iter()
+        .rev()
+        .max_by_key(|block| block.estimated_states)
+}
+
+fn collect_par_blocks<'a>(seq: &'a Sequence, blocks: &mut Vec<ParBlock<'a>>) {
+    for stmt in &seq.0 {
+        match stmt {
+            Stmt::Message(_) | Stmt::State(_) => {}
+            Stmt::Par(seqs) => {
+                blocks.push(ParBlock {
+                    branches: seqs.0.iter().filter_map(Sequence::first_message).collect(),
+                    estimated_states: estimate_par_states(seqs),
+                });
+                for seq in &seqs.0 {
+                    collect_par_blocks(seq, blocks);
                 }
             }
-        }
-        Stmt::Par(seqs) => {
-            for (i, seq) in seqs.0.iter().enumerate() {
-                for (msg, rem_seq) in generate_transitions(seq) {
-                    let mut new_seqs = vec![];
-                    new_seqs.extend(seqs.0.iter().take(i).cloned());
-                    new_seqs.push(rem_seq);
-                    new_seqs.extend(
-                        seqs.0
-                            .iter()
-                            .rev()
-                            .take(seqs.0.len() - i - 1)
-                            .rev()
-                            .cloned(),
-                    );
-
-                    trans.push((msg, Sequence(vec![Stmt::Par(Sequences(new_seqs))])))
+            Stmt::Choice(seqs) => {
+                for seq in &seqs.0 {
+                    collect_par_blocks(seq, blocks);
                 }
             }
+            Stmt::Fin(seq) | Stmt::Inf(seq) => collect_par_blocks(seq, blocks),
         }
-        Stmt::Inf(seq) => {
-            for (msg, rem_seq) in generate_transitions(seq) {
-                let mut stmts = vec![];
-                stmts.extend(rem_seq.0);
-                stmts.push(Stmt::Inf(seq.clone()));
-                trans.push((msg, Sequence(stmts)))
-            }
+    }
+}
+
+impl<'a> Error<'a> {
+    pub fn pretty_print(&'a self, source: &'a str) -> PrettyPrintError<'a> {
+        PrettyPrintError {
+            error: self,
+            source,
         }
-        Stmt::Fin(seq) => {
-            for (msg, rem_seq) in generate_transitions(seq) {
-                let mut stmts = vec![];
-                stmts.extend(rem_seq.0);
-                stmts.push(Stmt::Fin(seq.clone()));
-                trans.push((msg, Sequence(stmts)));
+    }
+}
+
+impl<'a> fmt::Display for PrettyPrintError<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let protocol = self.error.protocol;
+        write!(f, "{}: ", "compile error".red())?;
+        let (limit, what, par) = match &self.error.err {
+            ProtocolError::TooManyStates { limit, par } => (limit, "states", par),
+            ProtocolError::TooManyTransitions { limit, par } => (limit, "transitions", par),
+            ProtocolError::AmbiguousStateLabel(labels) => {
+                writeln!(f, "error in protocol '{}'", protocol.inner.name)?;
+                write!(f, "{}", protocol.pretty_print(self.source))?;
+                writeln!(
+                    f,
+                    "info: the following state labels all name the same point in the protocol:"
+                )?;
+                for label in labels {
+                    write!(f, "{}", label.pretty_print(self.source))?;
+                }
+                writeln!(f, "info: remove all but one of these labels")?;
+                return Ok(());
             }
+        };
+        writeln!(
+            f,
+            "protocol '{}' exceeds the limit of {} {}",
+            protocol.inner.name, limit, what
+        )?;
+        write!(f, "{}", protocol.pretty_print(self.source))?;
+        match par {
+            Some(par) => {
+                writeln!(
+                    f,
+                    "info: most of the state space comes from the 'par' block with the following branches:"
+                )?;
+                for msg in &par.branches {
+                    write!(f, "{}", msg.pretty_print(self.source))?;
+                }
+                writeln!(
+                    f,
+                    "info: interleaving the branches of this block produces an estimated {} states",
+                    par.estimated_states
+                )?;
+                writeln!(
+                    f,
+                    "info: reduce the number of branches or messages in this 'par' block, or raise the limit"
+                )?;
+            }
+            None => writeln!(f, "info: simplify the protocol, or raise the limit")?,
         }
+        Ok(())
     }
-    let mut final_trans = vec![];
-    for (msg, seq) in trans {
-        let mut stmts = vec![];
-        stmts.extend(seq.0);
-        stmts.extend(iter.clone().cloned());
-        final_trans.push((msg, Sequence(stmts)));
-    }
-    if stmt_may_terminate(stmt) {
-        let rem_seq = Sequence(iter.clone().cloned().collect());
-        for (msg, rem_seq) in generate_transitions(&rem_seq) {
-            final_trans.push((msg, rem_seq))
-        }
+}
+
+pub(crate) fn seq_may_terminate(seq: &Sequence) -> bool {
+    seq.0.iter().all(stmt_may_terminate)
+}
+
+fn stmt_may_terminate(stmt: &Stmt) -> bool {
+    match stmt {
+        Stmt::Message(_) => false,
+        Stmt::Choice(seqs) => seqs.0.iter().any(seq_may_terminate),
+        Stmt::Par(seqs) => seqs.0.iter().all(seq_may_terminate),
+        Stmt::Fin(_) | Stmt::State(_) => true,
+        Stmt::Inf(_) => false,
     }
-    final_trans
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::{ast::Sequence, parser::parse, report::Report};
-
-    use super::generate_transitions;
-
-    #[test]
-    fn test_empty_choice() {
-        let seq = parse("{ choice { X from C to S; } or { } Y from C to S; }").report();
-        let trans = generate_transitions(&seq);
+    use std::{
+        collections::HashSet,
+        time::{Duration, Instant},
+    };
 
-        assert_eq!(trans.len(), 2);
+    use crate::{ast::Protocol, parser::parse, report::Report, state_machine::State};
 
-        let (msg, rem_seq) = &trans[0];
-        assert_eq!(msg, &parse("X from C to S;").report());
-        assert_eq!(rem_seq, &parse("{ Y from C to S; }").report());
+    use super::{compile_protocol, Limits, ProtocolError};
 
-        let (msg, rem_seq) = &trans[1];
-        assert_eq!(msg, &parse("Y from C to S;").report());
-        assert_eq!(rem_seq, &parse("{ }").report());
+    #[test]
+    fn test_compile_deterministic() {
+        let protocol: Protocol = parse(
+            "protocol X { fin { choice { Y from C to S; } or { X from C to S; } } X from C to S; }",
+        )
+        .report();
+        let protocol = compile_protocol(&protocol, &Limits::default()).unwrap();
+        let state_machine = &protocol.state_machine;
+
+        assert_eq!(state_machine.iter_states().count(), 2);
+        for state in state_machine.iter_states() {
+            let labels: Vec<_> = state_machine
+                .iter_trans_from(state)
+                .map(|(msg, _)| msg.inner.label.as_str())
+                .collect();
+            let unique_labels = HashSet::<_>::from_iter(labels.iter());
+            assert_eq!(labels.len(), unique_labels.len());
+        }
     }
 
     #[test]
-    fn test_msg_trans() {
-        let seq = parse("{ X from C to S; Y from S to C; }").report();
-        let trans = generate_transitions(&seq);
-
-        assert_eq!(trans.len(), 1);
+    fn test_compile_minimized() {
+        let protocol: Protocol = parse(
+            "protocol X {
+                choice {
+                    A from C to S;
+                    fin { X from C to S; }
+                } or {
+                    B from C to S;
+                    fin { X from C to S; }
+                    fin { X from C to S; }
+                }
+                Y from S to C;
+            }",
+        )
+        .report();
+        let protocol = compile_protocol(&protocol, &Limits::default()).unwrap();
+        let state_machine = &protocol.state_machine;
 
-        let (msg, rem_seq) = &trans[0];
-        assert_eq!(msg, &parse("X from C to S;").report());
-        assert_eq!(rem_seq, &parse("{ Y from S to C; }").report());
+        assert_eq!(state_machine.iter_states().count(), 3);
+        assert_eq!(state_machine.iter_transitions().count(), 4);
     }
 
     #[test]
-    fn test_choice_trans() {
-        let seq: Sequence =
-            parse("{ choice { X from C to S; } or { Y from C to S; Z from S to C; } }").report();
-        let trans = generate_transitions(&seq);
-
-        assert_eq!(trans.len(), 2);
-
-        let (msg, rem_seq) = &trans[0];
-        assert_eq!(msg, &parse("X from C to S;").report());
-        assert_eq!(rem_seq, &parse("{ }").report());
+    fn test_compile_fork() {
+        let protocol: Protocol = parse(
+            "protocol X {
+                A from C to S;
+                par {
+                    fin { B from S to C; }
+                } and {
+                    C from C to S;
+                    D from C to S;
+                }
+                E from C to S;
+            }",
+        )
+        .report();
+        let protocol = compile_protocol(&protocol, &Limits::default()).unwrap();
+        let state_machine = &protocol.state_machine;
+
+        assert_eq!(state_machine.state_count(), 8);
+        let fork = state_machine.fork(State(1)).unwrap();
+        assert_eq!(fork.branches.len(), 2);
+        assert_eq!(state_machine.iter_trans_from(State(1)).count(), 0);
+        for (index, branch) in fork.branches.iter().enumerate() {
+            assert_eq!(state_machine.branch(*branch), Some((State(1), index)));
+        }
+        assert!(state_machine.is_final(fork.branches[0]));
+        assert!(!state_machine.is_final(fork.branches[1]));
+        assert_eq!(state_machine.branch(fork.join), None);
+    }
 
-        let (msg, rem_seq) = &trans[1];
-        assert_eq!(msg, &parse("Y from C to S;").report());
-        assert_eq!(rem_seq, &parse("{ Z from S to C; }").report());
+    fn state_names(source: &str) -> Vec<String> {
+        let protocol: Protocol = parse(source).report();
+        let protocol = compile_protocol(&protocol, &Limits::default()).unwrap();
+        let mut names: Vec<_> = protocol
+            .state_machine
+            .state_names()
+            .into_iter()
+            .map(|name| name.0)
+            .collect();
+        names.sort();
+        names
     }
 
     #[test]
-    fn test_par_trans() {
-        let seq: Sequence =
-            parse("{ par { X from C to S; } and { Y from C to S; } and { Z from C to S; W from S to C; } }")
-                .report();
-        let trans = generate_transitions(&seq);
-
-        assert_eq!(trans.len(), 3);
-
-        let (msg, rem_seq) = &trans[0];
-        assert_eq!(msg, &parse("X from C to S;").report());
+    fn test_state_names() {
         assert_eq!(
-            rem_seq,
-            &parse("{ par {} and { Y from C to S; } and { Z from C to S; W from S to C; } }")
-                .report()
+            state_names(
+                "protocol X {
+                    choice { A from C to S; } or { B from C to S; }
+                    state Ready;
+                    fin { state Ready; Ping from C to S; }
+                    Stop from C to S;
+                }"
+            ),
+            vec!["Ready", "ReadyStop", "Start"]
         );
-
-        let (msg, rem_seq) = &trans[1];
-        assert_eq!(msg, &parse("Y from C to S;").report());
         assert_eq!(
-            rem_seq,
-            &parse("{ par { X from C to S; } and {} and { Z from C to S; W from S to C; } }")
-                .report()
+            state_names(
+                "protocol X {
+                    choice { go_on from C to S; X from S to C; } or { Stop from C to S; }
+                }"
+            ),
+            vec!["GoOn", "Start", "Stop"]
         );
-
-        let (msg, rem_seq) = &trans[2];
-        assert_eq!(msg, &parse("Z from C to S;").report());
         assert_eq!(
-            rem_seq,
-            &parse("{ par { X from C to S; } and { Y from C to S; } and { W from S to C; } }")
-                .report()
+            state_names(
+                "protocol X {
+                    choice { Stop from C to S; } or { go_on from C to S; X from S to C; }
+                }"
+            ),
+            vec!["GoOn", "Start", "Stop"]
         );
     }
 
     #[test]
-    fn test_inf_trans() {
-        let seq: Sequence = parse("{ inf { X from C to S; Y from S to C; } }").report();
-        let trans = generate_transitions(&seq);
+    fn test_state_names_generated_types() {
+        assert_eq!(
+            state_names(
+                "protocol X {
+                    Foo from C to S;
+                    FooResponse(u8) from S to C;
+                    Bar from C to S;
+                }"
+            ),
+            vec!["Bar", "Foo", "FooResponse2", "Start"]
+        );
+    }
 
-        let (msg, rem_seq) = &trans[0];
-        assert_eq!(msg, &parse("X from C to S;").report());
+    #[test]
+    fn test_state_names_reserved() {
         assert_eq!(
-            rem_seq,
-            &parse("{ Y from S to C; inf { X from C to S; Y from S to C; } }").report()
+            state_names("protocol X { c from C to S; result from C to S; }"),
+            vec!["C2", "Result2", "Start"]
         );
     }
 
     #[test]
-    fn test_fin_trans() {
-        let seq: Sequence =
-            parse("{ fin { X from C to S; Y from C to S; } Z from C to S; }").report();
-        let trans = generate_transitions(&seq);
+    fn test_state_names_linear() {
+        assert_eq!(
+            state_names(
+                "protocol X {
+                    state Ready;
+                    Ping from C to S;
+                    Pong from S to C;
+                    Ack from C to S;
+                }"
+            ),
+            vec!["Ready", "ReadyAck", "ReadyPing", "ReadyPong"]
+        );
+    }
 
-        let (msg, rem_seq) = &trans[0];
-        assert_eq!(msg, &parse("X from C to S;").report());
+    #[test]
+    fn test_state_labels_split_predecessors() {
         assert_eq!(
-            rem_seq,
-            &parse("{ Y from C to S; fin { X from C to S; Y from C to S; } Z from C to S; }")
-                .report()
+            state_names(
+                "protocol X {
+                    choice {
+                        A from C to S; B from C to S; state X; D from C to S;
+                    } or {
+                        E from C to S; B from C to S; state Y; D from C to S;
+                    }
+                }"
+            ),
+            vec!["A", "E2", "Start", "X", "XD", "Y"]
         );
+    }
 
-        let (msg, rem_seq) = &trans[1];
-        assert_eq!(msg, &parse("Z from C to S;").report());
-        assert_eq!(rem_seq, &parse("{ }").report());
+    #[test]
+    fn test_ambiguous_state_label() {
+        let protocol: Protocol = parse(
+            "protocol X {
+                choice { A from C to S; state Y; } or { A from C to S; state Z; }
+            }",
+        )
+        .report();
+        let Err(ProtocolError::AmbiguousStateLabel(labels)) =
+            compile_protocol(&protocol, &Limits::default())
+        else {
+            panic!("expected an ambiguous state label error")
+        };
+        let names: Vec<_> = labels
+            .iter()
+            .map(|label| label.inner.name.as_str())
+            .collect();
+        assert_eq!(names, vec!["Y", "Z"]);
+    }
+
+    #[test]
+    fn test_compile_state_limit() {
+        let protocol: Protocol = parse(
+            "protocol X {
+                A from C to S;
+                choice {
+                    par {
+                        B from C to S;
+                    } and {
+                        C from C to S;
+                        D from C to S;
+                        E from C to S;
+                    } and {
+                        F from C to S;
+                        G from C to S;
+                    }
+                } or {
+                    H from C to S;
+                }
+            }",
+        )
+        .report();
+        let limits = Limits {
+            max_states: 10,
+            ..Limits::default()
+        };
+        let Err(ProtocolError::TooManyStates { limit, par }) = compile_protocol(&protocol, &limits)
+        else {
+            panic!("expected the state limit to be exceeded")
+        };
+        assert_eq!(limit, 10);
+
+        let par = par.unwrap();
+        assert_eq!(par.estimated_states, 24);
+        let labels: Vec<_> = par
+            .branches
+            .iter()
+            .map(|msg| msg.inner.label.as_str())
+            .collect();
+        assert_eq!(labels, vec!["B", "C", "F"]);
+    }
+
+    fn large_protocol() -> Protocol {
+        let mut source = String::from("protocol Large {");
+        for i in 0..2000 {
+            source.push_str(&format!(
+                "choice {{ A{i}(u32) from C to S; }} or {{ B{i} from C to S; fin {{ L{i} from S to C; }} }}
+                R{i} from S to C;"
+            ));
+        }
+        source.push('}');
+        parse(&source).report()
+    }
+
+    #[test]
+    fn test_compile_large_protocol() {
+        let protocol = compile_protocol(&large_protocol(), &Limits::default()).unwrap();
+
+        assert_eq!(protocol.state_machine.state_count(), 3 * 2000 + 1);
+        assert_eq!(protocol.state_machine.transition_count(), 5 * 2000);
+    }
+
+    #[test]
+    fn test_state_names_long_protocol() {
+        let mut source = String::from("protocol Long {");
+        for i in 0..2000 {
+            source.push_str(&format!("Msg{i} from C to S; Ack{i} from S to C;"));
+        }
+        source.push('}');
+        let names = state_names(&source);
+
+        assert_eq!(names.len(), 2 * 2000 + 1);
+        assert!(names.iter().all(|name| name.len() <= "Msg1999".len()));
+    }
+
+    // Timing depends on the machine, run with 'cargo test -- --ignored'.
+    #[test]
+    #[ignore]
+    fn bench_compile_large_protocol() {
+        let protocol = large_protocol();
+
+        let start = Instant::now();
+        compile_protocol(&protocol, &Limits::default()).unwrap();
+        let elapsed = start.elapsed();
+
+        assert!(
+            elapsed < Duration::from_secs(5),
+            "compiling took {:?}",
+            elapsed
+        );
     }
 }
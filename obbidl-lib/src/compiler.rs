@@ -0,0 +1,558 @@
+use std::{
+    fmt, fs,
+    path::{Path, PathBuf},
+};
+
+use crate::{
+    ast::File,
+    compile::{compile_protocol_file, Limits},
+    format::{
+        binary::{Binary, Compact},
+        cbor::Cbor,
+        json::Json,
+        protobuf::{id_errors, GenerateProto, Protobuf},
+        WireFormat,
+    },
+    format_rust,
+    generate::{async_pars, module_collisions, GenerateRust, Mode, Options, StructOptions},
+    lint::{lint_protocol_file, Level, Lint, LintConfig},
+    parser::parse,
+    validate::{self, validate_protocol_file},
+};
+
+// Compiles protocol files to Rust, e.g. from a build script:
+//
+//   let output = Compiler::new()
+//       .input("protocol.txt")
+//       .out_dir(env::var("OUT_DIR").unwrap())
+//       .format(WireFormat::Compact)
+//       .compile()?;
+#[derive(Debug, Clone, Default)]
+pub struct Compiler {
+    inputs: Vec<PathBuf>,
+    out_dir: Option<PathBuf>,
+    options: Options,
+    target: Target,
+    lints: LintConfig,
+    limits: Limits,
+    rustfmt: bool,
+}
+
+// What each input is compiled to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Target {
+    #[default]
+    Rust,
+    // A protocol buffers schema for the messages, see 'GenerateProto'.
+    Proto,
+    // The state machines in GraphViz's dot language. They are written out
+    // before linting and validation, so a protocol that doesn't validate
+    // can still be looked at.
+    Graph,
+}
+
+impl Target {
+    fn extension(self) -> &'static str {
+        match self {
+            Target::Rust => "rs",
+            Target::Proto => "proto",
+            Target::Graph => "dot",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Output {
+    pub files: Vec<OutputFile>,
+    // Lints at the 'Warn' level.
+    pub warnings: Vec<Diagnostic>,
+}
+
+#[derive(Debug, Clone)]
+pub struct OutputFile {
+    // 'None' for code compiled with 'compile_source'.
+    pub input: Option<PathBuf>,
+    // Where the code was written, if an output directory was given.
+    pub path: Option<PathBuf>,
+    pub code: String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Severity {
+    Warning,
+    Error,
+}
+
+// A message already printed against the source it refers to, since the
+// errors themselves borrow from it.
+#[derive(Debug, Clone)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    pub input: Option<PathBuf>,
+    pub message: String,
+}
+
+// Every error and warning of a failed compilation, there is at least one
+// error.
+#[derive(Debug, Clone, Default)]
+pub struct Diagnostics(pub Vec<Diagnostic>);
+
+impl Compiler {
+    pub fn new() -> Compiler {
+        Compiler::default()
+    }
+    pub fn input(mut self, path: impl AsRef<Path>) -> Compiler {
+        self.inputs.push(path.as_ref().to_path_buf());
+        self
+    }
+    pub fn inputs<P: AsRef<Path>>(mut self, paths: impl IntoIterator<Item = P>) -> Compiler {
+        self.inputs
+            .extend(paths.into_iter().map(|path| path.as_ref().to_path_buf()));
+        self
+    }
+    // Each input is written to '<out_dir>/<file name>.rs', or '.proto' or
+    // '.dot' for the other targets. Without an output directory the code is
+    // only returned.
+    pub fn out_dir(mut self, path: impl AsRef<Path>) -> Compiler {
+        self.out_dir = Some(path.as_ref().to_path_buf());
+        self
+    }
+    // Replaces all of the generation options set so far.
+    pub fn options(mut self, options: Options) -> Compiler {
+        self.options = options;
+        self
+    }
+    pub fn target(mut self, target: Target) -> Compiler {
+        self.target = target;
+        self
+    }
+    pub fn format(mut self, format: WireFormat) -> Compiler {
+        self.options.format = format;
+        self
+    }
+    pub fn mode(mut self, mode: Mode) -> Compiler {
+        self.options.mode = mode;
+        self
+    }
+    // Adds a derive to every generated struct without its own options.
+    pub fn derive(mut self, path: impl Into<String>) -> Compiler {
+        self.options.structs.derives.push(path.into());
+        self
+    }
+    pub fn struct_options(mut self, name: impl Into<String>, options: StructOptions) -> Compiler {
+        self.options.struct_overrides.insert(name.into(), options);
+        self
+    }
+    // Only generates the modules of the roles with this name, all of them
+    // if never called.
+    pub fn role(mut self, name: impl Into<String>) -> Compiler {
+        self.options.roles.push(name.into());
+        self
+    }
+    pub fn lints(mut self, lints: LintConfig) -> Compiler {
+        self.lints = lints;
+        self
+    }
+    pub fn lint(mut self, lint: Lint, level: Level) -> Compiler {
+        self.lints.set(lint, level);
+        self
+    }
+    pub fn limits(mut self, limits: Limits) -> Compiler {
+        self.limits = limits;
+        self
+    }
+    // Runs Rust output through rustfmt, which has to be installed.
+    pub fn rustfmt(mut self, rustfmt: bool) -> Compiler {
+        self.rustfmt = rustfmt;
+        self
+    }
+
+    // Compiles every input, even after one of them fails, so that all the
+    // errors are reported at once.
+    pub fn compile(&self) -> Result<Output, Diagnostics> {
+        let mut output = Output::default();
+        let mut diagnostics = Diagnostics::default();
+        for input in &self.inputs {
+            let source = match fs::read_to_string(input) {
+                Ok(source) => source,
+                Err(err) => {
+                    diagnostics.error(Some(input), format!("cannot read file: {}", err));
+                    continue;
+                }
+            };
+            let Some(code) = self.compile_file(&source, Some(input), &mut diagnostics) else {
+                continue;
+            };
+            let path = match &self.out_dir {
+                Some(out_dir) => {
+                    let file_name = input.file_name().unwrap_or(input.as_os_str());
+                    let path = out_dir
+                        .join(file_name)
+                        .with_extension(self.target.extension());
+                    if let Err(err) = fs::write(&path, &code) {
+                        let message = format!("cannot write '{}': {}", path.display(), err);
+                        diagnostics.error(Some(input), message);
+                        continue;
+                    }
+                    Some(path)
+                }
+                None => None,
+            };
+            output.files.push(OutputFile {
+                input: Some(input.clone()),
+                path,
+                code,
+            });
+        }
+        diagnostics.finish(output)
+    }
+
+    // Compiles source that doesn't come from a file, ignoring the inputs and
+    // the output directory.
+    pub fn compile_source(&self, source: &str) -> Result<Output, Diagnostics> {
+        let mut output = Output::default();
+        let mut diagnostics = Diagnostics::default();
+        if let Some(code) = self.compile_file(source, None, &mut diagnostics) {
+            output.files.push(OutputFile {
+                input: None,
+                path: None,
+                code,
+            });
+        }
+        diagnostics.finish(output)
+    }
+
+    fn compile_file(
+        &self,
+        source: &str,
+        input: Option<&Path>,
+        diagnostics: &mut Diagnostics,
+    ) -> Option<String> {
+        let file = match parse::<File>(source) {
+            Ok(ast) => ast,
+            Err(err) => {
+                diagnostics.error(input, err.to_string());
+                return None;
+            }
+        };
+        let file_fsm = match compile_protocol_file(&file, &self.limits) {
+            Ok(file_fsm) => file_fsm,
+            Err(errors) => {
+                for err in errors {
+                    diagnostics.error(input, err.pretty_print(source).to_string());
+                }
+                return None;
+            }
+        };
+        if self.target == Target::Graph {
+            return Some(file_fsm.graph_viz().to_string());
+        }
+        let warnings = lint_protocol_file(&file, &file_fsm, &self.lints);
+        for warning in &warnings {
+            let severity = match warning.level {
+                Level::Deny => Severity::Error,
+                _ => Severity::Warning,
+            };
+            diagnostics.0.push(Diagnostic {
+                severity,
+                input: input.map(Path::to_path_buf),
+                message: warning.pretty_print(source).to_string(),
+            });
+        }
+        let mut failed = warnings.iter().any(|warning| warning.level == Level::Deny);
+        if self.options.format == WireFormat::Protobuf || self.target == Target::Proto {
+            for error in id_errors(&file) {
+                diagnostics.error(input, error.pretty_print(source).to_string());
+                failed = true;
+            }
+        }
+        if self.target == Target::Rust && self.options.mode == Mode::Async {
+            for par in async_pars(&file, &file_fsm) {
+                diagnostics.error(input, par.pretty_print(source).to_string());
+                failed = true;
+            }
+        }
+        let file = match validate_protocol_file(&file_fsm, &file.structs) {
+            Ok(file) => file,
+            Err(errors) => {
+                for err in errors {
+                    diagnostics.error(input, err.pretty_print(source).to_string());
+                }
+                return None;
+            }
+        };
+        if self.target == Target::Rust {
+            for collision in module_collisions(&file_fsm, &self.options) {
+                diagnostics.error(input, collision.pretty_print(source).to_string());
+                failed = true;
+            }
+        }
+        for role in &self.options.roles {
+            let found = file
+                .protocols
+                .iter()
+                .any(|protocol| protocol.role_a.0 == *role || protocol.role_b.0 == *role);
+            if !found {
+                diagnostics.error(input, format!("no protocol has a role named '{}'", role));
+                failed = true;
+            }
+        }
+        if failed {
+            return None;
+        }
+        if self.target == Target::Proto {
+            return Some(GenerateProto(&file).to_string());
+        }
+        let code = generate(&file, self.options.clone());
+        Some(if self.rustfmt {
+            format_rust(&code)
+        } else {
+            code
+        })
+    }
+}
+
+fn generate(file: &validate::File, options: Options) -> String {
+    match options.format {
+        WireFormat::Binary => GenerateRust::<Binary>::with_options(file, options).to_string(),
+        WireFormat::Json => GenerateRust::<Json>::with_options(file, options).to_string(),
+        WireFormat::Cbor => GenerateRust::<Cbor>::with_options(file, options).to_string(),
+        WireFormat::Compact => GenerateRust::<Compact>::with_options(file, options).to_string(),
+        WireFormat::Protobuf => GenerateRust::<Protobuf>::with_options(file, options).to_string(),
+    }
+}
+
+impl Diagnostics {
+    pub fn has_errors(&self) -> bool {
+        self.0
+            .iter()
+            .any(|diagnostic| diagnostic.severity == Severity::Error)
+    }
+    fn error(&mut self, input: Option<&Path>, message: String) {
+        self.0.push(Diagnostic {
+            severity: Severity::Error,
+            input: input.map(Path::to_path_buf),
+            message,
+        });
+    }
+    fn finish(self, mut output: Output) -> Result<Output, Diagnostics> {
+        if self.has_errors() {
+            Err(self)
+        } else {
+            output.warnings = self.0;
+            Ok(output)
+        }
+    }
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(input) = &self.input {
+            writeln!(f, "in '{}':", input.display())?;
+        }
+        write!(f, "{}", self.message.trim_end())
+    }
+}
+
+impl fmt::Display for Diagnostics {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, diagnostic) in self.0.iter().enumerate() {
+            if i > 0 {
+                writeln!(f)?;
+            }
+            write!(f, "{}", diagnostic)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for Diagnostics {}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, fs};
+
+    use crate::{
+        format::WireFormat,
+        generate::{Layout, Nesting},
+        lint::{Level, Lint},
+    };
+
+    use super::{Compiler, Mode, Options, Severity, Target};
+
+    const SOURCE: &str = "protocol Ping(role C, role S) { PING(u8) from C to S; }";
+
+    #[test]
+    fn test_compile_source() {
+        let output = Compiler::new().compile_source(SOURCE).unwrap();
+        assert_eq!(output.files.len(), 1);
+        assert!(output.files[0].code.contains("pub mod c {"));
+        assert!(output.files[0].code.contains("pub mod s {"));
+        assert!(output.warnings.is_empty());
+    }
+
+    #[test]
+    fn test_roles() {
+        let output = Compiler::new().role("S").compile_source(SOURCE).unwrap();
+        assert!(!output.files[0].code.contains("pub mod c {"));
+        assert!(output.files[0].code.contains("pub mod s {"));
+
+        let diagnostics = Compiler::new()
+            .role("X")
+            .compile_source(SOURCE)
+            .unwrap_err();
+        assert_eq!(diagnostics.0.len(), 1);
+        assert!(diagnostics.to_string().contains("role named 'X'"));
+    }
+
+    #[test]
+    fn test_protobuf_ids() {
+        let diagnostics = Compiler::new()
+            .format(WireFormat::Protobuf)
+            .compile_source(SOURCE)
+            .unwrap_err();
+        assert_eq!(diagnostics.0.len(), 1);
+        assert!(diagnostics.to_string().contains("message 'PING'"));
+
+        let source = "protocol Ping(role C, role S) { PING(u8) = 0 from C to S; }";
+        Compiler::new()
+            .format(WireFormat::Protobuf)
+            .compile_source(source)
+            .unwrap();
+
+        // Field 536870912 is one past the largest, 19000 is reserved.
+        let source = "protocol Ping(role C, role S) { \
+            choice { PING = 536870911 from C to S; } or { PONG = 18999 from C to S; } }";
+        let diagnostics = Compiler::new()
+            .format(WireFormat::Protobuf)
+            .compile_source(source)
+            .unwrap_err();
+        assert_eq!(diagnostics.0.len(), 2);
+        assert!(diagnostics.to_string().contains("message 'PING'"));
+        assert!(diagnostics.to_string().contains("message 'PONG'"));
+    }
+
+    #[test]
+    fn test_async_par() {
+        let source = "protocol P(role C, role S) { \
+            A from C to S; par { B from C to S; } and { D from S to C; } }";
+        let diagnostics = Compiler::new()
+            .mode(Mode::Async)
+            .compile_source(source)
+            .unwrap_err();
+        assert_eq!(diagnostics.0.len(), 1);
+        assert!(diagnostics
+            .to_string()
+            .contains("protocol 'P' has a 'par' block"));
+
+        Compiler::new().compile_source(source).unwrap();
+    }
+
+    #[test]
+    fn test_flat_module_collision() {
+        let source = "protocol a_b(role c, role d) { A from c to d; } \
+            protocol a(role b_c, role e) { B from b_c to e; }";
+        let options = Options {
+            layout: Layout {
+                nesting: Nesting::Flat,
+                ..Layout::default()
+            },
+            ..Options::default()
+        };
+        let diagnostics = Compiler::new()
+            .options(options.clone())
+            .compile_source(source)
+            .unwrap_err();
+        assert_eq!(diagnostics.0.len(), 1);
+        assert!(diagnostics.to_string().contains("module 'a_b_c'"));
+
+        Compiler::new()
+            .options(options)
+            .role("d")
+            .compile_source(source)
+            .unwrap();
+        Compiler::new().compile_source(source).unwrap();
+    }
+
+    #[test]
+    fn test_diagnostics() {
+        let source = "struct Unused {} protocol P(role C, role S) { fin {} }";
+        let output = Compiler::new().compile_source(source).unwrap();
+        // An unused struct, an empty protocol and an empty 'fin'.
+        assert_eq!(output.warnings.len(), 3);
+        assert!(output
+            .warnings
+            .iter()
+            .all(|warning| warning.severity == Severity::Warning));
+
+        let diagnostics = Compiler::new()
+            .lint(Lint::UnusedStruct, Level::Deny)
+            .compile_source(source)
+            .unwrap_err();
+        assert_eq!(diagnostics.0.len(), 3);
+        let errors = diagnostics
+            .0
+            .iter()
+            .filter(|diagnostic| diagnostic.severity == Severity::Error);
+        assert_eq!(errors.count(), 1);
+
+        let diagnostics = Compiler::new().compile_source("protocol {").unwrap_err();
+        assert_eq!(diagnostics.0.len(), 1);
+    }
+
+    #[test]
+    fn test_targets() {
+        let source = "protocol Ping(role C, role S) { PING(u8) = 0 from C to S; }";
+        let output = Compiler::new()
+            .target(Target::Proto)
+            .compile_source(source)
+            .unwrap();
+        assert!(output.files[0].code.starts_with("syntax = \"proto3\";"));
+
+        // Proto output checks the ids whatever the wire format.
+        let diagnostics = Compiler::new()
+            .target(Target::Proto)
+            .compile_source(SOURCE)
+            .unwrap_err();
+        assert!(diagnostics.to_string().contains("message 'PING'"));
+
+        // Graphs are drawn even for protocols that don't validate.
+        let source = "protocol X(role C, role S) { fin { X(u8) from C to S; } X from C to S; }";
+        Compiler::new().compile_source(source).unwrap_err();
+        let output = Compiler::new()
+            .target(Target::Graph)
+            .compile_source(source)
+            .unwrap();
+        assert!(output.files[0].code.starts_with("digraph {"));
+    }
+
+    #[test]
+    fn test_compile_files() {
+        let dir = env::temp_dir().join(format!("obbidl-compiler-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let good = dir.join("good.txt");
+        let bad = dir.join("bad.txt");
+        fs::write(&good, SOURCE).unwrap();
+        fs::write(&bad, "protocol {").unwrap();
+
+        let output = Compiler::new()
+            .input(&good)
+            .out_dir(&dir)
+            .compile()
+            .unwrap();
+        let path = output.files[0].path.as_ref().unwrap();
+        assert_eq!(path, &dir.join("good.rs"));
+        assert_eq!(fs::read_to_string(path).unwrap(), output.files[0].code);
+
+        // Every input is compiled even if an earlier one fails.
+        let diagnostics = Compiler::new()
+            .inputs([&bad, &dir.join("missing.txt"), &good])
+            .compile()
+            .unwrap_err();
+        assert_eq!(diagnostics.0.len(), 2);
+        assert_eq!(diagnostics.0[0].input.as_ref(), Some(&bad));
+        assert!(diagnostics.to_string().contains("missing.txt"));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
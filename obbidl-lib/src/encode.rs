@@ -0,0 +1,221 @@
+use std::mem::size_of;
+
+use crate::channel::{AsyncChannel, Channel, RecvError};
+
+// The binary encoding of payloads. Generated code implements these for each
+// struct, so a message only has to encode or decode each of its payload
+// items.
+pub trait Encode {
+    fn encode(&self, buf: &mut Vec<u8>);
+}
+
+pub trait Decode: Sized {
+    fn decode<C: Channel>(channel: &mut C) -> Result<Self, RecvError<C::Error>>;
+
+    #[allow(async_fn_in_trait)]
+    async fn decode_async<C: AsyncChannel>(channel: &mut C) -> Result<Self, RecvError<C::Error>>;
+}
+
+impl Encode for bool {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        buf.push(*self as u8);
+    }
+}
+
+impl Decode for bool {
+    fn decode<C: Channel>(channel: &mut C) -> Result<Self, RecvError<C::Error>> {
+        match channel.recv_u8()? {
+            0 => Ok(false),
+            1 => Ok(true),
+            value => Err(RecvError::InvalidBool(value)),
+        }
+    }
+
+    async fn decode_async<C: AsyncChannel>(channel: &mut C) -> Result<Self, RecvError<C::Error>> {
+        match channel.recv_u8().await? {
+            0 => Ok(false),
+            1 => Ok(true),
+            value => Err(RecvError::InvalidBool(value)),
+        }
+    }
+}
+
+// Integers are big endian.
+macro_rules! impl_int {
+    ($($ty:ty),*) => {
+        $(
+            impl Encode for $ty {
+                fn encode(&self, buf: &mut Vec<u8>) {
+                    buf.extend_from_slice(&self.to_be_bytes());
+                }
+            }
+
+            impl Decode for $ty {
+                fn decode<C: Channel>(channel: &mut C) -> Result<Self, RecvError<C::Error>> {
+                    let mut bytes = [0; size_of::<$ty>()];
+                    channel.recv(&mut bytes)?;
+                    Ok(<$ty>::from_be_bytes(bytes))
+                }
+
+                async fn decode_async<C: AsyncChannel>(
+                    channel: &mut C,
+                ) -> Result<Self, RecvError<C::Error>> {
+                    let mut bytes = [0; size_of::<$ty>()];
+                    channel.recv(&mut bytes).await?;
+                    Ok(<$ty>::from_be_bytes(bytes))
+                }
+            }
+        )*
+    };
+}
+
+impl_int!(u8, u16, u32, u64, i8, i16, i32, i64);
+
+// Arrays without a fixed size are prefixed with their length as a u32.
+impl<T: Encode> Encode for [T] {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        (self.len() as u32).encode(buf);
+        for item in self {
+            item.encode(buf);
+        }
+    }
+}
+
+impl<T: Encode> Encode for Vec<T> {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        self.as_slice().encode(buf)
+    }
+}
+
+impl<T: Decode> Decode for Vec<T> {
+    fn decode<C: Channel>(channel: &mut C) -> Result<Self, RecvError<C::Error>> {
+        let length = channel.recv_length()?;
+        decode_items(length, || T::decode(channel))
+    }
+
+    async fn decode_async<C: AsyncChannel>(channel: &mut C) -> Result<Self, RecvError<C::Error>> {
+        let length = channel.recv_length().await?;
+        let mut items = Vec::with_capacity(length);
+        for _ in 0..length {
+            items.push(T::decode_async(channel).await?);
+        }
+        Ok(items)
+    }
+}
+
+impl<T: Encode, const N: usize> Encode for [T; N] {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        for item in self {
+            item.encode(buf);
+        }
+    }
+}
+
+// Items are decoded into a 'Vec' first so the element type doesn't need
+// 'Default' or 'Copy'.
+impl<T: Decode, const N: usize> Decode for [T; N] {
+    fn decode<C: Channel>(channel: &mut C) -> Result<Self, RecvError<C::Error>> {
+        let items = decode_items(N, || T::decode(channel))?;
+        Ok(into_array(items))
+    }
+
+    async fn decode_async<C: AsyncChannel>(channel: &mut C) -> Result<Self, RecvError<C::Error>> {
+        let mut items = Vec::with_capacity(N);
+        for _ in 0..N {
+            items.push(T::decode_async(channel).await?);
+        }
+        Ok(into_array(items))
+    }
+}
+
+fn decode_items<T, E>(
+    length: usize,
+    mut decode: impl FnMut() -> Result<T, RecvError<E>>,
+) -> Result<Vec<T>, RecvError<E>> {
+    let mut items = Vec::with_capacity(length);
+    for _ in 0..length {
+        items.push(decode()?);
+    }
+    Ok(items)
+}
+
+pub(crate) fn into_array<T, const N: usize>(items: Vec<T>) -> [T; N] {
+    match items.try_into() {
+        Ok(array) => array,
+        Err(_) => unreachable!(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        fmt::Debug,
+        future::Future,
+        pin::pin,
+        task::{Context, Poll, Waker},
+    };
+
+    use crate::channel::{AsyncChannel, AsyncTestChannel, Channel, RecvError, TestChannel};
+
+    use super::{Decode, Encode};
+
+    fn round_trip<T: Encode + Decode + PartialEq + Debug>(value: T) {
+        let (mut a, mut b) = TestChannel::new();
+        let mut buf = vec![];
+        value.encode(&mut buf);
+        a.send(&buf).unwrap();
+        assert_eq!(T::decode(&mut b).unwrap(), value);
+    }
+
+    // Everything is sent before it is received, so decoding never waits.
+    fn now<F: Future>(future: F) -> F::Output {
+        match pin!(future).poll(&mut Context::from_waker(Waker::noop())) {
+            Poll::Ready(output) => output,
+            Poll::Pending => panic!("expected the future to be ready"),
+        }
+    }
+
+    #[test]
+    fn test_round_trip() {
+        round_trip(true);
+        round_trip(0xabu8);
+        round_trip(-2i16);
+        round_trip(u64::MAX);
+        round_trip(vec![[1u16, 2], [3, 4]]);
+        round_trip(vec![vec![true], vec![], vec![false, true]]);
+        round_trip([vec![0u8], vec![7]]);
+    }
+
+    #[test]
+    fn test_wire_format() {
+        let mut buf = vec![];
+        [1u16, 2][..].encode(&mut buf);
+        [true, false].encode(&mut buf);
+        assert_eq!(buf, [0, 0, 0, 2, 0, 1, 0, 2, 1, 0]);
+    }
+
+    #[test]
+    fn test_decode_errors() {
+        let (mut a, mut b) = TestChannel::new();
+        a.send(&[2]).unwrap();
+        assert!(matches!(
+            bool::decode(&mut b),
+            Err(RecvError::InvalidBool(2))
+        ));
+        a.send(&u32::MAX.to_be_bytes()).unwrap();
+        assert!(matches!(
+            Vec::<u8>::decode(&mut b),
+            Err(RecvError::LengthTooLarge { .. })
+        ));
+    }
+
+    #[test]
+    fn test_decode_async() {
+        let (mut a, mut b) = AsyncTestChannel::new();
+        let value = vec![[-1i32, 1], [2, -2]];
+        let mut buf = vec![];
+        value.encode(&mut buf);
+        now(a.send(&buf)).unwrap();
+        assert_eq!(now(Vec::<[i32; 2]>::decode_async(&mut b)).unwrap(), value);
+    }
+}
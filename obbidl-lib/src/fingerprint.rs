@@ -0,0 +1,260 @@
+use std::collections::{HashMap, VecDeque};
+
+use crate::{
+    ast::{IntSize, IntType},
+    format::WireFormat,
+    state_machine::StateName,
+    validate::{Direction, Message, Payload, Protocol, State, Type},
+};
+
+// FNV-1a over a canonical encoding of the protocol. The encoding is written
+// out by hand rather than derived with `Hash` so the fingerprint doesn't
+// depend on the platform or the version of the compiler that generated it.
+struct Fingerprint(u64);
+
+impl Fingerprint {
+    const OFFSET: u64 = 0xcbf29ce484222325;
+    const PRIME: u64 = 0x100000001b3;
+
+    fn bytes(&mut self, bytes: &[u8]) {
+        for byte in bytes {
+            self.0 ^= *byte as u64;
+            self.0 = self.0.wrapping_mul(Fingerprint::PRIME);
+        }
+    }
+    fn u64(&mut self, value: u64) {
+        self.bytes(&value.to_le_bytes());
+    }
+    fn str(&mut self, value: &str) {
+        self.u64(value.len() as u64);
+        self.bytes(value.as_bytes());
+    }
+    fn int_type(&mut self, ty: &IntType) {
+        let size = match ty.size {
+            IntSize::B8 => 8,
+            IntSize::B16 => 16,
+            IntSize::B32 => 32,
+            IntSize::B64 => 64,
+        };
+        self.bytes(&[ty.signed as u8, size]);
+    }
+    fn ty(&mut self, ty: &Type) {
+        match ty {
+            Type::Bool => self.bytes(&[0]),
+            Type::Int(ty) => {
+                self.bytes(&[1]);
+                self.int_type(ty);
+            }
+            Type::Array(ty, size) => {
+                self.bytes(&[2]);
+                self.u64(size.map_or(0, |size| size + 1));
+                self.ty(ty);
+            }
+            Type::Struct(struct_) => {
+                self.bytes(&[3]);
+                self.str(&struct_.name);
+                self.u64(struct_.fields.len() as u64);
+                for (name, ty) in &struct_.fields {
+                    self.str(name);
+                    self.ty(ty);
+                }
+            }
+        }
+    }
+    fn payload(&mut self, payload: &Payload) {
+        self.u64(payload.items.len() as u64);
+        for (name, ty) in &payload.items {
+            self.str(name);
+            self.ty(ty);
+        }
+    }
+}
+
+impl Protocol {
+    /// A hash of everything that determines what goes over the wire: the
+    /// wire format, the shape of the state machine, the labels, ids, tag
+    /// widths and payloads of the messages, and their directions. State names
+    /// are left out, so naming states doesn't change the fingerprint.
+    pub fn fingerprint(&self, format: WireFormat) -> u64 {
+        // States are numbered in breadth first order, following messages in
+        // the order of their ids, so the order the states were compiled in
+        // doesn't matter.
+        let states: HashMap<&StateName, &State> = self
+            .states
+            .iter()
+            .map(|state| (&state.name, state))
+            .collect();
+        let mut order = vec![];
+        let mut indices = HashMap::new();
+        let mut queue = VecDeque::new();
+        if let Some(start) = self.states.first() {
+            indices.insert(&start.name, 0);
+            queue.push_back(start);
+        }
+        while let Some(state) = queue.pop_front() {
+            order.push(state);
+            for next in successors(state) {
+                if !indices.contains_key(next) {
+                    indices.insert(next, indices.len() as u64);
+                    queue.push_back(states[next]);
+                }
+            }
+        }
+
+        let mut fingerprint = Fingerprint(Fingerprint::OFFSET);
+        // Whether a message is tagged at all depends on the format, the width
+        // of the tag is hashed with each state below.
+        let format = match format {
+            WireFormat::Binary => 0,
+            WireFormat::Json => 1,
+            WireFormat::Cbor => 2,
+            WireFormat::Compact => 3,
+            WireFormat::Protobuf => 4,
+        };
+        fingerprint.bytes(&[format]);
+        fingerprint.u64(order.len() as u64);
+        for state in order {
+            match &state.trans {
+                Some(trans) => {
+                    let dir = match trans.dir {
+                        Direction::AToB => 1,
+                        Direction::BToA => 2,
+                    };
+                    fingerprint.bytes(&[dir]);
+                    fingerprint.int_type(&trans.tag);
+                    fingerprint.u64(trans.messages.len() as u64);
+                    for msg in messages_by_id(state) {
+                        fingerprint.u64(msg.id);
+                        fingerprint.str(&msg.label);
+                        fingerprint.payload(&msg.payload);
+                        fingerprint.u64(indices[&msg.dest_state_name]);
+                    }
+                }
+                None => fingerprint.bytes(&[0]),
+            }
+            match &state.fork {
+                Some(fork) => {
+                    fingerprint.u64(fork.branches.len() as u64);
+                    for branch in &fork.branches {
+                        fingerprint.u64(indices[branch]);
+                    }
+                    fingerprint.u64(indices[&fork.join]);
+                }
+                None => fingerprint.u64(0),
+            }
+            match &state.branch {
+                Some(branch) => {
+                    fingerprint.bytes(&[1, branch.may_end as u8]);
+                    fingerprint.u64(indices[&branch.fork]);
+                    fingerprint.u64(branch.index as u64);
+                }
+                None => fingerprint.bytes(&[0]),
+            }
+        }
+        fingerprint.0
+    }
+}
+
+fn messages_by_id(state: &State) -> Vec<&Message> {
+    let mut messages: Vec<_> = state
+        .trans
+        .iter()
+        .flat_map(|trans| &trans.messages)
+        .collect();
+    messages.sort_by_key(|msg| msg.id);
+    messages
+}
+
+fn successors(state: &State) -> Vec<&StateName> {
+    let mut names: Vec<_> = messages_by_id(state)
+        .into_iter()
+        .map(|msg| &msg.dest_state_name)
+        .collect();
+    if let Some(fork) = &state.fork {
+        names.extend(&fork.branches);
+        names.push(&fork.join);
+    }
+    names
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        ast,
+        compile::{compile_protocol_file, Limits},
+        format::WireFormat,
+        parser::parse,
+        report::Report,
+        validate::validate_protocol_file,
+    };
+
+    fn fingerprint_with(source: &str, format: WireFormat) -> u64 {
+        let file = parse::<ast::File>(source).report();
+        let file_fsm = compile_protocol_file(&file, &Limits::default()).unwrap();
+        let file = validate_protocol_file(&file_fsm, &file.structs).unwrap();
+        file.protocols[0].fingerprint(format)
+    }
+
+    fn fingerprint(source: &str) -> u64 {
+        fingerprint_with(source, WireFormat::Binary)
+    }
+
+    #[test]
+    fn test_fingerprint_stable() {
+        let a = fingerprint(
+            "protocol X { choice { A(u32) from C to S; } or { B from C to S; } Y from S to C; }",
+        );
+        let b = fingerprint(
+            "protocol X { choice { B from C to S; } or { A(u32) from C to S; } state Done; Y from S to C; }",
+        );
+        assert_eq!(a, b);
+    }
+
+    #[test]
+    fn test_fingerprint_changes() {
+        let base = "protocol X { choice { A(u32) from C to S; } or { B from C to S; } }";
+        let changed = [
+            "protocol X { choice { A(u16) from C to S; } or { B from C to S; } }",
+            "protocol X { choice { A(x: u32) from C to S; } or { B from C to S; } }",
+            "protocol X { choice { A(u32) = 5 from C to S; } or { B from C to S; } }",
+            "protocol X { choice { A(u32) from S to C; } or { B from S to C; } }",
+            "protocol X { choice { A(u32) from C to S; } or { C from C to S; } }",
+            "protocol X { choice { A(u32) from C to S; } or { B from C to S; } Y from S to C; }",
+        ];
+        for source in changed {
+            assert_ne!(fingerprint(base), fingerprint(source), "{}", source);
+        }
+    }
+
+    #[test]
+    fn test_fingerprint_format() {
+        let source = "protocol X { A(u32) from C to S; }";
+        let formats = [
+            WireFormat::Binary,
+            WireFormat::Json,
+            WireFormat::Cbor,
+            WireFormat::Compact,
+            WireFormat::Protobuf,
+        ];
+        for (i, a) in formats.iter().enumerate() {
+            for b in &formats[i + 1..] {
+                assert_ne!(
+                    fingerprint_with(source, *a),
+                    fingerprint_with(source, *b),
+                    "{:?} {:?}",
+                    a,
+                    b
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_fingerprint_tag_width() {
+        let a =
+            fingerprint("protocol X { choice { A = 1 from C to S; } or { B = 2 from C to S; } }");
+        let b =
+            fingerprint("protocol X { choice { A = 1 from C to S; } or { B = 256 from C to S; } }");
+        assert_ne!(a, b);
+    }
+}
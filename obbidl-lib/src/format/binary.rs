@@ -1,116 +1,254 @@
 use std::fmt;
 
-use crate::validate::{Message, Type};
+use crate::{
+    ast::{IntSize, IntType},
+    generate::{Context, Mode, RustType},
+    naming::{snake_case, Camel, Snake},
+    state_machine::StateName,
+    validate::{Message, Struct, Type},
+};
 
-use super::Format;
+use super::{Format, WireFormat};
 
 pub struct Binary;
 
+// The same layout as 'Binary' with varints for integers and lengths, for slow
+// links.
+pub struct Compact;
+
+const ENCODE: &str = "obbidl_lib::encode";
+const COMPACT: &str = "obbidl_lib::compact";
+
 impl Format for Binary {
+    const WIRE_FORMAT: WireFormat = WireFormat::Binary;
+
     fn send_message(
         f: &mut fmt::Formatter<'_>,
         message: &Message,
-        insert_tag: bool,
+        tag: Option<IntType>,
+        cx: &Context,
     ) -> fmt::Result {
-        if insert_tag {
-            writeln!(f, "self.0.send_u8({})?;", message.id)?;
-        }
-
-        for (name, ty) in &message.payload.items {
-            send_type(f, name, ty)?;
-        }
-
-        writeln!(f, "return Ok({}(self.0));", message.dest_state_name)?;
+        send_message(f, message, tag, cx, ENCODE)
+    }
 
-        Ok(())
+    fn recv_messages(
+        f: &mut fmt::Formatter<'_>,
+        state: &StateName,
+        messages: &[Message],
+        tag: Option<IntType>,
+        cx: &Context,
+    ) -> fmt::Result {
+        recv_messages(f, state, messages, tag, cx, ENCODE)
     }
 
-    fn recv_messages(f: &mut fmt::Formatter<'_>, messages: &[Message]) -> fmt::Result {
-        if messages.len() == 1 {
-            recv_msg(f, &messages[0])?;
-        } else {
-            writeln!(f, "let id = self.0.recv_u8()?;")?;
-            for msg in messages {
-                writeln!(f, "if id == {} {{", msg.id)?;
-                recv_msg(f, msg)?;
-                writeln!(f, "}}")?;
-            }
-            writeln!(f, "panic!(\"invalid message!\")")?;
-        }
-        Ok(())
+    fn struct_impls(f: &mut fmt::Formatter<'_>, struct_: &Struct, cx: &Context) -> fmt::Result {
+        struct_impls(f, struct_, cx, ENCODE)
     }
 }
 
-fn recv_msg(f: &mut fmt::Formatter<'_>, msg: &Message) -> fmt::Result {
-    for (name, ty) in &msg.payload.items {
-        recv_type(f, name, ty)?;
+impl Format for Compact {
+    const WIRE_FORMAT: WireFormat = WireFormat::Compact;
+
+    fn send_message(
+        f: &mut fmt::Formatter<'_>,
+        message: &Message,
+        tag: Option<IntType>,
+        cx: &Context,
+    ) -> fmt::Result {
+        send_message(f, message, tag, cx, COMPACT)
     }
 
-    write!(
-        f,
-        "return Ok(receiver.recv_{}({}(self.0), ",
-        msg.label, msg.dest_state_name
-    )?;
-    for (name, _) in &msg.payload.items {
-        write!(f, "{}, ", name)?;
+    fn recv_messages(
+        f: &mut fmt::Formatter<'_>,
+        state: &StateName,
+        messages: &[Message],
+        tag: Option<IntType>,
+        cx: &Context,
+    ) -> fmt::Result {
+        recv_messages(f, state, messages, tag, cx, COMPACT)
+    }
+
+    fn struct_impls(f: &mut fmt::Formatter<'_>, struct_: &Struct, cx: &Context) -> fmt::Result {
+        struct_impls(f, struct_, cx, COMPACT)
     }
-    writeln!(f, ")?);")?;
-    Ok(())
 }
 
-fn recv_type(f: &mut fmt::Formatter<'_>, name: &str, ty: &Type) -> fmt::Result {
-    match ty {
-        Type::Bool => writeln!(f, "let {} = self.0.recv_u8()? != 0;", name)?,
-        Type::Int(ty) => {
-            writeln!(f, "let mut bytes = [0; size_of::<{}>()];", ty)?;
-            writeln!(f, "self.0.recv(&mut bytes)?;")?;
-            writeln!(f, "let {} = {}::from_be_bytes(bytes);", name, ty)?;
-        }
-        Type::Array(ty, size) => {
-            match size {
-                Some(size) => writeln!(f, "let mut {} = [{}::default(); {}];", name, ty, size)?,
-                None => writeln!(
-                    f,
-                    "let mut {} = vec![{}::default(); self.0.recv_u32()? as usize];",
-                    name, ty
-                )?,
-            }
-            writeln!(f, "for i in 0..{}.len() {{", name)?;
-            recv_type(f, "x", ty)?;
-            writeln!(f, "{}[i] = x;", name)?;
-            writeln!(f, "}}")?;
+// 'module' is the path of the module with the 'Encode' and 'Decode' traits.
+fn send_message(
+    f: &mut fmt::Formatter<'_>,
+    message: &Message,
+    tag: Option<IntType>,
+    cx: &Context,
+    module: &str,
+) -> fmt::Result {
+    // The whole message is encoded first so it is sent in one go.
+    if tag.is_some() || !message.payload.items.is_empty() {
+        writeln!(f, "let mut __buf = Vec::new();")?;
+        if let Some(tag) = tag {
+            writeln!(
+                f,
+                "{}::Encode::encode(&{}{}, &mut __buf);",
+                module, message.id, tag
+            )?;
         }
-        Type::Struct(struct_) => {
-            for (field_name, ty) in &struct_.fields {
-                recv_type(f, &field_name, ty)?;
-            }
-            write!(f, "let {} = super::super::{} {{", name, struct_.name)?;
-            for (field_name, _) in &struct_.fields {
-                write!(f, "{},", field_name)?;
-            }
-            writeln!(f, "}};")?;
+        for (name, ty) in &message.payload.items {
+            // Arrays and structs are already borrowed.
+            let borrow = match ty {
+                Type::Bool | Type::Int(_) => "&",
+                Type::Array(..) | Type::Struct(_) => "",
+            };
+            writeln!(
+                f,
+                "{}::Encode::encode({}{}, &mut __buf);",
+                module,
+                borrow,
+                Snake(name)
+            )?;
         }
+        writeln!(f, "self.0.send(&__buf){}?;", cx.mode.await_())?;
     }
+
+    writeln!(f, "Ok({}(self.0))", message.dest_state_name)?;
+
     Ok(())
 }
 
-fn send_type(f: &mut fmt::Formatter<'_>, name: &str, ty: &Type) -> fmt::Result {
-    match ty {
-        Type::Bool => writeln!(f, "self.0.send_u8(if {} {{ 1 }} else {{ 0 }})?;", name)?,
-        Type::Int(ty) => writeln!(f, "self.0.send(&{}::to_be_bytes({}))?;", ty, name)?,
-        Type::Array(ty, size) => {
-            if size.is_none() {
-                writeln!(f, "self.0.send(&u32::to_be_bytes({}.len() as u32))?;", name)?;
-            }
-            writeln!(f, "for i in 0..{}.len() {{", name)?;
-            send_type(f, &format!("{}[i]", name), ty)?;
+fn recv_messages(
+    f: &mut fmt::Formatter<'_>,
+    state: &StateName,
+    messages: &[Message],
+    tag: Option<IntType>,
+    cx: &Context,
+    module: &str,
+) -> fmt::Result {
+    if let Some(tag) = tag {
+        let id = Type::Int(tag);
+        writeln!(f, "let __id = {};", Decode(&id, "&mut self.0", cx, module))?;
+        writeln!(f, "match __id {{")?;
+        for msg in messages {
+            writeln!(f, "{} => {{", msg.id)?;
+            recv_msg(f, msg, cx, module)?;
             writeln!(f, "}}")?;
         }
-        Type::Struct(struct_) => {
-            for (field_name, ty) in &struct_.fields {
-                send_type(f, &format!("{}.{}", name, field_name), ty)?;
-            }
+        let cast = if tag.size == IntSize::B64 {
+            ""
+        } else {
+            " as u64"
+        };
+        writeln!(
+            f,
+            "tag => Err(RecvError::UnknownTag {{ state: \"{}\", tag: tag{} }}),",
+            state, cast
+        )?;
+        writeln!(f, "}}")?;
+    } else {
+        recv_msg(f, &messages[0], cx, module)?;
+    }
+    Ok(())
+}
+
+fn struct_impls(
+    f: &mut fmt::Formatter<'_>,
+    struct_: &Struct,
+    cx: &Context,
+    module: &str,
+) -> fmt::Result {
+    let name = Camel(&struct_.name);
+    writeln!(f, "impl {}::Encode for {} {{", module, name)?;
+    writeln!(f, "fn encode(&self, buf: &mut Vec<u8>) {{")?;
+    for (field_name, _) in &struct_.fields {
+        writeln!(
+            f,
+            "{}::Encode::encode(&self.{}, buf);",
+            module,
+            Snake(field_name)
+        )?;
+    }
+    writeln!(f, "}}")?;
+    writeln!(f, "}}")?;
+
+    // Both are generated whatever the mode, since the structs may be
+    // shared by protocols generated in either mode.
+    let channel = if struct_.fields.is_empty() {
+        "_channel"
+    } else {
+        "channel"
+    };
+    writeln!(f, "impl {}::Decode for {} {{", module, name)?;
+    for mode in [Mode::Blocking, Mode::Async] {
+        let cx = Context {
+            mode,
+            structs: cx.structs.clone(),
+        };
+        writeln!(
+            f,
+            "{} {}<C: obbidl_lib::channel::{}>({}: &mut C) -> Result<Self, obbidl_lib::channel::RecvError<C::Error>> {{",
+            mode.fn_keyword(),
+            decode_fn(mode),
+            mode.channel(),
+            channel
+        )?;
+        // Fields are decoded in the order they are written in.
+        write!(f, "Ok(Self {{")?;
+        for (field_name, ty) in &struct_.fields {
+            write!(
+                f,
+                "{}: {},",
+                Snake(field_name),
+                Decode(ty, "channel", &cx, module)
+            )?;
         }
+        writeln!(f, "}})")?;
+        writeln!(f, "}}")?;
+    }
+    writeln!(f, "}}")?;
+    Ok(())
+}
+
+// Decodes a value from a channel. The type is always given since it can't be
+// inferred through the future in async code.
+struct Decode<'a>(&'a Type, &'a str, &'a Context, &'a str);
+
+impl<'a> fmt::Display for Decode<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "<{} as {}::Decode>::{}({}){}?",
+            RustType(self.0, self.2),
+            self.3,
+            decode_fn(self.2.mode),
+            self.1,
+            self.2.mode.await_()
+        )
+    }
+}
+
+fn decode_fn(mode: Mode) -> &'static str {
+    match mode {
+        Mode::Blocking => "decode",
+        Mode::Async => "decode_async",
+    }
+}
+
+fn recv_msg(f: &mut fmt::Formatter<'_>, msg: &Message, cx: &Context, module: &str) -> fmt::Result {
+    for (name, ty) in &msg.payload.items {
+        writeln!(
+            f,
+            "let {} = {};",
+            Snake(name),
+            Decode(ty, "&mut self.0", cx, module)
+        )?;
+    }
+
+    write!(
+        f,
+        "Ok(__receiver.recv_{}({}(self.0), ",
+        snake_case(&msg.label),
+        msg.dest_state_name
+    )?;
+    for (name, _) in &msg.payload.items {
+        write!(f, "{}, ", Snake(name))?;
     }
+    writeln!(f, ")?)")?;
     Ok(())
 }
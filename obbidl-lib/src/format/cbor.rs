@@ -0,0 +1,52 @@
+use std::fmt;
+
+use crate::{
+    ast::IntType,
+    generate::Context,
+    state_machine::StateName,
+    validate::{Message, Struct},
+};
+
+use super::{
+    json::{recv_messages, send_message, struct_impls, Runtime},
+    Format, WireFormat,
+};
+
+// CBOR maps, using 'obbidl_lib::cbor'. Messages are laid out the same way as
+// in the JSON format.
+pub struct Cbor;
+
+const CBOR: Runtime = Runtime {
+    module: "obbidl_lib::cbor",
+    map: "Map",
+    string: "Text",
+    name: "Cbor",
+};
+
+impl Format for Cbor {
+    const TAG_EVERY_MESSAGE: bool = true;
+    const WIRE_FORMAT: WireFormat = WireFormat::Cbor;
+
+    fn send_message(
+        f: &mut fmt::Formatter<'_>,
+        message: &Message,
+        tag: Option<IntType>,
+        cx: &Context,
+    ) -> fmt::Result {
+        send_message(f, message, tag, cx, &CBOR)
+    }
+
+    fn recv_messages(
+        f: &mut fmt::Formatter<'_>,
+        state: &StateName,
+        messages: &[Message],
+        tag: Option<IntType>,
+        cx: &Context,
+    ) -> fmt::Result {
+        recv_messages(f, state, messages, tag, cx, &CBOR)
+    }
+
+    fn struct_impls(f: &mut fmt::Formatter<'_>, struct_: &Struct, _cx: &Context) -> fmt::Result {
+        struct_impls(f, struct_, &CBOR)
+    }
+}
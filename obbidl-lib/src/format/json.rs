@@ -1,119 +1,241 @@
 use std::fmt;
 
-use crate::validate::{Message, Type};
+use crate::{
+    ast::IntType,
+    generate::{Context, RustType},
+    naming::{snake_case, Camel, Snake},
+    state_machine::StateName,
+    validate::{Message, Struct, Type},
+};
 
-use super::Format;
+use super::{async_suffix, Format, WireFormat};
 
-struct Json;
+// Newline delimited JSON, using 'obbidl_lib::json'. Payload items and struct
+// fields are keyed by their names in the protocol file rather than their
+// Rust names.
+pub struct Json;
+
+// The names in the runtime module of a format whose messages are maps keyed
+// by name, which is all that differs between JSON and CBOR.
+pub(super) struct Runtime {
+    pub module: &'static str,
+    // The 'Value' variants for maps and strings.
+    pub map: &'static str,
+    pub string: &'static str,
+    // As in 'ToJson', 'to_json' and 'JsonError'.
+    pub name: &'static str,
+}
+
+const JSON: Runtime = Runtime {
+    module: "obbidl_lib::json",
+    map: "Object",
+    string: "String",
+    name: "Json",
+};
 
 impl Format for Json {
+    const TAG_EVERY_MESSAGE: bool = true;
+    const WIRE_FORMAT: WireFormat = WireFormat::Json;
+
     fn send_message(
         f: &mut fmt::Formatter<'_>,
         message: &Message,
-        insert_tag: bool,
+        tag: Option<IntType>,
+        cx: &Context,
     ) -> fmt::Result {
-        writeln!(f, "let mut object = HashMap::new()")?;
-        if insert_tag {
-            writeln!(f, "object.insert(\"label\", \"{}\".into());", message.label)?;
-        }
-        for (name, ty) in &message.payload.items {
-            to_json_value(f, name, ty)?;
-            writeln!(f, "object.insert(\"{}\", value);", name)?;
-        }
+        send_message(f, message, tag, cx, &JSON)
+    }
 
-        // send json value
+    fn recv_messages(
+        f: &mut fmt::Formatter<'_>,
+        state: &StateName,
+        messages: &[Message],
+        tag: Option<IntType>,
+        cx: &Context,
+    ) -> fmt::Result {
+        recv_messages(f, state, messages, tag, cx, &JSON)
+    }
 
-        Ok(())
+    fn struct_impls(f: &mut fmt::Formatter<'_>, struct_: &Struct, _cx: &Context) -> fmt::Result {
+        struct_impls(f, struct_, &JSON)
     }
+}
 
-    fn recv_messages(f: &mut fmt::Formatter<'_>, messages: &[Message]) -> fmt::Result {
-        writeln!(f, "let value = ")?;
-        if messages.len() == 1 {
-            msg_to_json(f, &messages[0])?;
-        } else {
-            writeln!(f, "let label = value[\"label\"].as_str().unwrap();")?;
-            for msg in messages {
-                writeln!(f, "if label == \"{}\" {{", msg.label)?;
-                msg_to_json(f, msg)?;
-                writeln!(f, "}}")?;
-            }
-            writeln!(f, "panic!(\"invalid message!\")")?;
+pub(super) fn send_message(
+    f: &mut fmt::Formatter<'_>,
+    message: &Message,
+    tag: Option<IntType>,
+    cx: &Context,
+    rt: &Runtime,
+) -> fmt::Result {
+    if tag.is_some() || !message.payload.items.is_empty() {
+        writeln!(f, "let __message = {}::Value::{}(vec![", rt.module, rt.map)?;
+        if tag.is_some() {
+            writeln!(
+                f,
+                "(\"label\".to_string(), {}::Value::{}({:?}.to_string())),",
+                rt.module, rt.string, message.label
+            )?;
+        }
+        // The payload has its own map so that an item named "label" doesn't
+        // clash with the label.
+        if !message.payload.items.is_empty() {
+            writeln!(
+                f,
+                "(\"data\".to_string(), {}::Value::{}(vec![",
+                rt.module, rt.map
+            )?;
         }
-        Ok(())
+        for (name, ty) in &message.payload.items {
+            // Arrays and structs are already borrowed.
+            let borrow = match ty {
+                Type::Bool | Type::Int(_) => "&",
+                Type::Array(..) | Type::Struct(_) => "",
+            };
+            writeln!(
+                f,
+                "({:?}.to_string(), {}::{}({}{})),",
+                name,
+                rt.module,
+                ToValue(rt),
+                borrow,
+                Snake(name)
+            )?;
+        }
+        if !message.payload.items.is_empty() {
+            writeln!(f, "])),")?;
+        }
+        writeln!(f, "]);")?;
+        writeln!(
+            f,
+            "{}::send{}(&mut self.0, &__message){}?;",
+            rt.module,
+            async_suffix(cx.mode),
+            cx.mode.await_()
+        )?;
     }
+
+    writeln!(f, "Ok({}(self.0))", message.dest_state_name)?;
+    Ok(())
 }
 
-fn msg_to_json(f: &mut fmt::Formatter<'_>, msg: &Message) -> fmt::Result {
-    for (name, ty) in &msg.payload.items {
-        from_json_value(f, name, ty)?;
+pub(super) fn recv_messages(
+    f: &mut fmt::Formatter<'_>,
+    state: &StateName,
+    messages: &[Message],
+    tag: Option<IntType>,
+    cx: &Context,
+    rt: &Runtime,
+) -> fmt::Result {
+    writeln!(
+        f,
+        "let __message = {}::recv{}(&mut self.0){}?;",
+        rt.module,
+        async_suffix(cx.mode),
+        cx.mode.await_()
+    )?;
+    if tag.is_none() {
+        return recv_msg(f, &messages[0], cx);
     }
-
-    write!(
+    writeln!(
         f,
-        "return Ok(receiver.recv_{}({}(self.0), ",
-        msg.label, msg.dest_state_name
+        "match __message.label().map_err(RecvError::malformed)? {{"
     )?;
-    for (name, _) in &msg.payload.items {
-        write!(f, "{}, ", name)?;
+    for msg in messages {
+        writeln!(f, "{:?} => {{", msg.label)?;
+        recv_msg(f, msg, cx)?;
+        writeln!(f, "}}")?;
     }
-    writeln!(f, ")?);")?;
+    writeln!(
+        f,
+        "label => Err(RecvError::UnknownLabel {{ state: \"{}\", label: label.to_string() }}),",
+        state
+    )?;
+    writeln!(f, "}}")?;
     Ok(())
 }
 
-fn from_json_value(f: &mut fmt::Formatter<'_>, name: &str, ty: &Type) -> fmt::Result {
-    match ty {
-        Type::Bool => writeln!(f, "let {} = value.as_bool().unwrap();", name)?,
-        Type::Int(ty) => writeln!(f, "let {} = value.as_{}().unwrap()", name, ty)?,
-        Type::Array(ty, size) => {
-            match size {
-                Some(size) => writeln!(f, "let mut {} = [{}::default(); {}];", name, ty, size)?,
-                None => writeln!(
-                    f,
-                    "let mut {} = vec![{}::default(); self.0.recv_u32()? as usize];",
-                    name, ty
-                )?,
-            };
-            writeln!(f, "for (i, value) in value.members().enumerate() {{")?;
-            from_json_value(f, "x", ty)?;
-            writeln!(f, "{}[i] = x;", name)?;
-            writeln!(f, "}}")?;
-        }
-        Type::Struct(struct_) => {
-            for (field_name, ty) in &struct_.fields {
-                writeln!(f, "{{")?;
-                writeln!(f, "let value = value[\"{}\"].unwrap()", field_name)?;
-                from_json_value(f, &field_name, ty)?;
-                writeln!(f, "}}")?;
-            }
-            write!(f, "let {} = super::super::{} {{", name, struct_.name)?;
-            for (field_name, _) in &struct_.fields {
-                write!(f, "{},", field_name)?;
-            }
-            writeln!(f, "}};")?;
-        }
+pub(super) fn struct_impls(
+    f: &mut fmt::Formatter<'_>,
+    struct_: &Struct,
+    rt: &Runtime,
+) -> fmt::Result {
+    let name = Camel(&struct_.name);
+    let lower = rt.name.to_lowercase();
+    writeln!(f, "impl {}::To{} for {} {{", rt.module, rt.name, name)?;
+    writeln!(f, "fn to_{}(&self) -> {}::Value {{", lower, rt.module)?;
+    writeln!(f, "{}::Value::{}(vec![", rt.module, rt.map)?;
+    for (field_name, _) in &struct_.fields {
+        writeln!(
+            f,
+            "({:?}.to_string(), {}::{}(&self.{})),",
+            field_name,
+            rt.module,
+            ToValue(rt),
+            Snake(field_name)
+        )?;
+    }
+    writeln!(f, "])")?;
+    writeln!(f, "}}")?;
+    writeln!(f, "}}")?;
+
+    let value = if struct_.fields.is_empty() {
+        "_value"
+    } else {
+        "value"
     };
+    writeln!(f, "impl {}::From{} for {} {{", rt.module, rt.name, name)?;
+    writeln!(
+        f,
+        "fn from_{}({}: &{}::Value) -> Result<Self, {}::{}Error> {{",
+        lower, value, rt.module, rt.module, rt.name
+    )?;
+    write!(f, "Ok(Self {{")?;
+    for (field_name, _) in &struct_.fields {
+        write!(f, "{}: value.get({:?})?,", Snake(field_name), field_name)?;
+    }
+    writeln!(f, "}})")?;
+    writeln!(f, "}}")?;
+    writeln!(f, "}}")?;
     Ok(())
 }
 
-fn to_json_value(f: &mut fmt::Formatter<'_>, name: &str, ty: &Type) -> fmt::Result {
-    match ty {
-        Type::Bool | Type::Int(_) => writeln!(f, "let value = {}.into()", name)?,
-        Type::Array(ty, _) => {
-            writeln!(f, "let mut array = vec![]")?;
-            writeln!(f, "for i in 0..{}.len() {{", name)?;
-            to_json_value(f, &format!("{}[i]", name), ty)?;
-            writeln!(f, "array.push(value)")?;
-            writeln!(f, "}}")?;
-            writeln!(f, "let value = JsonValue::from(array);")?;
-        }
-        Type::Struct(struct_) => {
-            writeln!(f, "let mut object = HashMap::new();")?;
-            for (field_name, ty) in &struct_.fields {
-                to_json_value(f, &format!("{}.{}", name, field_name), ty)?;
-                writeln!(f, "object.insert({}, value);", field_name)?;
-            }
-            writeln!(f, "let value = JsonValue::from(object);")?;
-        }
-    };
+// The trait method that turns a value into the format's 'Value', e.g.
+// 'ToJson::to_json'.
+struct ToValue<'a>(&'a Runtime);
+
+impl<'a> fmt::Display for ToValue<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "To{}::to_{}", self.0.name, self.0.name.to_lowercase())
+    }
+}
+
+fn recv_msg(f: &mut fmt::Formatter<'_>, msg: &Message, cx: &Context) -> fmt::Result {
+    if !msg.payload.items.is_empty() {
+        writeln!(
+            f,
+            "let __data = __message.field(\"data\").map_err(RecvError::malformed)?;"
+        )?;
+    }
+    for (name, ty) in &msg.payload.items {
+        writeln!(
+            f,
+            "let {}: {} = __data.get({:?}).map_err(RecvError::malformed)?;",
+            Snake(name),
+            RustType(ty, cx),
+            name
+        )?;
+    }
+
+    write!(
+        f,
+        "Ok(__receiver.recv_{}({}(self.0), ",
+        snake_case(&msg.label),
+        msg.dest_state_name
+    )?;
+    for (name, _) in &msg.payload.items {
+        write!(f, "{}, ", Snake(name))?;
+    }
+    writeln!(f, ")?)")?;
     Ok(())
 }
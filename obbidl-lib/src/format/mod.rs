@@ -1,15 +1,58 @@
 use std::fmt;
 
-use crate::validate::Message;
+use crate::{
+    ast::IntType,
+    generate::{Context, Mode},
+    state_machine::StateName,
+    validate::{Message, Struct},
+};
 
 pub mod binary;
+pub mod cbor;
 pub mod json;
+pub mod protobuf;
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum WireFormat {
+    #[default]
+    Binary,
+    Json,
+    Cbor,
+    Compact,
+    Protobuf,
+}
+
+// Generated code shares its scope with payload items, so its own locals
+// start with '__', which names converted with 'snake_case' never do.
 pub trait Format {
+    // Whether messages are tagged even in states where only one message can
+    // be sent, rather than just where they have to be told apart.
+    const TAG_EVERY_MESSAGE: bool = false;
+    // Part of the fingerprint, so peers using different formats don't connect.
+    const WIRE_FORMAT: WireFormat;
+
     fn send_message(
         f: &mut fmt::Formatter<'_>,
         messages: &Message,
-        insert_tag: bool,
+        tag: Option<IntType>,
+        cx: &Context,
     ) -> fmt::Result;
-    fn recv_messages(f: &mut fmt::Formatter<'_>, messages: &[Message]) -> fmt::Result;
+    fn recv_messages(
+        f: &mut fmt::Formatter<'_>,
+        state: &StateName,
+        messages: &[Message],
+        tag: Option<IntType>,
+        cx: &Context,
+    ) -> fmt::Result;
+    // Anything the format needs for each generated struct.
+    fn struct_impls(f: &mut fmt::Formatter<'_>, struct_: &Struct, cx: &Context) -> fmt::Result;
+}
+
+// Runtime modules have an '_async' version of every function that uses a
+// channel.
+fn async_suffix(mode: Mode) -> &'static str {
+    match mode {
+        Mode::Blocking => "",
+        Mode::Async => "_async",
+    }
 }
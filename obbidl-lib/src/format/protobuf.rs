@@ -0,0 +1,558 @@
+use std::fmt;
+
+use colored::Colorize;
+
+use crate::{
+    ast::{self, IntSize, IntType},
+    generate::{Context, RustType},
+    naming::{camel_case, snake_case, Camel, Snake},
+    parser::Span,
+    protobuf::{MAX_FIELD_NUMBER, RESERVED_FIELD_NUMBERS},
+    state_machine::StateName,
+    validate::{File, Message, Protocol, Struct, Type},
+};
+
+use super::{async_suffix, Format, WireFormat};
+
+// Protocol Buffers, using 'obbidl_lib::protobuf'. The schema the messages
+// follow is written by 'GenerateProto'.
+pub struct Protobuf;
+
+impl Format for Protobuf {
+    const TAG_EVERY_MESSAGE: bool = true;
+    const WIRE_FORMAT: WireFormat = WireFormat::Protobuf;
+
+    fn send_message(
+        f: &mut fmt::Formatter<'_>,
+        message: &Message,
+        _tag: Option<IntType>,
+        cx: &Context,
+    ) -> fmt::Result {
+        let payload = if message.payload.items.is_empty() {
+            "&[]"
+        } else {
+            writeln!(f, "let mut __payload = Vec::new();")?;
+            for (index, (name, ty)) in message.payload.items.iter().enumerate() {
+                match ty {
+                    Type::Array(..) => writeln!(
+                        f,
+                        "obbidl_lib::protobuf::write_repeated({}, {}, &mut __payload);",
+                        Snake(name),
+                        index + 1
+                    )?,
+                    Type::Bool | Type::Int(_) => writeln!(
+                        f,
+                        "obbidl_lib::protobuf::Field::write_field(&{}, {}, &mut __payload);",
+                        Snake(name),
+                        index + 1
+                    )?,
+                    // Structs are already borrowed.
+                    Type::Struct(_) => writeln!(
+                        f,
+                        "obbidl_lib::protobuf::Field::write_field({}, {}, &mut __payload);",
+                        Snake(name),
+                        index + 1
+                    )?,
+                }
+            }
+            "&__payload"
+        };
+        writeln!(
+            f,
+            "obbidl_lib::protobuf::send{}(&mut self.0, {}, {}){}?;",
+            async_suffix(cx.mode),
+            message.id + 1,
+            payload,
+            cx.mode.await_()
+        )?;
+
+        writeln!(f, "Ok({}(self.0))", message.dest_state_name)?;
+        Ok(())
+    }
+
+    fn recv_messages(
+        f: &mut fmt::Formatter<'_>,
+        state: &StateName,
+        messages: &[Message],
+        _tag: Option<IntType>,
+        cx: &Context,
+    ) -> fmt::Result {
+        writeln!(
+            f,
+            "let __message = obbidl_lib::protobuf::recv{}(&mut self.0){}?;",
+            async_suffix(cx.mode),
+            cx.mode.await_()
+        )?;
+        writeln!(
+            f,
+            "let (__number, __payload) = obbidl_lib::protobuf::choice(&__message).map_err(RecvError::malformed)?;"
+        )?;
+        writeln!(f, "match __number {{")?;
+        for msg in messages {
+            writeln!(f, "{} => {{", msg.id + 1)?;
+            recv_msg(f, msg, cx)?;
+            writeln!(f, "}}")?;
+        }
+        writeln!(
+            f,
+            "number => Err(RecvError::UnknownTag {{ state: \"{}\", tag: number as u64 - 1 }}),",
+            state
+        )?;
+        writeln!(f, "}}")?;
+        Ok(())
+    }
+
+    fn struct_impls(f: &mut fmt::Formatter<'_>, struct_: &Struct, _cx: &Context) -> fmt::Result {
+        writeln!(
+            f,
+            "impl obbidl_lib::protobuf::Message for {} {{",
+            Camel(&struct_.name)
+        )?;
+        writeln!(f, "fn write_fields(&self, buf: &mut Vec<u8>) {{")?;
+        for (index, (field_name, _)) in struct_.fields.iter().enumerate() {
+            writeln!(
+                f,
+                "obbidl_lib::protobuf::Field::write_field(&self.{}, {}, buf);",
+                Snake(field_name),
+                index + 1
+            )?;
+        }
+        writeln!(f, "}}")?;
+
+        let fields = if struct_.fields.is_empty() {
+            "_fields"
+        } else {
+            "fields"
+        };
+        writeln!(
+            f,
+            "fn read_fields({}: &obbidl_lib::protobuf::Fields) -> Result<Self, obbidl_lib::protobuf::ProtobufError> {{",
+            fields
+        )?;
+        write!(f, "Ok(Self {{")?;
+        for (index, (field_name, _)) in struct_.fields.iter().enumerate() {
+            write!(f, "{}: fields.get({})?,", Snake(field_name), index + 1)?;
+        }
+        writeln!(f, "}})")?;
+        writeln!(f, "}}")?;
+        writeln!(f, "}}")?;
+        Ok(())
+    }
+}
+
+fn recv_msg(f: &mut fmt::Formatter<'_>, msg: &Message, cx: &Context) -> fmt::Result {
+    for (index, (name, ty)) in msg.payload.items.iter().enumerate() {
+        writeln!(
+            f,
+            "let {}: {} = __payload.get({}).map_err(RecvError::malformed)?;",
+            Snake(name),
+            RustType(ty, cx),
+            index + 1
+        )?;
+    }
+
+    write!(
+        f,
+        "Ok(__receiver.recv_{}({}(self.0), ",
+        snake_case(&msg.label),
+        msg.dest_state_name
+    )?;
+    for (name, _) in &msg.payload.items {
+        write!(f, "{}, ", Snake(name))?;
+    }
+    writeln!(f, ")?)")?;
+    Ok(())
+}
+
+/// Writes the '.proto' schema of the messages sent by the 'Protobuf' format.
+/// Each struct is a message, and each protocol is a message with a message
+/// for each payload and one for each state, with a 'oneof' of the messages
+/// that can be sent in it. Fields are numbered by their position and
+/// messages by their id plus one.
+pub struct GenerateProto<'a>(pub &'a File);
+
+impl<'a> fmt::Display for GenerateProto<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "syntax = \"proto3\";")?;
+
+        let mut lists = vec![];
+        for struct_ in &self.0.structs {
+            writeln!(f)?;
+            write_message(f, &camel_case(&struct_.name), &struct_.fields, "")?;
+            for (_, ty) in &struct_.fields {
+                find_lists(ty, &mut lists);
+            }
+        }
+        for protocol in &self.0.protocols {
+            for state in &protocol.states {
+                for msg in state.trans.iter().flat_map(|trans| &trans.messages) {
+                    for (_, ty) in &msg.payload.items {
+                        find_lists(ty, &mut lists);
+                    }
+                }
+            }
+        }
+        for ty in &lists {
+            writeln!(f)?;
+            write_message(
+                f,
+                &ProtoType(ty).to_string(),
+                &[("items".to_string(), ty.clone())],
+                "",
+            )?;
+        }
+
+        for protocol in &self.0.protocols {
+            writeln!(f)?;
+            write_protocol(f, protocol)?;
+        }
+        Ok(())
+    }
+}
+
+// A message whose id can't be used as its protocol buffers field number,
+// which is the id plus one.
+#[derive(Debug, Clone)]
+pub struct IdError<'a> {
+    pub protocol: &'a Span<ast::Protocol>,
+    pub msg: &'a Span<ast::Message>,
+    pub kind: IdErrorKind,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IdErrorKind {
+    // Implicit ids move when a message is added to a state, and with them
+    // the field numbers, which have to stay the same for older readers.
+    Missing,
+    // Above the largest field number.
+    TooLarge,
+    // In the range protocol buffers reserves for itself.
+    Reserved,
+}
+
+pub struct PrettyPrintIdError<'a> {
+    error: &'a IdError<'a>,
+    source: &'a str,
+}
+
+pub fn id_errors(file: &ast::File) -> Vec<IdError<'_>> {
+    file.protocols
+        .iter()
+        .flat_map(|protocol| {
+            protocol
+                .inner
+                .seq
+                .messages()
+                .into_iter()
+                .filter_map(move |msg| {
+                    let kind = match msg.inner.id.map(|id| id.checked_add(1)) {
+                        None => IdErrorKind::Missing,
+                        Some(Some(number)) if RESERVED_FIELD_NUMBERS.contains(&number) => {
+                            IdErrorKind::Reserved
+                        }
+                        Some(Some(number)) if number <= MAX_FIELD_NUMBER => return None,
+                        Some(_) => IdErrorKind::TooLarge,
+                    };
+                    Some(IdError {
+                        protocol,
+                        msg,
+                        kind,
+                    })
+                })
+        })
+        .collect()
+}
+
+impl<'a> IdError<'a> {
+    pub fn pretty_print(&'a self, source: &'a str) -> PrettyPrintIdError<'a> {
+        PrettyPrintIdError {
+            error: self,
+            source,
+        }
+    }
+}
+
+impl<'a> fmt::Display for PrettyPrintIdError<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let IdError {
+            protocol,
+            msg,
+            kind,
+        } = self.error;
+        let problem = match kind {
+            IdErrorKind::Missing => "has no id",
+            IdErrorKind::TooLarge => "has an id that is too large",
+            IdErrorKind::Reserved => "has an id in a reserved range",
+        };
+        writeln!(
+            f,
+            "{}: message '{}' in protocol '{}' {}",
+            "error".red(),
+            msg.inner.label,
+            protocol.inner.name,
+            problem
+        )?;
+        write!(f, "{}", msg.pretty_print(self.source))?;
+        match kind {
+            IdErrorKind::Missing => writeln!(
+                f,
+                "info: protocol buffers field numbers are message ids plus one, so they have to be given explicitly to stay the same when messages are added"
+            ),
+            IdErrorKind::TooLarge => writeln!(
+                f,
+                "info: protocol buffers field numbers are message ids plus one, and can be at most {}",
+                MAX_FIELD_NUMBER
+            ),
+            IdErrorKind::Reserved => writeln!(
+                f,
+                "info: protocol buffers field numbers are message ids plus one, and {} to {} are reserved",
+                RESERVED_FIELD_NUMBERS.start(),
+                RESERVED_FIELD_NUMBERS.end()
+            ),
+        }
+    }
+}
+
+fn write_protocol(f: &mut fmt::Formatter<'_>, protocol: &Protocol) -> fmt::Result {
+    writeln!(f, "message {} {{", camel_case(&protocol.name))?;
+
+    // A message for each label, or more than one if a label has different
+    // payloads in different states.
+    let mut payloads: Vec<(String, &Message)> = vec![];
+    for state in &protocol.states {
+        for msg in state.trans.iter().flat_map(|trans| &trans.messages) {
+            if payloads
+                .iter()
+                .any(|(_, other)| other.label == msg.label && same_payload(msg, other))
+            {
+                continue;
+            }
+            let mut name = camel_case(&msg.label);
+            let count = payloads
+                .iter()
+                .filter(|(_, other)| other.label == msg.label)
+                .count();
+            if count > 0 {
+                name = format!("{}{}", name, count + 1);
+            }
+            payloads.push((name, msg));
+        }
+    }
+    for (name, msg) in &payloads {
+        write_message(f, name, &msg.payload.items, "  ")?;
+    }
+
+    for state in &protocol.states {
+        let Some(trans) = &state.trans else {
+            continue;
+        };
+        writeln!(f, "  message {}Message {{", state.name)?;
+        writeln!(f, "    oneof message {{")?;
+        for msg in &trans.messages {
+            let (name, _) = payloads
+                .iter()
+                .find(|(_, other)| other.label == msg.label && same_payload(msg, other))
+                .unwrap();
+            writeln!(
+                f,
+                "      {} {} = {};",
+                name,
+                snake_case(&msg.label),
+                msg.id + 1
+            )?;
+        }
+        writeln!(f, "    }}")?;
+        writeln!(f, "  }}")?;
+    }
+    writeln!(f, "}}")?;
+    Ok(())
+}
+
+fn same_payload(a: &Message, b: &Message) -> bool {
+    let fields = |msg: &Message| {
+        msg.payload
+            .items
+            .iter()
+            .map(|(name, ty)| format!("{} {}", ProtoField(ty), name))
+            .collect::<Vec<_>>()
+    };
+    fields(a) == fields(b)
+}
+
+fn write_message(
+    f: &mut fmt::Formatter<'_>,
+    name: &str,
+    fields: &[(String, Type)],
+    indent: &str,
+) -> fmt::Result {
+    if fields.is_empty() {
+        return writeln!(f, "{}message {} {{}}", indent, name);
+    }
+    writeln!(f, "{}message {} {{", indent, name)?;
+    for (index, (field_name, ty)) in fields.iter().enumerate() {
+        write!(
+            f,
+            "{}  {} {} = {};",
+            indent,
+            ProtoField(ty),
+            snake_case(field_name),
+            index + 1
+        )?;
+        if let Type::Array(_, Some(size)) = ty {
+            write!(f, " // {} items", size)?;
+        }
+        writeln!(f)?;
+    }
+    writeln!(f, "{}}}", indent)?;
+    Ok(())
+}
+
+// Arrays of arrays are repeated messages with the inner array as their only
+// field, e.g. 'Uint32List' for 'u8[][]'. Finds the inner arrays, innermost
+// first.
+fn find_lists(ty: &Type, lists: &mut Vec<Type>) {
+    if let Type::Array(inner, _) = ty {
+        find_lists(inner, lists);
+        // The size of the inner array is only known where it is used.
+        if let Type::Array(item, _) = &**inner {
+            let list = Type::Array(item.clone(), None);
+            let name = ProtoType(&list).to_string();
+            if !lists.iter().any(|list| ProtoType(list).to_string() == name) {
+                lists.push(list);
+            }
+        }
+    }
+}
+
+// The type of a single value.
+struct ProtoType<'a>(&'a Type);
+
+impl<'a> fmt::Display for ProtoType<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.0 {
+            Type::Bool => write!(f, "bool"),
+            Type::Int(ty) => {
+                let sign = if ty.signed { "sint" } else { "uint" };
+                let bits = if ty.size == IntSize::B64 { 64 } else { 32 };
+                write!(f, "{}{}", sign, bits)
+            }
+            Type::Array(inner, _) => {
+                write!(f, "{}List", camel_case(&ProtoType(inner).to_string()))
+            }
+            Type::Struct(struct_) => write!(f, "{}", camel_case(&struct_.name)),
+        }
+    }
+}
+
+// The type of a field, which is repeated for arrays. Messages are referred to
+// from the root, since a payload may have the same name as a struct.
+struct ProtoField<'a>(&'a Type);
+
+impl<'a> fmt::Display for ProtoField<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let (repeated, ty) = match self.0 {
+            Type::Array(inner, _) => ("repeated ", &**inner),
+            ty => ("", ty),
+        };
+        let root = match ty {
+            Type::Bool | Type::Int(_) => "",
+            Type::Array(..) | Type::Struct(_) => ".",
+        };
+        write!(f, "{}{}{}", repeated, root, ProtoType(ty))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        ast,
+        compile::{compile_protocol_file, Limits},
+        parser::parse,
+        report::Report,
+        validate::validate_protocol_file,
+    };
+
+    use super::{id_errors, GenerateProto, IdErrorKind};
+
+    #[test]
+    fn test_generate_proto() {
+        let source = "struct point { x: i32, y: u64, tags: u8[2][] } \
+            protocol shapes(role client, role server) { \
+                choice { \
+                    line(start: struct point, end: struct point) = 4 from client to server; \
+                } or { \
+                    stop from client to server; \
+                } \
+                ok(bool[][][]) from server to client; \
+            }";
+        let file = parse::<ast::File>(source).report();
+        let file_fsm = compile_protocol_file(&file, &Limits::default()).unwrap();
+        let file = validate_protocol_file(&file_fsm, &file.structs).unwrap();
+        let expected = r#"syntax = "proto3";
+
+message Point {
+  sint32 x = 1;
+  uint64 y = 2;
+  repeated .Uint32List tags = 3;
+}
+
+message Uint32List {
+  repeated uint32 items = 1;
+}
+
+message BoolList {
+  repeated bool items = 1;
+}
+
+message BoolListList {
+  repeated .BoolList items = 1;
+}
+
+message Shapes {
+  message Line {
+    .Point start = 1;
+    .Point end = 2;
+  }
+  message Stop {}
+  message Ok {
+    repeated .BoolListList param0 = 1;
+  }
+  message StartMessage {
+    oneof message {
+      Line line = 5;
+      Stop stop = 1;
+    }
+  }
+  message LineMessage {
+    oneof message {
+      Ok ok = 1;
+    }
+  }
+}
+"#;
+        assert_eq!(GenerateProto(&file).to_string(), expected);
+    }
+
+    #[test]
+    fn test_id_errors() {
+        let source = "protocol X {
+            choice { A = 0 from C to S; } or { B from C to S; } or { D = 18999 from C to S; }
+            choice { E = 536870910 from S to C; } or { F = 536870911 from S to C; }
+            choice { G = 18998 from C to S; } or { H = 18446744073709551615 from C to S; }
+        }";
+        let file = parse::<ast::File>(source).report();
+        let errors: Vec<_> = id_errors(&file)
+            .iter()
+            .map(|error| (error.msg.inner.label.as_str(), error.kind))
+            .collect();
+        assert_eq!(
+            errors,
+            [
+                ("B", IdErrorKind::Missing),
+                ("D", IdErrorKind::Reserved),
+                ("F", IdErrorKind::TooLarge),
+                ("H", IdErrorKind::TooLarge),
+            ]
+        );
+    }
+}
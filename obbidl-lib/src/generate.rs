@@ -1,9 +1,14 @@
-use std::{fmt, marker::PhantomData};
+use std::{collections::HashMap, fmt, marker::PhantomData};
+
+use colored::Colorize;
 
 use crate::{
-    ast::{IntSize, IntType},
-    format::Format,
-    validate::{Direction, File, Payload, Protocol, SimpleRole, Type},
+    ast::{self, IntSize, IntType, Sequence},
+    compile::{ProtocolFileStateMachines, ProtocolStateMachine},
+    format::{Format, WireFormat},
+    naming::{snake_case, Camel, Snake},
+    parser::Span,
+    validate::{Branch, Direction, File, Fork, Payload, Protocol, SimpleRole, State, Type},
 };
 
 impl fmt::Display for IntType {
@@ -22,206 +27,886 @@ impl fmt::Display for IntType {
     }
 }
 
-impl fmt::Display for Type {
+// What formats need to know about the code they are generating into.
+pub struct Context {
+    pub(crate) mode: Mode,
+    // Goes in front of struct names, e.g. "super::super::" or
+    // "crate::proto::".
+    pub(crate) structs: String,
+}
+
+pub(crate) struct RustType<'a>(pub &'a Type, pub &'a Context);
+
+impl<'a> fmt::Display for RustType<'a> {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        match self {
+        match self.0 {
             Type::Bool => write!(f, "bool"),
             Type::Int(ty) => write!(f, "{}", ty),
             Type::Array(ty, size) => match size {
-                Some(size) => write!(f, "[{}; {}]", ty, size),
-                None => write!(f, "Vec<{}>", ty),
+                Some(size) => write!(f, "[{}; {}]", RustType(ty, self.1), size),
+                None => write!(f, "Vec<{}>", RustType(ty, self.1)),
             },
-            Type::Struct(struct_) => write!(f, "super::super::{}", struct_.name),
+            Type::Struct(struct_) => write!(f, "{}{}", self.1.structs, Camel(&struct_.name)),
         }
     }
 }
 
-struct BorrowedType<'a>(&'a Type);
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Mode {
+    #[default]
+    Blocking,
+    Async,
+}
+
+impl Mode {
+    pub(crate) fn channel(self) -> &'static str {
+        match self {
+            Mode::Blocking => "Channel",
+            Mode::Async => "AsyncChannel",
+        }
+    }
+    pub(crate) fn fn_keyword(self) -> &'static str {
+        match self {
+            Mode::Blocking => "fn",
+            Mode::Async => "async fn",
+        }
+    }
+    // Goes after every call that may block on the channel.
+    pub(crate) fn await_(self) -> &'static str {
+        match self {
+            Mode::Blocking => "",
+            Mode::Async => ".await",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Options {
+    pub mode: Mode,
+    // Only used by 'Compiler' and the 'build' functions,
+    // 'GenerateRust' takes the format as a type parameter.
+    pub format: WireFormat,
+    pub layout: Layout,
+    pub structs: StructOptions,
+    // Replaces 'structs' for the structs with these names.
+    pub struct_overrides: HashMap<String, StructOptions>,
+    // The names of the roles to generate modules for, all of them if empty.
+    pub roles: Vec<String>,
+}
+
+impl Options {
+    pub fn struct_options(&self, name: &str) -> &StructOptions {
+        self.struct_overrides.get(name).unwrap_or(&self.structs)
+    }
+    pub fn generates_role(&self, name: &str) -> bool {
+        self.roles.is_empty() || self.roles.iter().any(|role| role == name)
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Layout {
+    // The module the structs are generated in, e.g. "crate::proto". Without
+    // it structs are referred to with relative paths, which only works if
+    // they are generated in the same module as the protocols.
+    pub structs_path: Option<String>,
+    pub nesting: Nesting,
+    pub items: Items,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Nesting {
+    // A module for each role inside a module for each protocol, e.g.
+    // 'ping::client'.
+    #[default]
+    Nested,
+    // A single module for each role, e.g. 'ping_client'.
+    Flat,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Items {
+    #[default]
+    All,
+    Structs,
+    Protocols,
+}
+
+impl Layout {
+    // The prefix for struct names in code 'depth' modules below the module
+    // the output is included in.
+    fn structs_prefix(&self, depth: usize) -> String {
+        match &self.structs_path {
+            Some(path) => format!("{}::", path),
+            None => "super::".repeat(depth),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct StructOptions {
+    // Paths of the derive macros, e.g. "Debug" or "serde::Serialize".
+    pub derives: Vec<String>,
+    pub visibility: Visibility,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub enum Visibility {
+    Private,
+    Crate,
+    // What goes in the parentheses, e.g. "super" or "in crate::points".
+    Restricted(String),
+    #[default]
+    Public,
+}
+
+impl fmt::Display for Visibility {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Visibility::Private => Ok(()),
+            Visibility::Crate => write!(f, "pub(crate) "),
+            Visibility::Restricted(path) => write!(f, "pub({}) ", path),
+            Visibility::Public => write!(f, "pub "),
+        }
+    }
+}
+
+struct BorrowedType<'a>(&'a Type, &'a Context);
 
 impl<'a> fmt::Display for BorrowedType<'a> {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match &self.0 {
-            Type::Bool => write!(f, "bool"),
-            Type::Int(ty) => write!(f, "{}", ty),
+            Type::Bool | Type::Int(_) => write!(f, "{}", RustType(self.0, self.1)),
             Type::Array(ty, size) => match size {
-                Some(size) => write!(f, "&[{}; {}]", ty, size),
-                None => write!(f, "&[{}]", ty),
+                Some(size) => write!(f, "&[{}; {}]", RustType(ty, self.1), size),
+                None => write!(f, "&[{}]", RustType(ty, self.1)),
             },
-            Type::Struct(struct_) => write!(f, "&super::super::{}", struct_.name),
+            Type::Struct(_) => write!(f, "&{}", RustType(self.0, self.1)),
         }
     }
 }
 
-struct BorrowedPayload<'a>(&'a Payload);
+struct BorrowedPayload<'a>(&'a Payload, &'a Context);
 
 impl<'a> fmt::Display for BorrowedPayload<'a> {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         for (name, ty) in &self.0.items {
-            write!(f, "{}: {}, ", name, BorrowedType(ty))?;
+            write!(f, "{}: {}, ", Snake(name), BorrowedType(ty, self.1))?;
         }
         Ok(())
     }
 }
 
-impl fmt::Display for Payload {
+struct RustPayload<'a>(&'a Payload, &'a Context);
+
+impl<'a> fmt::Display for RustPayload<'a> {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        for (name, ty) in &self.items {
-            write!(f, "{}: {}, ", name, ty)?;
+        for (name, ty) in &self.0.items {
+            write!(f, "{}: {}, ", Snake(name), RustType(ty, self.1))?;
         }
         Ok(())
     }
 }
 
+// A protocol with a 'par' block in a file generated in async mode. Branches
+// are multiplexed by blocking on the channel, which isn't possible in async
+// code.
+#[derive(Debug, Clone)]
+pub struct AsyncPar<'a> {
+    pub protocol: &'a Span<ast::Protocol>,
+    // The first message of the block, if it has one.
+    pub msg: Option<&'a Span<ast::Message>>,
+}
+
+pub struct PrettyPrintAsyncPar<'a> {
+    par: &'a AsyncPar<'a>,
+    source: &'a str,
+}
+
+// Only protocols which fork have to be rejected, which they all do unless
+// every 'par' block in them has a single branch.
+pub fn async_pars<'a>(
+    file: &'a ast::File,
+    file_fsm: &ProtocolFileStateMachines,
+) -> Vec<AsyncPar<'a>> {
+    file.protocols
+        .iter()
+        .zip(&file_fsm.protocols)
+        .filter(|(_, protocol_fsm)| {
+            let state_machine = &protocol_fsm.inner.state_machine;
+            state_machine
+                .iter_states()
+                .any(|state| state_machine.fork(state).is_some())
+        })
+        .map(|(protocol, _)| AsyncPar {
+            protocol,
+            msg: protocol
+                .inner
+                .seq
+                .first_par()
+                .and_then(|seqs| seqs.0.iter().find_map(Sequence::first_message)),
+        })
+        .collect()
+}
+
+impl<'a> AsyncPar<'a> {
+    pub fn pretty_print(&'a self, source: &'a str) -> PrettyPrintAsyncPar<'a> {
+        PrettyPrintAsyncPar { par: self, source }
+    }
+}
+
+impl<'a> fmt::Display for PrettyPrintAsyncPar<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let AsyncPar { protocol, msg } = self.par;
+        writeln!(
+            f,
+            "{}: protocol '{}' has a 'par' block, which is not supported in async mode",
+            "error".red(),
+            protocol.inner.name
+        )?;
+        match msg {
+            Some(msg) => write!(f, "{}", msg.pretty_print(self.source))?,
+            None => write!(f, "{}", protocol.pretty_print(self.source))?,
+        }
+        writeln!(
+            f,
+            "info: branches are multiplexed by blocking on the channel, use the blocking mode for this protocol"
+        )
+    }
+}
+
+// Two roles whose modules have the same name in the 'Flat' layout, e.g. role
+// 'c' of protocol 'a_b' and role 'b_c' of protocol 'a'.
+#[derive(Debug, Clone)]
+pub struct ModuleCollision<'a> {
+    pub first: (&'a Span<ProtocolStateMachine>, &'a ast::Role),
+    pub second: (&'a Span<ProtocolStateMachine>, &'a ast::Role),
+}
+
+pub struct PrettyPrintModuleCollision<'a> {
+    collision: &'a ModuleCollision<'a>,
+    source: &'a str,
+}
+
+fn flat_module(protocol: &str, role: &ast::Role) -> String {
+    snake_case(&format!("{}_{}", protocol, role))
+}
+
+// Only roles that are generated can clash. Expects protocols that passed
+// validation, so the roles of a protocol never clash with each other.
+pub fn module_collisions<'a>(
+    file_fsm: &'a ProtocolFileStateMachines,
+    options: &Options,
+) -> Vec<ModuleCollision<'a>> {
+    if options.layout.nesting != Nesting::Flat || options.layout.items == Items::Structs {
+        return vec![];
+    }
+    let roles: Vec<_> = file_fsm
+        .protocols
+        .iter()
+        .flat_map(|protocol| {
+            protocol
+                .inner
+                .roles
+                .iter()
+                .map(move |role| (protocol, role))
+        })
+        .filter(|(_, role)| options.generates_role(&role.0))
+        .collect();
+    let mut collisions = vec![];
+    for (index, &(protocol, role)) in roles.iter().enumerate() {
+        let module = flat_module(&protocol.inner.name, role);
+        if let Some(&first) = roles[..index]
+            .iter()
+            .find(|(first, role)| flat_module(&first.inner.name, role) == module)
+        {
+            collisions.push(ModuleCollision {
+                first,
+                second: (protocol, role),
+            });
+        }
+    }
+    collisions
+}
+
+impl<'a> ModuleCollision<'a> {
+    pub fn pretty_print(&'a self, source: &'a str) -> PrettyPrintModuleCollision<'a> {
+        PrettyPrintModuleCollision {
+            collision: self,
+            source,
+        }
+    }
+}
+
+impl<'a> fmt::Display for PrettyPrintModuleCollision<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let ModuleCollision {
+            first: (first, first_role),
+            second: (second, second_role),
+        } = self.collision;
+        writeln!(
+            f,
+            "{}: role '{}' of protocol '{}' and role '{}' of protocol '{}' are both generated in the module '{}'",
+            "error".red(),
+            first_role,
+            first.inner.name,
+            second_role,
+            second.inner.name,
+            flat_module(&first.inner.name, first_role)
+        )?;
+        write!(f, "{}", first.pretty_print(self.source))?;
+        writeln!(f, "info: the second protocol is defined here:")?;
+        write!(f, "{}", second.pretty_print(self.source))?;
+        writeln!(
+            f,
+            "info: rename one of the protocols or roles, or use the nested layout"
+        )
+    }
+}
+
 fn generate_protocol<F: Format>(
     f: &mut fmt::Formatter<'_>,
     protocol: &Protocol,
     role: SimpleRole,
+    cx: &Context,
+    fingerprint: &str,
 ) -> fmt::Result {
-    writeln!(f, "use std::mem::size_of;")?;
-    writeln!(f, "use obbidl_lib::channel::Channel;")?;
+    let mode = cx.mode;
+    // Rejected by 'Compiler' with a diagnostic, see 'async_pars'.
+    if mode == Mode::Async && protocol.states.iter().any(|state| state.fork.is_some()) {
+        writeln!(
+            f,
+            "compile_error!(\"'par' blocks are not supported in async mode\");"
+        )?;
+        return Ok(());
+    }
+    let channel = mode.channel();
+
+    let mut imports = vec![channel];
+    if protocol.states.iter().any(|state| state.fork.is_some()) {
+        imports.extend(["Branch", "SplitChannel"]);
+    }
+    if protocol.states.iter().any(|state| {
+        state.trans.is_some() && state.branch.as_ref().is_some_and(|branch| branch.may_end)
+    }) {
+        imports.push("MuxError");
+    }
+    if protocol
+        .states
+        .iter()
+        .filter_map(|state| state.trans.as_ref())
+        .any(|trans| receives(trans.dir, role))
+    {
+        imports.push("RecvError");
+    }
+    writeln!(f, "use obbidl_lib::channel::{{{}}};", imports.join(", "))?;
 
     for state in &protocol.states {
         writeln!(f, "#[must_use]")?;
-        writeln!(f, "pub struct {}<C: Channel>(C);", state.name)?;
-
-        if let Some(trans) = &state.trans {
-            if (trans.dir == Direction::AToB && role == SimpleRole::B)
-                || (trans.dir == Direction::BToA && role == SimpleRole::A)
-            {
+        writeln!(f, "pub struct {}<C: {}>(C);", state.name, channel)?;
+
+        // A branch that may end while the other role is sending has to check
+        // for the end of the branch before receiving, which needs the
+        // multiplexed channel rather than any channel.
+        let done = state
+            .branch
+            .as_ref()
+            .filter(|branch| branch.may_end)
+            .map(BranchDone);
+        let (generics, chan, err) = if done.is_some() && state.trans.is_some() {
+            (
+                "C: SplitChannel".to_string(),
+                "Branch<C>",
+                "MuxError<C::Error>",
+            )
+        } else {
+            (format!("C: {}<Error = E>, E", channel), "C", "E")
+        };
+
+        if let Some(fork) = &state.fork {
+            generate_fork(f, state, fork)?;
+        } else if let Some(trans) = &state.trans {
+            // Every message is tagged where a branch may end, so none of them
+            // are empty and can be told apart from the end of the branch.
+            let tag = if F::TAG_EVERY_MESSAGE || trans.messages.len() > 1 || done.is_some() {
+                Some(trans.tag)
+            } else {
+                None
+            };
+            if receives(trans.dir, role) {
                 writeln!(
                     f,
-                    "pub trait {}Receiver<C: Channel<Error = E>, E> {{",
-                    state.name
+                    "pub trait {}Receiver<C: {}<Error = E>, E> {{",
+                    state.name, channel
                 )?;
                 writeln!(f, "type Type;")?;
 
                 for msg in &trans.messages {
+                    write!(f, "{}", AllowManyArguments(msg.payload.items.len() + 2))?;
                     writeln!(
                         f,
                         "fn recv_{}(self, state: {}<C>, {}) -> Result<Self::Type, E>;",
-                        msg.label, msg.dest_state_name, msg.payload
+                        snake_case(&msg.label),
+                        msg.dest_state_name,
+                        RustPayload(&msg.payload, cx)
+                    )?;
+                }
+                if let Some(done) = &done {
+                    writeln!(
+                        f,
+                        "fn done(self, state: {}<C>) -> Result<Self::Type, E>;",
+                        done
                     )?;
                 }
                 writeln!(f, "}}")?;
 
-                writeln!(f, "pub enum {}Response<C: Channel> {{", state.name)?;
+                writeln!(f, "pub enum {}Response<C: {}> {{", state.name, channel)?;
                 for msg in &trans.messages {
-                    writeln!(f, "#[allow(non_camel_case_types)]")?;
-                    writeln!(f, "{} {{", msg.label)?;
-                    writeln!(f, "state: {}<C>, {}", msg.dest_state_name, msg.payload)?;
+                    writeln!(f, "{} {{", Camel(&msg.label))?;
+                    writeln!(
+                        f,
+                        "state: {}<C>, {}",
+                        msg.dest_state_name,
+                        RustPayload(&msg.payload, cx)
+                    )?;
                     writeln!(f, "}},")?;
                 }
+                if let Some(done) = &done {
+                    writeln!(f, "Done {{ state: {}<C> }},", done)?;
+                }
                 writeln!(f, "}}")?;
 
                 writeln!(f, "struct {}DefaultReceiver;", state.name)?;
 
                 writeln!(
                     f,
-                    "impl<C: Channel<Error = E>, E> {}Receiver<C, E> for {}DefaultReceiver {{",
-                    state.name, state.name
+                    "impl<C: {}<Error = E>, E> {}Receiver<C, E> for {}DefaultReceiver {{",
+                    channel, state.name, state.name
                 )?;
                 writeln!(f, "type Type = {}Response<C>;", state.name)?;
                 for msg in &trans.messages {
+                    write!(f, "{}", AllowManyArguments(msg.payload.items.len() + 2))?;
                     writeln!(
                         f,
                         "fn recv_{}(self, state: {}<C>, {}) -> Result<Self::Type, E> {{",
-                        msg.label, msg.dest_state_name, msg.payload
+                        snake_case(&msg.label),
+                        msg.dest_state_name,
+                        RustPayload(&msg.payload, cx)
+                    )?;
+                    write!(
+                        f,
+                        "Ok({}Response::{} {{ state, ",
+                        state.name,
+                        Camel(&msg.label)
                     )?;
-                    write!(f, "Ok({}Response::{} {{ state, ", state.name, msg.label)?;
                     for (name, _) in &msg.payload.items {
-                        write!(f, "{}, ", name)?;
+                        write!(f, "{}, ", Snake(name))?;
                     }
                     writeln!(f, "}})")?;
                     writeln!(f, "}}")?;
                 }
+                if let Some(done) = &done {
+                    writeln!(
+                        f,
+                        "fn done(self, state: {}<C>) -> Result<Self::Type, E> {{",
+                        done
+                    )?;
+                    writeln!(f, "Ok({}Response::Done {{ state }})", state.name)?;
+                    writeln!(f, "}}")?;
+                }
                 writeln!(f, "}}")?;
 
-                writeln!(f, "impl<C: Channel<Error = E>, E> {}<C> {{", state.name)?;
-                writeln!(f, "pub fn recv<T>(mut self, receiver: impl {}Receiver<C, E, Type = T>) -> Result<T, E> {{", state.name)?;
-                F::recv_messages(f, &trans.messages)?;
+                let has_payload = trans
+                    .messages
+                    .iter()
+                    .any(|msg| !msg.payload.items.is_empty());
+                writeln!(f, "impl<{}> {}<{}> {{", generics, state.name, chan)?;
+                writeln!(
+                    f,
+                    "pub {} recv<T>({}self, __receiver: impl {}Receiver<{}, {}, Type = T>) -> Result<T, RecvError<{}>> {{",
+                    mode.fn_keyword(),
+                    Mut(tag.is_some() || has_payload),
+                    state.name,
+                    chan,
+                    err,
+                    err
+                )?;
+                if let Some(done) = &done {
+                    writeln!(f, "if self.0.recv_end()? {{")?;
+                    writeln!(f, "return Ok(__receiver.done({}(self.0))?);", done)?;
+                    writeln!(f, "}}")?;
+                }
+                F::recv_messages(f, &state.name, &trans.messages, tag, cx)?;
                 writeln!(f, "}}")?;
 
                 writeln!(
                     f,
-                    "pub fn recv_default(self) -> Result<{}Response<C>, E> {{",
-                    state.name
+                    "pub {} recv_default(self) -> Result<{}Response<{}>, RecvError<{}>> {{",
+                    mode.fn_keyword(),
+                    state.name,
+                    chan,
+                    err
+                )?;
+                writeln!(
+                    f,
+                    "self.recv({}DefaultReceiver){}",
+                    state.name,
+                    mode.await_()
                 )?;
-                writeln!(f, "self.recv({}DefaultReceiver)", state.name)?;
                 writeln!(f, "}}")?;
             }
 
-            if (trans.dir == Direction::AToB && role == SimpleRole::A)
-                || (trans.dir == Direction::BToA && role == SimpleRole::B)
-            {
-                writeln!(f, "impl<C: Channel<Error = E>, E> {}<C> {{", state.name)?;
+            if !receives(trans.dir, role) {
+                writeln!(f, "impl<{}> {}<{}> {{", generics, state.name, chan)?;
 
                 for msg in &trans.messages {
+                    write!(f, "{}", AllowManyArguments(msg.payload.items.len() + 1))?;
                     writeln!(
                         f,
-                        "pub fn send_{}(mut self, {}) -> Result<{}<C>, E> {{",
-                        msg.label,
-                        BorrowedPayload(&msg.payload),
-                        msg.dest_state_name
+                        "pub {} send_{}({}self, {}) -> Result<{}<{}>, {}> {{",
+                        mode.fn_keyword(),
+                        snake_case(&msg.label),
+                        Mut(tag.is_some() || !msg.payload.items.is_empty()),
+                        BorrowedPayload(&msg.payload, cx),
+                        msg.dest_state_name,
+                        chan,
+                        err
                     )?;
 
-                    F::send_message(f, msg, trans.messages.len() > 1)?;
+                    F::send_message(f, msg, tag, cx)?;
+
+                    writeln!(f, "}}")?;
+                }
 
+                if let Some(done) = &done {
+                    writeln!(
+                        f,
+                        "pub fn done(mut self) -> Result<{}<{}>, {}> {{",
+                        done, chan, err
+                    )?;
+                    writeln!(f, "self.0.send_end()?;")?;
+                    writeln!(f, "Ok({}(self.0))", done)?;
                     writeln!(f, "}}")?;
                 }
             }
             writeln!(f, "}}")?;
+        } else if let Some(branch) = &state.branch {
+            let done = BranchDone(branch);
+            writeln!(f, "impl<C: Channel> {}<C> {{", state.name)?;
+            writeln!(f, "pub fn done(self) -> {}<C> {{", done)?;
+            writeln!(f, "{}(self.0)", done)?;
+            writeln!(f, "}}")?;
+            writeln!(f, "}}")?;
         } else {
-            writeln!(f, "impl<C: Channel<Error = E>, E> {}<C> {{", state.name)?;
-            writeln!(f, "pub fn finish(self) {{}}")?;
+            writeln!(f, "impl<C: {}<Error = E>, E> {}<C> {{", channel, state.name)?;
+            // The channel is handed back so buffered sends can be flushed and
+            // their errors seen.
+            writeln!(f, "pub fn finish(self) -> C {{")?;
+            writeln!(f, "self.0")?;
+            writeln!(f, "}}")?;
             writeln!(f, "}}")?;
         }
     }
 
-    writeln!(f, "impl<C: Channel> S0<C> {{")?;
-    writeln!(f, "pub fn new(channel: C) -> S0<C> {{")?;
-    writeln!(f, "S0(channel)")?;
+    let start = &protocol.states[0].name;
+    writeln!(f, "impl<C: {}> {}<C> {{", channel, start)?;
+    writeln!(f, "pub fn new(channel: C) -> {}<C> {{", start)?;
+    writeln!(f, "{}(channel)", start)?;
+    writeln!(f, "}}")?;
+    writeln!(
+        f,
+        "pub {} connect(mut channel: C) -> Result<{}<C>, obbidl_lib::channel::HandshakeError<C::Error>> {{",
+        mode.fn_keyword(),
+        start
+    )?;
+    let handshake = match mode {
+        Mode::Blocking => "handshake",
+        Mode::Async => "handshake_async",
+    };
+    writeln!(
+        f,
+        "obbidl_lib::channel::{}(&mut channel, {}){}?;",
+        handshake,
+        fingerprint,
+        mode.await_()
+    )?;
+    writeln!(f, "Ok({}(channel))", start)?;
     writeln!(f, "}}")?;
     writeln!(f, "}}")?;
 
     Ok(())
 }
 
-fn generate_protocol_file<F: Format>(f: &mut fmt::Formatter<'_>, file: &File) -> fmt::Result {
-    for struct_ in &file.structs {
-        writeln!(f, "pub struct {} {{", struct_.name)?;
-        for (name, ty) in &struct_.fields {
-            writeln!(f, "{}: {},", name, ty)?;
+fn receives(dir: Direction, role: SimpleRole) -> bool {
+    match dir {
+        Direction::AToB => role == SimpleRole::B,
+        Direction::BToA => role == SimpleRole::A,
+    }
+}
+
+// Only channels that are used are taken mutably, so messages without a
+// payload don't generate unused 'mut' warnings.
+struct Mut(bool);
+
+impl fmt::Display for Mut {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.0 {
+            write!(f, "mut ")?;
         }
-        writeln!(f, "}}")?;
+        Ok(())
     }
+}
 
-    for protocol in &file.protocols {
-        writeln!(f, "pub mod {} {{", protocol.name)?;
+// Every payload item is an argument, and there can be any number of them.
+struct AllowManyArguments(usize);
+
+impl fmt::Display for AllowManyArguments {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.0 > 7 {
+            writeln!(f, "#[allow(clippy::too_many_arguments)]")?;
+        }
+        Ok(())
+    }
+}
+
+// The name of the state a branch of a fork ends in.
+struct BranchDone<'a>(&'a Branch);
+
+impl<'a> fmt::Display for BranchDone<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}Done{}", self.0.fork, self.0.index)
+    }
+}
 
-        writeln!(f, "pub mod {} {{", protocol.role_a)?;
-        generate_protocol::<F>(f, protocol, SimpleRole::A)?;
-        writeln!(f, "}}")?;
+fn generate_fork(f: &mut fmt::Formatter<'_>, state: &State, fork: &Fork) -> fmt::Result {
+    for index in 0..fork.branches.len() {
+        writeln!(f, "#[must_use]")?;
+        writeln!(f, "pub struct {}Done{}<C: Channel>(C);", state.name, index)?;
+    }
 
-        writeln!(f, "pub mod {} {{", protocol.role_b)?;
-        generate_protocol::<F>(f, protocol, SimpleRole::B)?;
-        writeln!(f, "}}")?;
+    writeln!(f, "impl<C: SplitChannel> {}<C> {{", state.name)?;
 
-        writeln!(f, "}}")?;
+    writeln!(f, "#[allow(clippy::type_complexity)]")?;
+    write!(f, "pub fn split(self) -> Result<(")?;
+    for branch in &fork.branches {
+        write!(f, "{}<Branch<C>>, ", branch)?;
+    }
+    writeln!(f, "), C::Error> {{")?;
+    writeln!(
+        f,
+        "let mut branches = Branch::split(self.0, {})?.into_iter();",
+        fork.branches.len()
+    )?;
+    write!(f, "Ok((")?;
+    for branch in &fork.branches {
+        write!(f, "{}(branches.next().unwrap()), ", branch)?;
+    }
+    writeln!(f, "))")?;
+    writeln!(f, "}}")?;
+
+    write!(f, "pub fn join(")?;
+    for index in 0..fork.branches.len() {
+        write!(
+            f,
+            "branch{}: {}Done{}<Branch<C>>, ",
+            index, state.name, index
+        )?;
+    }
+    writeln!(f, ") -> {}<C> {{", fork.join)?;
+    write!(f, "{}(Branch::join(vec![", fork.join)?;
+    for index in 0..fork.branches.len() {
+        write!(f, "branch{}.0, ", index)?;
+    }
+    writeln!(f, "]))")?;
+    writeln!(f, "}}")?;
+
+    writeln!(f, "}}")?;
+    Ok(())
+}
+
+fn generate_protocol_file<F: Format>(
+    f: &mut fmt::Formatter<'_>,
+    file: &File,
+    options: &Options,
+) -> fmt::Result {
+    let layout = &options.layout;
+
+    if layout.items != Items::Protocols {
+        let cx = Context {
+            mode: options.mode,
+            structs: layout.structs_prefix(0),
+        };
+        for struct_ in &file.structs {
+            let struct_options = options.struct_options(&struct_.name);
+            if !struct_options.derives.is_empty() {
+                writeln!(f, "#[derive({})]", struct_options.derives.join(", "))?;
+            }
+            writeln!(f, "pub struct {} {{", Camel(&struct_.name))?;
+            for (name, ty) in &struct_.fields {
+                writeln!(
+                    f,
+                    "{}{}: {},",
+                    struct_options.visibility,
+                    Snake(name),
+                    RustType(ty, &cx)
+                )?;
+            }
+            writeln!(f, "}}")?;
+            F::struct_impls(f, struct_, &cx)?;
+        }
+    }
+
+    if layout.items == Items::Structs {
+        return Ok(());
+    }
+    for protocol in &file.protocols {
+        let fingerprint = format!("0x{:016x}", protocol.fingerprint(F::WIRE_FORMAT));
+        let roles = [
+            (&protocol.role_a, SimpleRole::A),
+            (&protocol.role_b, SimpleRole::B),
+        ]
+        .into_iter()
+        .filter(|(name, _)| options.generates_role(&name.0));
+        match layout.nesting {
+            Nesting::Nested => {
+                let cx = Context {
+                    mode: options.mode,
+                    structs: layout.structs_prefix(2),
+                };
+                writeln!(f, "pub mod {} {{", Snake(&protocol.name))?;
+                writeln!(f, "pub const FINGERPRINT: u64 = {};", fingerprint)?;
+                for (name, role) in roles {
+                    writeln!(f, "pub mod {} {{", Snake(&name.0))?;
+                    generate_protocol::<F>(f, protocol, role, &cx, "super::FINGERPRINT")?;
+                    writeln!(f, "}}")?;
+                }
+                writeln!(f, "}}")?;
+            }
+            Nesting::Flat => {
+                let cx = Context {
+                    mode: options.mode,
+                    structs: layout.structs_prefix(1),
+                };
+                for (name, role) in roles {
+                    writeln!(
+                        f,
+                        "pub mod {} {{",
+                        Snake(&flat_module(&protocol.name, name))
+                    )?;
+                    writeln!(f, "pub const FINGERPRINT: u64 = {};", fingerprint)?;
+                    generate_protocol::<F>(f, protocol, role, &cx, "FINGERPRINT")?;
+                    writeln!(f, "}}")?;
+                }
+            }
+        }
     }
 
     Ok(())
 }
 
-pub struct GenerateRust<'a, F: Format>(&'a File, PhantomData<F>);
+pub struct GenerateRust<'a, F: Format>(&'a File, Options, PhantomData<F>);
 
 impl<'a, F: Format> GenerateRust<'a, F> {
     pub fn new(file: &'a File) -> GenerateRust<'a, F> {
-        GenerateRust(file, PhantomData)
+        GenerateRust::with_options(file, Options::default())
+    }
+    pub fn with_options(file: &'a File, options: Options) -> GenerateRust<'a, F> {
+        GenerateRust(file, options, PhantomData)
     }
 }
 
 impl<'a, F: Format> fmt::Display for GenerateRust<'a, F> {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        generate_protocol_file::<F>(f, self.0)
+        generate_protocol_file::<F>(f, self.0, &self.1)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        ast,
+        compile::{compile_protocol_file, Limits},
+        format::binary::Binary,
+        parser::parse,
+        report::Report,
+        validate::validate_protocol_file,
+    };
+
+    use super::{GenerateRust, Items, Layout, Nesting, Options, StructOptions, Visibility};
+
+    fn generate(source: &str, layout: Layout) -> String {
+        generate_with_options(
+            source,
+            Options {
+                layout,
+                ..Options::default()
+            },
+        )
+    }
+
+    fn generate_with_options(source: &str, options: Options) -> String {
+        let file = parse::<ast::File>(source).report();
+        let file_fsm = compile_protocol_file(&file, &Limits::default()).unwrap();
+        let file = validate_protocol_file(&file_fsm, &file.structs).unwrap();
+        GenerateRust::<Binary>::with_options(&file, options).to_string()
+    }
+
+    const SOURCE: &str = "struct P { x: u8 } struct L { a: struct P } \
+        protocol ping(role client, role server) { ping(struct L) from client to server; }";
+
+    #[test]
+    fn test_relative_layout() {
+        let output = generate(SOURCE, Layout::default());
+        assert!(output.contains("pub a: P,"));
+        assert!(output.contains("pub mod ping {"));
+        assert!(output.contains("pub mod client {"));
+        assert!(output.contains("param0: &super::super::L"));
+        assert!(output.contains("pub fn finish(self) -> C {\nself.0\n}"));
+    }
+
+    #[test]
+    fn test_absolute_flat_layout() {
+        let layout = Layout {
+            structs_path: Some("crate::proto".to_string()),
+            nesting: Nesting::Flat,
+            items: Items::Protocols,
+        };
+        let output = generate(SOURCE, layout);
+        assert!(!output.contains("pub struct P {"));
+        assert!(!output.contains("pub mod ping {"));
+        assert!(output.contains("pub mod ping_client {"));
+        assert!(output.contains("param0: &crate::proto::L"));
+        assert!(!output.contains("super::"));
+    }
+
+    #[test]
+    fn test_struct_visibility() {
+        let mut options = Options::default();
+        options.structs.visibility = Visibility::Restricted("super".to_string());
+        options.struct_overrides.insert(
+            "L".to_string(),
+            StructOptions {
+                derives: vec![],
+                visibility: Visibility::Restricted("in crate::points".to_string()),
+            },
+        );
+        let output = generate_with_options(SOURCE, options);
+        assert!(output.contains("pub(super) x: u8,"));
+        assert!(output.contains("pub(in crate::points) a: P,"));
+    }
+
+    #[test]
+    fn test_rust_names() {
+        let source = "struct point_2d { Type: u8 } \
+            protocol Move(role Client, role self) { \
+            choice { PING(type: struct point_2d) from Client to self; } or { get_value from Client to self; } }";
+        let output = generate(source, Layout::default());
+        assert!(output.contains("pub struct Point2d {"));
+        assert!(output.contains("pub r#type: u8,"));
+        assert!(output.contains("pub mod r#move {"));
+        assert!(output.contains("pub mod self_ {"));
+        assert!(output.contains("pub fn send_ping(mut self, r#type: &super::super::Point2d, )"));
+        assert!(output.contains("fn recv_get_value("));
+        assert!(output.contains("StartResponse::Ping {"));
+        assert!(output.contains("StartResponse::GetValue {"));
     }
 }
@@ -17,6 +17,23 @@ impl<'a> fmt::Display for GraphViz<'a> {
                     trans.start.0, trans.end.0, trans.msg.inner.label,
                 )?;
             }
+            let state_machine = &protocol.inner.state_machine;
+            for state in state_machine.iter_states() {
+                if let Some(fork) = state_machine.fork(state) {
+                    for (index, branch) in fork.branches.iter().enumerate() {
+                        writeln!(
+                            f,
+                            "  {} -> {}[label=\"branch {}\", style=dashed];",
+                            state.0, branch.0, index
+                        )?;
+                    }
+                    writeln!(
+                        f,
+                        "  {} -> {}[label=\"join\", style=dashed];",
+                        state.0, fork.join.0
+                    )?;
+                }
+            }
             writeln!(f, "}}")?;
         }
         Ok(())
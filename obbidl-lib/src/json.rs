@@ -0,0 +1,527 @@
+use std::{error::Error, fmt, iter::Peekable, str::Chars, str::FromStr};
+
+use crate::{
+    channel::{AsyncChannel, Channel, RecvError},
+    encode::into_array,
+};
+
+// The runtime side of the JSON wire format. Each message is an object on its
+// own line with the label of the message under "label" and, unless the
+// payload is empty, an object under "data" with the payload items under
+// their names, e.g. {"label":"move","data":{"x":1}}.
+
+// The longest line that will be received, so a missing newline can't make
+// the receiver allocate arbitrary amounts of memory.
+pub const MAX_LINE_LENGTH: usize = 1 << 24;
+
+// How deeply arrays and objects may be nested.
+const MAX_DEPTH: usize = 128;
+
+// Only the subset of JSON that payloads need. Numbers are integers.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    Null,
+    Bool(bool),
+    Int(i128),
+    String(String),
+    Array(Vec<Value>),
+    Object(Vec<(String, Value)>),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct JsonError(String);
+
+impl fmt::Display for JsonError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl Error for JsonError {}
+
+impl Value {
+    pub fn field(&self, name: &str) -> Result<&Value, JsonError> {
+        let Value::Object(fields) = self else {
+            return Err(expected("an object", self));
+        };
+        fields
+            .iter()
+            .find(|(key, _)| key == name)
+            .map(|(_, value)| value)
+            .ok_or_else(|| JsonError(format!("missing field '{}'", name)))
+    }
+
+    pub fn get<T: FromJson>(&self, name: &str) -> Result<T, JsonError> {
+        T::from_json(self.field(name)?).map_err(|err| JsonError(format!("'{}': {}", name, err)))
+    }
+
+    pub fn label(&self) -> Result<&str, JsonError> {
+        match self.field("label")? {
+            Value::String(label) => Ok(label),
+            value => Err(expected("a string", value)),
+        }
+    }
+
+    fn kind(&self) -> &'static str {
+        match self {
+            Value::Null => "null",
+            Value::Bool(_) => "a bool",
+            Value::Int(_) => "an integer",
+            Value::String(_) => "a string",
+            Value::Array(_) => "an array",
+            Value::Object(_) => "an object",
+        }
+    }
+}
+
+fn expected(kind: &str, value: &Value) -> JsonError {
+    JsonError(format!("expected {}, found {}", kind, value.kind()))
+}
+
+// Written without any whitespace, so a value always fits on one line.
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Value::Null => write!(f, "null"),
+            Value::Bool(value) => write!(f, "{}", value),
+            Value::Int(value) => write!(f, "{}", value),
+            Value::String(value) => write_string(f, value),
+            Value::Array(items) => {
+                write!(f, "[")?;
+                for (index, item) in items.iter().enumerate() {
+                    if index > 0 {
+                        write!(f, ",")?;
+                    }
+                    write!(f, "{}", item)?;
+                }
+                write!(f, "]")
+            }
+            Value::Object(fields) => {
+                write!(f, "{{")?;
+                for (index, (key, value)) in fields.iter().enumerate() {
+                    if index > 0 {
+                        write!(f, ",")?;
+                    }
+                    write_string(f, key)?;
+                    write!(f, ":{}", value)?;
+                }
+                write!(f, "}}")
+            }
+        }
+    }
+}
+
+fn write_string(f: &mut fmt::Formatter<'_>, string: &str) -> fmt::Result {
+    write!(f, "\"")?;
+    for c in string.chars() {
+        match c {
+            '"' => write!(f, "\\\"")?,
+            '\\' => write!(f, "\\\\")?,
+            '\n' => write!(f, "\\n")?,
+            '\r' => write!(f, "\\r")?,
+            '\t' => write!(f, "\\t")?,
+            c if c.is_control() => write!(f, "\\u{:04x}", c as u32)?,
+            c => write!(f, "{}", c)?,
+        }
+    }
+    write!(f, "\"")
+}
+
+impl FromStr for Value {
+    type Err = JsonError;
+
+    fn from_str(source: &str) -> Result<Value, JsonError> {
+        let mut parser = Parser {
+            chars: source.chars().peekable(),
+            depth: 0,
+        };
+        let value = parser.value()?;
+        parser.whitespace();
+        match parser.chars.next() {
+            Some(c) => Err(unexpected(Some(c))),
+            None => Ok(value),
+        }
+    }
+}
+
+struct Parser<'a> {
+    chars: Peekable<Chars<'a>>,
+    depth: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn whitespace(&mut self) {
+        while let Some(' ' | '\t' | '\n' | '\r') = self.chars.peek() {
+            self.chars.next();
+        }
+    }
+
+    fn expect(&mut self, expected: char) -> Result<(), JsonError> {
+        self.whitespace();
+        match self.chars.next() {
+            Some(c) if c == expected => Ok(()),
+            c => Err(unexpected(c)),
+        }
+    }
+
+    fn value(&mut self) -> Result<Value, JsonError> {
+        self.whitespace();
+        match self.chars.peek().copied() {
+            Some('n') => self.keyword("null", Value::Null),
+            Some('t') => self.keyword("true", Value::Bool(true)),
+            Some('f') => self.keyword("false", Value::Bool(false)),
+            Some('"') => Ok(Value::String(self.string()?)),
+            Some('[') => self.nested(']', |parser| parser.value()).map(Value::Array),
+            Some('{') => self
+                .nested('}', |parser| {
+                    parser.whitespace();
+                    let key = parser.string()?;
+                    parser.expect(':')?;
+                    Ok((key, parser.value()?))
+                })
+                .map(Value::Object),
+            Some(c) if c == '-' || c.is_ascii_digit() => self.int(),
+            c => Err(unexpected(c)),
+        }
+    }
+
+    fn keyword(&mut self, keyword: &str, value: Value) -> Result<Value, JsonError> {
+        for expected in keyword.chars() {
+            match self.chars.next() {
+                Some(c) if c == expected => (),
+                c => return Err(unexpected(c)),
+            }
+        }
+        Ok(value)
+    }
+
+    // The items of an array or object, after the opening bracket.
+    fn nested<T>(
+        &mut self,
+        close: char,
+        mut item: impl FnMut(&mut Self) -> Result<T, JsonError>,
+    ) -> Result<Vec<T>, JsonError> {
+        self.chars.next();
+        self.depth += 1;
+        if self.depth > MAX_DEPTH {
+            return Err(JsonError(format!("nested more than {} deep", MAX_DEPTH)));
+        }
+        let mut items = vec![];
+        self.whitespace();
+        if self.chars.peek() == Some(&close) {
+            self.chars.next();
+        } else {
+            loop {
+                items.push(item(self)?);
+                self.whitespace();
+                match self.chars.next() {
+                    Some(',') => (),
+                    Some(c) if c == close => break,
+                    c => return Err(unexpected(c)),
+                }
+            }
+        }
+        self.depth -= 1;
+        Ok(items)
+    }
+
+    fn int(&mut self) -> Result<Value, JsonError> {
+        let mut digits = String::new();
+        if self.chars.peek() == Some(&'-') {
+            digits.push('-');
+            self.chars.next();
+        }
+        while let Some(c) = self.chars.peek().filter(|c| c.is_ascii_digit()) {
+            digits.push(*c);
+            self.chars.next();
+        }
+        if let Some('.' | 'e' | 'E') = self.chars.peek() {
+            return Err(JsonError("only integers are supported".to_string()));
+        }
+        digits
+            .parse()
+            .map(Value::Int)
+            .map_err(|_| JsonError(format!("invalid integer '{}'", digits)))
+    }
+
+    fn string(&mut self) -> Result<String, JsonError> {
+        match self.chars.next() {
+            Some('"') => (),
+            c => return Err(unexpected(c)),
+        }
+        let mut string = String::new();
+        loop {
+            match self.chars.next() {
+                Some('"') => return Ok(string),
+                Some('\\') => string.push(self.escape()?),
+                Some(c) if (c as u32) < 0x20 => return Err(unexpected(Some(c))),
+                Some(c) => string.push(c),
+                None => return Err(JsonError("unterminated string".to_string())),
+            }
+        }
+    }
+
+    fn escape(&mut self) -> Result<char, JsonError> {
+        Ok(match self.chars.next() {
+            Some('"') => '"',
+            Some('\\') => '\\',
+            Some('/') => '/',
+            Some('b') => '\u{8}',
+            Some('f') => '\u{c}',
+            Some('n') => '\n',
+            Some('r') => '\r',
+            Some('t') => '\t',
+            Some('u') => {
+                let mut code = self.hex()?;
+                // Characters outside the basic plane are escaped as a pair of
+                // surrogates.
+                if (0xd800..0xdc00).contains(&code) {
+                    let next = (self.chars.next(), self.chars.next());
+                    let low = self.hex()?;
+                    if next != (Some('\\'), Some('u')) || !(0xdc00..0xe000).contains(&low) {
+                        return Err(JsonError("invalid surrogate pair".to_string()));
+                    }
+                    code = 0x10000 + ((code - 0xd800) << 10) + (low - 0xdc00);
+                }
+                char::from_u32(code)
+                    .ok_or_else(|| JsonError(format!("invalid character {:04x}", code)))?
+            }
+            c => return Err(unexpected(c)),
+        })
+    }
+
+    fn hex(&mut self) -> Result<u32, JsonError> {
+        let mut code = 0;
+        for _ in 0..4 {
+            let c = self.chars.next();
+            let digit = c
+                .and_then(|c| c.to_digit(16))
+                .ok_or_else(|| unexpected(c))?;
+            code = code * 16 + digit;
+        }
+        Ok(code)
+    }
+}
+
+fn unexpected(c: Option<char>) -> JsonError {
+    match c {
+        Some(c) => JsonError(format!("unexpected '{}'", c.escape_default())),
+        None => JsonError("unexpected end of input".to_string()),
+    }
+}
+
+// Generated code implements these for each struct, as objects with a field
+// for each of the struct's fields.
+pub trait ToJson {
+    fn to_json(&self) -> Value;
+}
+
+pub trait FromJson: Sized {
+    fn from_json(value: &Value) -> Result<Self, JsonError>;
+}
+
+impl ToJson for bool {
+    fn to_json(&self) -> Value {
+        Value::Bool(*self)
+    }
+}
+
+impl FromJson for bool {
+    fn from_json(value: &Value) -> Result<Self, JsonError> {
+        match value {
+            Value::Bool(value) => Ok(*value),
+            value => Err(expected("a bool", value)),
+        }
+    }
+}
+
+macro_rules! impl_int {
+    ($($ty:ty),*) => {
+        $(
+            impl ToJson for $ty {
+                fn to_json(&self) -> Value {
+                    Value::Int(*self as i128)
+                }
+            }
+
+            impl FromJson for $ty {
+                fn from_json(value: &Value) -> Result<Self, JsonError> {
+                    match value {
+                        Value::Int(value) => <$ty>::try_from(*value).map_err(|_| {
+                            JsonError(format!("{} is out of range for {}", value, stringify!($ty)))
+                        }),
+                        value => Err(expected("an integer", value)),
+                    }
+                }
+            }
+        )*
+    };
+}
+
+impl_int!(u8, u16, u32, u64, i8, i16, i32, i64);
+
+impl<T: ToJson> ToJson for [T] {
+    fn to_json(&self) -> Value {
+        Value::Array(self.iter().map(T::to_json).collect())
+    }
+}
+
+impl<T: ToJson> ToJson for Vec<T> {
+    fn to_json(&self) -> Value {
+        self.as_slice().to_json()
+    }
+}
+
+impl<T: FromJson> FromJson for Vec<T> {
+    fn from_json(value: &Value) -> Result<Self, JsonError> {
+        match value {
+            Value::Array(items) => items.iter().map(T::from_json).collect(),
+            value => Err(expected("an array", value)),
+        }
+    }
+}
+
+impl<T: ToJson, const N: usize> ToJson for [T; N] {
+    fn to_json(&self) -> Value {
+        self.as_slice().to_json()
+    }
+}
+
+impl<T: FromJson, const N: usize> FromJson for [T; N] {
+    fn from_json(value: &Value) -> Result<Self, JsonError> {
+        let items = Vec::<T>::from_json(value)?;
+        if items.len() != N {
+            return Err(JsonError(format!(
+                "expected {} items, found {}",
+                N,
+                items.len()
+            )));
+        }
+        Ok(into_array(items))
+    }
+}
+
+pub fn send<C: Channel>(channel: &mut C, value: &Value) -> Result<(), C::Error> {
+    channel.send(format!("{}\n", value).as_bytes())
+}
+
+pub async fn send_async<C: AsyncChannel>(channel: &mut C, value: &Value) -> Result<(), C::Error> {
+    channel.send(format!("{}\n", value).as_bytes()).await
+}
+
+pub fn recv<C: Channel>(channel: &mut C) -> Result<Value, RecvError<C::Error>> {
+    let mut line = vec![];
+    loop {
+        let byte = channel.recv_u8()?;
+        if byte == b'\n' {
+            return parse_line(line);
+        }
+        push_byte(&mut line, byte)?;
+    }
+}
+
+pub async fn recv_async<C: AsyncChannel>(channel: &mut C) -> Result<Value, RecvError<C::Error>> {
+    let mut line = vec![];
+    loop {
+        let byte = channel.recv_u8().await?;
+        if byte == b'\n' {
+            return parse_line(line);
+        }
+        push_byte(&mut line, byte)?;
+    }
+}
+
+fn push_byte<E>(line: &mut Vec<u8>, byte: u8) -> Result<(), RecvError<E>> {
+    if line.len() == MAX_LINE_LENGTH {
+        return Err(RecvError::Malformed(format!(
+            "line is longer than {} bytes",
+            MAX_LINE_LENGTH
+        )));
+    }
+    line.push(byte);
+    Ok(())
+}
+
+fn parse_line<E>(line: Vec<u8>) -> Result<Value, RecvError<E>> {
+    let line = String::from_utf8(line).map_err(RecvError::malformed)?;
+    line.parse().map_err(RecvError::malformed)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::channel::{Channel, RecvError, TestChannel};
+
+    use super::{recv, send, FromJson, ToJson, Value};
+
+    #[test]
+    fn test_print_parse() {
+        let value = Value::Object(vec![
+            (
+                "label".to_string(),
+                Value::String("a \"b\"\n\u{1}é𝄞".to_string()),
+            ),
+            (
+                "items".to_string(),
+                Value::Array(vec![Value::Int(-1), Value::Bool(true), Value::Null]),
+            ),
+            ("empty".to_string(), Value::Object(vec![])),
+        ]);
+        let line = value.to_string();
+        assert!(!line.contains('\n'));
+        assert_eq!(line.parse::<Value>().unwrap(), value);
+        assert_eq!(
+            " { \"a\" : [ 1 , 2 ] , \"b\":\"\\ud834\\udd1e\\/\" } "
+                .parse::<Value>()
+                .unwrap(),
+            Value::Object(vec![
+                (
+                    "a".to_string(),
+                    Value::Array(vec![Value::Int(1), Value::Int(2)])
+                ),
+                ("b".to_string(), Value::String("𝄞/".to_string())),
+            ])
+        );
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        for source in [
+            "",
+            "[1,]",
+            "{\"a\" 1}",
+            "1.5",
+            "\"abc",
+            "tru",
+            "[1] 2",
+            "\"\\x\"",
+        ] {
+            assert!(source.parse::<Value>().is_err(), "{}", source);
+        }
+        assert!("[".repeat(1000).parse::<Value>().is_err());
+    }
+
+    #[test]
+    fn test_convert() {
+        let value = vec![[1u16, 2], [3, 4]];
+        assert_eq!(Vec::<[u16; 2]>::from_json(&value.to_json()).unwrap(), value);
+        assert_eq!(i8::from_json(&(-128i64).to_json()).unwrap(), -128);
+        assert!(u8::from_json(&Value::Int(256)).is_err());
+        assert!(<[bool; 2]>::from_json(&[true].to_json()).is_err());
+        assert!(bool::from_json(&Value::Int(1)).is_err());
+        let object = Value::Object(vec![("x".to_string(), Value::Int(3))]);
+        assert_eq!(object.get::<u32>("x").unwrap(), 3);
+        assert!(object.get::<u32>("y").is_err());
+    }
+
+    #[test]
+    fn test_lines() {
+        let (mut a, mut b) = TestChannel::new();
+        let value = Value::Array(vec![Value::String("\n".to_string())]);
+        send(&mut a, &value).unwrap();
+        send(&mut a, &Value::Null).unwrap();
+        a.send(b"{\n").unwrap();
+        assert_eq!(recv(&mut b).unwrap(), value);
+        assert_eq!(recv(&mut b).unwrap(), Value::Null);
+        assert!(matches!(recv(&mut b), Err(RecvError::Malformed(_))));
+    }
+}
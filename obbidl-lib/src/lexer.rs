@@ -2,6 +2,7 @@ use strum::IntoEnumIterator;
 
 use crate::token::{Keyword, Symbol, Token, TokenType};
 
+#[derive(Clone)]
 pub struct Lexer<'a> {
     source: &'a str,
     pos: Position,
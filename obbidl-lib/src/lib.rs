@@ -1,85 +1,93 @@
 use std::{
-    env, fs,
+    env,
     io::{Read, Write},
     path::Path,
     process::{Command, Stdio},
 };
 
-use ast::File;
-use compile::compile_protocol_file;
-use parser::parse;
-
-use crate::{format::binary::Binary, generate::GenerateRust, validate::validate_protocol_file};
+use crate::{compiler::Compiler, generate::Options, lint::LintConfig};
 
 pub mod ast;
+pub mod cbor;
 pub mod channel;
+pub mod compact;
 pub mod compile;
+pub mod compiler;
+pub mod encode;
+mod fingerprint;
 pub mod format;
 pub mod generate;
 mod graph;
+pub mod json;
 mod lexer;
+pub mod lint;
+pub mod naming;
 pub mod parser;
+pub mod protobuf;
+#[cfg(test)]
 mod report;
+mod residual;
 mod state_machine;
+#[cfg(any(test, feature = "test-support"))]
+pub mod test_support;
 mod token;
+pub mod transcript;
 pub mod validate;
 
 pub fn build1(source: &str) -> String {
-    let file = match parse::<File>(source) {
-        Ok(ast) => ast,
-        Err(err) => {
-            print!("{}", err);
-            panic!()
-        }
-    };
-    let file_fsm = compile_protocol_file(&file);
-    let file = match validate_protocol_file(&file_fsm, &file.structs) {
-        Ok(file) => file,
-        Err(errors) => {
-            for err in errors {
-                println!("{}", err.pretty_print(&source));
-            }
-            panic!()
-        }
-    };
-    format_rust(&GenerateRust::<Binary>::new(&file).to_string())
+    build1_with_options(source, Options::default())
+}
+
+pub fn build1_with_options(source: &str, options: Options) -> String {
+    match Compiler::new()
+        .options(options)
+        .rustfmt(true)
+        .compile_source(source)
+    {
+        // Warnings are dropped, 'Compiler' returns them with the code.
+        Ok(mut output) => output.files.remove(0).code,
+        Err(diagnostics) => panic!("{}", diagnostics),
+    }
 }
 
 pub fn build(path: impl AsRef<Path>) {
+    build_with_lints(path, &LintConfig::default())
+}
+
+pub fn build_with_lints(path: impl AsRef<Path>, lints: &LintConfig) {
+    build_with_options(path, lints, Options::default())
+}
+
+pub fn build_with_options(path: impl AsRef<Path>, lints: &LintConfig, options: Options) {
     let path = path.as_ref();
     println!("cargo:rerun-if-changed={}", path.display());
 
-    let source = fs::read_to_string(path).unwrap();
-    let file = match parse::<File>(&source) {
-        Ok(ast) => ast,
-        Err(err) => {
-            print!("{}", err);
-            panic!()
-        }
+    let out_dir = env::var("OUT_DIR").unwrap();
+    let result = Compiler::new()
+        .input(path)
+        .out_dir(out_dir)
+        .lints(lints.clone())
+        .options(options)
+        .compile();
+    // Cargo only shows the output of a build script that fails, except for
+    // warnings.
+    let diagnostics = match &result {
+        Ok(output) => &output.warnings,
+        Err(diagnostics) => &diagnostics.0,
     };
-    let file_fsm = compile_protocol_file(&file);
-    let file = match validate_protocol_file(&file_fsm, &file.structs) {
-        Ok(file) => file,
-        Err(errors) => {
-            for err in errors {
-                println!("{}", err.pretty_print(&source));
-            }
-            panic!()
+    for diagnostic in diagnostics {
+        for line in diagnostic.to_string().lines() {
+            println!("cargo:warning={}", line);
         }
-    };
-    let output = GenerateRust::<Binary>::new(&file).to_string();
-    let file_name = path.file_name().unwrap();
-
-    let out_dir = env::var("OUT_DIR").unwrap();
-    fs::write(
-        Path::new(&out_dir).join(file_name).with_extension("rs"),
-        output,
-    )
-    .unwrap();
+    }
+    if result.is_err() {
+        panic!("cannot compile '{}'", path.display())
+    }
 }
 
 pub fn format_rust(string: &str) -> String {
     let child = Command::new("rustfmt")
+        .args(["--edition", "2021"])
         .stdin(Stdio::piped())
         .stdout(Stdio::piped())
         .spawn()
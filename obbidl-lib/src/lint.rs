@@ -0,0 +1,624 @@
+use std::{
+    collections::{HashMap, HashSet},
+    fmt,
+};
+
+use colored::Colorize;
+use strum::{EnumIter, IntoEnumIterator};
+
+use crate::{
+    ast::{self, Sequence, Stmt},
+    compile::{seq_may_terminate, ProtocolFileStateMachines, ProtocolStateMachine},
+    parser::Span,
+    state_machine::{State, StateName},
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, EnumIter)]
+pub enum Lint {
+    UnusedStruct,
+    UnreachableCode,
+    EmptyProtocol,
+    EmptyFin,
+    UnreachableStates,
+    CannotFinish,
+    MixedIds,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Block {
+    Inf,
+    Choice,
+    Par,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Level {
+    Allow,
+    Warn,
+    Deny,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct LintConfig {
+    levels: HashMap<Lint, Level>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Warning<'a> {
+    pub level: Level,
+    pub kind: WarningKind<'a>,
+}
+
+#[derive(Debug, Clone)]
+pub enum WarningKind<'a> {
+    UnusedStruct(&'a Span<ast::Struct>),
+    UnreachableCode {
+        protocol: &'a Span<ast::Protocol>,
+        msg: Option<&'a Span<ast::Message>>,
+        // The statement before it which never ends.
+        after: Block,
+    },
+    EmptyProtocol(&'a Span<ast::Protocol>),
+    EmptyFin {
+        protocol: &'a Span<ast::Protocol>,
+        msg: Option<&'a Span<ast::Message>>,
+    },
+    UnreachableStates {
+        protocol: &'a Span<ProtocolStateMachine>,
+        states: Vec<StateName>,
+    },
+    CannotFinish {
+        protocol: &'a Span<ProtocolStateMachine>,
+        states: Vec<StateName>,
+    },
+    // The messages without an explicit id in a state where others have one.
+    MixedIds {
+        protocol: &'a Span<ProtocolStateMachine>,
+        msgs: Vec<&'a Span<ast::Message>>,
+    },
+}
+
+pub struct PrettyPrintWarning<'a> {
+    warning: &'a Warning<'a>,
+    source: &'a str,
+}
+
+impl Lint {
+    pub fn as_str(&self) -> &str {
+        match self {
+            Lint::UnusedStruct => "unused_struct",
+            Lint::UnreachableCode => "unreachable_code",
+            Lint::EmptyProtocol => "empty_protocol",
+            Lint::EmptyFin => "empty_fin",
+            Lint::UnreachableStates => "unreachable_states",
+            Lint::CannotFinish => "cannot_finish",
+            Lint::MixedIds => "mixed_ids",
+        }
+    }
+    pub fn from_name(name: &str) -> Option<Lint> {
+        Lint::iter().find(|lint| lint.as_str() == name)
+    }
+}
+
+impl LintConfig {
+    pub fn set(&mut self, lint: Lint, level: Level) {
+        self.levels.insert(lint, level);
+    }
+    pub fn level(&self, lint: Lint) -> Level {
+        self.levels.get(&lint).copied().unwrap_or(Level::Warn)
+    }
+}
+
+impl<'a> WarningKind<'a> {
+    pub fn lint(&self) -> Lint {
+        match self {
+            WarningKind::UnusedStruct(_) => Lint::UnusedStruct,
+            WarningKind::UnreachableCode { .. } => Lint::UnreachableCode,
+            WarningKind::EmptyProtocol(_) => Lint::EmptyProtocol,
+            WarningKind::EmptyFin { .. } => Lint::EmptyFin,
+            WarningKind::UnreachableStates { .. } => Lint::UnreachableStates,
+            WarningKind::CannotFinish { .. } => Lint::CannotFinish,
+            WarningKind::MixedIds { .. } => Lint::MixedIds,
+        }
+    }
+}
+
+impl<'a> Warning<'a> {
+    pub fn pretty_print(&'a self, source: &'a str) -> PrettyPrintWarning<'a> {
+        PrettyPrintWarning {
+            warning: self,
+            source,
+        }
+    }
+}
+
+pub fn lint_protocol_file<'a>(
+    file: &'a ast::File,
+    file_fsm: &'a ProtocolFileStateMachines,
+    config: &LintConfig,
+) -> Vec<Warning<'a>> {
+    let mut kinds = vec![];
+
+    let mut used_structs = HashSet::new();
+    for protocol in &file.protocols {
+        if protocol.inner.seq.first_message().is_none() {
+            kinds.push(WarningKind::EmptyProtocol(protocol));
+        }
+        lint_sequence(protocol, &protocol.inner.seq, &mut kinds);
+        for msg in protocol.inner.seq.messages() {
+            for item in &msg.inner.payload.items {
+                mark_used_structs(&item.inner.ty, &file.structs, &mut used_structs);
+            }
+        }
+    }
+    for struct_ in &file.structs {
+        if !used_structs.contains(struct_.inner.name.as_str()) {
+            kinds.push(WarningKind::UnusedStruct(struct_));
+        }
+    }
+
+    for protocol in &file_fsm.protocols {
+        let names = protocol.inner.state_machine.state_names();
+        let named = |states: Vec<State>| -> Vec<StateName> {
+            states
+                .into_iter()
+                .map(|state| names[state.0 as usize].clone())
+                .collect()
+        };
+        let finishing = finishing_states(&protocol.inner);
+        let states = unreachable_states(&protocol.inner, &finishing);
+        if !states.is_empty() {
+            kinds.push(WarningKind::UnreachableStates {
+                protocol,
+                states: named(states),
+            });
+        }
+        let states = states_that_cannot_finish(&protocol.inner, &finishing);
+        if !states.is_empty() {
+            kinds.push(WarningKind::CannotFinish {
+                protocol,
+                states: named(states),
+            });
+        }
+        // Implicit ids are numbered in label order, so adding a message can
+        // move them, which matters most where the other ids were fixed on
+        // purpose. The same messages can be sent from more than one state,
+        // but are only reported once.
+        let state_machine = &protocol.inner.state_machine;
+        let mut reported = HashSet::new();
+        for state in state_machine.iter_states() {
+            let (explicit, implicit): (Vec<_>, Vec<_>) = state_machine
+                .iter_trans_from(state)
+                .map(|(msg, _)| msg)
+                .partition(|msg| msg.inner.id.is_some());
+            if explicit.is_empty() || implicit.is_empty() {
+                continue;
+            }
+            let mut offsets: Vec<_> = implicit.iter().map(|msg| msg.span.start.offset).collect();
+            offsets.sort();
+            if reported.insert(offsets) {
+                kinds.push(WarningKind::MixedIds {
+                    protocol,
+                    msgs: implicit,
+                });
+            }
+        }
+    }
+
+    kinds
+        .into_iter()
+        .map(|kind| Warning {
+            level: config.level(kind.lint()),
+            kind,
+        })
+        .filter(|warning| warning.level != Level::Allow)
+        .collect()
+}
+
+fn lint_sequence<'a>(
+    protocol: &'a Span<ast::Protocol>,
+    seq: &'a Sequence,
+    kinds: &mut Vec<WarningKind<'a>>,
+) {
+    for (index, stmt) in seq.0.iter().enumerate() {
+        if !stmt_may_finish(stmt) {
+            if let Some(next) = seq.0.get(index + 1) {
+                let after = match stmt {
+                    Stmt::Choice(_) => Block::Choice,
+                    Stmt::Par(_) => Block::Par,
+                    _ => Block::Inf,
+                };
+                kinds.push(WarningKind::UnreachableCode {
+                    protocol,
+                    msg: next.first_message(),
+                    after,
+                });
+            }
+        }
+        match stmt {
+            Stmt::Message(_) | Stmt::State(_) => {}
+            Stmt::Par(seqs) | Stmt::Choice(seqs) => {
+                for seq in &seqs.0 {
+                    lint_sequence(protocol, seq, kinds);
+                }
+            }
+            Stmt::Fin(body) => {
+                if seq_may_terminate(body) {
+                    kinds.push(WarningKind::EmptyFin {
+                        protocol,
+                        msg: body.first_message(),
+                    });
+                }
+                lint_sequence(protocol, body, kinds);
+            }
+            Stmt::Inf(body) => lint_sequence(protocol, body, kinds),
+        }
+    }
+}
+
+fn stmt_may_finish(stmt: &Stmt) -> bool {
+    match stmt {
+        Stmt::Message(_) | Stmt::Fin(_) | Stmt::State(_) => true,
+        Stmt::Choice(seqs) => seqs.0.iter().any(|seq| seq.0.iter().all(stmt_may_finish)),
+        Stmt::Par(seqs) => seqs.0.iter().all(|seq| seq.0.iter().all(stmt_may_finish)),
+        Stmt::Inf(_) => false,
+    }
+}
+
+fn mark_used_structs<'a>(
+    ty: &'a ast::Type,
+    structs: &'a [Span<ast::Struct>],
+    used_structs: &mut HashSet<&'a str>,
+) {
+    match ty {
+        ast::Type::Bool | ast::Type::Int(_) => {}
+        ast::Type::Array(ty, _) => mark_used_structs(ty, structs, used_structs),
+        ast::Type::Struct(name) => {
+            if !used_structs.insert(name) {
+                return;
+            }
+            for struct_ in structs.iter().filter(|struct_| &struct_.inner.name == name) {
+                for field in &struct_.inner.fields {
+                    mark_used_structs(&field.inner.ty, structs, used_structs);
+                }
+            }
+        }
+    }
+}
+
+// States from which the protocol, or the branch they are in, can end.
+fn finishing_states(protocol: &ProtocolStateMachine) -> HashSet<State> {
+    let state_machine = &protocol.state_machine;
+    let mut finishing: HashSet<State> = state_machine
+        .iter_states()
+        .filter(|state| state_machine.is_final(*state))
+        .collect();
+    let mut changed = true;
+    while changed {
+        changed = false;
+        for state in state_machine.iter_states() {
+            if finishing.contains(&state) {
+                continue;
+            }
+            let by_transition = state_machine
+                .iter_trans_from(state)
+                .any(|(_, end)| finishing.contains(&end));
+            // A fork ends once all of its branches and then the join have.
+            let by_fork = state_machine.fork(state).is_some_and(|fork| {
+                fork.branches
+                    .iter()
+                    .all(|branch| finishing.contains(branch))
+                    && finishing.contains(&fork.join)
+            });
+            if by_transition || by_fork {
+                finishing.insert(state);
+                changed = true;
+            }
+        }
+    }
+    finishing
+}
+
+// States the protocol can never get to. Every state is connected to the
+// start, but the join of a fork is only reached once all of its branches
+// have ended.
+fn unreachable_states(protocol: &ProtocolStateMachine, finishing: &HashSet<State>) -> Vec<State> {
+    let state_machine = &protocol.state_machine;
+    let mut reachable = HashSet::new();
+    let mut queue = vec![];
+    if state_machine.contains_state(State(0)) {
+        reachable.insert(State(0));
+        queue.push(State(0));
+    }
+    while let Some(state) = queue.pop() {
+        let mut ends: Vec<State> = state_machine
+            .iter_trans_from(state)
+            .map(|(_, end)| end)
+            .collect();
+        if let Some(fork) = state_machine.fork(state) {
+            ends.extend(&fork.branches);
+            if fork
+                .branches
+                .iter()
+                .all(|branch| finishing.contains(branch))
+            {
+                ends.push(fork.join);
+            }
+        }
+        for end in ends {
+            if reachable.insert(end) {
+                queue.push(end);
+            }
+        }
+    }
+    state_machine
+        .iter_states()
+        .filter(|state| !reachable.contains(state))
+        .collect()
+}
+
+// States from which the protocol, or the branch they are in, can never end,
+// in protocols that can end from the start. Protocols that never end are
+// left alone, since that is what 'inf' is for.
+fn states_that_cannot_finish(
+    protocol: &ProtocolStateMachine,
+    finishing: &HashSet<State>,
+) -> Vec<State> {
+    let state_machine = &protocol.state_machine;
+    if !finishing.contains(&State(0)) {
+        return vec![];
+    }
+    state_machine
+        .iter_states()
+        .filter(|state| !finishing.contains(state))
+        .collect()
+}
+
+impl<'a> fmt::Display for PrettyPrintWarning<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let kind = &self.warning.kind;
+        match self.warning.level {
+            Level::Deny => write!(f, "{}", "error".red())?,
+            Level::Warn | Level::Allow => write!(f, "{}", "warning".yellow())?,
+        }
+        write!(f, "[{}]: ", kind.lint().as_str())?;
+        match kind {
+            WarningKind::UnusedStruct(struct_) => {
+                writeln!(f, "struct '{}' is never used", struct_.inner.name)?;
+                write!(f, "{}", struct_.pretty_print(self.source))?;
+                writeln!(
+                    f,
+                    "info: no message payload refers to this struct, directly or through another struct"
+                )?;
+            }
+            WarningKind::UnreachableCode {
+                protocol,
+                msg,
+                after,
+            } => {
+                writeln!(f, "unreachable code in protocol '{}'", protocol.inner.name)?;
+                match msg {
+                    Some(msg) => write!(f, "{}", msg.pretty_print(self.source))?,
+                    None => write!(f, "{}", protocol.pretty_print(self.source))?,
+                }
+                let after = match after {
+                    Block::Inf => "an 'inf' block which never terminates",
+                    Block::Choice => "a 'choice' block none of whose branches terminate",
+                    Block::Par => "a 'par' block with a branch that never terminates",
+                };
+                writeln!(f, "info: this follows {}, so it can never happen", after)?;
+            }
+            WarningKind::EmptyProtocol(protocol) => {
+                writeln!(f, "protocol '{}' has no messages", protocol.inner.name)?;
+                write!(f, "{}", protocol.pretty_print(self.source))?;
+            }
+            WarningKind::EmptyFin { protocol, msg } => {
+                writeln!(
+                    f,
+                    "'fin' block with a body that may be empty in protocol '{}'",
+                    protocol.inner.name
+                )?;
+                match msg {
+                    Some(msg) => write!(f, "{}", msg.pretty_print(self.source))?,
+                    None => write!(f, "{}", protocol.pretty_print(self.source))?,
+                }
+                writeln!(
+                    f,
+                    "info: the loop can repeat without exchanging any message"
+                )?;
+            }
+            WarningKind::UnreachableStates { protocol, states } => {
+                let states: Vec<_> = states.iter().map(|state| state.to_string()).collect();
+                writeln!(
+                    f,
+                    "protocol '{}' never gets to {}",
+                    protocol.inner.name,
+                    states.join(", ")
+                )?;
+                write!(f, "{}", protocol.pretty_print(self.source))?;
+                writeln!(
+                    f,
+                    "info: these states follow a 'par' block with a branch that never ends"
+                )?;
+            }
+            WarningKind::CannotFinish { protocol, states } => {
+                let states: Vec<_> = states.iter().map(|state| state.to_string()).collect();
+                writeln!(
+                    f,
+                    "protocol '{}' can't finish from {}",
+                    protocol.inner.name,
+                    states.join(", ")
+                )?;
+                write!(f, "{}", protocol.pretty_print(self.source))?;
+                writeln!(
+                    f,
+                    "info: the protocol can finish, but not once it is in one of these states, e.g. inside an 'inf' block"
+                )?;
+            }
+            WarningKind::MixedIds { protocol, msgs } => {
+                writeln!(
+                    f,
+                    "messages without an id next to messages with one in protocol '{}'",
+                    protocol.inner.name
+                )?;
+                for msg in msgs {
+                    write!(f, "{}", msg.pretty_print(self.source))?;
+                }
+                writeln!(
+                    f,
+                    "info: implicit ids are numbered in the order of the labels, so adding a message can change them, give these messages ids too"
+                )?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        ast::File,
+        compile::{compile_protocol_file, Limits},
+        parser::parse,
+        report::Report,
+    };
+
+    use super::{lint_protocol_file, Block, Level, Lint, LintConfig, WarningKind};
+
+    fn lints(source: &str, config: &LintConfig) -> Vec<(Lint, Level)> {
+        let file = parse::<File>(source).report();
+        let file_fsm = compile_protocol_file(&file, &Limits::default()).unwrap();
+        lint_protocol_file(&file, &file_fsm, config)
+            .iter()
+            .map(|warning| (warning.kind.lint(), warning.level))
+            .collect()
+    }
+
+    #[test]
+    fn test_lint_unused_struct() {
+        let source = "struct A { x: u8 } struct B { a: struct A } struct C { x: u8 }
+            protocol X { M(struct B) from C to S; }";
+        assert_eq!(
+            lints(source, &LintConfig::default()),
+            vec![(Lint::UnusedStruct, Level::Warn)]
+        );
+    }
+
+    #[test]
+    fn test_lint_unreachable_code() {
+        let source = "protocol X { inf { A from C to S; } B from S to C; }";
+        assert_eq!(
+            lints(source, &LintConfig::default()),
+            vec![(Lint::UnreachableCode, Level::Warn)]
+        );
+
+        let sources = [
+            (
+                "protocol X { choice { inf { A from C to S; } } or { inf { B from C to S; } } \
+                    D from S to C; }",
+                Block::Choice,
+                "a 'choice' block",
+            ),
+            (
+                "protocol X { par { inf { A from C to S; } } and { B from S to C; } \
+                    D from S to C; }",
+                Block::Par,
+                "a 'par' block",
+            ),
+        ];
+        for (source, block, info) in sources {
+            let file = parse::<File>(source).report();
+            let file_fsm = compile_protocol_file(&file, &Limits::default()).unwrap();
+            let warnings = lint_protocol_file(&file, &file_fsm, &LintConfig::default());
+            let warning = warnings
+                .iter()
+                .find(|warning| warning.kind.lint() == Lint::UnreachableCode)
+                .unwrap();
+            let WarningKind::UnreachableCode { after, .. } = warning.kind else {
+                unreachable!()
+            };
+            assert_eq!(after, block);
+            assert!(warning.pretty_print(source).to_string().contains(info));
+        }
+    }
+
+    #[test]
+    fn test_lint_empty_protocol() {
+        assert_eq!(
+            lints("protocol X {}", &LintConfig::default()),
+            vec![(Lint::EmptyProtocol, Level::Warn)]
+        );
+    }
+
+    #[test]
+    fn test_lint_empty_fin() {
+        let source = "protocol X { fin { choice { A from C to S; } or { } } }";
+        assert_eq!(
+            lints(source, &LintConfig::default()),
+            vec![(Lint::EmptyFin, Level::Warn)]
+        );
+    }
+
+    #[test]
+    fn test_lint_unreachable_states() {
+        let source = "protocol X {
+            par { inf { A from C to S; } } and { B from S to C; }
+            C from C to S;
+        }";
+        let mut config = LintConfig::default();
+        config.set(Lint::UnreachableCode, Level::Allow);
+        assert_eq!(
+            lints(source, &config),
+            vec![(Lint::UnreachableStates, Level::Warn)]
+        );
+    }
+
+    #[test]
+    fn test_lint_cannot_finish() {
+        let source = "protocol X { choice { inf { A from C to S; } } or { B from C to S; } }";
+        assert_eq!(
+            lints(source, &LintConfig::default()),
+            vec![(Lint::CannotFinish, Level::Warn)]
+        );
+
+        // Protocols that never finish and forks whose branches all finish
+        // are fine.
+        let source = "protocol X { inf { A from C to S; } }
+            protocol Y { par { fin { A from C to S; } } and { B from S to C; } C from C to S; }";
+        assert_eq!(lints(source, &LintConfig::default()), vec![]);
+    }
+
+    #[test]
+    fn test_lint_mixed_ids() {
+        let source = "protocol X { choice { A = 1 from C to S; } or { B from C to S; } \
+            D from S to C; }";
+        assert_eq!(
+            lints(source, &LintConfig::default()),
+            vec![(Lint::MixedIds, Level::Warn)]
+        );
+
+        // 'A' and 'B' can be sent before and after 'X', but are reported
+        // once.
+        let source = "protocol X { choice { X = 2 from C to S; } or { } \
+            choice { A = 1 from C to S; } or { B from C to S; } }";
+        assert_eq!(
+            lints(source, &LintConfig::default()),
+            vec![(Lint::MixedIds, Level::Warn)]
+        );
+
+        let source = "protocol X { choice { A = 1 from C to S; } or { B = 0 from C to S; } }
+            protocol Y { choice { A from C to S; } or { B from C to S; } }";
+        assert_eq!(lints(source, &LintConfig::default()), vec![]);
+    }
+
+    #[test]
+    fn test_lint_levels() {
+        let mut config = LintConfig::default();
+        config.set(Lint::EmptyProtocol, Level::Allow);
+        config.set(Lint::UnusedStruct, Level::Deny);
+        assert_eq!(
+            lints("protocol X {} struct A { x: u8 }", &config),
+            vec![(Lint::UnusedStruct, Level::Deny)]
+        );
+    }
+}
@@ -0,0 +1,134 @@
+use std::{fmt, mem};
+
+// Strict and reserved keywords in every edition, which need to be written as
+// raw identifiers.
+const KEYWORDS: &[&str] = &[
+    "abstract", "as", "async", "await", "become", "box", "break", "const", "continue", "do", "dyn",
+    "else", "enum", "extern", "false", "final", "fn", "for", "gen", "if", "impl", "in", "let",
+    "loop", "macro", "match", "mod", "move", "mut", "override", "priv", "pub", "ref", "return",
+    "static", "struct", "trait", "true", "try", "type", "typeof", "unsafe", "unsized", "use",
+    "virtual", "where", "while", "yield",
+];
+
+// Keywords that can't be raw identifiers.
+const PATH_KEYWORDS: &[&str] = &["crate", "self", "Self", "super"];
+
+/// Splits a name into words at underscores and changes of case, so
+/// 'getValue', 'get_value' and 'GET_VALUE' all have the words 'get' and
+/// 'value', and 'HTTPRequest' has 'HTTP' and 'Request'.
+fn words(name: &str) -> Vec<String> {
+    let chars: Vec<char> = name.chars().collect();
+    let mut words = vec![];
+    let mut word = String::new();
+    for (index, &c) in chars.iter().enumerate() {
+        if !c.is_alphanumeric() {
+            if !word.is_empty() {
+                words.push(mem::take(&mut word));
+            }
+            continue;
+        }
+        if c.is_uppercase() && !word.is_empty() {
+            let after_lower = !chars[index - 1].is_uppercase();
+            let before_lower = chars.get(index + 1).is_some_and(|c| c.is_lowercase());
+            if after_lower || before_lower {
+                words.push(mem::take(&mut word));
+            }
+        }
+        word.push(c);
+    }
+    if !word.is_empty() {
+        words.push(word);
+    }
+    words
+}
+
+pub fn snake_case(name: &str) -> String {
+    let words = words(name);
+    if words.is_empty() {
+        return name.to_string();
+    }
+    words
+        .iter()
+        .map(|word| word.to_lowercase())
+        .collect::<Vec<_>>()
+        .join("_")
+}
+
+pub fn camel_case(name: &str) -> String {
+    let words = words(name);
+    if words.is_empty() {
+        return name.to_string();
+    }
+    words
+        .iter()
+        .flat_map(|word| {
+            let mut chars = word.chars();
+            let first = chars.next().into_iter().flat_map(char::to_uppercase);
+            first.chain(chars.flat_map(char::to_lowercase))
+        })
+        .collect()
+}
+
+/// Makes a name that may be a keyword usable as an identifier.
+pub fn escape(name: String) -> String {
+    if PATH_KEYWORDS.contains(&name.as_str()) {
+        format!("{}_", name)
+    } else if KEYWORDS.contains(&name.as_str()) {
+        format!("r#{}", name)
+    } else {
+        name
+    }
+}
+
+/// The identifier for a module, function, field or variable.
+pub struct Snake<'a>(pub &'a str);
+
+impl<'a> fmt::Display for Snake<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", escape(snake_case(self.0)))
+    }
+}
+
+/// The identifier for a type or enum variant.
+pub struct Camel<'a>(pub &'a str);
+
+impl<'a> fmt::Display for Camel<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", escape(camel_case(self.0)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{camel_case, snake_case, Camel, Snake};
+
+    #[test]
+    fn test_snake_case() {
+        assert_eq!(snake_case("PING"), "ping");
+        assert_eq!(snake_case("getValue"), "get_value");
+        assert_eq!(snake_case("GetValue"), "get_value");
+        assert_eq!(snake_case("GET_VALUE"), "get_value");
+        assert_eq!(snake_case("HTTPRequest"), "http_request");
+        assert_eq!(snake_case("param0"), "param0");
+        assert_eq!(snake_case("a"), "a");
+    }
+
+    #[test]
+    fn test_camel_case() {
+        assert_eq!(camel_case("PING"), "Ping");
+        assert_eq!(camel_case("get_value"), "GetValue");
+        assert_eq!(camel_case("getValue"), "GetValue");
+        assert_eq!(camel_case("HTTPRequest"), "HttpRequest");
+        assert_eq!(camel_case("A0"), "A0");
+    }
+
+    #[test]
+    fn test_keywords() {
+        assert_eq!(Snake("type").to_string(), "r#type");
+        assert_eq!(Snake("MOVE").to_string(), "r#move");
+        assert_eq!(Snake("self").to_string(), "self_");
+        assert_eq!(Camel("self").to_string(), "Self_");
+        assert_eq!(Camel("type").to_string(), "Type");
+        assert_eq!(Snake("send").to_string(), "send");
+    }
+}
@@ -188,6 +188,20 @@ impl<'a> Parser<'a> {
             None
         }
     }
+    // Eats an identifier that is used as a keyword when it is followed by a
+    // token of type 'next', so it can still be used as a name everywhere
+    // else.
+    pub fn eat_contextual_keyword(&mut self, keyword: &str, next: TokenType) -> Option<&'a str> {
+        let mut lexer = self.lexer.clone();
+        if self.token.ty == TokenType::Ident
+            && self.token.contents == keyword
+            && lexer.next_token().ty == next
+        {
+            Some(self.next_token())
+        } else {
+            None
+        }
+    }
     pub fn expect_token(&mut self, token: TokenType) -> ParseResult<'a, &'a str> {
         if let Some(source) = self.eat_token(token) {
             return Ok(source);
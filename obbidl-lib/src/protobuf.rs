@@ -0,0 +1,566 @@
+use std::{error::Error, fmt, ops::RangeInclusive};
+
+use crate::{
+    channel::{AsyncChannel, Channel, RecvError},
+    compact::{decode_varint, decode_varint_async, encode_varint, unzigzag, zigzag},
+    encode::into_array,
+};
+
+// The runtime side of the Protocol Buffers wire format. Each message is sent
+// as a length delimited protobuf message with a single field, numbered by the
+// message's id plus one, that holds the payload. Payload items and struct
+// fields are numbered by their position.
+
+// The longest message that will be received.
+pub const MAX_MESSAGE_LENGTH: usize = 1 << 24;
+
+// Wire types, the bottom three bits of the key of each field.
+const VARINT: u64 = 0;
+const I64: u64 = 1;
+const LEN: u64 = 2;
+const I32: u64 = 5;
+
+pub(crate) const MAX_FIELD_NUMBER: u64 = (1 << 29) - 1;
+// Reserved for the implementation of protocol buffers.
+pub(crate) const RESERVED_FIELD_NUMBERS: RangeInclusive<u64> = 19000..=19999;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ProtobufError(String);
+
+impl fmt::Display for ProtobufError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl Error for ProtobufError {}
+
+// One occurrence of a field.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Record<'a> {
+    Varint(u64),
+    I64(u64),
+    Len(&'a [u8]),
+    I32(u32),
+}
+
+// The fields of a message, in the order they were written in. Fields that
+// aren't asked for are ignored, so messages can gain fields.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Fields<'a>(Vec<(u32, Record<'a>)>);
+
+impl<'a> Fields<'a> {
+    pub fn parse(mut bytes: &'a [u8]) -> Result<Fields<'a>, ProtobufError> {
+        let mut fields = vec![];
+        while !bytes.is_empty() {
+            let key = read_varint(&mut bytes)?;
+            let number = key >> 3;
+            if number == 0 || number > MAX_FIELD_NUMBER {
+                return Err(ProtobufError(format!("invalid field number {}", number)));
+            }
+            let record = match key & 7 {
+                VARINT => Record::Varint(read_varint(&mut bytes)?),
+                I64 => Record::I64(u64::from_le_bytes(take(&mut bytes, 8)?.try_into().unwrap())),
+                LEN => {
+                    let length = read_varint(&mut bytes)?;
+                    let length = usize::try_from(length).unwrap_or(usize::MAX);
+                    Record::Len(take(&mut bytes, length)?)
+                }
+                I32 => Record::I32(u32::from_le_bytes(take(&mut bytes, 4)?.try_into().unwrap())),
+                wire_type => {
+                    return Err(ProtobufError(format!(
+                        "unsupported wire type {}",
+                        wire_type
+                    )))
+                }
+            };
+            fields.push((number as u32, record));
+        }
+        Ok(Fields(fields))
+    }
+
+    pub fn get<T: Field>(&self, number: u32) -> Result<T, ProtobufError> {
+        let records: Vec<Record> = self
+            .0
+            .iter()
+            .filter(|(field, _)| *field == number)
+            .map(|(_, record)| *record)
+            .collect();
+        T::read_field(&records).map_err(|err| ProtobufError(format!("field {}: {}", number, err)))
+    }
+}
+
+// Splits a message into the number of its last field and the fields of the
+// payload in it.
+pub fn choice(message: &[u8]) -> Result<(u32, Fields<'_>), ProtobufError> {
+    let fields = Fields::parse(message)?;
+    match fields.0.last() {
+        Some((number, Record::Len(payload))) => Ok((*number, Fields::parse(payload)?)),
+        Some(_) => Err(ProtobufError("expected a message".to_string())),
+        None => Err(ProtobufError("empty message".to_string())),
+    }
+}
+
+fn read_varint(bytes: &mut &[u8]) -> Result<u64, ProtobufError> {
+    let mut value = 0;
+    for index in 0..10 {
+        let byte = take(bytes, 1)?[0];
+        // The tenth byte holds only the top bit of a u64.
+        if index == 9 && byte > 1 {
+            return Err(ProtobufError("varint is too long".to_string()));
+        }
+        value |= ((byte & 0x7f) as u64) << (7 * index);
+        if byte & 0x80 == 0 {
+            break;
+        }
+    }
+    Ok(value)
+}
+
+fn take<'a>(bytes: &mut &'a [u8], length: usize) -> Result<&'a [u8], ProtobufError> {
+    if bytes.len() < length {
+        return Err(ProtobufError("unexpected end of message".to_string()));
+    }
+    let (taken, rest) = bytes.split_at(length);
+    *bytes = rest;
+    Ok(taken)
+}
+
+fn write_key(number: u32, wire_type: u64, buf: &mut Vec<u8>) {
+    encode_varint((number as u64) << 3 | wire_type, buf);
+}
+
+fn write_len(number: u32, contents: &[u8], buf: &mut Vec<u8>) {
+    write_key(number, LEN, buf);
+    encode_varint(contents.len() as u64, buf);
+    buf.extend_from_slice(contents);
+}
+
+fn expected(kind: &str, record: &Record) -> ProtobufError {
+    let found = match record {
+        Record::Varint(_) => "a varint",
+        Record::I64(_) => "a 64 bit value",
+        Record::Len(_) => "a length delimited value",
+        Record::I32(_) => "a 32 bit value",
+    };
+    ProtobufError(format!("expected {}, found {}", kind, found))
+}
+
+// A type that can be the type of a field.
+pub trait Field: Sized {
+    // Writes the value as field 'number', or nothing if it is the default
+    // value of a scalar.
+    fn write_field(&self, number: u32, buf: &mut Vec<u8>);
+    // Reads the value from every occurrence of its field.
+    fn read_field(records: &[Record]) -> Result<Self, ProtobufError>;
+}
+
+// A type that can be an item of a repeated field. Arrays of arrays are
+// repeated messages with the inner array as field 1.
+pub trait Item: Sized {
+    // Scalars are packed into one length delimited record.
+    const PACKED: bool;
+
+    fn write_item(&self, buf: &mut Vec<u8>);
+    fn read_item(record: Record) -> Result<Self, ProtobufError>;
+}
+
+// Generated code implements this for each struct, with a field for each of
+// the struct's fields.
+pub trait Message: Sized {
+    fn write_fields(&self, buf: &mut Vec<u8>);
+    fn read_fields(fields: &Fields) -> Result<Self, ProtobufError>;
+}
+
+impl<T: Message> Field for T {
+    fn write_field(&self, number: u32, buf: &mut Vec<u8>) {
+        let mut contents = vec![];
+        self.write_fields(&mut contents);
+        write_len(number, &contents, buf);
+    }
+
+    // A message that occurs more than once is merged, and a missing one has
+    // default fields.
+    fn read_field(records: &[Record]) -> Result<Self, ProtobufError> {
+        let mut contents = vec![];
+        for record in records {
+            match record {
+                Record::Len(bytes) => contents.extend_from_slice(bytes),
+                record => return Err(expected("a message", record)),
+            }
+        }
+        T::read_fields(&Fields::parse(&contents)?)
+    }
+}
+
+impl<T: Message> Item for T {
+    const PACKED: bool = false;
+
+    fn write_item(&self, buf: &mut Vec<u8>) {
+        self.write_fields(buf);
+    }
+
+    fn read_item(record: Record) -> Result<Self, ProtobufError> {
+        match record {
+            Record::Len(bytes) => T::read_fields(&Fields::parse(bytes)?),
+            record => Err(expected("a message", &record)),
+        }
+    }
+}
+
+// Unsigned integers are 'uint32' or 'uint64', and signed ones 'sint32' or
+// 'sint64', which are zigzag encoded.
+macro_rules! impl_scalar {
+    ($($ty:ty: $to_varint:expr, $from_varint:expr;)*) => {
+        $(
+            impl Field for $ty {
+                fn write_field(&self, number: u32, buf: &mut Vec<u8>) {
+                    if *self != <$ty>::default() {
+                        write_key(number, VARINT, buf);
+                        self.write_item(buf);
+                    }
+                }
+
+                // The last occurrence wins.
+                fn read_field(records: &[Record]) -> Result<Self, ProtobufError> {
+                    match records.last() {
+                        Some(record) => <$ty>::read_item(*record),
+                        None => Ok(<$ty>::default()),
+                    }
+                }
+            }
+
+            impl Item for $ty {
+                const PACKED: bool = true;
+
+                fn write_item(&self, buf: &mut Vec<u8>) {
+                    let to_varint: fn($ty) -> u64 = $to_varint;
+                    encode_varint(to_varint(*self), buf);
+                }
+
+                fn read_item(record: Record) -> Result<Self, ProtobufError> {
+                    let Record::Varint(value) = record else {
+                        return Err(expected("a varint", &record));
+                    };
+                    let from_varint: fn(u64) -> Option<$ty> = $from_varint;
+                    from_varint(value).ok_or_else(|| {
+                        ProtobufError(format!("{} is out of range for {}", value, stringify!($ty)))
+                    })
+                }
+            }
+        )*
+    };
+}
+
+impl_scalar! {
+    bool: |value| value as u64, |value| Some(value != 0);
+    u8: |value| value as u64, |value| value.try_into().ok();
+    u16: |value| value as u64, |value| value.try_into().ok();
+    u32: |value| value as u64, |value| value.try_into().ok();
+    u64: |value| value, Some;
+    i8: |value| zigzag(value as i64), |value| unzigzag(value).try_into().ok();
+    i16: |value| zigzag(value as i64), |value| unzigzag(value).try_into().ok();
+    i32: |value| zigzag(value as i64), |value| unzigzag(value).try_into().ok();
+    i64: zigzag, |value| Some(unzigzag(value));
+}
+
+// Writes an array as a repeated field. Arrays are passed to generated send
+// methods as slices, which can't implement 'Field'.
+pub fn write_repeated<T: Item>(items: &[T], number: u32, buf: &mut Vec<u8>) {
+    if items.is_empty() {
+        return;
+    }
+    if T::PACKED {
+        let mut packed = vec![];
+        for item in items {
+            item.write_item(&mut packed);
+        }
+        write_len(number, &packed, buf);
+    } else {
+        for item in items {
+            let mut contents = vec![];
+            item.write_item(&mut contents);
+            write_len(number, &contents, buf);
+        }
+    }
+}
+
+// Scalars are accepted packed or not, as protobuf decoders do.
+fn read_items<T: Item>(records: &[Record]) -> Result<Vec<T>, ProtobufError> {
+    let mut items = vec![];
+    for record in records {
+        match record {
+            Record::Len(mut packed) if T::PACKED => {
+                while !packed.is_empty() {
+                    items.push(T::read_item(Record::Varint(read_varint(&mut packed)?))?);
+                }
+            }
+            record => items.push(T::read_item(*record)?),
+        }
+    }
+    Ok(items)
+}
+
+impl<T: Item> Field for Vec<T> {
+    fn write_field(&self, number: u32, buf: &mut Vec<u8>) {
+        write_repeated(self, number, buf);
+    }
+
+    fn read_field(records: &[Record]) -> Result<Self, ProtobufError> {
+        read_items(records)
+    }
+}
+
+impl<T: Item> Item for Vec<T> {
+    const PACKED: bool = false;
+
+    fn write_item(&self, buf: &mut Vec<u8>) {
+        self.write_field(1, buf);
+    }
+
+    fn read_item(record: Record) -> Result<Self, ProtobufError> {
+        match record {
+            Record::Len(bytes) => Fields::parse(bytes)?.get(1),
+            record => Err(expected("a message", &record)),
+        }
+    }
+}
+
+impl<T: Item, const N: usize> Field for [T; N] {
+    fn write_field(&self, number: u32, buf: &mut Vec<u8>) {
+        write_repeated(self, number, buf);
+    }
+
+    fn read_field(records: &[Record]) -> Result<Self, ProtobufError> {
+        let items = read_items(records)?;
+        if items.len() != N {
+            return Err(ProtobufError(format!(
+                "expected {} items, found {}",
+                N,
+                items.len()
+            )));
+        }
+        Ok(into_array(items))
+    }
+}
+
+impl<T: Item, const N: usize> Item for [T; N] {
+    const PACKED: bool = false;
+
+    fn write_item(&self, buf: &mut Vec<u8>) {
+        self.write_field(1, buf);
+    }
+
+    fn read_item(record: Record) -> Result<Self, ProtobufError> {
+        match record {
+            Record::Len(bytes) => Fields::parse(bytes)?.get(1),
+            record => Err(expected("a message", &record)),
+        }
+    }
+}
+
+// The message is prefixed with its length as a varint, as protobuf's
+// delimited streams are.
+fn frame(number: u32, payload: &[u8]) -> Vec<u8> {
+    let mut message = vec![];
+    write_len(number, payload, &mut message);
+    let mut buf = vec![];
+    encode_varint(message.len() as u64, &mut buf);
+    buf.extend_from_slice(&message);
+    buf
+}
+
+pub fn send<C: Channel>(channel: &mut C, number: u32, payload: &[u8]) -> Result<(), C::Error> {
+    channel.send(&frame(number, payload))
+}
+
+pub async fn send_async<C: AsyncChannel>(
+    channel: &mut C,
+    number: u32,
+    payload: &[u8],
+) -> Result<(), C::Error> {
+    channel.send(&frame(number, payload)).await
+}
+
+pub fn recv<C: Channel>(channel: &mut C) -> Result<Vec<u8>, RecvError<C::Error>> {
+    let length = check_length(decode_varint(channel)?)?;
+    let mut message = vec![0; length];
+    channel.recv(&mut message)?;
+    Ok(message)
+}
+
+pub async fn recv_async<C: AsyncChannel>(channel: &mut C) -> Result<Vec<u8>, RecvError<C::Error>> {
+    let length = check_length(decode_varint_async(channel).await?)?;
+    let mut message = vec![0; length];
+    channel.recv(&mut message).await?;
+    Ok(message)
+}
+
+fn check_length<E>(length: u64) -> Result<usize, RecvError<E>> {
+    if length > MAX_MESSAGE_LENGTH as u64 {
+        return Err(RecvError::Malformed(format!(
+            "message is longer than {} bytes",
+            MAX_MESSAGE_LENGTH
+        )));
+    }
+    Ok(length as usize)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fmt::Debug;
+
+    use crate::channel::{Channel, TestChannel};
+
+    use super::{
+        choice, recv, send, Field, Fields, Message, ProtobufError, Record, MAX_MESSAGE_LENGTH,
+    };
+
+    fn encoded<T: Field>(value: &T, number: u32) -> Vec<u8> {
+        let mut buf = vec![];
+        value.write_field(number, &mut buf);
+        buf
+    }
+
+    fn round_trip<T: Field + PartialEq + Debug>(value: T) {
+        let buf = encoded(&value, 1);
+        assert_eq!(Fields::parse(&buf).unwrap().get::<T>(1).unwrap(), value);
+    }
+
+    #[derive(Debug, PartialEq)]
+    struct Point {
+        x: i32,
+        tags: Vec<u8>,
+    }
+
+    impl Message for Point {
+        fn write_fields(&self, buf: &mut Vec<u8>) {
+            self.x.write_field(1, buf);
+            self.tags.write_field(2, buf);
+        }
+
+        fn read_fields(fields: &Fields) -> Result<Self, ProtobufError> {
+            Ok(Point {
+                x: fields.get(1)?,
+                tags: fields.get(2)?,
+            })
+        }
+    }
+
+    // Examples from the protobuf encoding guide.
+    #[test]
+    fn test_wire_format() {
+        assert_eq!(encoded(&150u32, 1), [0x08, 0x96, 0x01]);
+        assert_eq!(encoded(&-1i32, 1), [0x08, 0x01]);
+        assert_eq!(encoded(&-2i64, 1), [0x08, 0x03]);
+        assert_eq!(encoded(&i32::MIN, 1), [0x08, 0xff, 0xff, 0xff, 0xff, 0x0f]);
+        assert_eq!(
+            encoded(&vec![3u32, 270, 86942], 4),
+            [0x22, 0x06, 0x03, 0x8e, 0x02, 0x9e, 0xa7, 0x05]
+        );
+        assert_eq!(encoded(&true, 2), [0x10, 0x01]);
+        // Default values are left out.
+        assert_eq!(encoded(&0u64, 1), []);
+        assert_eq!(encoded(&false, 1), []);
+        assert_eq!(encoded(&Vec::<u8>::new(), 1), []);
+        let point = Point {
+            x: 150,
+            tags: vec![],
+        };
+        assert_eq!(encoded(&point, 3), [0x1a, 0x03, 0x08, 0xac, 0x02]);
+    }
+
+    #[test]
+    fn test_round_trip() {
+        round_trip(true);
+        round_trip(u8::MAX);
+        round_trip(u16::MAX);
+        round_trip(u32::MAX);
+        round_trip(u64::MAX);
+        round_trip(i8::MIN);
+        round_trip(i16::MIN);
+        round_trip(i32::MIN);
+        round_trip(i64::MIN);
+        round_trip(i64::MAX);
+        round_trip(vec![[1u16, 2], [3, 4]]);
+        round_trip(vec![vec![true], vec![], vec![false, true]]);
+        round_trip([vec![0i64], vec![-7]]);
+        round_trip(vec![vec![vec![1u8]], vec![]]);
+        round_trip([
+            Point {
+                x: -1,
+                tags: vec![0, 255],
+            },
+            Point { x: 0, tags: vec![] },
+        ]);
+    }
+
+    #[test]
+    fn test_decode() {
+        // Missing fields are default, unknown ones are skipped and the last
+        // occurrence of a scalar wins.
+        let bytes = [0x08, 0x02, 0x28, 0x07, 0x08, 0x04, 0x2d, 0, 0, 0, 0];
+        let fields = Fields::parse(&bytes).unwrap();
+        assert_eq!(fields.get::<i32>(1).unwrap(), 2);
+        assert_eq!(fields.get::<Vec<u8>>(2).unwrap(), vec![]);
+        // Unpacked repeated scalars are accepted too.
+        let bytes = [0x10, 0x01, 0x10, 0x02, 0x12, 0x01, 0x03];
+        let fields = Fields::parse(&bytes).unwrap();
+        assert_eq!(fields.get::<Vec<u8>>(2).unwrap(), vec![1, 2, 3]);
+        // Messages that occur more than once are merged.
+        let bytes = [0x0a, 0x02, 0x08, 0x02, 0x0a, 0x02, 0x12, 0x00];
+        let fields = Fields::parse(&bytes).unwrap();
+        assert_eq!(
+            fields.get::<Point>(1).unwrap(),
+            Point { x: 1, tags: vec![] }
+        );
+    }
+
+    #[test]
+    fn test_decode_errors() {
+        for bytes in [
+            &[0x08][..],         // a missing value
+            &[0x00, 0x01],       // field number 0
+            &[0x0b],             // a group
+            &[0x0a, 0x05, 0x01], // a value longer than the message
+            &[
+                0x08, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x02,
+            ],
+        ] {
+            assert!(Fields::parse(bytes).is_err(), "{:?}", bytes);
+        }
+        let fields = Fields::parse(&[0x08, 0x80, 0x02, 0x12, 0x01, 0x00]).unwrap();
+        assert!(fields.get::<u8>(1).is_err());
+        assert!(fields.get::<u32>(2).is_err());
+        assert!(fields.get::<[u8; 2]>(2).is_err());
+        assert!(fields.get::<Point>(1).is_err());
+        assert_eq!(fields.get::<[u8; 0]>(3).unwrap(), []);
+    }
+
+    #[test]
+    fn test_messages() {
+        let (mut a, mut b) = TestChannel::new();
+        let mut payload = vec![];
+        7u8.write_field(1, &mut payload);
+        send(&mut a, 4, &payload).unwrap();
+        send(&mut a, 1, &[]).unwrap();
+        let message = recv(&mut b).unwrap();
+        assert_eq!(message, [0x22, 0x02, 0x08, 0x07]);
+        let (number, payload) = choice(&message).unwrap();
+        assert_eq!(number, 4);
+        assert_eq!(payload.get::<u8>(1).unwrap(), 7);
+        let message = recv(&mut b).unwrap();
+        assert_eq!(choice(&message).unwrap(), (1, Fields::parse(&[]).unwrap()));
+        assert!(choice(&[]).is_err());
+        assert!(choice(&[0x08, 0x01]).is_err());
+
+        let mut length = vec![];
+        crate::compact::encode_varint(MAX_MESSAGE_LENGTH as u64 + 1, &mut length);
+        a.send(&length).unwrap();
+        assert!(recv(&mut b).is_err());
+        assert_eq!(
+            Fields::parse(&[0x0d, 1, 0, 0, 0]).unwrap(),
+            Fields(vec![(1, Record::I32(1))])
+        );
+    }
+}
@@ -0,0 +1,488 @@
+use std::{collections::HashMap, rc::Rc};
+
+use crate::{
+    ast::{Message, Sequence, StateLabel, Stmt},
+    parser::Span,
+};
+
+// Residual protocols are hash-consed: every distinct node is stored once and
+// referred to by its id, so comparing and hashing residuals is O(1) and the
+// transitions out of each node are only computed once.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct NodeId(u32);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct MessageId(u32);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct LabelId(u32);
+
+type Transitions = Rc<[(MessageId, NodeId)]>;
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+enum Node {
+    Empty,
+    Message(MessageId),
+    Seq(NodeId, NodeId),
+    Choice(Vec<NodeId>),
+    Par(Vec<NodeId>),
+    Fin(NodeId),
+    Inf(NodeId),
+    // A residual that starts at a point named with 'state', which otherwise
+    // behaves exactly like the residual it wraps.
+    Named(LabelId, NodeId),
+}
+
+pub struct Residuals<'a> {
+    nodes: Vec<Node>,
+    node_ids: HashMap<Node, NodeId>,
+    may_terminate: Vec<bool>,
+    transitions: Vec<Option<Transitions>>,
+    messages: Vec<&'a Span<Message>>,
+    message_ids: HashMap<&'a Message, MessageId>,
+    labels: Vec<&'a Span<StateLabel>>,
+}
+
+impl<'a> Residuals<'a> {
+    pub const EMPTY: NodeId = NodeId(0);
+
+    pub fn new() -> Residuals<'a> {
+        let mut residuals = Residuals {
+            nodes: vec![],
+            node_ids: HashMap::new(),
+            may_terminate: vec![],
+            transitions: vec![],
+            messages: vec![],
+            message_ids: HashMap::new(),
+            labels: vec![],
+        };
+        residuals.node(Node::Empty);
+        residuals
+    }
+    pub fn message(&self, id: MessageId) -> &'a Span<Message> {
+        self.messages[id.0 as usize]
+    }
+    pub fn may_terminate(&self, id: NodeId) -> bool {
+        self.may_terminate[id.0 as usize]
+    }
+    pub fn label(&self, id: LabelId) -> &'a Span<StateLabel> {
+        self.labels[id.0 as usize]
+    }
+    pub fn intern_seq(&mut self, seq: &'a Sequence) -> NodeId {
+        seq.0
+            .iter()
+            .rev()
+            .fold(Residuals::EMPTY, |rest, stmt| self.intern_stmt(stmt, rest))
+    }
+    fn intern_stmt(&mut self, stmt: &'a Stmt, rest: NodeId) -> NodeId {
+        let stmt = match stmt {
+            Stmt::Message(msg) => {
+                let next_id = MessageId(self.messages.len() as u32);
+                let id = *self.message_ids.entry(&msg.inner).or_insert(next_id);
+                if id == next_id {
+                    self.messages.push(msg);
+                }
+                self.node(Node::Message(id))
+            }
+            Stmt::Choice(seqs) => {
+                let ids = seqs.0.iter().map(|seq| self.intern_seq(seq)).collect();
+                self.choice(ids)
+            }
+            Stmt::Par(seqs) => {
+                let ids = seqs.0.iter().map(|seq| self.intern_seq(seq)).collect();
+                self.par(ids)
+            }
+            Stmt::Fin(seq) => {
+                let id = self.intern_seq(seq);
+                self.fin(id)
+            }
+            Stmt::Inf(seq) => {
+                let id = self.intern_seq(seq);
+                self.node(Node::Inf(id))
+            }
+            Stmt::State(label) => {
+                let id = LabelId(self.labels.len() as u32);
+                self.labels.push(label);
+                return self.node(Node::Named(id, rest));
+            }
+        };
+        self.seq(stmt, rest)
+    }
+    fn node(&mut self, node: Node) -> NodeId {
+        if let Some(id) = self.node_ids.get(&node) {
+            return *id;
+        }
+        let may_terminate = match &node {
+            Node::Empty | Node::Fin(_) => true,
+            Node::Message(_) | Node::Inf(_) => false,
+            Node::Seq(a, b) => self.may_terminate(*a) && self.may_terminate(*b),
+            Node::Named(_, id) => self.may_terminate(*id),
+            Node::Choice(ids) => ids.iter().any(|id| self.may_terminate(*id)),
+            Node::Par(ids) => ids.iter().all(|id| self.may_terminate(*id)),
+        };
+        let id = NodeId(self.nodes.len() as u32);
+        self.nodes.push(node.clone());
+        self.node_ids.insert(node, id);
+        self.may_terminate.push(may_terminate);
+        self.transitions.push(None);
+        id
+    }
+    fn seq(&mut self, a: NodeId, b: NodeId) -> NodeId {
+        if a == Residuals::EMPTY {
+            return b;
+        }
+        if b == Residuals::EMPTY {
+            return a;
+        }
+        if let Node::Seq(a1, a2) = self.nodes[a.0 as usize] {
+            let rest = self.seq(a2, b);
+            return self.seq(a1, rest);
+        }
+        self.node(Node::Seq(a, b))
+    }
+    // A choice is a set of residuals, which also makes it the state of the
+    // subset construction.
+    pub fn choice(&mut self, ids: Vec<NodeId>) -> NodeId {
+        let mut flat_ids = vec![];
+        for id in ids {
+            match &self.nodes[id.0 as usize] {
+                Node::Choice(inner) => flat_ids.extend(inner.iter().copied()),
+                _ => flat_ids.push(id),
+            }
+        }
+        flat_ids.sort();
+        flat_ids.dedup();
+        if flat_ids.len() == 1 {
+            return flat_ids[0];
+        }
+        self.node(Node::Choice(flat_ids))
+    }
+    // Unlike a choice, the branches of a par are a multiset: two identical
+    // branches both have to happen.
+    fn par(&mut self, ids: Vec<NodeId>) -> NodeId {
+        let mut flat_ids = vec![];
+        for id in ids {
+            match &self.nodes[id.0 as usize] {
+                Node::Empty => {}
+                Node::Par(inner) => flat_ids.extend(inner.iter().copied()),
+                _ => flat_ids.push(id),
+            }
+        }
+        flat_ids.sort();
+        match flat_ids.len() {
+            0 => Residuals::EMPTY,
+            1 => flat_ids[0],
+            _ => self.node(Node::Par(flat_ids)),
+        }
+    }
+    fn fin(&mut self, id: NodeId) -> NodeId {
+        if id == Residuals::EMPTY {
+            return Residuals::EMPTY;
+        }
+        self.node(Node::Fin(id))
+    }
+    // Splits a residual that has to start with a 'par' block into the
+    // branches of the block and the residual that follows it. Residuals that
+    // may skip the block, like a choice or a 'fin' loop, are not split.
+    pub fn fork(&mut self, id: NodeId) -> Option<(Vec<NodeId>, NodeId)> {
+        match self.nodes[id.0 as usize].clone() {
+            Node::Par(ids) => Some((ids, Residuals::EMPTY)),
+            Node::Seq(a, b) => match self.nodes[a.0 as usize].clone() {
+                Node::Par(ids) => Some((ids, b)),
+                Node::Named(_, a) => {
+                    let id = self.seq(a, b);
+                    self.fork(id)
+                }
+                _ => None,
+            },
+            Node::Named(_, id) => self.fork(id),
+            Node::Inf(body) => {
+                let unrolled = self.seq(body, id);
+                match self.nodes[unrolled.0 as usize] {
+                    Node::Seq(..) => self.fork(unrolled),
+                    _ => None,
+                }
+            }
+            _ => None,
+        }
+    }
+    pub fn transitions(&mut self, id: NodeId) -> Transitions {
+        if let Some(trans) = &self.transitions[id.0 as usize] {
+            return Rc::clone(trans);
+        }
+        let mut trans = vec![];
+        match self.nodes[id.0 as usize].clone() {
+            Node::Empty => {}
+            Node::Message(msg) => trans.push((msg, Residuals::EMPTY)),
+            Node::Seq(a, b) => {
+                for &(msg, rem) in self.transitions(a).iter() {
+                    trans.push((msg, self.seq(rem, b)));
+                }
+                if self.may_terminate(a) {
+                    trans.extend(self.transitions(b).iter());
+                }
+            }
+            Node::Choice(ids) => {
+                for id in ids {
+                    trans.extend(self.transitions(id).iter());
+                }
+            }
+            Node::Par(ids) => {
+                for (i, id) in ids.iter().enumerate() {
+                    for &(msg, rem) in self.transitions(*id).iter() {
+                        let mut rem_ids = ids.clone();
+                        rem_ids[i] = rem;
+                        trans.push((msg, self.par(rem_ids)));
+                    }
+                }
+            }
+            Node::Fin(body) | Node::Inf(body) => {
+                for &(msg, rem) in self.transitions(body).iter() {
+                    trans.push((msg, self.seq(rem, id)));
+                }
+            }
+            Node::Named(_, id) => trans.extend(self.transitions(id).iter()),
+        }
+        let trans: Transitions = trans.into();
+        self.transitions[id.0 as usize] = Some(Rc::clone(&trans));
+        trans
+    }
+    // The labels naming the point a residual starts at. There may be more
+    // than one when the residual is a choice between differently named
+    // points.
+    pub fn labels(&self, id: NodeId) -> Vec<LabelId> {
+        let mut labels = match &self.nodes[id.0 as usize] {
+            Node::Named(label, _) => vec![*label],
+            Node::Seq(a, b) => {
+                let mut labels = self.labels(*a);
+                if self.may_terminate(*a) {
+                    labels.extend(self.labels(*b));
+                }
+                labels
+            }
+            Node::Fin(id) | Node::Inf(id) => self.labels(*id),
+            Node::Choice(ids) => ids.iter().flat_map(|id| self.labels(*id)).collect(),
+            Node::Empty | Node::Message(_) | Node::Par(_) => vec![],
+        };
+        labels.sort_by_key(|label| &self.label(*label).inner.name);
+        labels.dedup_by_key(|label| &self.label(*label).inner.name);
+        labels
+    }
+    // Merges transitions with the same message (label, id, roles and payload
+    // types) into a single transition to the choice of all their residuals,
+    // which keeps the resulting state machine deterministic. Payload item
+    // names are not part of the wire format, so messages differing only in
+    // them are merged too and the first message's names are kept. Messages
+    // sharing a label but differing in anything else are kept as separate
+    // transitions so validation can report them.
+    pub fn merged_transitions(&mut self, id: NodeId) -> Vec<(MessageId, NodeId)> {
+        let mut merged: Vec<(MessageId, Vec<NodeId>)> = vec![];
+        for &(msg, rem) in self.transitions(id).iter() {
+            match merged
+                .iter_mut()
+                .find(|(other, _)| self.same_message(*other, msg))
+            {
+                Some((_, rems)) => rems.push(rem),
+                None => merged.push((msg, vec![rem])),
+            }
+        }
+        merged
+            .into_iter()
+            .map(|(msg, rems)| (msg, self.choice(rems)))
+            .collect()
+    }
+    fn same_message(&self, a: MessageId, b: MessageId) -> bool {
+        let a = &self.message(a).inner;
+        let b = &self.message(b).inner;
+        a.label == b.label
+            && a.id == b.id
+            && a.from == b.from
+            && a.to == b.to
+            && a.payload.items.len() == b.payload.items.len()
+            && a.payload
+                .items
+                .iter()
+                .zip(&b.payload.items)
+                .all(|(a, b)| a.inner.ty == b.inner.ty)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        ast::{Message, Sequence},
+        parser::parse,
+        report::Report,
+    };
+
+    use super::Residuals;
+
+    fn assert_trans(seq: &str, expected: &[(&str, &str)]) {
+        let seq: Sequence = parse(seq).report();
+        let expected: Vec<(Message, Sequence)> = expected
+            .iter()
+            .map(|(msg, rem_seq)| (parse(msg).report(), parse(rem_seq).report()))
+            .collect();
+
+        let mut residuals = Residuals::new();
+        let id = residuals.intern_seq(&seq);
+        let trans = residuals.transitions(id);
+
+        assert_eq!(trans.len(), expected.len());
+        for ((msg, rem), (expected_msg, expected_rem_seq)) in trans.iter().zip(&expected) {
+            assert_eq!(&residuals.message(*msg).inner, expected_msg);
+            assert_eq!(*rem, residuals.intern_seq(expected_rem_seq));
+        }
+    }
+
+    #[test]
+    fn test_empty_choice() {
+        assert_trans(
+            "{ choice { X from C to S; } or { } Y from C to S; }",
+            &[
+                ("X from C to S;", "{ Y from C to S; }"),
+                ("Y from C to S;", "{ }"),
+            ],
+        );
+    }
+
+    #[test]
+    fn test_msg_trans() {
+        assert_trans(
+            "{ X from C to S; Y from S to C; }",
+            &[("X from C to S;", "{ Y from S to C; }")],
+        );
+    }
+
+    #[test]
+    fn test_choice_trans() {
+        assert_trans(
+            "{ choice { X from C to S; } or { Y from C to S; Z from S to C; } }",
+            &[
+                ("X from C to S;", "{ }"),
+                ("Y from C to S;", "{ Z from S to C; }"),
+            ],
+        );
+    }
+
+    #[test]
+    fn test_par_trans() {
+        assert_trans(
+            "{ par { X from C to S; } and { Y from C to S; } and { Z from C to S; W from S to C; } }",
+            &[
+                (
+                    "X from C to S;",
+                    "{ par {} and { Y from C to S; } and { Z from C to S; W from S to C; } }",
+                ),
+                (
+                    "Y from C to S;",
+                    "{ par { X from C to S; } and {} and { Z from C to S; W from S to C; } }",
+                ),
+                (
+                    "Z from C to S;",
+                    "{ par { X from C to S; } and { Y from C to S; } and { W from S to C; } }",
+                ),
+            ],
+        );
+    }
+
+    #[test]
+    fn test_par_duplicate_branches() {
+        assert_trans(
+            "{ par { X from C to S; } and { X from C to S; } }",
+            &[
+                ("X from C to S;", "{ X from C to S; }"),
+                ("X from C to S;", "{ X from C to S; }"),
+            ],
+        );
+    }
+
+    #[test]
+    fn test_inf_trans() {
+        assert_trans(
+            "{ inf { X from C to S; Y from S to C; } }",
+            &[(
+                "X from C to S;",
+                "{ Y from S to C; inf { X from C to S; Y from S to C; } }",
+            )],
+        );
+    }
+
+    #[test]
+    fn test_fin_trans() {
+        assert_trans(
+            "{ fin { X from C to S; Y from C to S; } Z from C to S; }",
+            &[
+                (
+                    "X from C to S;",
+                    "{ Y from C to S; fin { X from C to S; Y from C to S; } Z from C to S; }",
+                ),
+                ("Z from C to S;", "{ }"),
+            ],
+        );
+    }
+
+    #[test]
+    fn test_merged_trans() {
+        let seqs: Vec<Sequence> = vec![
+            parse("{ X from C to S; Y from S to C; }").report(),
+            parse("{ X from C to S; }").report(),
+            parse("{ X(u8) from C to S; }").report(),
+        ];
+        let rem_seq: Sequence = parse("{ Y from S to C; }").report();
+
+        let mut residuals = Residuals::new();
+        let ids = seqs.iter().map(|seq| residuals.intern_seq(seq)).collect();
+        let id = residuals.choice(ids);
+        let trans = residuals.merged_transitions(id);
+
+        assert_eq!(trans.len(), 2);
+
+        let (msg, rem) = trans[0];
+        assert_eq!(
+            residuals.message(msg).inner,
+            parse("X from C to S;").report()
+        );
+        let rem_id = residuals.intern_seq(&rem_seq);
+        assert_eq!(rem, residuals.choice(vec![rem_id, Residuals::EMPTY]));
+
+        let (msg, rem) = trans[1];
+        assert_eq!(
+            residuals.message(msg).inner,
+            parse("X(u8) from C to S;").report()
+        );
+        assert_eq!(rem, Residuals::EMPTY);
+    }
+
+    #[test]
+    fn test_merged_trans_item_names() {
+        let seqs: Vec<Sequence> = vec![
+            parse("{ X(a: u8) from C to S; Y from S to C; }").report(),
+            parse("{ X(b: u8) from C to S; }").report(),
+            parse("{ X(a: u16) from C to S; }").report(),
+        ];
+        let rem_seq: Sequence = parse("{ Y from S to C; }").report();
+
+        let mut residuals = Residuals::new();
+        let ids = seqs.iter().map(|seq| residuals.intern_seq(seq)).collect();
+        let id = residuals.choice(ids);
+        let trans = residuals.merged_transitions(id);
+
+        assert_eq!(trans.len(), 2);
+
+        let (msg, rem) = trans[0];
+        assert_eq!(
+            residuals.message(msg).inner,
+            parse("X(a: u8) from C to S;").report()
+        );
+        let rem_id = residuals.intern_seq(&rem_seq);
+        assert_eq!(rem, residuals.choice(vec![rem_id, Residuals::EMPTY]));
+
+        let (msg, rem) = trans[1];
+        assert_eq!(
+            residuals.message(msg).inner,
+            parse("X(a: u16) from C to S;").report()
+        );
+        assert_eq!(rem, Residuals::EMPTY);
+    }
+}
@@ -1,6 +1,15 @@
-use std::fmt;
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    fmt,
+};
 
-use crate::{ast::Message, compile::ProtocolFileStateMachines, graph::GraphViz, parser::Span};
+use crate::{
+    ast::{Message, StateLabel},
+    compile::ProtocolFileStateMachines,
+    graph::GraphViz,
+    naming::{camel_case, escape, Camel},
+    parser::Span,
+};
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub struct State(pub u32);
@@ -12,25 +21,96 @@ pub struct Transition {
     pub msg: Span<Message>,
 }
 
+// A state at the start of a 'par' block whose branches progress
+// independently. Each branch starts in its own state and the protocol
+// continues in the join state once every branch has ended.
+#[derive(Debug, Clone)]
+pub struct Fork {
+    pub branches: Vec<State>,
+    pub join: State,
+}
+
 #[derive(Debug, Clone)]
 pub struct StateMachine {
-    state_count: u32,
     transitions: Vec<Transition>,
+    outgoing: Vec<Vec<usize>>,
+    final_states: HashSet<State>,
+    forks: HashMap<State, Fork>,
+    branches: HashMap<State, (State, usize)>,
+    labels: HashMap<State, Span<StateLabel>>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum Label<'a> {
+    Message(&'a Message),
+    Branch(usize),
+    Join,
 }
 
 impl StateMachine {
     pub fn new() -> StateMachine {
         StateMachine {
-            state_count: 0,
             transitions: vec![],
+            outgoing: vec![],
+            final_states: HashSet::new(),
+            forks: HashMap::new(),
+            branches: HashMap::new(),
+            labels: HashMap::new(),
         }
     }
     pub fn new_state(&mut self) -> State {
-        self.state_count += 1;
-        State(self.state_count - 1)
+        self.outgoing.push(vec![]);
+        State(self.outgoing.len() as u32 - 1)
     }
     pub fn contains_state(&self, state: State) -> bool {
-        state.0 < self.state_count
+        (state.0 as usize) < self.outgoing.len()
+    }
+    pub fn set_final(&mut self, state: State) {
+        if !self.contains_state(state) {
+            panic!()
+        }
+        self.final_states.insert(state);
+    }
+    pub fn is_final(&self, state: State) -> bool {
+        self.final_states.contains(&state)
+    }
+    pub fn add_fork(&mut self, state: State, fork: Fork) {
+        if !self.contains_state(state) {
+            panic!()
+        }
+        if !fork
+            .branches
+            .iter()
+            .all(|branch| self.contains_state(*branch))
+        {
+            panic!()
+        }
+        if !self.contains_state(fork.join) {
+            panic!()
+        }
+        self.forks.insert(state, fork);
+    }
+    pub fn fork(&self, state: State) -> Option<&Fork> {
+        self.forks.get(&state)
+    }
+    pub fn set_branch(&mut self, state: State, fork: State, index: usize) {
+        if !self.contains_state(state) {
+            panic!()
+        }
+        self.branches.insert(state, (fork, index));
+    }
+    /// The fork state and branch index of a state inside a 'par' branch.
+    pub fn branch(&self, state: State) -> Option<(State, usize)> {
+        self.branches.get(&state).copied()
+    }
+    pub fn set_label(&mut self, state: State, label: Span<StateLabel>) {
+        if !self.contains_state(state) {
+            panic!()
+        }
+        self.labels.insert(state, label);
+    }
+    pub fn label(&self, state: State) -> Option<&Span<StateLabel>> {
+        self.labels.get(&state)
     }
     pub fn add_transition(&mut self, transition: Transition) {
         if !self.contains_state(transition.start) {
@@ -39,42 +119,368 @@ impl StateMachine {
         if !self.contains_state(transition.end) {
             panic!()
         }
+        self.outgoing[transition.start.0 as usize].push(self.transitions.len());
         self.transitions.push(transition);
     }
+    pub fn state_count(&self) -> usize {
+        self.outgoing.len()
+    }
+    pub fn transition_count(&self) -> usize {
+        self.transitions.len()
+    }
     pub fn iter_transitions(&self) -> impl Iterator<Item = &Transition> {
         self.transitions.iter()
     }
     pub fn iter_states(&self) -> impl Iterator<Item = State> + '_ {
-        (0..self.state_count).map(|id| State(id))
+        (0..self.outgoing.len() as u32).map(State)
     }
     pub fn iter_trans_from(&self, start: State) -> impl Iterator<Item = (&Span<Message>, State)> {
-        self.iter_transitions().filter_map(move |trans| {
-            if trans.start == start {
-                Some((&trans.msg, trans.end))
-            } else {
-                None
-            }
+        self.outgoing[start.0 as usize].iter().map(|index| {
+            let trans = &self.transitions[*index];
+            (&trans.msg, trans.end)
         })
     }
+    /// Merges bisimilar states, i.e. states which are either both final or
+    /// both not, accept the same messages and move to states that are
+    /// themselves bisimilar. States in the result are numbered in breadth
+    /// first order from the start state and states that are unreachable from
+    /// it are dropped.
+    pub fn minimize(&self) -> StateMachine {
+        let labels = self.block_labels(&self.bisimilar_blocks(&[]));
+        let blocks = self.bisimilar_blocks(&labels);
+
+        let mut state_machine = StateMachine::new();
+        if self.state_count() == 0 {
+            return state_machine;
+        }
+        let mut new_states = HashMap::new();
+        let mut queue = VecDeque::new();
+        new_states.insert(blocks[0], state_machine.new_state());
+        queue.push_back(State(0));
+        while let Some(state) = queue.pop_front() {
+            let start = new_states[&blocks[state.0 as usize]];
+            if self.is_final(state) {
+                state_machine.set_final(start);
+            }
+            for (msg, end) in self.iter_trans_from(state) {
+                let end = *new_states.entry(blocks[end.0 as usize]).or_insert_with(|| {
+                    queue.push_back(end);
+                    state_machine.new_state()
+                });
+                state_machine.add_transition(Transition {
+                    start,
+                    end,
+                    msg: msg.clone(),
+                });
+            }
+            if let Some(fork) = self.fork(state) {
+                let mut new_state = |state: State| {
+                    *new_states
+                        .entry(blocks[state.0 as usize])
+                        .or_insert_with(|| {
+                            queue.push_back(state);
+                            state_machine.new_state()
+                        })
+                };
+                let fork = Fork {
+                    branches: fork
+                        .branches
+                        .iter()
+                        .map(|branch| new_state(*branch))
+                        .collect(),
+                    join: new_state(fork.join),
+                };
+                state_machine.add_fork(start, fork);
+            }
+            if let Some((fork, index)) = self.branch(state) {
+                let fork = new_states[&blocks[fork.0 as usize]];
+                state_machine.set_branch(start, fork, index);
+            }
+        }
+        for (state, label) in &self.labels {
+            if let Some(new_state) = new_states.get(&blocks[state.0 as usize]) {
+                state_machine.set_label(*new_state, label.clone());
+            }
+        }
+        state_machine
+    }
+    // The label each state has to keep when bisimilar states are merged, so
+    // every label still names its own state. States without a label take the
+    // label of their block when there is only one to pick from. Refining with
+    // these in the initial partition also splits the states leading to them.
+    fn block_labels(&self, blocks: &[usize]) -> Vec<Option<&str>> {
+        let mut block_labels: HashMap<usize, HashSet<&str>> = HashMap::new();
+        for (state, label) in &self.labels {
+            block_labels
+                .entry(blocks[state.0 as usize])
+                .or_default()
+                .insert(&label.inner.name);
+        }
+        self.iter_states()
+            .map(|state| {
+                let block = blocks[state.0 as usize];
+                match (self.label(state), block_labels.get(&block)) {
+                    (Some(label), _) => Some(label.inner.name.as_str()),
+                    (None, Some(labels)) if labels.len() == 1 => labels.iter().next().copied(),
+                    (None, _) => None,
+                }
+            })
+            .collect()
+    }
+    fn iter_labelled_edges(&self) -> impl Iterator<Item = (Label<'_>, State, State)> {
+        let transitions = self
+            .transitions
+            .iter()
+            .map(|trans| (Label::Message(&trans.msg.inner), trans.start, trans.end));
+        let forks = self.forks.iter().flat_map(|(start, fork)| {
+            let branches = fork
+                .branches
+                .iter()
+                .enumerate()
+                .map(|(index, branch)| (Label::Branch(index), *start, *branch));
+            branches.chain([(Label::Join, *start, fork.join)])
+        });
+        transitions.chain(forks)
+    }
+    // Hopcroft's partition refinement, starting from blocks of states that
+    // agree on being final, being a fork, the branch they belong to and their
+    // entry in 'labels', which may be empty for no labels at all. All
+    // initial blocks are used as splitters because the transition function
+    // is partial. Messages are compared by value, and there is at most one
+    // transition per message out of each state.
+    fn bisimilar_blocks(&self, labels: &[Option<&str>]) -> Vec<usize> {
+        let mut label_ids = HashMap::new();
+        let mut incoming = vec![vec![]; self.state_count()];
+        for (label, start, end) in self.iter_labelled_edges() {
+            let next_id = label_ids.len();
+            let id = *label_ids.entry(label).or_insert(next_id);
+            incoming[end.0 as usize].push((id, start.0 as usize));
+        }
+
+        let mut block_of = vec![0; self.state_count()];
+        let mut blocks: Vec<Vec<usize>> = vec![];
+        let mut block_ids = HashMap::new();
+        for state in self.iter_states() {
+            let key = (
+                self.is_final(state),
+                self.forks.contains_key(&state),
+                self.branch(state),
+                labels.get(state.0 as usize).copied().flatten(),
+            );
+            let block = *block_ids.entry(key).or_insert_with(|| {
+                blocks.push(vec![]);
+                blocks.len() - 1
+            });
+            block_of[state.0 as usize] = block;
+            blocks[block].push(state.0 as usize);
+        }
+
+        let mut pending = vec![true; blocks.len()];
+        let mut queue: Vec<usize> = (0..blocks.len()).collect();
+        let mut marked = vec![false; self.state_count()];
+        while let Some(splitter) = queue.pop() {
+            pending[splitter] = false;
+            let mut preds: HashMap<usize, Vec<usize>> = HashMap::new();
+            for state in &blocks[splitter] {
+                for (id, pred) in &incoming[*state] {
+                    preds.entry(*id).or_default().push(*pred);
+                }
+            }
+            for (_, preds) in preds {
+                let mut touched = vec![];
+                for pred in preds {
+                    if !marked[pred] {
+                        marked[pred] = true;
+                        touched.push(pred);
+                    }
+                }
+                let mut touched_blocks: HashMap<usize, Vec<usize>> = HashMap::new();
+                for state in touched {
+                    marked[state] = false;
+                    touched_blocks
+                        .entry(block_of[state])
+                        .or_default()
+                        .push(state);
+                }
+                for (block, inside) in touched_blocks {
+                    if inside.len() == blocks[block].len() {
+                        continue;
+                    }
+                    let new_block = blocks.len();
+                    for state in &inside {
+                        block_of[*state] = new_block;
+                    }
+                    blocks[block].retain(|state| block_of[*state] == block);
+                    blocks.push(inside);
+                    pending.push(false);
+                    if pending[block] || blocks[new_block].len() <= blocks[block].len() {
+                        pending[new_block] = true;
+                        queue.push(new_block);
+                    } else {
+                        pending[block] = true;
+                        queue.push(block);
+                    }
+                }
+            }
+        }
+        block_of
+    }
 }
 
 impl ProtocolFileStateMachines {
-    pub fn graph_viz(&self) -> GraphViz {
+    pub fn graph_viz(&self) -> GraphViz<'_> {
         GraphViz(self)
     }
 }
 
-#[derive(Debug, Clone, Copy)]
-pub struct StateName(State);
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct StateName(pub String);
+
+// Names the generated code uses unqualified next to the state types, which a
+// state that is not labelled must not be named, e.g. 'C' for the state after
+// a message 'c' from the start.
+const RESERVED_NAMES: &[&str] = &[
+    "AsyncChannel",
+    "Branch",
+    "C",
+    "Channel",
+    "E",
+    "Err",
+    "MuxError",
+    "Ok",
+    "RecvError",
+    "Result",
+    "SplitChannel",
+    "T",
+    "Vec",
+];
+
+impl StateMachine {
+    /// Names every state in CamelCase. Labelled states are named after their
+    /// label, and the others after the nearest labelled state or the start
+    /// followed by the last message on the shortest path to them, e.g.
+    /// 'ReadyPing' for the state after a 'PING' message from the state
+    /// labelled 'Ready', or 'ReadyAck' for the state after an 'ACK' that
+    /// follows it. Names after the start which is not labelled are just the
+    /// message label, and names that are already taken, including by the
+    /// types generated for other states, get a number appended.
+    pub fn state_names(&self) -> Vec<StateName> {
+        let mut names: Vec<Option<String>> = vec![None; self.state_count()];
+        let mut taken: HashSet<String> =
+            RESERVED_NAMES.iter().map(|name| name.to_string()).collect();
+        let mut queue = VecDeque::new();
+        for state in self.iter_states() {
+            if let Some(label) = self.label(state) {
+                let name = Camel(&label.inner.name).to_string();
+                names[state.0 as usize] = Some(name.clone());
+                taken.insert(name);
+            }
+        }
+        if self.contains_state(State(0)) {
+            if names[0].is_none() {
+                names[0] = Some(unique_name("Start".to_string(), &mut taken));
+            }
+            queue.push_back(State(0));
+        }
+        for state in self.iter_states().skip(1) {
+            if self.label(state).is_some() {
+                queue.push_back(state);
+            }
+        }
+
+        // The name of the nearest labelled state or the start, which the
+        // names of the states after it are built on.
+        let mut bases: Vec<String> = vec![String::new(); self.state_count()];
+        while let Some(state) = queue.pop_front() {
+            let base = match (state, self.label(state)) {
+                (_, Some(_)) => names[state.0 as usize].clone().unwrap(),
+                (State(0), None) => String::new(),
+                _ => bases[state.0 as usize].clone(),
+            };
+            let mut edges: Vec<_> = self
+                .iter_trans_from(state)
+                .map(|(msg, end)| (camel_case(&msg.inner.label), end))
+                .collect();
+            edges.sort_by(|(a, _), (b, _)| a.cmp(b));
+            if let Some(fork) = self.fork(state) {
+                for (index, branch) in fork.branches.iter().enumerate() {
+                    edges.push((format!("Branch{}", index), *branch));
+                }
+                edges.push(("Join".to_string(), fork.join));
+            }
+            for (step, end) in edges {
+                let name = &mut names[end.0 as usize];
+                if name.is_none() {
+                    let step = escape(format!("{}{}", base, step));
+                    *name = Some(unique_name(step, &mut taken));
+                    bases[end.0 as usize] = base.clone();
+                    queue.push_back(end);
+                }
+            }
+        }
+
+        let mut names: Vec<String> = names
+            .into_iter()
+            .enumerate()
+            .map(|(index, name)| {
+                name.unwrap_or_else(|| unique_name(format!("S{}", index), &mut taken))
+            })
+            .collect();
+
+        // A name can be the same as one of the types generated for another
+        // state, e.g. 'FooResponse' for the state after 'FooResponse' from
+        // the start when another state is named 'Foo', so those states get a
+        // number appended too. The new name must not clash with anything
+        // either, and neither may the types generated for it.
+        let generated: HashSet<String> = self
+            .iter_states()
+            .flat_map(|state| self.generated_names(state, &names[state.0 as usize]))
+            .collect();
+        taken.extend(generated.iter().cloned());
+        for state in self.iter_states() {
+            let index = state.0 as usize;
+            if !generated.contains(&names[index]) {
+                continue;
+            }
+            loop {
+                let name = unique_name(names[index].clone(), &mut taken);
+                let types = self.generated_names(state, &name);
+                if types.iter().all(|ty| !taken.contains(ty)) {
+                    taken.extend(types);
+                    names[index] = name;
+                    break;
+                }
+            }
+        }
+
+        names.into_iter().map(StateName).collect()
+    }
+    // The names of the types generated for a state besides its own.
+    fn generated_names(&self, state: State, name: &str) -> Vec<String> {
+        let mut names = vec![
+            format!("{}Response", name),
+            format!("{}Receiver", name),
+            format!("{}DefaultReceiver", name),
+        ];
+        if let Some(fork) = self.fork(state) {
+            names.extend((0..fork.branches.len()).map(|index| format!("{}Done{}", name, index)));
+        }
+        names
+    }
+}
 
-impl State {
-    pub fn name(&self) -> StateName {
-        StateName(*self)
+fn unique_name(name: String, taken: &mut HashSet<String>) -> String {
+    let mut unique = name.clone();
+    let mut index = 2;
+    while !taken.insert(unique.clone()) {
+        unique = format!("{}{}", name, index);
+        index += 1;
     }
+    unique
 }
 
 impl fmt::Display for StateName {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "S{}", self.0 .0)
+        write!(f, "{}", self.0)
     }
 }
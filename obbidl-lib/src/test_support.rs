@@ -0,0 +1,51 @@
+use std::{
+    future::Future,
+    pin::pin,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    task::{Context, Poll, Wake, Waker},
+};
+
+struct Flag(AtomicBool);
+
+impl Wake for Flag {
+    fn wake(self: Arc<Self>) {
+        self.0.store(true, Ordering::SeqCst);
+    }
+}
+
+// Runs the two ends of an 'AsyncTestChannel' to completion on the current
+// thread. They are polled again only after one of them woke the other, so a
+// round in which neither finishes nor wakes anything means both are waiting
+// on each other, which panics rather than spinning forever.
+pub fn run_both<A: Future, B: Future>(a: A, b: B) -> (A::Output, B::Output) {
+    let mut a = pin!(a);
+    let mut b = pin!(b);
+    let mut a_output = None;
+    let mut b_output = None;
+    let flag = Arc::new(Flag(AtomicBool::new(false)));
+    let waker = Waker::from(flag.clone());
+    let mut cx = Context::from_waker(&waker);
+    while a_output.is_none() || b_output.is_none() {
+        flag.0.store(false, Ordering::SeqCst);
+        let mut finished = false;
+        if a_output.is_none() {
+            if let Poll::Ready(output) = a.as_mut().poll(&mut cx) {
+                a_output = Some(output);
+                finished = true;
+            }
+        }
+        if b_output.is_none() {
+            if let Poll::Ready(output) = b.as_mut().poll(&mut cx) {
+                b_output = Some(output);
+                finished = true;
+            }
+        }
+        if !finished && !flag.0.load(Ordering::SeqCst) {
+            panic!("both futures are waiting on each other");
+        }
+    }
+    (a_output.unwrap(), b_output.unwrap())
+}
@@ -56,6 +56,7 @@ pub enum Symbol {
     Comma,
     OpenSquareBrace,
     CloseSquareBrace,
+    Equals,
 }
 
 impl Keyword {
@@ -98,6 +99,7 @@ impl Symbol {
             Symbol::Comma => ',',
             Symbol::OpenSquareBrace => '[',
             Symbol::CloseSquareBrace => ']',
+            Symbol::Equals => '=',
         }
     }
 }
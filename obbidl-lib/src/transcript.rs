@@ -0,0 +1,521 @@
+use std::{
+    collections::VecDeque,
+    error::Error,
+    fmt,
+    fs::File,
+    io::{self, BufRead, BufReader, BufWriter, Write},
+    path::Path,
+    thread,
+    time::{Duration, Instant},
+};
+
+use crate::channel::{AsyncChannel, Channel};
+
+// Transcripts are text, one record per line with the time since the
+// recording started, the direction and the data in hex:
+//
+//   0.000012 send 00000000000000ff
+//   0.001534 recv 0102
+//
+// Lines starting with '#' are comments.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Record {
+    pub time: Duration,
+    pub direction: Direction,
+    pub data: Vec<u8>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    Sent,
+    Received,
+}
+
+impl Direction {
+    fn as_str(self) -> &'static str {
+        match self {
+            Direction::Sent => "send",
+            Direction::Received => "recv",
+        }
+    }
+}
+
+impl fmt::Display for Record {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}.{:06} {} ",
+            self.time.as_secs(),
+            self.time.subsec_micros(),
+            self.direction.as_str()
+        )?;
+        for byte in &self.data {
+            write!(f, "{:02x}", byte)?;
+        }
+        Ok(())
+    }
+}
+
+impl Record {
+    fn parse(line: &str) -> Option<Record> {
+        let mut parts = line.split_whitespace();
+        let (secs, micros) = parts.next()?.split_once('.')?;
+        if micros.len() != 6 {
+            return None;
+        }
+        let time =
+            Duration::from_secs(secs.parse().ok()?) + Duration::from_micros(micros.parse().ok()?);
+        let direction = match parts.next()? {
+            "send" => Direction::Sent,
+            "recv" => Direction::Received,
+            _ => return None,
+        };
+        let hex = parts.next()?;
+        if parts.next().is_some() || hex.len() % 2 != 0 || !hex.is_ascii() {
+            return None;
+        }
+        let data = (0..hex.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).ok())
+            .collect::<Option<_>>()?;
+        Some(Record {
+            time,
+            direction,
+            data,
+        })
+    }
+}
+
+pub fn read_transcript(reader: impl BufRead) -> io::Result<Vec<Record>> {
+    let mut records = vec![];
+    for (i, line) in reader.lines().enumerate() {
+        let line = line?;
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let record = Record::parse(line).ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("invalid record on line {}", i + 1),
+            )
+        })?;
+        records.push(record);
+    }
+    Ok(records)
+}
+
+// Records everything sent and received on a channel. Each record is flushed
+// as soon as it is written, so the transcript is complete even if the
+// program crashes right after.
+pub struct Recorder<C, W: Write> {
+    channel: C,
+    output: W,
+    start: Instant,
+}
+
+#[derive(Debug)]
+pub enum RecordError<E> {
+    Channel(E),
+    Io(io::Error),
+}
+
+impl<E> From<E> for RecordError<E> {
+    fn from(err: E) -> RecordError<E> {
+        RecordError::Channel(err)
+    }
+}
+
+impl<E: fmt::Display> fmt::Display for RecordError<E> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RecordError::Channel(err) => write!(f, "{}", err),
+            RecordError::Io(err) => write!(f, "cannot write transcript: {}", err),
+        }
+    }
+}
+
+impl<E: Error> Error for RecordError<E> {}
+
+impl<C> Recorder<C, BufWriter<File>> {
+    pub fn create(channel: C, path: impl AsRef<Path>) -> io::Result<Recorder<C, BufWriter<File>>> {
+        Ok(Recorder::new(channel, BufWriter::new(File::create(path)?)))
+    }
+}
+
+impl<C, W: Write> Recorder<C, W> {
+    pub fn new(channel: C, output: W) -> Recorder<C, W> {
+        Recorder {
+            channel,
+            output,
+            start: Instant::now(),
+        }
+    }
+    pub fn into_inner(self) -> (C, W) {
+        (self.channel, self.output)
+    }
+
+    fn record<E>(&mut self, direction: Direction, data: &[u8]) -> Result<(), RecordError<E>> {
+        if data.is_empty() {
+            return Ok(());
+        }
+        let record = Record {
+            time: self.start.elapsed(),
+            direction,
+            data: data.to_vec(),
+        };
+        writeln!(self.output, "{}", record)
+            .and_then(|()| self.output.flush())
+            .map_err(RecordError::Io)
+    }
+}
+
+impl<C: Channel, W: Write> Channel for Recorder<C, W> {
+    type Error = RecordError<C::Error>;
+
+    const MAX_LENGTH: u32 = C::MAX_LENGTH;
+
+    fn recv(&mut self, data: &mut [u8]) -> Result<(), Self::Error> {
+        self.channel.recv(data)?;
+        self.record(Direction::Received, data)
+    }
+
+    fn send(&mut self, data: &[u8]) -> Result<(), Self::Error> {
+        self.channel.send(data)?;
+        self.record(Direction::Sent, data)
+    }
+}
+
+impl<C: AsyncChannel, W: Write> AsyncChannel for Recorder<C, W> {
+    type Error = RecordError<C::Error>;
+
+    const MAX_LENGTH: u32 = C::MAX_LENGTH;
+
+    async fn recv(&mut self, data: &mut [u8]) -> Result<(), Self::Error> {
+        self.channel.recv(data).await?;
+        self.record(Direction::Received, data)
+    }
+
+    async fn send(&mut self, data: &[u8]) -> Result<(), Self::Error> {
+        self.channel.send(data).await?;
+        self.record(Direction::Sent, data)
+    }
+}
+
+// Plays the other end of a recorded session: receives return the data that
+// was received in the recording, and sends have to match the data that was
+// sent. Records in the same direction run together, so the endpoint doesn't
+// have to send and receive in blocks of the same sizes as when it was
+// recorded, only the same bytes in the same order. Timestamps are ignored.
+pub struct Replay {
+    records: VecDeque<Record>,
+    // The index of the first record in 'records' in the transcript, and the
+    // number of its bytes that have been replayed.
+    record: usize,
+    offset: usize,
+    // Set once the end has been checked or a divergence returned, after
+    // which dropping the replay is fine.
+    checked: bool,
+    check_on_drop: bool,
+}
+
+// What the endpoint did, or what the recording has, at a point in the
+// transcript.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Event {
+    Send(u8),
+    Recv,
+    End,
+}
+
+// The first point where the endpoint did something else than what was
+// recorded. 'record' is the index of the record in the transcript and
+// 'offset' the index of the byte in it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ReplayError {
+    pub record: usize,
+    pub offset: usize,
+    pub expected: Event,
+    pub found: Event,
+}
+
+impl fmt::Display for Event {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Event::Send(byte) => write!(f, "a send of 0x{:02x}", byte),
+            Event::Recv => write!(f, "a receive"),
+            Event::End => write!(f, "the end of the session"),
+        }
+    }
+}
+
+impl fmt::Display for ReplayError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "replay diverged at byte {} of record {}: expected {}, found {}",
+            self.offset, self.record, self.expected, self.found
+        )
+    }
+}
+
+impl Error for ReplayError {}
+
+impl Replay {
+    pub fn new(records: Vec<Record>) -> Replay {
+        Replay {
+            records: records.into(),
+            record: 0,
+            offset: 0,
+            checked: false,
+            check_on_drop: false,
+        }
+    }
+    pub fn open(path: impl AsRef<Path>) -> io::Result<Replay> {
+        let file = BufReader::new(File::open(path)?);
+        Ok(Replay::new(read_transcript(file)?))
+    }
+    // Panics when the replay is dropped before the end of the recording
+    // without an error having been returned, for tests of endpoints that
+    // consume the channel and so can't call 'finish'.
+    pub fn check_on_drop(mut self) -> Replay {
+        self.check_on_drop = true;
+        self
+    }
+    // Checks that the endpoint went through the whole recording.
+    pub fn finish(mut self) -> Result<(), ReplayError> {
+        self.next(Event::End).map(|_| ())
+    }
+
+    // Replays the next byte, which the endpoint either sends or receives as
+    // given by 'found'.
+    fn next(&mut self, found: Event) -> Result<u8, ReplayError> {
+        while self
+            .records
+            .front()
+            .is_some_and(|record| record.data.is_empty())
+        {
+            self.records.pop_front();
+            self.record += 1;
+        }
+        let expected = match self.records.front() {
+            Some(record) => match record.direction {
+                Direction::Sent => Event::Send(record.data[self.offset]),
+                Direction::Received => Event::Recv,
+            },
+            None => Event::End,
+        };
+        if expected != found || found == Event::End {
+            self.checked = true;
+        }
+        if expected != found {
+            return Err(ReplayError {
+                record: self.record,
+                offset: self.offset,
+                expected,
+                found,
+            });
+        }
+        let Some(record) = self.records.front() else {
+            return Ok(0);
+        };
+        let byte = record.data[self.offset];
+        self.offset += 1;
+        if self.offset == record.data.len() {
+            self.records.pop_front();
+            self.record += 1;
+            self.offset = 0;
+        }
+        Ok(byte)
+    }
+    fn recv_bytes(&mut self, data: &mut [u8]) -> Result<(), ReplayError> {
+        for byte in data {
+            *byte = self.next(Event::Recv)?;
+        }
+        Ok(())
+    }
+    fn send_bytes(&mut self, data: &[u8]) -> Result<(), ReplayError> {
+        for &byte in data {
+            self.next(Event::Send(byte))?;
+        }
+        Ok(())
+    }
+}
+
+impl Drop for Replay {
+    fn drop(&mut self) {
+        if !self.check_on_drop || self.checked || thread::panicking() {
+            return;
+        }
+        if let Err(err) = self.next(Event::End) {
+            panic!("{}", err);
+        }
+    }
+}
+
+impl Channel for Replay {
+    type Error = ReplayError;
+
+    fn recv(&mut self, data: &mut [u8]) -> Result<(), Self::Error> {
+        self.recv_bytes(data)
+    }
+
+    fn send(&mut self, data: &[u8]) -> Result<(), Self::Error> {
+        self.send_bytes(data)
+    }
+}
+
+impl AsyncChannel for Replay {
+    type Error = ReplayError;
+
+    async fn recv(&mut self, data: &mut [u8]) -> Result<(), Self::Error> {
+        self.recv_bytes(data)
+    }
+
+    async fn send(&mut self, data: &[u8]) -> Result<(), Self::Error> {
+        self.send_bytes(data)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use crate::channel::{Channel, TestChannel};
+
+    use super::{read_transcript, Direction, Event, Record, Recorder, Replay, ReplayError};
+
+    // Records a session of 'a' with 'b' answering, and returns the
+    // transcript.
+    fn record() -> String {
+        let (a, mut b) = TestChannel::new();
+        let mut a = Recorder::new(a, vec![]);
+        a.send(&[1, 2]).unwrap();
+        a.send_u8(3).unwrap();
+        b.send(&[4, 5, 6]).unwrap();
+        let mut data = [0; 2];
+        a.recv(&mut data).unwrap();
+        assert_eq!(a.recv_u8().unwrap(), 6);
+        a.send(&[]).unwrap();
+        let (_, output) = a.into_inner();
+        String::from_utf8(output).unwrap()
+    }
+
+    #[test]
+    fn test_record() {
+        let transcript = record();
+        let records = read_transcript(transcript.as_bytes()).unwrap();
+        let directions: Vec<_> = records.iter().map(|record| record.direction).collect();
+        assert_eq!(
+            directions,
+            [
+                Direction::Sent,
+                Direction::Sent,
+                Direction::Received,
+                Direction::Received
+            ]
+        );
+        let data: Vec<_> = records.iter().map(|record| record.data.clone()).collect();
+        assert_eq!(data, [vec![1, 2], vec![3], vec![4, 5], vec![6]]);
+        assert!(records.windows(2).all(|pair| pair[0].time <= pair[1].time));
+    }
+
+    #[test]
+    fn test_transcript_format() {
+        let record = Record {
+            time: Duration::from_micros(1_000_042),
+            direction: Direction::Received,
+            data: vec![0, 0xab],
+        };
+        assert_eq!(record.to_string(), "1.000042 recv 00ab");
+        let transcript = format!("# comment\n\n{}\n", record);
+        assert_eq!(read_transcript(transcript.as_bytes()).unwrap(), [record]);
+
+        for line in [
+            "1 send 00",
+            "1.5 send 00",
+            "1.000000 sent 00",
+            "1.000000 send 0",
+            "1.000000 send zz",
+            "1.000000 send",
+            "1.000000 send 00 00",
+        ] {
+            assert!(read_transcript(line.as_bytes()).is_err(), "{}", line);
+        }
+    }
+
+    // With 'check_on_drop', dropping the replay checks for the end of the
+    // transcript like 'finish' does.
+    #[test]
+    #[should_panic(expected = "expected a send of 0x02, found the end of the session")]
+    fn test_replay_stopped_early() {
+        let records = read_transcript(record().as_bytes()).unwrap();
+        let mut replay = Replay::new(records).check_on_drop();
+        replay.send_u8(1).unwrap();
+    }
+
+    #[test]
+    fn test_replay_dropped() {
+        let records = read_transcript(record().as_bytes()).unwrap();
+        let mut replay = Replay::new(records);
+        replay.send_u8(1).unwrap();
+    }
+
+    #[test]
+    fn test_replay() {
+        let records = read_transcript(record().as_bytes()).unwrap();
+
+        // The same bytes in different blocks replay fine.
+        let mut replay = Replay::new(records.clone());
+        replay.send(&[1]).unwrap();
+        replay.send(&[2, 3]).unwrap();
+        let mut data = [0; 3];
+        replay.recv(&mut data).unwrap();
+        assert_eq!(data, [4, 5, 6]);
+        replay.finish().unwrap();
+
+        let mut replay = Replay::new(records.clone());
+        replay.send_u8(1).unwrap();
+        assert_eq!(
+            replay.send(&[2, 4]),
+            Err(ReplayError {
+                record: 1,
+                offset: 0,
+                expected: Event::Send(3),
+                found: Event::Send(4),
+            })
+        );
+
+        let mut replay = Replay::new(records.clone());
+        assert_eq!(
+            replay.recv_u8(),
+            Err(ReplayError {
+                record: 0,
+                offset: 0,
+                expected: Event::Send(1),
+                found: Event::Recv,
+            })
+        );
+
+        let mut replay = Replay::new(records);
+        replay.send(&[1, 2, 3]).unwrap();
+        replay.recv_u8().unwrap();
+        let err = replay.finish().unwrap_err();
+        assert_eq!((err.record, err.offset), (2, 1));
+        assert_eq!(err.expected, Event::Recv);
+        assert_eq!(err.found, Event::End);
+        assert_eq!(
+            err.to_string(),
+            "replay diverged at byte 1 of record 2: expected a receive, found the end of the session"
+        );
+
+        let mut replay = Replay::new(vec![]);
+        assert!(matches!(
+            replay.send_u8(1),
+            Err(ReplayError {
+                expected: Event::End,
+                ..
+            })
+        ));
+    }
+}
@@ -1,12 +1,18 @@
-use std::{collections::HashSet, fmt, rc::Rc};
+use std::{
+    collections::{HashMap, HashSet},
+    fmt,
+    hash::Hash,
+    rc::Rc,
+};
 
 use colored::Colorize;
 
 use crate::{
     ast,
     compile::{ProtocolFileStateMachines, ProtocolStateMachine},
+    naming::{camel_case, snake_case},
     parser::Span,
-    state_machine::StateName,
+    state_machine::{self, StateName},
 };
 
 #[derive(Debug, Clone)]
@@ -27,11 +33,27 @@ pub struct Protocol {
 pub struct State {
     pub name: StateName,
     pub trans: Option<Transitions>,
+    pub fork: Option<Fork>,
+    pub branch: Option<Branch>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Fork {
+    pub branches: Vec<StateName>,
+    pub join: StateName,
+}
+
+#[derive(Debug, Clone)]
+pub struct Branch {
+    pub fork: StateName,
+    pub index: usize,
+    pub may_end: bool,
 }
 
 #[derive(Debug, Clone)]
 pub struct Transitions {
     pub dir: Direction,
+    pub tag: ast::IntType,
     pub messages: Vec<Message>,
 }
 
@@ -50,7 +72,7 @@ pub enum SimpleRole {
 #[derive(Debug, Clone)]
 pub struct Message {
     pub label: String,
-    pub id: u8,
+    pub id: u64,
     pub payload: Payload,
     pub dest_state_name: StateName,
 }
@@ -80,6 +102,42 @@ pub fn validate_protocol_file<'a>(
 ) -> Result<File, Vec<Error<'a>>> {
     let mut errors = vec![];
 
+    for (index, struct_) in structs.iter().enumerate() {
+        if let Some(first) = structs[..index]
+            .iter()
+            .find(|first| camel_case(&first.inner.name) == camel_case(&struct_.inner.name))
+        {
+            errors.push(Error::DuplicateStruct {
+                first,
+                second: struct_,
+            });
+        }
+        if let Some((first, second)) = find_collision(
+            struct_
+                .inner
+                .fields
+                .iter()
+                .map(|field| (snake_case(&field.inner.name), field)),
+        ) {
+            errors.push(Error::StructError {
+                struct_,
+                err: StructError::DuplicateField { first, second },
+            });
+        }
+    }
+
+    for (index, protocol) in file.protocols.iter().enumerate() {
+        if let Some(first) = file.protocols[..index]
+            .iter()
+            .find(|first| snake_case(&first.inner.name) == snake_case(&protocol.inner.name))
+        {
+            errors.push(Error::DuplicateProtocol {
+                first,
+                second: protocol,
+            });
+        }
+    }
+
     let mut output_structs = vec![];
     for struct_ in structs {
         match validate_struct(
@@ -101,7 +159,7 @@ pub fn validate_protocol_file<'a>(
         }
     }
 
-    if errors.len() > 0 {
+    if !errors.is_empty() {
         return Err(errors);
     }
 
@@ -111,30 +169,93 @@ pub fn validate_protocol_file<'a>(
     })
 }
 
+fn find_duplicate<T: Eq + Hash + Copy>(items: impl Iterator<Item = T>) -> Option<T> {
+    let mut seen = HashSet::new();
+    items.into_iter().find(|item| !seen.insert(*item))
+}
+
+// Names are converted before they are used as Rust identifiers, so names
+// that are different in the protocol file can still clash, e.g. 'getValue'
+// and 'get_value'. Takes the converted names and returns the first two
+// items with the same one.
+fn find_collision<T: Copy>(items: impl Iterator<Item = (String, T)>) -> Option<(T, T)> {
+    let mut seen = HashMap::new();
+    for (name, item) in items {
+        if let Some(first) = seen.insert(name, item) {
+            return Some((first, item));
+        }
+    }
+    None
+}
+
+// Labels become both method names and enum variants.
+fn same_label(a: &str, b: &str) -> bool {
+    snake_case(a) == snake_case(b) || camel_case(a) == camel_case(b)
+}
+
+// Messages without an explicit id are numbered in the order of their labels,
+// skipping the ids given explicitly, so reordering the branches of a choice
+// doesn't change the wire format. Adding a message still can, which the
+// 'mixed_ids' lint warns about where other ids are explicit.
+fn assign_implicit_ids(messages: &mut [Message], explicit_ids: &[Option<u64>]) {
+    let used_ids: HashSet<_> = explicit_ids.iter().flatten().collect();
+    let mut implicit: Vec<_> = (0..messages.len())
+        .filter(|index| explicit_ids[*index].is_none())
+        .collect();
+    implicit.sort_by(|a, b| messages[*a].label.cmp(&messages[*b].label));
+    let mut free_ids = (0..).filter(|id| !used_ids.contains(id));
+    for index in implicit {
+        messages[index].id = free_ids.next().unwrap();
+    }
+}
+
+// The smallest unsigned integer that can hold the id of every message.
+fn tag_type(messages: &[Message]) -> ast::IntType {
+    let max_id = messages.iter().map(|msg| msg.id).max().unwrap_or(0);
+    let size = if max_id <= u8::MAX as u64 {
+        ast::IntSize::B8
+    } else if max_id <= u16::MAX as u64 {
+        ast::IntSize::B16
+    } else if max_id <= u32::MAX as u64 {
+        ast::IntSize::B32
+    } else {
+        ast::IntSize::B64
+    };
+    ast::IntType {
+        signed: false,
+        size,
+    }
+}
+
 pub fn validate_struct<'a>(
     name: &'a str,
     structs: &'a [Span<ast::Struct>],
     previous_structs: &mut HashSet<&'a str>,
     output_structs: &mut Vec<Rc<Struct>>,
 ) -> Result<Rc<Struct>, StructError<'a>> {
+    if let Some(struct_) = output_structs.iter().find(|struct_| struct_.name == name) {
+        return Ok(Rc::clone(struct_));
+    }
     if !previous_structs.insert(name) {
         return Err(StructError::RecursiveStruct(name));
     }
     let fields = structs
         .iter()
-        .find(|struct_| &struct_.inner.name == name)
+        .find(|struct_| struct_.inner.name == name)
         .ok_or(StructError::UndefinedStruct(name))?
         .inner
         .fields
         .iter()
-        .map(|(name, ty)| {
+        .map(|field| {
             Ok((
-                name.clone(),
-                validate_type(ty, structs, previous_structs, output_structs)?,
+                field.inner.name.clone(),
+                validate_type(&field.inner.ty, structs, previous_structs, output_structs)?,
             ))
         })
         .collect::<Result<_, _>>()?;
 
+    previous_structs.remove(name);
+
     let struct_ = Rc::new(Struct {
         name: name.to_string(),
         fields,
@@ -156,7 +277,7 @@ pub fn validate_type<'a>(
         ast::Type::Int(ty) => Type::Int(*ty),
         ast::Type::Array(ty, size) => Type::Array(
             Box::new(validate_type(
-                &ty,
+                ty,
                 structs,
                 previous_structs,
                 output_structs,
@@ -179,9 +300,7 @@ pub fn validate_type_ref<'a>(
     Ok(match ty {
         ast::Type::Bool => Type::Bool,
         ast::Type::Int(ty) => Type::Int(*ty),
-        ast::Type::Array(ty, size) => {
-            Type::Array(Box::new(validate_type_ref(&ty, structs)?), *size)
-        }
+        ast::Type::Array(ty, size) => Type::Array(Box::new(validate_type_ref(ty, structs)?), *size),
         ast::Type::Struct(name) => Type::Struct(Rc::clone(
             structs
                 .iter()
@@ -201,6 +320,14 @@ pub enum Error<'a> {
         struct_: &'a Span<ast::Struct>,
         err: StructError<'a>,
     },
+    DuplicateProtocol {
+        first: &'a Span<ProtocolStateMachine>,
+        second: &'a Span<ProtocolStateMachine>,
+    },
+    DuplicateStruct {
+        first: &'a Span<ast::Struct>,
+        second: &'a Span<ast::Struct>,
+    },
 }
 
 pub struct PrettyPrintError<'a> {
@@ -214,6 +341,19 @@ pub enum ProtocolError<'a> {
     InvalidDirection(&'a Span<ast::Message>),
     MixedDirections(Vec<&'a Span<ast::Message>>),
     RepeatedLabel(Vec<&'a Span<ast::Message>>),
+    DuplicatePayloadItem {
+        msg: &'a Span<ast::Message>,
+        first: &'a Span<ast::PayloadItem>,
+        second: &'a Span<ast::PayloadItem>,
+        // As it is named in the generated code, unnamed items are 'param0'
+        // and so on.
+        name: String,
+    },
+    ReservedPayloadItem(&'a Span<ast::Message>),
+    ReservedLabel(&'a Span<ast::Message>),
+    DuplicateMessageId(Vec<&'a Span<ast::Message>>, u64),
+    DuplicateStateLabel(&'a Span<ast::StateLabel>, &'a Span<ast::StateLabel>),
+    DuplicateRole(String),
     UndefinedStruct(&'a str),
 }
 
@@ -221,6 +361,10 @@ pub enum ProtocolError<'a> {
 pub enum StructError<'a> {
     UndefinedStruct(&'a str),
     RecursiveStruct(&'a str),
+    DuplicateField {
+        first: &'a Span<ast::Field>,
+        second: &'a Span<ast::Field>,
+    },
 }
 
 impl<'a> Error<'a> {
@@ -270,13 +414,89 @@ impl<'a> fmt::Display for PrettyPrintError<'a> {
                             "info: make sure all the messages have the same roles in the 'from' and 'to' section"
                         )?;
                     }
+                    ProtocolError::RepeatedLabel(messages)
+                        if messages
+                            .iter()
+                            .all(|msg| msg.inner.label == messages[0].inner.label) =>
+                    {
+                        writeln!(f, "info: the following messages are part of the same decision state and have the same label but a different payload or id:")?;
+                        for msg in messages {
+                            write!(f, "{}", msg.pretty_print(self.source))?;
+                        }
+                        writeln!(f, "info: either give the messages the same payload and id or rename the message labels so they are unique")?;
+                    }
                     ProtocolError::RepeatedLabel(messages) => {
-                        writeln!(f, "info: the following messages are part of the same decision state but have the same label:")?;
+                        writeln!(f, "info: the following messages are part of the same decision state and their labels are the same once converted to Rust names:")?;
                         for msg in messages {
                             write!(f, "{}", msg.pretty_print(self.source))?;
                         }
                         writeln!(f, "info: rename the message labels so they are unique")?;
                     }
+                    ProtocolError::DuplicatePayloadItem {
+                        msg,
+                        first,
+                        second,
+                        name,
+                    } => {
+                        writeln!(
+                            f,
+                            "info: message '{}' has more than one payload item named '{}' in the generated code:",
+                            msg.inner.label, name
+                        )?;
+                        write!(f, "{}", first.pretty_print(self.source))?;
+                        writeln!(f, "info: it is used again here:")?;
+                        write!(f, "{}", second.pretty_print(self.source))?;
+                        writeln!(f, "info: rename the payload items so they are unique")?;
+                    }
+                    ProtocolError::ReservedPayloadItem(msg) => {
+                        writeln!(
+                            f,
+                            "info: the following message has a payload item named 'state' in the generated code, which is the name of the state it goes to"
+                        )?;
+                        write!(f, "{}", msg.pretty_print(self.source))?;
+                        writeln!(f, "info: rename the payload item")?;
+                    }
+                    ProtocolError::ReservedLabel(msg) => {
+                        writeln!(
+                            f,
+                            "info: the following message is named 'Done' in the generated code, which is the name of the response for the end of the branch it is in"
+                        )?;
+                        write!(f, "{}", msg.pretty_print(self.source))?;
+                        writeln!(f, "info: rename the message label")?;
+                    }
+                    ProtocolError::DuplicateMessageId(messages, id) => {
+                        writeln!(f, "info: the following messages are part of the same decision state and have the same id {}:", id)?;
+                        for msg in messages {
+                            write!(f, "{}", msg.pretty_print(self.source))?;
+                        }
+                        writeln!(f, "info: give each message a unique id, or leave it out to pick one automatically")?;
+                    }
+                    ProtocolError::DuplicateStateLabel(first, second) => {
+                        if first.inner.name == second.inner.name {
+                            writeln!(
+                                f,
+                                "info: the label '{}' names two different states:",
+                                first.inner.name
+                            )?;
+                        } else {
+                            writeln!(
+                                f,
+                                "info: the labels '{}' and '{}' name two different states but are the same once converted to Rust names:",
+                                first.inner.name, second.inner.name
+                            )?;
+                        }
+                        write!(f, "{}", first.pretty_print(self.source))?;
+                        write!(f, "{}", second.pretty_print(self.source))?;
+                        writeln!(f, "info: rename one of the labels so the names are unique")?;
+                    }
+                    ProtocolError::DuplicateRole(name) => {
+                        writeln!(
+                            f,
+                            "info: both roles of the protocol above are named '{}' in the generated code",
+                            name
+                        )?;
+                        writeln!(f, "info: rename one of the roles so the names are unique")?;
+                    }
                     ProtocolError::UndefinedStruct(name) => {
                         writeln!(
                             f,
@@ -298,6 +518,17 @@ impl<'a> fmt::Display for PrettyPrintError<'a> {
                         writeln!(f, "info: the struct below contains a recursive definition")?;
                         writeln!(f, "info: remove the recursive definition")?;
                     }
+                    StructError::DuplicateField { first, second } => {
+                        writeln!(
+                            f,
+                            "info: the struct above has more than one field named '{}' in the generated code:",
+                            snake_case(&first.inner.name)
+                        )?;
+                        write!(f, "{}", first.pretty_print(self.source))?;
+                        writeln!(f, "info: it is defined again here:")?;
+                        write!(f, "{}", second.pretty_print(self.source))?;
+                        writeln!(f, "info: rename the fields so they are unique")?;
+                    }
                     StructError::UndefinedStruct(name) => {
                         writeln!(
                             f,
@@ -311,6 +542,51 @@ impl<'a> fmt::Display for PrettyPrintError<'a> {
                     }
                 }
             }
+            Error::DuplicateProtocol { first, second } => {
+                if first.inner.name == second.inner.name {
+                    writeln!(
+                        f,
+                        "the protocol '{}' is defined more than once",
+                        &first.inner.name
+                    )?;
+                } else {
+                    writeln!(
+                        f,
+                        "the protocols '{}' and '{}' are both named '{}' in the generated code",
+                        &first.inner.name,
+                        &second.inner.name,
+                        snake_case(&first.inner.name)
+                    )?;
+                }
+                write!(f, "{}", first.pretty_print(self.source))?;
+                writeln!(f, "info: it is defined again here:")?;
+                write!(f, "{}", second.pretty_print(self.source))?;
+                writeln!(
+                    f,
+                    "info: rename one of the protocols so the names are unique"
+                )?;
+            }
+            Error::DuplicateStruct { first, second } => {
+                if first.inner.name == second.inner.name {
+                    writeln!(
+                        f,
+                        "the struct '{}' is defined more than once",
+                        &first.inner.name
+                    )?;
+                } else {
+                    writeln!(
+                        f,
+                        "the structs '{}' and '{}' are both named '{}' in the generated code",
+                        &first.inner.name,
+                        &second.inner.name,
+                        camel_case(&first.inner.name)
+                    )?;
+                }
+                write!(f, "{}", first.pretty_print(self.source))?;
+                writeln!(f, "info: it is defined again here:")?;
+                write!(f, "{}", second.pretty_print(self.source))?;
+                writeln!(f, "info: rename one of the structs so the names are unique")?;
+            }
         };
         Ok(())
     }
@@ -328,14 +604,29 @@ pub fn validate_protocol<'a>(
 
     let a = protocol.roles[0].clone();
     let b = protocol.roles[1].clone();
+    if snake_case(&a.0) == snake_case(&b.0) {
+        return Err(ProtocolError::DuplicateRole(snake_case(&a.0)));
+    }
 
+    let mut labels: HashMap<String, &Span<ast::StateLabel>> = HashMap::new();
     for state in protocol.state_machine.iter_states() {
+        if let Some(label) = protocol.state_machine.label(state) {
+            if let Some(first) = labels.insert(camel_case(&label.inner.name), label) {
+                return Err(ProtocolError::DuplicateStateLabel(first, label));
+            }
+        }
+    }
+
+    let names = protocol.state_machine.state_names();
+    let name = |state: state_machine::State| names[state.0 as usize].clone();
+    for state in protocol.state_machine.iter_states() {
+        let may_end = protocol.state_machine.branch(state).is_some()
+            && protocol.state_machine.is_final(state);
         let mut overall_dir = None;
-        let mut labels = HashSet::new();
+        let mut labels: Vec<&str> = vec![];
         let mut messages = vec![];
 
-        for (index, (msg, final_state)) in protocol.state_machine.iter_trans_from(state).enumerate()
-        {
+        for (msg, final_state) in protocol.state_machine.iter_trans_from(state) {
             let dir = if msg.inner.from == a && msg.inner.to == b {
                 Direction::AToB
             } else if msg.inner.from == b && msg.inner.to == a {
@@ -358,12 +649,15 @@ pub fn validate_protocol<'a>(
                 None => overall_dir = Some(dir),
             }
 
-            if !labels.insert(msg.inner.label.as_str()) {
+            if labels
+                .iter()
+                .any(|label| same_label(label, &msg.inner.label))
+            {
                 let msgs = protocol
                     .state_machine
                     .iter_trans_from(state)
                     .filter_map(|(m, _)| {
-                        if m.inner.label == msg.inner.label {
+                        if same_label(&m.inner.label, &msg.inner.label) {
                             Some(m)
                         } else {
                             None
@@ -372,8 +666,14 @@ pub fn validate_protocol<'a>(
                     .collect();
                 return Err(ProtocolError::RepeatedLabel(msgs));
             }
+            labels.push(&msg.inner.label);
+            // The receiver of a branch that may end gets a 'Done' response
+            // when it does.
+            if may_end && camel_case(&msg.inner.label) == "Done" {
+                return Err(ProtocolError::ReservedLabel(msg));
+            }
 
-            let items = msg
+            let items: Vec<(String, Type)> = msg
                 .inner
                 .payload
                 .items
@@ -381,28 +681,75 @@ pub fn validate_protocol<'a>(
                 .enumerate()
                 .map(|(index, item)| {
                     Ok((
-                        item.name
+                        item.inner
+                            .name
                             .clone()
                             .unwrap_or_else(|| format!("param{}", index)),
-                        validate_type_ref(&item.ty, structs)?,
+                        validate_type_ref(&item.inner.ty, structs)?,
                     ))
                 })
                 .collect::<Result<_, _>>()?;
 
+            if let Some(((name, first), (_, second))) = find_collision(
+                items
+                    .iter()
+                    .zip(&msg.inner.payload.items)
+                    .map(|((name, _), item)| (snake_case(name), (name.as_str(), item))),
+            ) {
+                return Err(ProtocolError::DuplicatePayloadItem {
+                    msg,
+                    first,
+                    second,
+                    name: snake_case(name),
+                });
+            }
+            // Payload items are next to the state in the responses and the
+            // arguments of the receivers.
+            if items.iter().any(|(name, _)| snake_case(name) == "state") {
+                return Err(ProtocolError::ReservedPayloadItem(msg));
+            }
+
             messages.push(Message {
                 label: msg.inner.label.clone(),
-                id: index as u8,
+                id: msg.inner.id.unwrap_or_default(),
                 payload: Payload { items },
-                dest_state_name: final_state.name(),
+                dest_state_name: name(final_state),
             })
         }
 
+        let explicit_ids: Vec<_> = protocol
+            .state_machine
+            .iter_trans_from(state)
+            .map(|(msg, _)| msg.inner.id)
+            .collect();
+        if let Some(id) = find_duplicate(explicit_ids.iter().flatten()) {
+            let msgs = protocol
+                .state_machine
+                .iter_trans_from(state)
+                .filter(|(msg, _)| msg.inner.id == Some(*id))
+                .map(|(msg, _)| msg)
+                .collect();
+            return Err(ProtocolError::DuplicateMessageId(msgs, *id));
+        }
+        assign_implicit_ids(&mut messages, &explicit_ids);
+
+        let state_machine = &protocol.state_machine;
         states.push(State {
-            name: state.name(),
-            trans: match overall_dir {
-                Some(dir) => Some(Transitions { dir, messages }),
-                None => None,
-            },
+            name: name(state),
+            trans: overall_dir.map(|dir| Transitions {
+                dir,
+                tag: tag_type(&messages),
+                messages,
+            }),
+            fork: state_machine.fork(state).map(|fork| Fork {
+                branches: fork.branches.iter().map(|branch| name(*branch)).collect(),
+                join: name(fork.join),
+            }),
+            branch: state_machine.branch(state).map(|(fork, index)| Branch {
+                fork: name(fork),
+                index,
+                may_end: state_machine.is_final(state),
+            }),
         })
     }
 
@@ -413,3 +760,287 @@ pub fn validate_protocol<'a>(
         states,
     })
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        ast,
+        compile::{compile_protocol_file, Limits},
+        parser::parse,
+        report::Report,
+    };
+
+    use super::{validate_protocol_file, Error, ProtocolError, StructError};
+
+    fn validate_errors(source: &str, check: impl FnOnce(Vec<Error>)) {
+        let file = parse::<ast::File>(source).report();
+        let file_fsm = compile_protocol_file(&file, &Limits::default()).unwrap();
+        match validate_protocol_file(&file_fsm, &file.structs) {
+            Ok(_) => panic!("expected validation to fail"),
+            Err(errors) => check(errors),
+        }
+    }
+
+    #[test]
+    fn test_duplicate_protocol() {
+        validate_errors(
+            "protocol X { A from C to S; } protocol X { B from C to S; }",
+            |errors| {
+                assert_eq!(errors.len(), 1);
+                let Error::DuplicateProtocol { first, second } = &errors[0] else {
+                    panic!("expected a duplicate protocol error")
+                };
+                assert_eq!(first.span.start.offset, 0);
+                assert_eq!(second.span.start.offset, 30);
+            },
+        );
+    }
+
+    #[test]
+    fn test_duplicate_struct() {
+        validate_errors("struct P { x: u8 } struct P { y: u8 }", |errors| {
+            assert_eq!(errors.len(), 1);
+            let Error::DuplicateStruct { first, second } = &errors[0] else {
+                panic!("expected a duplicate struct error")
+            };
+            assert_eq!(first.inner.fields[0].inner.name, "x");
+            assert_eq!(second.inner.fields[0].inner.name, "y");
+        });
+    }
+
+    #[test]
+    fn test_shared_struct() {
+        let source =
+            "struct P { x: u8 } struct L { a: struct P, b: struct P } struct M { p: struct P }";
+        let file = parse::<ast::File>(source).report();
+        let file_fsm = compile_protocol_file(&file, &Limits::default()).unwrap();
+        let file = validate_protocol_file(&file_fsm, &file.structs).unwrap();
+        let names: Vec<_> = file.structs.iter().map(|struct_| &*struct_.name).collect();
+        assert_eq!(names, ["P", "L", "M"]);
+    }
+
+    #[test]
+    fn test_duplicate_field() {
+        let source = "struct P { x: u8, x: u16 }";
+        validate_errors(source, |errors| {
+            assert_eq!(errors.len(), 1);
+            let Error::StructError {
+                err: StructError::DuplicateField { first, second },
+                ..
+            } = &errors[0]
+            else {
+                panic!("expected a duplicate field error")
+            };
+            assert_eq!(first.span.start.offset, 11);
+            assert_eq!(second.span.start.offset, 18);
+            let output = errors[0].pretty_print(source).to_string();
+            assert!(output.contains("x: u8"));
+            assert!(output.contains("x: u16"));
+        });
+    }
+
+    #[test]
+    fn test_duplicate_payload_item() {
+        validate_errors(
+            "protocol X { A(param1: u8, bool) from C to S; }",
+            |errors| {
+                assert_eq!(errors.len(), 1);
+                let Error::ProtocolError {
+                    err:
+                        ProtocolError::DuplicatePayloadItem {
+                            msg,
+                            first,
+                            second,
+                            name,
+                        },
+                    ..
+                } = &errors[0]
+                else {
+                    panic!("expected a duplicate payload item error")
+                };
+                assert_eq!(msg.inner.label, "A");
+                assert_eq!(name, "param1");
+                assert_eq!(first.inner.name.as_deref(), Some("param1"));
+                assert_eq!(second.inner.name, None);
+                assert_eq!(second.span.start.offset, 27);
+            },
+        );
+    }
+
+    #[test]
+    fn test_reserved_names() {
+        validate_errors("protocol X { A(u8, State: u8) from C to S; }", |errors| {
+            assert_eq!(errors.len(), 1);
+            assert!(matches!(
+                &errors[0],
+                Error::ProtocolError {
+                    err: ProtocolError::ReservedPayloadItem(msg),
+                    ..
+                } if msg.inner.label == "A"
+            ));
+        });
+        validate_errors(
+            "protocol X { par { fin { done from C to S; } } and { B from S to C; } }",
+            |errors| {
+                assert_eq!(errors.len(), 1);
+                assert!(matches!(
+                    &errors[0],
+                    Error::ProtocolError {
+                        err: ProtocolError::ReservedLabel(msg),
+                        ..
+                    } if msg.inner.label == "done"
+                ));
+            },
+        );
+    }
+
+    #[test]
+    fn test_repeated_label() {
+        validate_errors(
+            "protocol X { fin { X(u8) from C to S; } X from C to S; }",
+            |errors| {
+                assert_eq!(errors.len(), 1);
+                let Error::ProtocolError {
+                    err: ProtocolError::RepeatedLabel(msgs),
+                    ..
+                } = &errors[0]
+                else {
+                    panic!("expected a repeated label error")
+                };
+                assert_eq!(msgs.len(), 2);
+            },
+        );
+    }
+
+    #[test]
+    fn test_duplicate_message_id() {
+        validate_errors(
+            "protocol X { choice { A = 1 from C to S; } or { B = 1 from C to S; } }",
+            |errors| {
+                assert_eq!(errors.len(), 1);
+                let Error::ProtocolError {
+                    err: ProtocolError::DuplicateMessageId(msgs, 1),
+                    ..
+                } = &errors[0]
+                else {
+                    panic!("expected a duplicate message id error")
+                };
+                assert_eq!(msgs.len(), 2);
+            },
+        );
+    }
+
+    #[test]
+    fn test_label_collision() {
+        validate_errors(
+            "protocol X { choice { getValue from C to S; } or { get_value(u8) from C to S; } }",
+            |errors| {
+                assert_eq!(errors.len(), 1);
+                let Error::ProtocolError {
+                    err: ProtocolError::RepeatedLabel(msgs),
+                    ..
+                } = &errors[0]
+                else {
+                    panic!("expected a repeated label error")
+                };
+                assert_eq!(msgs.len(), 2);
+            },
+        );
+    }
+
+    #[test]
+    fn test_name_collisions() {
+        validate_errors(
+            "struct P { x_y: u8, xY: u16 } struct p { z: u8 }",
+            |errors| {
+                assert_eq!(errors.len(), 2);
+                assert!(matches!(
+                    errors[0],
+                    Error::StructError {
+                        err: StructError::DuplicateField { second, .. },
+                        ..
+                    } if second.inner.name == "xY"
+                ));
+                assert!(matches!(errors[1], Error::DuplicateStruct { .. }));
+            },
+        );
+        validate_errors("protocol X(role c, role C) { A from c to C; }", |errors| {
+            assert!(matches!(
+                &errors[0],
+                Error::ProtocolError {
+                    err: ProtocolError::DuplicateRole(name),
+                    ..
+                } if name == "c"
+            ));
+        });
+    }
+
+    fn message_ids(source: &str) -> (ast::IntType, Vec<(String, u64)>) {
+        let file = parse::<ast::File>(source).report();
+        let file_fsm = compile_protocol_file(&file, &Limits::default()).unwrap();
+        let file = validate_protocol_file(&file_fsm, &file.structs).unwrap();
+        let trans = file.protocols[0].states[0].trans.clone().unwrap();
+        let mut ids: Vec<_> = trans
+            .messages
+            .iter()
+            .map(|msg| (msg.label.clone(), msg.id))
+            .collect();
+        ids.sort();
+        (trans.tag, ids)
+    }
+
+    #[test]
+    fn test_implicit_message_ids() {
+        let ids = |source| message_ids(source).1;
+        let expected = vec![
+            ("A".to_string(), 0),
+            ("B".to_string(), 2),
+            ("C".to_string(), 1),
+        ];
+        assert_eq!(
+            ids("protocol X { choice { C from C to S; } or { B = 2 from C to S; } or { A from C to S; } }"),
+            expected
+        );
+        assert_eq!(
+            ids("protocol X { choice { A from C to S; } or { C from C to S; } or { B = 2 from C to S; } }"),
+            expected
+        );
+    }
+
+    #[test]
+    fn test_message_id_tag_type() {
+        let (tag, _) =
+            message_ids("protocol X { choice { A = 255 from C to S; } or { B from C to S; } }");
+        assert_eq!(tag.size, ast::IntSize::B8);
+        let (tag, _) =
+            message_ids("protocol X { choice { A = 256 from C to S; } or { B from C to S; } }");
+        assert_eq!(tag.size, ast::IntSize::B16);
+
+        let mut source = String::from("protocol X { choice { A0 from C to S; }");
+        for i in 1..300 {
+            source.push_str(&format!(" or {{ A{} from C to S; }}", i));
+        }
+        source.push_str(" }");
+        let (tag, ids) = message_ids(&source);
+        assert_eq!(tag.size, ast::IntSize::B16);
+        assert_eq!(ids.iter().map(|(_, id)| *id).max(), Some(299));
+    }
+
+    #[test]
+    fn test_duplicate_state_label() {
+        validate_errors(
+            "protocol X { state Y; A from C to S; state Y; B from C to S; }",
+            |errors| {
+                assert_eq!(errors.len(), 1);
+                let Error::ProtocolError {
+                    err: ProtocolError::DuplicateStateLabel(first, second),
+                    ..
+                } = &errors[0]
+                else {
+                    panic!("expected a duplicate state label error")
+                };
+                assert_ne!(first.span.start.offset, second.span.start.offset);
+            },
+        );
+    }
+}